@@ -0,0 +1,132 @@
+// Compares parsing a pipeline of commands into RESPValues, which copy every string, with parsing
+// it into Frames, which slice the read buffer, and with the path the server reads commands
+// through, which copies each argument once out of the buffer the reads accumulate in. Run with:
+//
+//     cargo run --release --example parse_bench
+use bytes::Buf;
+use bytes::Bytes;
+use bytes::BytesMut;
+use redis_starter_rust::parse_command;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::Frame;
+use redis_starter_rust::RESPValue;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::convert::TryInto;
+use std::hint::black_box;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Instant;
+
+const COMMANDS: usize = 100_000;
+const VALUE_LEN: usize = 100;
+// The size of the reads of the server from client sockets
+const READ_LEN: usize = 4096;
+
+// Counts the allocations made through the global allocator, reallocations included
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// SET commands as a client pipelines them
+fn pipeline() -> Bytes {
+    let value = "x".repeat(VALUE_LEN);
+    let mut buf = vec![];
+    for i in 0..COMMANDS {
+        let command = RESPValue::Array(Some(
+            ["SET".to_string(), format!("key:{}", i), value.clone()]
+                .iter()
+                .map(|s| RESPValue::bulk_string(Some(s.clone())))
+                .collect(),
+        ));
+        buf.extend(command.to_bytes());
+    }
+    Bytes::from(buf)
+}
+
+// Runs the parser, which returns the number of commands parsed, and reports what it cost
+fn measure(name: &str, parse: impl FnOnce() -> usize) {
+    ALLOCATIONS.store(0, Relaxed);
+    ALLOCATED_BYTES.store(0, Relaxed);
+    let started = Instant::now();
+    let commands = parse();
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Relaxed);
+    println!(
+        "{:<18} {} commands in {:?}, {} allocations ({:.1} per command), {} bytes allocated",
+        name,
+        commands,
+        elapsed,
+        allocations,
+        allocations as f64 / commands as f64,
+        allocated_bytes
+    );
+}
+
+fn main() {
+    let buf = pipeline();
+    println!("Parsing {} bytes of pipelined SET commands", buf.len());
+
+    measure("RESPValue::parse", || {
+        let mut bytes = &buf[..];
+        let mut commands = 0;
+        while !bytes.is_empty() {
+            let (value, rest) = RESPValue::parse(bytes).unwrap();
+            let command: Option<Vec<BulkString>> = black_box(value).try_into().unwrap();
+            black_box(command);
+            bytes = rest;
+            commands += 1;
+        }
+        commands
+    });
+
+    measure("Frame::parse", || {
+        let mut buf = buf.clone();
+        let mut commands = 0;
+        while !buf.is_empty() {
+            let (frame, len) = Frame::parse(&buf).unwrap();
+            black_box(frame);
+            buf.advance(len);
+            commands += 1;
+        }
+        commands
+    });
+
+    measure("server read path", || {
+        let mut read_buf = BytesMut::new();
+        let mut commands = 0;
+        for chunk in buf.chunks(READ_LEN) {
+            read_buf.extend_from_slice(chunk);
+            while let Ok((command, len)) = parse_command(&read_buf) {
+                black_box(command);
+                read_buf.advance(len);
+                commands += 1;
+            }
+        }
+        commands
+    });
+}
@@ -0,0 +1,947 @@
+// Users, their permissions and the AUTH and ACL commands. Like Redis, every connection runs
+// commands as a user, and connections start as the default user when it needs no password.
+use crate::commands;
+use crate::commands::Command;
+use crate::commands::CATEGORIES;
+use crate::commands::COMMANDS;
+use crate::glob;
+use crate::sha256::sha256;
+use crate::sha256::to_hex;
+use crate::strings::wrong_number_of_arguments;
+use crate::value;
+use crate::Server;
+use crate::Session;
+use redis_starter_rust::string_to_bytes;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub const DEFAULT_USER: &str = "default";
+// Denials of the same kind within this many milliseconds are counted in the same log entry
+const LOG_GROUPING_MILLIS: u64 = 60_000;
+const UNKNOWN_COMMAND: &str = "Unknown command or category name in ACL";
+
+#[derive(Clone, Debug, PartialEq)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        match (self.read, self.write) {
+            (true, true) => format!("~{}", self.pattern),
+            (true, false) => format!("%R~{}", self.pattern),
+            _ => format!("%W~{}", self.pattern),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct User {
+    enabled: bool,
+    nopass: bool,
+    // SHA-256 hashes, the passwords themselves are never kept
+    passwords: Vec<[u8; 32]>,
+    // Command rules in the order they were given, replayed to describe the user
+    command_rules: Vec<String>,
+    // Names of the command table entries the user can run
+    allowed: BTreeSet<&'static str>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+}
+
+// Why a command was refused
+#[derive(Debug, PartialEq)]
+enum Denial {
+    Command,
+    Key(String),
+    Channel(String),
+}
+
+impl User {
+    // What the default user is when nothing configures it: anyone can do anything
+    fn unrestricted() -> Self {
+        let mut user = User::default();
+        for rule in ["on", "nopass", "~*", "&*", "+@all"] {
+            let _ = user.apply(rule);
+        }
+        user
+    }
+
+    // Applies one of ACL SETUSER's rules, the error tells what's wrong with it
+    fn apply(&mut self, rule: &str) -> Result<(), &'static str> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.passwords.clear();
+                self.nopass = true;
+            }
+            "resetpass" => {
+                self.passwords.clear();
+                self.nopass = false;
+            }
+            "allkeys" => return self.apply("~*"),
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => return self.apply("&*"),
+            "resetchannels" => self.channels.clear(),
+            "allcommands" => return self.apply("+@all"),
+            "nocommands" => return self.apply("-@all"),
+            "reset" => {
+                for rule in ["resetpass", "resetkeys", "resetchannels", "off", "-@all"] {
+                    self.apply(rule)?;
+                }
+            }
+            _ => return self.apply_pattern_rule(rule),
+        }
+        Ok(())
+    }
+
+    fn apply_pattern_rule(&mut self, rule: &str) -> Result<(), &'static str> {
+        if let Some(password) = rule.strip_prefix('>') {
+            let hash = sha256(&string_to_bytes(password));
+            if !self.passwords.contains(&hash) {
+                self.passwords.push(hash);
+            }
+            self.nopass = false;
+        } else if let Some(password) = rule.strip_prefix('<') {
+            self.remove_password(sha256(&string_to_bytes(password)))?;
+        } else if let Some(hash) = rule.strip_prefix('#') {
+            let hash = parse_hash(hash)?;
+            if !self.passwords.contains(&hash) {
+                self.passwords.push(hash);
+            }
+            self.nopass = false;
+        } else if let Some(hash) = rule.strip_prefix('!') {
+            self.remove_password(parse_hash(hash)?)?;
+        } else if let Some(pattern) = rule.strip_prefix('~') {
+            self.add_key_pattern(pattern, true, true)?;
+        } else if let Some(rest) = rule.strip_prefix('%') {
+            let (permissions, pattern) = rest.split_once('~').ok_or("Syntax error")?;
+            let permissions = permissions.to_uppercase();
+            if permissions.is_empty() || permissions.chars().any(|c| c != 'R' && c != 'W') {
+                return Err("Syntax error");
+            }
+            self.add_key_pattern(
+                pattern,
+                permissions.contains('R'),
+                permissions.contains('W'),
+            )?;
+        } else if let Some(pattern) = rule.strip_prefix('&') {
+            if self.channels.iter().any(|p| p == "*") && pattern != "*" {
+                return Err("Adding a pattern after the * pattern (or the 'allchannels' flag) is not valid and does not have any effect. Try 'resetchannels' to start with an empty list of channels");
+            }
+            if pattern == "*" {
+                self.channels.clear();
+            }
+            if !self.channels.iter().any(|p| p == pattern) {
+                self.channels.push(pattern.to_string());
+            }
+        } else if rule.starts_with('+') || rule.starts_with('-') {
+            self.apply_command_rule(rule)?;
+        } else {
+            return Err("Syntax error");
+        }
+        Ok(())
+    }
+
+    fn remove_password(&mut self, hash: [u8; 32]) -> Result<(), &'static str> {
+        match self.passwords.iter().position(|h| *h == hash) {
+            Some(i) => {
+                self.passwords.remove(i);
+                Ok(())
+            }
+            None => Err("The password you are trying to remove from the user does not exist"),
+        }
+    }
+
+    fn add_key_pattern(
+        &mut self,
+        pattern: &str,
+        read: bool,
+        write: bool,
+    ) -> Result<(), &'static str> {
+        let all_keys = |p: &KeyPattern| p.pattern == "*" && p.read && p.write;
+        if self.keys.iter().any(all_keys) {
+            return Err("Adding a pattern after the * pattern (or the 'allkeys' flag) is not valid and does not have any effect. Try 'resetkeys' to start with an empty list of patterns");
+        }
+        let key_pattern = KeyPattern {
+            pattern: pattern.to_string(),
+            read,
+            write,
+        };
+        if all_keys(&key_pattern) {
+            self.keys.clear();
+        }
+        if !self.keys.contains(&key_pattern) {
+            self.keys.push(key_pattern);
+        }
+        Ok(())
+    }
+
+    // +command, -command, +command|subcommand, +@category and -@category
+    fn apply_command_rule(&mut self, rule: &str) -> Result<(), &'static str> {
+        let allow = rule.starts_with('+');
+        let name = rule[1..].to_lowercase();
+        let affected: Vec<&'static str> = match name.strip_prefix('@') {
+            Some("all") => COMMANDS.iter().map(|c| c.name).collect(),
+            Some(category) if CATEGORIES.contains(&category) => COMMANDS
+                .iter()
+                .filter(|c| c.has_category(category))
+                .map(|c| c.name)
+                .collect(),
+            Some(_) => return Err(UNKNOWN_COMMAND),
+            None => {
+                let command = commands::find(&name).ok_or(UNKNOWN_COMMAND)?;
+                // A command includes its subcommands
+                COMMANDS
+                    .iter()
+                    .filter(|c| {
+                        c.name == command.name
+                            || c.name
+                                .strip_prefix(command.name)
+                                .is_some_and(|s| s.starts_with('|'))
+                    })
+                    .map(|c| c.name)
+                    .collect()
+            }
+        };
+        for command in affected {
+            if allow {
+                self.allowed.insert(command);
+            } else {
+                self.allowed.remove(command);
+            }
+        }
+        // +@all and -@all override every rule before them
+        if name == "@all" {
+            self.command_rules.clear();
+            if allow {
+                self.command_rules.push("+@all".to_string());
+            }
+        } else {
+            self.command_rules
+                .push(format!("{}{}", if allow { '+' } else { '-' }, name));
+        }
+        Ok(())
+    }
+
+    fn describe_commands(&self) -> String {
+        let mut rules = self.command_rules.clone();
+        if rules.first().map(|r| r.as_str()) != Some("+@all") {
+            rules.insert(0, "-@all".to_string());
+        }
+        rules.join(" ")
+    }
+
+    fn describe_keys(&self) -> String {
+        let keys: Vec<String> = self.keys.iter().map(|p| p.describe()).collect();
+        keys.join(" ")
+    }
+
+    fn describe_channels(&self) -> String {
+        let channels: Vec<String> = self.channels.iter().map(|p| format!("&{}", p)).collect();
+        channels.join(" ")
+    }
+
+    // The user as a line of ACL LIST and of the ACL file
+    fn describe(&self, name: &str) -> String {
+        let mut parts = vec![
+            "user".to_string(),
+            name.to_string(),
+            if self.enabled { "on" } else { "off" }.to_string(),
+        ];
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        parts.extend(self.passwords.iter().map(|h| format!("#{}", to_hex(h))));
+        parts.extend(self.keys.iter().map(|p| p.describe()));
+        if self.channels.is_empty() {
+            parts.push("resetchannels".to_string());
+        } else {
+            parts.push(self.describe_channels());
+        }
+        parts.push(self.describe_commands());
+        parts.join(" ")
+    }
+
+    fn check_password(&self, password: &str) -> bool {
+        self.enabled
+            && (self.nopass || self.passwords.contains(&sha256(&string_to_bytes(password))))
+    }
+
+    // Keys are checked for reading unless the command writes, in which case it needs both
+    fn check(&self, command: &Command, args: &[&str]) -> Option<Denial> {
+        if !self.allowed.contains(command.name) {
+            return Some(Denial::Command);
+        }
+        let write = command.has_category("write");
+        let key = command.keys(args).into_iter().find(|key| {
+            !self
+                .keys
+                .iter()
+                .any(|p| p.read && (p.write || !write) && glob::matches(&p.pattern, key, false))
+        });
+        if let Some(key) = key {
+            return Some(Denial::Key(key.to_string()));
+        }
+        self.check_channels(command, args)
+    }
+
+    // Channels have to match one of the patterns, while subscribing to a pattern requires
+    // that very pattern to be allowed, unless every channel is
+    fn check_channels(&self, command: &Command, args: &[&str]) -> Option<Denial> {
+        let (channels, literal) = match command.name {
+            "publish" => (args.get(1..2).unwrap_or_default(), false),
+            "subscribe" => (args.get(1..).unwrap_or_default(), false),
+            "psubscribe" => (args.get(1..).unwrap_or_default(), true),
+            _ => return None,
+        };
+        channels
+            .iter()
+            .find(|channel| {
+                !self.channels.iter().any(|p| {
+                    p == "*"
+                        || if literal {
+                            p == *channel
+                        } else {
+                            glob::matches(p, channel, false)
+                        }
+                })
+            })
+            .map(|channel| Denial::Channel(channel.to_string()))
+    }
+}
+
+fn parse_hash(hash: &str) -> Result<[u8; 32], &'static str> {
+    let invalid = "The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters";
+    if hash.len() != 64
+        || !hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        return Err(invalid);
+    }
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16).map_err(|_| invalid)?;
+    }
+    Ok(bytes)
+}
+
+struct LogEntry {
+    id: u64,
+    count: u64,
+    reason: &'static str,
+    context: &'static str,
+    object: String,
+    username: String,
+    client_info: String,
+    created: u64,
+    updated: u64,
+}
+
+#[derive(Default)]
+struct Log {
+    // Newest first
+    entries: VecDeque<LogEntry>,
+    next_id: u64,
+}
+
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+    log: Mutex<Log>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self {
+            users: RwLock::new(BTreeMap::from([(
+                DEFAULT_USER.to_string(),
+                User::unrestricted(),
+            )])),
+            log: Mutex::new(Log::default()),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl Acl {
+    fn users(&self) -> Result<RwLockReadGuard<'_, BTreeMap<String, User>>, String> {
+        self.users
+            .read()
+            .map_err(|e| format!("Failed to acquire lock for users {}", e))
+    }
+
+    fn users_mut(&self) -> Result<RwLockWriteGuard<'_, BTreeMap<String, User>>, String> {
+        self.users
+            .write()
+            .map_err(|e| format!("Failed to acquire lock for users {}", e))
+    }
+
+    // The user new connections are authenticated as, None when they have to AUTH first
+    pub fn initial_user(&self) -> Result<Option<String>, String> {
+        Ok(self
+            .users()?
+            .get(DEFAULT_USER)
+            .filter(|user| user.enabled && user.nopass)
+            .map(|_| DEFAULT_USER.to_string()))
+    }
+
+    // Makes the password the only one of the default user, or lets anyone in when it's empty,
+    // which is what requirepass does
+    pub fn set_requirepass(&self, password: &str) -> Result<(), String> {
+        let mut users = self.users_mut()?;
+        let user = users
+            .entry(DEFAULT_USER.to_string())
+            .or_insert_with(User::unrestricted);
+        let _ = user.apply("resetpass");
+        let _ = user.apply(&if password.is_empty() {
+            "nopass".to_string()
+        } else {
+            format!(">{}", password)
+        });
+        Ok(())
+    }
+
+    // Replaces the users with the ones of the ACL file, changing nothing if it has errors.
+    // Returns the users that no longer exist.
+    pub fn load_file(&self, file: &Path) -> Result<Vec<String>, String> {
+        let contents = fs::read_to_string(file).map_err(|e| {
+            format!(
+                "Error loading ACLs, opening file '{}': {}",
+                file.display(),
+                e
+            )
+        })?;
+        let mut users = BTreeMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |reason: &str| {
+                format!(
+                    "{}:{}: {}. WARNING: ACL errors detected, no change to the previously active ACL rules was performed",
+                    file.display(),
+                    i + 1,
+                    reason
+                )
+            };
+            let mut words = line.split_whitespace();
+            if words.next() != Some("user") {
+                return Err(error("line should start with user keyword"));
+            }
+            let name = words.next().ok_or_else(|| error("missing user name"))?;
+            if users.contains_key(name) {
+                return Err(error(&format!("Duplicate user '{}' found", name)));
+            }
+            let mut user = User::default();
+            for rule in words {
+                user.apply(rule)
+                    .map_err(|e| error(&format!("Error in user declaration '{}': {}", rule, e)))?;
+            }
+            users.insert(name.to_string(), user);
+        }
+        // The default user is kept as it is when the file doesn't declare it
+        let mut current = self.users_mut()?;
+        let default_user = current.get(DEFAULT_USER).cloned();
+        users
+            .entry(DEFAULT_USER.to_string())
+            .or_insert_with(|| default_user.unwrap_or_else(User::unrestricted));
+        let removed = current
+            .keys()
+            .filter(|name| !users.contains_key(*name))
+            .cloned()
+            .collect();
+        *current = users;
+        Ok(removed)
+    }
+
+    pub fn save_file(&self, file: &Path) -> Result<(), String> {
+        let contents: String = self
+            .users()?
+            .iter()
+            .map(|(name, user)| format!("{}\n", user.describe(name)))
+            .collect();
+        // Written to a temporary file first so that a failure can't leave a truncated file behind
+        let temporary = file.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&temporary, contents)
+            .and_then(|_| fs::rename(&temporary, file))
+            .map_err(|e| {
+                let _ = fs::remove_file(&temporary);
+                format!("Failed to save ACLs to '{}': {}", file.display(), e)
+            })
+    }
+
+    // Adds a denial to ACL LOG, counting it with a recent identical one
+    fn log(
+        &self,
+        reason: &'static str,
+        context: &'static str,
+        object: &str,
+        username: &str,
+        client_info: String,
+        max_len: usize,
+    ) -> Result<(), String> {
+        let mut log = self
+            .log
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for ACL log {}", e))?;
+        let now = now_millis();
+        let similar = log.entries.iter_mut().find(|e| {
+            e.reason == reason
+                && e.context == context
+                && e.object == object
+                && e.username == username
+                && now.saturating_sub(e.updated) < LOG_GROUPING_MILLIS
+        });
+        if let Some(entry) = similar {
+            entry.count += 1;
+            entry.updated = now;
+            entry.client_info = client_info;
+            return Ok(());
+        }
+        let id = log.next_id;
+        log.next_id += 1;
+        log.entries.push_front(LogEntry {
+            id,
+            count: 1,
+            reason,
+            context,
+            object: object.to_string(),
+            username: username.to_string(),
+            client_info,
+            created: now,
+            updated: now,
+        });
+        log.entries.truncate(max_len);
+        Ok(())
+    }
+}
+
+// Returns the error to reply with when the session can't run the command. `context` is where
+// the command comes from, as ACL LOG reports it.
+pub fn check(
+    args: &[&str],
+    session: &Session,
+    context: &'static str,
+    server: &Server,
+) -> Result<Option<RESPValue>, String> {
+    let username = match &session.user {
+        Some(user) => user,
+        None if args[0].eq_ignore_ascii_case("AUTH") => return Ok(None),
+        None => {
+            return Ok(Some(RESPValue::error(
+                "NOAUTH Authentication required.".to_string(),
+            )))
+        }
+    };
+    // Unknown commands are refused later on
+    let command = match commands::lookup(args) {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+    // A deleted user can't do anything until its connections are closed
+    let denial = match server.acl.users()?.get(username) {
+        Some(user) => user.check(command, args),
+        None => Some(Denial::Command),
+    };
+    let (reason, object, error) = match denial {
+        None => return Ok(None),
+        Some(Denial::Command) => (
+            "command",
+            command.name.to_string(),
+            format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                username, command.name
+            ),
+        ),
+        Some(Denial::Key(key)) => (
+            "key",
+            key,
+            "NOPERM No permissions to access a key".to_string(),
+        ),
+        Some(Denial::Channel(channel)) => (
+            "channel",
+            channel,
+            "NOPERM No permissions to access a channel".to_string(),
+        ),
+    };
+    let client_info = client_info(session, server)?;
+    server.acl.log(
+        reason,
+        context,
+        &object,
+        username,
+        client_info,
+        server.config()?.acllog_max_len,
+    )?;
+    Ok(Some(RESPValue::error(error)))
+}
+
+fn client_info(session: &Session, server: &Server) -> Result<String, String> {
+    let info = server.clients.info(session.client_id)?.unwrap_or_default();
+    Ok(info.trim_end().to_string())
+}
+
+pub fn auth(
+    args: &[BulkString],
+    session: &mut Session,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let args = match Args::new("auth", args).rest() {
+        Ok(args) => args,
+        Err(e) => return Ok(e.into()),
+    };
+    let (username, password) = match args[..] {
+        [password] => {
+            let default_nopass = server
+                .acl
+                .users()?
+                .get(DEFAULT_USER)
+                .is_some_and(|user| user.nopass);
+            if default_nopass {
+                return Ok(RESPValue::error("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string()));
+            }
+            (DEFAULT_USER.to_string(), password.to_string())
+        }
+        [username, password] => (username.to_string(), password.to_string()),
+        _ => return Ok(wrong_number_of_arguments("auth")),
+    };
+    let valid = server
+        .acl
+        .users()?
+        .get(&username)
+        .is_some_and(|user| user.check_password(&password));
+    if !valid {
+        let client_info = client_info(session, server)?;
+        server.acl.log(
+            "auth",
+            "toplevel",
+            "AUTH",
+            &username,
+            client_info,
+            server.config()?.acllog_max_len,
+        )?;
+        return Ok(RESPValue::error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+        ));
+    }
+    session.user = Some(username);
+    server.clients.record_user(session)?;
+    Ok(RESPValue::simple_string("OK".to_string()))
+}
+
+pub fn acl_command(
+    args: &[BulkString],
+    session: &Session,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let mut args = Args::new("acl", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    let ok = || Ok(RESPValue::simple_string("OK".to_string()));
+    let bulk = |s: &str| RESPValue::bulk_string(Some(s.to_string()));
+    match (subcommand.as_str(), &args[..]) {
+        ("WHOAMI", []) => Ok(bulk(session.user.as_deref().unwrap_or(DEFAULT_USER))),
+        ("USERS", []) => Ok(RESPValue::Array(Some(
+            server.acl.users()?.keys().map(|name| bulk(name)).collect(),
+        ))),
+        ("LIST", []) => Ok(RESPValue::Array(Some(
+            server
+                .acl
+                .users()?
+                .iter()
+                .map(|(name, user)| bulk(&user.describe(name)))
+                .collect(),
+        ))),
+        ("SETUSER", [name, rules @ ..]) => {
+            if name.contains(|c: char| c.is_whitespace() || c == '\0') {
+                return Ok(RESPValue::error(
+                    "ERR Usernames can't contain spaces or null characters".to_string(),
+                ));
+            }
+            let mut users = server.acl.users_mut()?;
+            // Rules are applied to a copy so that an invalid one changes nothing
+            let mut user = users.get(*name).cloned().unwrap_or_default();
+            for rule in rules {
+                if let Err(e) = user.apply(rule) {
+                    return Ok(RESPValue::error(format!(
+                        "ERR Error in ACL SETUSER modifier '{}': {}",
+                        rule, e
+                    )));
+                }
+            }
+            users.insert(name.to_string(), user);
+            ok()
+        }
+        ("GETUSER", [name]) => Ok(match server.acl.users()?.get(*name) {
+            Some(user) => getuser(user),
+            None => RESPValue::Array(None),
+        }),
+        ("DELUSER", names) if !names.is_empty() => {
+            if names.contains(&DEFAULT_USER) {
+                return Ok(RESPValue::error(
+                    "ERR The 'default' user cannot be removed".to_string(),
+                ));
+            }
+            let mut deleted = 0;
+            let mut users = server.acl.users_mut()?;
+            for name in names {
+                if users.remove(*name).is_some() {
+                    server.clients.kill_user(name)?;
+                    deleted += 1;
+                }
+            }
+            Ok(RESPValue::integer(deleted))
+        }
+        ("CAT", []) => Ok(RESPValue::Array(Some(
+            CATEGORIES.iter().map(|c| bulk(c)).collect(),
+        ))),
+        ("CAT", [category]) => {
+            let category = category.to_lowercase();
+            if !CATEGORIES.contains(&category.as_str()) {
+                return Ok(RESPValue::error(format!(
+                    "ERR Unknown category '{}'",
+                    category
+                )));
+            }
+            Ok(RESPValue::Array(Some(
+                COMMANDS
+                    .iter()
+                    .filter(|c| c.has_category(&category))
+                    .map(|c| bulk(c.name))
+                    .collect(),
+            )))
+        }
+        ("LOG", []) => acl_log(server, 10),
+        ("LOG", [option]) if option.eq_ignore_ascii_case("RESET") => {
+            server
+                .acl
+                .log
+                .lock()
+                .map_err(|e| format!("Failed to acquire lock for ACL log {}", e))?
+                .entries
+                .clear();
+            ok()
+        }
+        ("LOG", [count]) => match value::parse_integer(count) {
+            Some(count) if count >= 0 => acl_log(server, count as usize),
+            _ => Ok(RESPValue::error(
+                "ERR value is out of range, must be positive".to_string(),
+            )),
+        },
+        ("LOAD", []) | ("SAVE", []) => {
+            let file = server.config()?.aclfile;
+            if file.is_empty() {
+                return Ok(RESPValue::error("ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) in order to store users in the Redis configuration.".to_string()));
+            }
+            let result = if subcommand == "LOAD" {
+                server.acl.load_file(Path::new(&file)).and_then(|removed| {
+                    for name in removed {
+                        server.clients.kill_user(&name)?;
+                    }
+                    Ok(())
+                })
+            } else {
+                server.acl.save_file(Path::new(&file))
+            };
+            match result {
+                Ok(()) => ok(),
+                Err(e) => Ok(RESPValue::error(format!("ERR {}", e))),
+            }
+        }
+        ("WHOAMI", _)
+        | ("USERS", _)
+        | ("LIST", _)
+        | ("SETUSER", _)
+        | ("GETUSER", _)
+        | ("DELUSER", _)
+        | ("CAT", _)
+        | ("LOG", _)
+        | ("LOAD", _)
+        | ("SAVE", _) => Ok(wrong_number_of_arguments(&format!(
+            "acl|{}",
+            subcommand.to_lowercase()
+        ))),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'. Try ACL HELP.",
+            s.to_lowercase()
+        ))),
+    }
+}
+
+fn getuser(user: &User) -> RESPValue {
+    let bulk = |s: &str| RESPValue::bulk_string(Some(s.to_string()));
+    let mut flags = vec![bulk(if user.enabled { "on" } else { "off" })];
+    if user.nopass {
+        flags.push(bulk("nopass"));
+    }
+    RESPValue::Array(Some(vec![
+        bulk("flags"),
+        RESPValue::Array(Some(flags)),
+        bulk("passwords"),
+        RESPValue::Array(Some(
+            user.passwords.iter().map(|h| bulk(&to_hex(h))).collect(),
+        )),
+        bulk("commands"),
+        bulk(&user.describe_commands()),
+        bulk("keys"),
+        bulk(&user.describe_keys()),
+        bulk("channels"),
+        bulk(&user.describe_channels()),
+        bulk("selectors"),
+        RESPValue::Array(Some(vec![])),
+    ]))
+}
+
+fn acl_log(server: &Server, count: usize) -> Result<RESPValue, String> {
+    let bulk = |s: &str| RESPValue::bulk_string(Some(s.to_string()));
+    let log = server
+        .acl
+        .log
+        .lock()
+        .map_err(|e| format!("Failed to acquire lock for ACL log {}", e))?;
+    let now = now_millis();
+    Ok(RESPValue::Array(Some(
+        log.entries
+            .iter()
+            .take(count)
+            .map(|e| {
+                let age = now.saturating_sub(e.created) as f64 / 1000.0;
+                RESPValue::Array(Some(vec![
+                    bulk("count"),
+                    RESPValue::integer(e.count as i64),
+                    bulk("reason"),
+                    bulk(e.reason),
+                    bulk("context"),
+                    bulk(e.context),
+                    bulk("object"),
+                    bulk(&e.object),
+                    bulk("username"),
+                    bulk(&e.username),
+                    bulk("age-seconds"),
+                    bulk(&format!("{:.3}", age)),
+                    bulk("client-info"),
+                    bulk(&e.client_info),
+                    bulk("entry-id"),
+                    RESPValue::integer(e.id as i64),
+                    bulk("timestamp-created"),
+                    RESPValue::integer(e.created as i64),
+                    bulk("timestamp-last-updated"),
+                    RESPValue::integer(e.updated as i64),
+                ]))
+            })
+            .collect(),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::default();
+        for rule in rules {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    #[test]
+    fn test_rules() {
+        let alice = user(&[
+            "on",
+            ">secret",
+            "~cache:*",
+            "%R~shared:*",
+            "+@read",
+            "-keys",
+        ]);
+        assert_eq!(
+            alice.describe("alice"),
+            format!(
+                "user alice on #{} ~cache:* %R~shared:* resetchannels -@all +@read -keys",
+                to_hex(&sha256(b"secret"))
+            )
+        );
+        assert!(alice.check_password("secret"));
+        assert!(!alice.check_password("wrong"));
+        assert_eq!(
+            User::unrestricted().describe("default"),
+            "user default on nopass ~* &* +@all"
+        );
+
+        let mut bob = user(&["+@all", "-@dangerous", "nopass", "off"]);
+        assert!(!bob.check_password(""));
+        assert_eq!(bob.describe_commands(), "+@all -@dangerous");
+        assert_eq!(bob.apply("+@nope"), Err(UNKNOWN_COMMAND));
+        assert_eq!(bob.apply("+nope"), Err(UNKNOWN_COMMAND));
+        assert_eq!(bob.apply("#abc"), Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters"));
+        assert_eq!(
+            bob.apply("<nope"),
+            Err("The password you are trying to remove from the user does not exist")
+        );
+        assert!(user(&["allkeys"]).apply("~a").is_err());
+        bob.apply("reset").unwrap();
+        assert_eq!(bob.describe("bob"), "user bob off resetchannels -@all");
+    }
+
+    #[test]
+    fn test_check() {
+        let alice = user(&["~cache:*", "%R~shared:*", "+@read", "+set", "+config|get"]);
+        let check = |args: &[&str]| alice.check(commands::lookup(args).unwrap(), args);
+        assert_eq!(check(&["GET", "cache:1"]), None);
+        assert_eq!(check(&["GET", "shared:1"]), None);
+        assert_eq!(
+            check(&["SET", "shared:1", "x"]),
+            Some(Denial::Key("shared:1".to_string()))
+        );
+        assert_eq!(
+            check(&["MGET", "cache:1", "other"]),
+            Some(Denial::Key("other".to_string()))
+        );
+        assert_eq!(check(&["INCR", "cache:1"]), Some(Denial::Command));
+        assert_eq!(check(&["CONFIG", "GET", "port"]), None);
+        assert_eq!(
+            check(&["CONFIG", "SET", "port", "1"]),
+            Some(Denial::Command)
+        );
+        assert_eq!(check(&["KEYS", "*"]), None);
+
+        let bob = user(&["&news.*", "+@pubsub"]);
+        let check = |args: &[&str]| bob.check(commands::lookup(args).unwrap(), args);
+        assert_eq!(check(&["PUBLISH", "news.tech", "hi"]), None);
+        assert_eq!(
+            check(&["SUBSCRIBE", "news.tech", "sports"]),
+            Some(Denial::Channel("sports".to_string()))
+        );
+        assert_eq!(check(&["PSUBSCRIBE", "news.*"]), None);
+        assert_eq!(
+            check(&["PSUBSCRIBE", "news.t*"]),
+            Some(Denial::Channel("news.t*".to_string()))
+        );
+    }
+}
@@ -0,0 +1,643 @@
+// Bit level commands operating on string values. Bit 0 is the most significant bit of the first byte.
+use crate::dict::Keyspace;
+use crate::get_live_entry;
+use crate::not_an_integer;
+use crate::strings::get_string;
+use crate::strings::set_string;
+use crate::strings::syntax_error;
+use crate::strings::MAX_STRING_LEN;
+use crate::value;
+use crate::value::Value;
+use crate::write_table;
+use crate::Table;
+use redis_starter_rust::bytes_to_string;
+use redis_starter_rust::string_to_bytes;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+
+const MAX_BIT_OFFSET: u64 = MAX_STRING_LEN as u64 * 8 - 1;
+
+fn bit_offset_error() -> RESPValue {
+    RESPValue::error("ERR bit offset is not an integer or out of range".to_string())
+}
+
+fn get_bytes(t: &mut Keyspace, key: &str) -> Result<Vec<u8>, RESPValue> {
+    get_string(t, key).map(|s| string_to_bytes(&s.unwrap_or_default()))
+}
+
+// Stores the bytes keeping the key's expiry, deleting the key if there are none
+fn put_bytes(t: &mut Keyspace, key: &str, bytes: &[u8]) {
+    if bytes.is_empty() {
+        t.remove(key);
+        return;
+    }
+    match get_live_entry(t, key) {
+        Some((value, _, _)) => *value = Value::from_string(bytes_to_string(bytes)),
+        None => set_string(t, key, bytes_to_string(bytes), None),
+    }
+}
+
+fn get_bit(bytes: &[u8], offset: u64) -> u8 {
+    match bytes.get((offset / 8) as usize) {
+        Some(byte) => (byte >> (7 - offset % 8)) & 1,
+        None => 0,
+    }
+}
+
+// The caller makes sure `bytes` is long enough
+fn set_bit(bytes: &mut [u8], offset: u64, bit: u8) {
+    let mask = 1 << (7 - offset % 8);
+    let byte = &mut bytes[(offset / 8) as usize];
+    if bit == 1 {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+}
+
+fn grow_for_bit(bytes: &mut Vec<u8>, offset: u64) {
+    let len = (offset / 8) as usize + 1;
+    if bytes.len() < len {
+        bytes.resize(len, 0);
+    }
+}
+
+fn parse_bit_offset(s: &str) -> Option<u64> {
+    value::parse_integer(s)
+        .filter(|&o| o >= 0 && o as u64 <= MAX_BIT_OFFSET)
+        .map(|o| o as u64)
+}
+
+pub fn setbit(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let parsed = Args::new("setbit", args)
+        .parse(|args| Ok((args.next_key()?, args.next_str()?, args.next_str()?)));
+    let (key, offset, bit) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let offset = match parse_bit_offset(offset) {
+        Some(o) => o,
+        None => return Ok(bit_offset_error()),
+    };
+    let bit = match bit {
+        "0" => 0,
+        "1" => 1,
+        _ => {
+            return Ok(RESPValue::error(
+                "ERR bit is not an integer or out of range".to_string(),
+            ))
+        }
+    };
+
+    let mut t = write_table(&table)?;
+    let mut bytes = match get_bytes(&mut t, key) {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok(e),
+    };
+    grow_for_bit(&mut bytes, offset);
+    let old_bit = get_bit(&bytes, offset);
+    set_bit(&mut bytes, offset, bit);
+    put_bytes(&mut t, key, &bytes);
+    Ok(RESPValue::integer(old_bit as i64))
+}
+
+pub fn getbit(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let parsed = Args::new("getbit", args).parse(|args| Ok((args.next_key()?, args.next_str()?)));
+    let (key, offset) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let offset = match parse_bit_offset(offset) {
+        Some(o) => o,
+        None => return Ok(bit_offset_error()),
+    };
+    let mut t = write_table(&table)?;
+    let bytes = match get_bytes(&mut t, key) {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok(e),
+    };
+    Ok(RESPValue::integer(get_bit(&bytes, offset) as i64))
+}
+
+// Resolves a start/end pair given in BYTE or BIT units into an inclusive bit range,
+// following the same negative index and clamping rules as GETRANGE
+fn bit_range(start: i64, end: i64, len_bytes: usize, bit_units: bool) -> Option<(u64, u64)> {
+    let len = if bit_units {
+        len_bytes as i64 * 8
+    } else {
+        len_bytes as i64
+    };
+    let (mut start, mut end) = (start, end);
+    if start < 0 {
+        start += len;
+    }
+    if end < 0 {
+        end += len;
+    }
+    start = start.max(0);
+    end = end.max(0).min(len - 1);
+    if len == 0 || start > end {
+        return None;
+    }
+    Some(if bit_units {
+        (start as u64, end as u64)
+    } else {
+        (start as u64 * 8, end as u64 * 8 + 7)
+    })
+}
+
+// Parses an optional trailing BYTE|BIT unit
+fn parse_unit(unit: Option<&&str>) -> Option<bool> {
+    match unit {
+        None => Some(false),
+        Some(u) if u.eq_ignore_ascii_case("BYTE") => Some(false),
+        Some(u) if u.eq_ignore_ascii_case("BIT") => Some(true),
+        Some(_) => None,
+    }
+}
+
+fn count_bits(bytes: &[u8], start: u64, end: u64) -> u64 {
+    let (first, last) = ((start / 8) as usize, (end / 8) as usize);
+    let mut count: u32 = bytes[first..=last].iter().map(|b| b.count_ones()).sum();
+    // Take out the bits before `start` in the first byte and after `end` in the last one
+    let leading = start % 8;
+    let trailing = 7 - end % 8;
+    count -= ((bytes[first] as u16) >> (8 - leading)).count_ones();
+    count -= ((bytes[last] as u16) & ((1 << trailing) - 1)).count_ones();
+    count as u64
+}
+
+pub fn bitcount(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("bitcount", args);
+    let (key, options) = match args.next_key().and_then(|key| Ok((key, args.rest()?))) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let range = match &options[..] {
+        [] => None,
+        [start, end, unit @ ..] if unit.len() <= 1 => {
+            let bit_units = match parse_unit(unit.first()) {
+                Some(b) => b,
+                None => return Ok(syntax_error()),
+            };
+            match (value::parse_integer(start), value::parse_integer(end)) {
+                (Some(start), Some(end)) => Some((start, end, bit_units)),
+                _ => return Ok(not_an_integer()),
+            }
+        }
+        _ => return Ok(syntax_error()),
+    };
+
+    let mut t = write_table(&table)?;
+    let bytes = match get_bytes(&mut t, key) {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok(e),
+    };
+    let (start, end, bit_units) = range.unwrap_or((0, -1, false));
+    let count = match bit_range(start, end, bytes.len(), bit_units) {
+        Some((start, end)) => count_bits(&bytes, start, end),
+        None => 0,
+    };
+    Ok(RESPValue::integer(count as i64))
+}
+
+pub fn bitpos(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("bitpos", args);
+    let parsed = args
+        .next_key()
+        .and_then(|key| Ok((key, args.next_str()?, args.rest()?)));
+    let (key, bit, options) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let bit = match bit {
+        "0" => 0,
+        "1" => 1,
+        _ => {
+            return Ok(RESPValue::error(
+                "ERR The bit argument must be 1 or 0.".to_string(),
+            ))
+        }
+    };
+    if options.len() > 3 {
+        return Ok(syntax_error());
+    }
+    let bit_units = match parse_unit(options.get(2)) {
+        Some(b) => b,
+        None => return Ok(syntax_error()),
+    };
+    let mut bounds = vec![];
+    for bound in options.iter().take(2) {
+        match value::parse_integer(bound) {
+            Some(b) => bounds.push(b),
+            None => return Ok(not_an_integer()),
+        }
+    }
+    let end_given = bounds.len() == 2;
+
+    let mut t = write_table(&table)?;
+    if get_live_entry(&mut t, key).is_none() {
+        return Ok(RESPValue::integer(if bit == 1 { -1 } else { 0 }));
+    }
+    let bytes = match get_bytes(&mut t, key) {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok(e),
+    };
+    let start = bounds.first().copied().unwrap_or(0);
+    let end = bounds.get(1).copied().unwrap_or(-1);
+    let (start, end) = match bit_range(start, end, bytes.len(), bit_units) {
+        Some(range) => range,
+        None => return Ok(RESPValue::integer(-1)),
+    };
+    let position = (start..=end).find(|&offset| get_bit(&bytes, offset) == bit);
+    Ok(RESPValue::integer(match position {
+        Some(p) => p as i64,
+        // Without an explicit end the string is considered to be padded with zeros on the right
+        None if bit == 0 && !end_given => end as i64 + 1,
+        None => -1,
+    }))
+}
+
+pub fn bitop(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("bitop", args);
+    let parsed = args
+        .next_str()
+        .and_then(|op| Ok((op.to_uppercase(), args.next_key()?, args.rest_non_empty()?)));
+    let (op, dest, sources) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    if !["AND", "OR", "XOR", "NOT"].contains(&op.as_str()) {
+        return Ok(syntax_error());
+    }
+    if op == "NOT" && sources.len() != 1 {
+        return Ok(RESPValue::error(
+            "ERR BITOP NOT must be called with a single source key.".to_string(),
+        ));
+    }
+
+    let mut t = write_table(&table)?;
+    let inputs = match sources
+        .iter()
+        .map(|k| get_bytes(&mut t, k))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(inputs) => inputs,
+        Err(e) => return Ok(e),
+    };
+    let len = inputs.iter().map(Vec::len).max().unwrap_or(0);
+    // Shorter strings behave as if they were padded with zeros
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = inputs
+                .iter()
+                .map(|input| input.get(i).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            match op.as_str() {
+                "AND" => bytes.fold(first, |acc, b| acc & b),
+                "OR" => bytes.fold(first, |acc, b| acc | b),
+                "XOR" => bytes.fold(first, |acc, b| acc ^ b),
+                _ => !first,
+            }
+        })
+        .collect();
+
+    // The destination is overwritten, including its expiry
+    t.remove(dest);
+    put_bytes(&mut t, dest, &result);
+    Ok(RESPValue::integer(result.len() as i64))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FieldType {
+    signed: bool,
+    bits: u32,
+}
+
+enum FieldOp {
+    Get,
+    Set(i64),
+    IncrBy(i64),
+}
+
+struct FieldCommand {
+    op: FieldOp,
+    field: FieldType,
+    offset: u64,
+    overflow: Overflow,
+}
+
+fn parse_field_type(s: &str) -> Option<FieldType> {
+    let (signed, bits) = match s.as_bytes().first() {
+        Some(b'i') | Some(b'I') => (true, &s[1..]),
+        Some(b'u') | Some(b'U') => (false, &s[1..]),
+        _ => return None,
+    };
+    let bits: u32 = bits.parse().ok()?;
+    let max_bits = if signed { 64 } else { 63 };
+    if bits == 0 || bits > max_bits {
+        return None;
+    }
+    Some(FieldType { signed, bits })
+}
+
+// Offsets prefixed with '#' are multiplied by the field width
+fn parse_field_offset(s: &str, field: FieldType) -> Option<u64> {
+    let offset = match s.strip_prefix('#') {
+        Some(n) => value::parse_integer(n)?.checked_mul(field.bits as i64)?,
+        None => value::parse_integer(s)?,
+    };
+    if offset < 0 || offset as u64 + field.bits as u64 - 1 > MAX_BIT_OFFSET {
+        return None;
+    }
+    Some(offset as u64)
+}
+
+fn get_field(bytes: &[u8], offset: u64, field: FieldType) -> i64 {
+    let mut value: u64 = 0;
+    for i in 0..field.bits as u64 {
+        value = (value << 1) | get_bit(bytes, offset + i) as u64;
+    }
+    if field.signed && field.bits < 64 && value & (1 << (field.bits - 1)) != 0 {
+        // Sign extend
+        value |= u64::MAX << field.bits;
+    }
+    value as i64
+}
+
+fn set_field(bytes: &mut [u8], offset: u64, field: FieldType, value: i64) {
+    let value = value as u64;
+    for i in 0..field.bits as u64 {
+        let bit = (value >> (field.bits as u64 - 1 - i)) & 1;
+        set_bit(bytes, offset + i, bit as u8);
+    }
+}
+
+// Adds `increment` to `value` within the range of the field type.
+// Returns None if the result overflows and the overflow behavior is FAIL.
+fn add_with_overflow(
+    value: i64,
+    increment: i64,
+    field: FieldType,
+    overflow: Overflow,
+) -> Option<i64> {
+    let (min, max): (i128, i128) = if field.signed {
+        (
+            -(1i128 << (field.bits - 1)),
+            (1i128 << (field.bits - 1)) - 1,
+        )
+    } else {
+        (0, (1i128 << field.bits) - 1)
+    };
+    // Unsigned fields read values as unsigned, so SET u8 -1 is an overflow rather than an underflow
+    let value_wide = if field.signed {
+        value as i128
+    } else {
+        value as u64 as i128
+    };
+    let result = value_wide + increment as i128;
+    if (min..=max).contains(&value_wide) && (min..=max).contains(&result) {
+        return Some(result as i64);
+    }
+
+    match overflow {
+        Overflow::Fail => None,
+        Overflow::Sat if value_wide > max || result > max => Some(max as i64),
+        Overflow::Sat => Some(min as i64),
+        Overflow::Wrap => {
+            let wrapped = (value as u64).wrapping_add(increment as u64);
+            if field.bits == 64 {
+                return Some(wrapped as i64);
+            }
+            let mask = u64::MAX << field.bits;
+            let sign_bit = 1 << (field.bits - 1);
+            Some(if field.signed && wrapped & sign_bit != 0 {
+                (wrapped | mask) as i64
+            } else {
+                (wrapped & !mask) as i64
+            })
+        }
+    }
+}
+
+fn parse_bitfield_commands(args: &[&str], read_only: bool) -> Result<Vec<FieldCommand>, RESPValue> {
+    let invalid_type = || {
+        RESPValue::error(
+            "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is."
+                .to_string(),
+        )
+    };
+    let mut commands = vec![];
+    let mut overflow = Overflow::Wrap;
+    let mut args = args.iter();
+    while let Some(subcommand) = args.next() {
+        let subcommand = subcommand.to_uppercase();
+        if subcommand == "OVERFLOW" {
+            overflow = match args.next().map(|o| o.to_uppercase()).as_deref() {
+                Some("WRAP") => Overflow::Wrap,
+                Some("SAT") => Overflow::Sat,
+                Some("FAIL") => Overflow::Fail,
+                Some(_) => {
+                    return Err(RESPValue::error(
+                        "ERR Invalid OVERFLOW type specified".to_string(),
+                    ))
+                }
+                None => return Err(syntax_error()),
+            };
+            continue;
+        }
+        if !["GET", "SET", "INCRBY"].contains(&subcommand.as_str()) {
+            return Err(syntax_error());
+        }
+        if read_only && subcommand != "GET" {
+            return Err(RESPValue::error(
+                "ERR BITFIELD_RO only supports the GET subcommand".to_string(),
+            ));
+        }
+        let (field, offset) = match (args.next(), args.next()) {
+            (Some(field), Some(offset)) => (field, offset),
+            _ => return Err(syntax_error()),
+        };
+        let field = parse_field_type(field).ok_or_else(invalid_type)?;
+        let offset = parse_field_offset(offset, field).ok_or_else(bit_offset_error)?;
+        let op = match subcommand.as_str() {
+            "GET" => FieldOp::Get,
+            _ => {
+                let value = args.next().ok_or_else(syntax_error)?;
+                let value = value::parse_integer(value).ok_or_else(not_an_integer)?;
+                if subcommand == "SET" {
+                    FieldOp::Set(value)
+                } else {
+                    FieldOp::IncrBy(value)
+                }
+            }
+        };
+        commands.push(FieldCommand {
+            op,
+            field,
+            offset,
+            overflow,
+        });
+    }
+    Ok(commands)
+}
+
+// Handles BITFIELD and BITFIELD_RO
+pub fn bitfield(args: &[BulkString], table: Table, read_only: bool) -> Result<RESPValue, String> {
+    let mut args = Args::new(if read_only { "bitfield_ro" } else { "bitfield" }, args);
+    let (key, options) = match args.next_key().and_then(|key| Ok((key, args.rest()?))) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let commands = match parse_bitfield_commands(&options, read_only) {
+        Ok(commands) => commands,
+        Err(e) => return Ok(e),
+    };
+
+    let mut t = write_table(&table)?;
+    let mut bytes = match get_bytes(&mut t, key) {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok(e),
+    };
+    let mut modified = false;
+    let mut replies = vec![];
+    for command in commands {
+        let FieldCommand {
+            op,
+            field,
+            offset,
+            overflow,
+        } = command;
+        let last_bit = offset + field.bits as u64 - 1;
+        let reply = match op {
+            FieldOp::Get => Some(get_field(&bytes, offset, field)),
+            // SET replies with the previous value
+            FieldOp::Set(value) => {
+                grow_for_bit(&mut bytes, last_bit);
+                modified = true;
+                let old_value = get_field(&bytes, offset, field);
+                let new_value = add_with_overflow(value, 0, field, overflow);
+                if let Some(v) = new_value {
+                    set_field(&mut bytes, offset, field, v);
+                }
+                new_value.map(|_| old_value)
+            }
+            // INCRBY replies with the new value
+            FieldOp::IncrBy(increment) => {
+                grow_for_bit(&mut bytes, last_bit);
+                modified = true;
+                let old_value = get_field(&bytes, offset, field);
+                let new_value = add_with_overflow(old_value, increment, field, overflow);
+                if let Some(v) = new_value {
+                    set_field(&mut bytes, offset, field, v);
+                }
+                new_value
+            }
+        };
+        replies.push(match reply {
+            Some(v) => RESPValue::integer(v),
+            None => RESPValue::bulk_string(None),
+        });
+    }
+    if modified {
+        put_bytes(&mut t, key, &bytes);
+    }
+    Ok(RESPValue::Array(Some(replies)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_count_bits() {
+        let bytes = b"foobar";
+        assert_eq!(count_bits(bytes, 0, 47), 26);
+        assert_eq!(count_bits(bytes, 8, 15), 6);
+        assert_eq!(count_bits(bytes, 5, 30), 17);
+    }
+
+    #[test]
+    fn test_bit_range() {
+        assert_eq!(bit_range(0, -1, 6, false), Some((0, 47)));
+        assert_eq!(bit_range(1, 1, 6, false), Some((8, 15)));
+        assert_eq!(bit_range(5, 30, 6, true), Some((5, 30)));
+        assert_eq!(bit_range(-2, -1, 6, false), Some((32, 47)));
+        assert_eq!(bit_range(3, 1, 6, false), None);
+        assert_eq!(bit_range(0, -1, 0, false), None);
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut bytes = vec![0; 2];
+        let i5 = FieldType {
+            signed: true,
+            bits: 5,
+        };
+        set_field(&mut bytes, 3, i5, -3);
+        assert_eq!(get_field(&bytes, 3, i5), -3);
+        assert_eq!(
+            get_field(
+                &bytes,
+                3,
+                FieldType {
+                    signed: false,
+                    bits: 5
+                }
+            ),
+            29
+        );
+    }
+
+    #[test]
+    fn test_add_with_overflow() {
+        let u2 = FieldType {
+            signed: false,
+            bits: 2,
+        };
+        assert_eq!(add_with_overflow(3, 1, u2, Overflow::Wrap), Some(0));
+        assert_eq!(add_with_overflow(3, 1, u2, Overflow::Sat), Some(3));
+        assert_eq!(add_with_overflow(3, 1, u2, Overflow::Fail), None);
+        assert_eq!(add_with_overflow(0, -1, u2, Overflow::Sat), Some(0));
+        assert_eq!(add_with_overflow(-1, 0, u2, Overflow::Wrap), Some(3));
+
+        let i8 = FieldType {
+            signed: true,
+            bits: 8,
+        };
+        assert_eq!(add_with_overflow(127, 1, i8, Overflow::Wrap), Some(-128));
+        assert_eq!(add_with_overflow(-128, -1, i8, Overflow::Sat), Some(-128));
+        assert_eq!(add_with_overflow(100, 100, i8, Overflow::Sat), Some(127));
+
+        let i64 = FieldType {
+            signed: true,
+            bits: 64,
+        };
+        assert_eq!(
+            add_with_overflow(i64::MAX, 1, i64, Overflow::Wrap),
+            Some(i64::MIN)
+        );
+    }
+
+    #[test]
+    fn test_parse_field_type() {
+        assert_eq!(
+            parse_field_type("i64"),
+            Some(FieldType {
+                signed: true,
+                bits: 64
+            })
+        );
+        assert_eq!(parse_field_type("u64"), None);
+        assert_eq!(parse_field_type("u0"), None);
+        assert_eq!(parse_field_type("x8"), None);
+    }
+}
@@ -0,0 +1,1056 @@
+// The cluster bus, on which the nodes of a cluster talk to each other on the client port plus
+// 10000. Like in Redis, nodes ping each other and gossip about the nodes they know. A node that
+// doesn't answer within cluster-node-timeout is flagged as possibly failing (PFAIL), then as
+// failing (FAIL) once a majority of the masters serving slots agree, and the replicas of a failed
+// master elect one of them to take over its slots. Replicas replicate their master over a
+// replication link that the bus keeps pointed at it, and only those that completed a full sync
+// can be elected. Messages are arrays of bulk strings rather than Redis' binary format, so only
+// nodes of this server can join the cluster.
+use crate::cluster::Node;
+use crate::cluster::Role;
+use crate::cluster::State;
+use crate::info;
+use crate::random_u64;
+use crate::replication;
+use crate::Databases;
+use crate::Functions;
+use crate::Server;
+use redis_starter_rust::Frame;
+use redis_starter_rust::ParseError;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+// Messages to send, with the address of the bus of the node they are for
+type Outbox = mpsc::UnboundedSender<(String, Message)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Meet,
+    Ping,
+    Pong,
+    Fail,
+    AuthRequest,
+    AuthAck,
+}
+
+const KINDS: &[(Kind, &str)] = &[
+    (Kind::Meet, "MEET"),
+    (Kind::Ping, "PING"),
+    (Kind::Pong, "PONG"),
+    (Kind::Fail, "FAIL"),
+    (Kind::AuthRequest, "AUTH-REQUEST"),
+    (Kind::AuthAck, "AUTH-ACK"),
+];
+
+// What the sender of a message knows about another node
+#[derive(Clone, Debug, PartialEq)]
+struct Gossip {
+    id: String,
+    ip: String,
+    port: u16,
+    cport: u16,
+    pfail: bool,
+    fail: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Message {
+    kind: Kind,
+    sender: String,
+    port: u16,
+    cport: u16,
+    role: Role,
+    master: Option<String>,
+    config_epoch: u64,
+    current_epoch: u64,
+    // The replication offset of the sender
+    offset: u64,
+    // The slots the sender serves
+    slots: Vec<(u16, u16)>,
+    // The node a FAIL message is about
+    failing: Option<String>,
+    gossip: Vec<Gossip>,
+}
+
+// How many node timeouts the replication link of a replica may have been down for it to take over
+// its master, like Redis' cluster-replica-validity-factor
+const REPLICA_VALIDITY_FACTOR: u64 = 10;
+
+// The fields of a message before the gossip, which comes in groups of GOSSIP_FIELDS
+const HEADER_FIELDS: usize = 11;
+const GOSSIP_FIELDS: usize = 5;
+
+impl Message {
+    fn to_resp(&self) -> RESPValue {
+        let kind = KINDS
+            .iter()
+            .find(|(kind, _)| *kind == self.kind)
+            .map_or("", |k| k.1);
+        let slots: Vec<String> = self
+            .slots
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start, end))
+            .collect();
+        let mut fields = vec![
+            kind.to_string(),
+            self.sender.clone(),
+            self.port.to_string(),
+            self.cport.to_string(),
+            role_name(self.role).to_string(),
+            self.master.clone().unwrap_or("-".to_string()),
+            self.config_epoch.to_string(),
+            self.current_epoch.to_string(),
+            self.offset.to_string(),
+            if slots.is_empty() {
+                "-".to_string()
+            } else {
+                slots.join(",")
+            },
+            self.failing.clone().unwrap_or("-".to_string()),
+        ];
+        for gossip in &self.gossip {
+            let flags = match (gossip.pfail, gossip.fail) {
+                (_, true) => "fail",
+                (true, false) => "fail?",
+                (false, false) => "-",
+            };
+            fields.extend([
+                gossip.id.clone(),
+                gossip.ip.clone(),
+                gossip.port.to_string(),
+                gossip.cport.to_string(),
+                flags.to_string(),
+            ]);
+        }
+        RESPValue::Array(Some(
+            fields
+                .into_iter()
+                .map(|field| RESPValue::bulk_string(Some(field)))
+                .collect(),
+        ))
+    }
+
+    fn from_resp(value: RESPValue) -> Option<Self> {
+        let fields: Vec<String> = match value {
+            RESPValue::Array(Some(values)) => values
+                .into_iter()
+                .map(|value| match value {
+                    RESPValue::BulkString(Some(s)) => Some(s),
+                    _ => None,
+                })
+                .collect::<Option<_>>()?,
+            _ => return None,
+        };
+        if fields.len() < HEADER_FIELDS
+            || !(fields.len() - HEADER_FIELDS).is_multiple_of(GOSSIP_FIELDS)
+        {
+            return None;
+        }
+        let (header, gossip) = fields.split_at(HEADER_FIELDS);
+        let optional = |field: &String| Some(field.clone()).filter(|f| f != "-");
+        let slots = match header[9].as_str() {
+            "-" => vec![],
+            slots => slots
+                .split(',')
+                .map(|range| {
+                    let (start, end) = range.split_once('-')?;
+                    Some((start.parse().ok()?, end.parse().ok()?))
+                })
+                .collect::<Option<_>>()?,
+        };
+        let gossip = gossip
+            .chunks(GOSSIP_FIELDS)
+            .map(|fields| {
+                Some(Gossip {
+                    id: fields[0].clone(),
+                    ip: fields[1].clone(),
+                    port: fields[2].parse().ok()?,
+                    cport: fields[3].parse().ok()?,
+                    pfail: fields[4] == "fail?",
+                    fail: fields[4] == "fail",
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            kind: KINDS.iter().find(|(_, name)| *name == header[0])?.0,
+            sender: header[1].clone(),
+            port: header[2].parse().ok()?,
+            cport: header[3].parse().ok()?,
+            role: match header[4].as_str() {
+                "master" => Role::Master,
+                "slave" => Role::Replica,
+                _ => return None,
+            },
+            master: optional(&header[5]),
+            config_epoch: header[6].parse().ok()?,
+            current_epoch: header[7].parse().ok()?,
+            offset: header[8].parse().ok()?,
+            slots,
+            failing: optional(&header[10]),
+            gossip,
+        })
+    }
+}
+
+// The election a replica runs to replace its failed master
+#[derive(Clone, Debug, PartialEq)]
+pub struct Election {
+    // When the votes are asked for, or were when `epoch` is set
+    start: u64,
+    // The epoch the votes are asked for, 0 until they are
+    epoch: u64,
+    votes: BTreeSet<String>,
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Master => "master",
+        Role::Replica => "slave",
+    }
+}
+
+// Unix time in milliseconds
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn bus_addr(node: &Node) -> String {
+    format!("{}:{}", node.ip, node.cport)
+}
+
+// Starts meeting a node, known by its address until it answers with its id
+pub fn start_handshake(state: &mut State, ip: &str, port: u16, cport: u16) {
+    if state
+        .nodes
+        .values()
+        .any(|node| node.handshake && node.ip == ip && node.port == port)
+    {
+        return;
+    }
+    let mut node = Node::new(&info::new_run_id(), ip, port);
+    node.cport = cport;
+    node.handshake = true;
+    node.ctime = now_ms();
+    state.nodes.insert(node.id.clone(), node);
+}
+
+// A message from this node, gossiping about the other nodes it knows but `to`
+fn message(state: &State, kind: Kind, to: Option<&str>) -> Message {
+    let myself = state.myself();
+    Message {
+        kind,
+        sender: myself.id.clone(),
+        port: myself.port,
+        cport: myself.cport,
+        role: myself.role,
+        master: myself.master.clone(),
+        config_epoch: myself.config_epoch,
+        current_epoch: state.current_epoch,
+        offset: myself.repl_offset,
+        slots: state.slot_ranges(&myself.id),
+        failing: None,
+        gossip: state
+            .nodes
+            .values()
+            .filter(|node| node.id != myself.id && !node.handshake && Some(node.id.as_str()) != to)
+            .map(|node| Gossip {
+                id: node.id.clone(),
+                ip: node.ip.clone(),
+                port: node.port,
+                cport: node.cport,
+                pfail: node.pfail,
+                fail: node.fail,
+            })
+            .collect(),
+    }
+}
+
+// The message for every other node that finished its handshake
+fn broadcast(state: &State, kind: Kind, failing: Option<&str>) -> Vec<(String, Message)> {
+    state
+        .nodes
+        .values()
+        .filter(|node| node.id != state.myself && !node.handshake)
+        .map(|node| {
+            let mut message = message(state, kind, Some(&node.id));
+            message.failing = failing.map(str::to_string);
+            (bus_addr(node), message)
+        })
+        .collect()
+}
+
+// Pings the node, remembering when if it isn't waiting for an answer already
+fn ping(state: &mut State, id: &str, kind: Kind, now: u64) -> Option<(String, Message)> {
+    let message = message(state, kind, Some(id));
+    let node = state.nodes.get_mut(id)?;
+    if node.handshake || node.ping_sent == 0 {
+        node.ping_sent = now;
+    }
+    Some((bus_addr(node), message))
+}
+
+// Handles a message received from `peer_ip` on `local_ip`, returning the messages to answer with
+fn handle(
+    state: &mut State,
+    message: Message,
+    peer_ip: &str,
+    local_ip: &str,
+    timeout: u64,
+) -> Vec<(String, Message)> {
+    let now = now_ms();
+    let sender = message.sender.clone();
+    if sender == state.myself {
+        return vec![];
+    }
+    if message.current_epoch > state.current_epoch {
+        state.current_epoch = message.current_epoch;
+        state.dirty = true;
+    }
+    let mut replies = vec![];
+    let reply_to = format!("{}:{}", peer_ip, message.cport);
+    match message.kind {
+        Kind::Meet => {
+            // Other nodes reach this one on the address it was met on
+            if state.myself().ip != local_ip {
+                state.myself_mut().ip = local_ip.to_string();
+                state.dirty = true;
+            }
+            if !state.nodes.contains_key(&sender) {
+                add_node(state, &message, peer_ip);
+            }
+        }
+        // The answer to the MEET of a handshake, which tells the id of the node
+        Kind::Pong if !state.nodes.contains_key(&sender) => {
+            let handshake = state
+                .nodes
+                .values()
+                .find(|node| node.handshake && node.ip == peer_ip && node.port == message.port)
+                .map(|node| node.id.clone());
+            if let Some(id) = handshake {
+                state.nodes.remove(&id);
+                add_node(state, &message, peer_ip);
+            }
+        }
+        _ => {}
+    }
+    if matches!(message.kind, Kind::Meet | Kind::Ping) {
+        replies.push((
+            reply_to.clone(),
+            self::message(state, Kind::Pong, Some(&sender)),
+        ));
+    }
+    let node = match state.nodes.get_mut(&sender) {
+        Some(node) => node,
+        None => return replies,
+    };
+    if message.kind == Kind::Pong {
+        node.pong_received = now;
+        node.ping_sent = 0;
+        node.pfail = false;
+        node.connected = true;
+        clear_failure_if_needed(state, &sender, now, timeout);
+    }
+
+    match message.kind {
+        Kind::Meet | Kind::Ping | Kind::Pong => {
+            update_role(state, &message);
+            if message.role == Role::Master {
+                update_slots(state, &sender, &message.slots, message.config_epoch);
+                handle_epoch_collision(state, &message);
+            }
+            if let Some(node) = state.nodes.get_mut(&sender) {
+                node.repl_offset = message.offset;
+                if node.config_epoch != message.config_epoch {
+                    node.config_epoch = message.config_epoch;
+                    state.dirty = true;
+                }
+            }
+            process_gossip(state, &sender, &message.gossip, now);
+        }
+        Kind::Fail => {
+            let failing = message.failing.as_deref().unwrap_or_default();
+            if failing != state.myself {
+                if let Some(node) = state.nodes.get_mut(failing).filter(|node| !node.fail) {
+                    node.fail = true;
+                    node.pfail = false;
+                    node.connected = false;
+                    node.fail_time = now;
+                    state.dirty = true;
+                }
+            }
+        }
+        Kind::AuthRequest => {
+            if vote(state, &message, now, timeout) {
+                replies.push((reply_to, self::message(state, Kind::AuthAck, Some(&sender))));
+            }
+        }
+        Kind::AuthAck => {
+            let voter = state.nodes[&sender].role == Role::Master && state.serves_slots(&sender);
+            if let Some(election) = &mut state.election {
+                if voter && election.epoch != 0 && message.current_epoch >= election.epoch {
+                    election.votes.insert(sender);
+                }
+            }
+        }
+    }
+    replies
+}
+
+fn add_node(state: &mut State, message: &Message, ip: &str) {
+    let mut node = Node::new(&message.sender, ip, message.port);
+    node.cport = message.cport;
+    state.nodes.insert(node.id.clone(), node);
+    state.dirty = true;
+}
+
+// A failing node that answers again is back, unless it's a master that still serves slots,
+// which is only trusted again after a while as its slots may be taken over in the meantime
+fn clear_failure_if_needed(state: &mut State, id: &str, now: u64, timeout: u64) {
+    let serves_slots = state.serves_slots(id);
+    if let Some(node) = state.nodes.get_mut(id).filter(|node| node.fail) {
+        if node.role == Role::Replica
+            || !serves_slots
+            || now.saturating_sub(node.fail_time) > 2 * timeout
+        {
+            node.fail = false;
+            state.dirty = true;
+        }
+    }
+}
+
+fn update_role(state: &mut State, message: &Message) {
+    let node = match state.nodes.get_mut(&message.sender) {
+        Some(node) => node,
+        None => return,
+    };
+    if node.role == message.role && node.master == message.master {
+        return;
+    }
+    node.role = message.role;
+    node.master = message.master.clone();
+    state.dirty = true;
+    // A master turned replica gives up its slots
+    if message.role == Role::Replica {
+        for owner in state.slots.iter_mut() {
+            if owner.as_deref() == Some(message.sender.as_str()) {
+                *owner = None;
+            }
+        }
+    }
+}
+
+// Gives the master the slots it claims that are served by a node with an older config, like
+// Redis' clusterUpdateSlotsConfigWith
+fn update_slots(state: &mut State, sender: &str, ranges: &[(u16, u16)], config_epoch: u64) {
+    let mut losers = BTreeSet::new();
+    for slot in ranges.iter().flat_map(|(start, end)| *start..=*end) {
+        let owner = state.slots[slot as usize].clone();
+        if owner.as_deref() == Some(sender) || state.importing.contains_key(&slot) {
+            continue;
+        }
+        let owner_epoch = owner
+            .as_ref()
+            .and_then(|id| state.nodes.get(id))
+            .map_or(0, |node| node.config_epoch);
+        if owner.is_none() || owner_epoch < config_epoch {
+            state.slots[slot as usize] = Some(sender.to_string());
+            state.dirty = true;
+            losers.extend(owner);
+        }
+    }
+    // A master that lost all its slots to the sender, because one of its replicas replaced it
+    // while it was down, becomes a replica of the sender, and so do its replicas
+    let myself = state.myself().clone();
+    for loser in losers {
+        if state.serves_slots(&loser) {
+            continue;
+        }
+        let follows = match myself.role {
+            Role::Master => loser == myself.id,
+            Role::Replica => myself.master.as_deref() == Some(loser.as_str()),
+        };
+        if follows {
+            let node = state.myself_mut();
+            node.role = Role::Replica;
+            node.master = Some(sender.to_string());
+        }
+    }
+}
+
+// When two masters have the same config epoch, the one with the smaller id takes a new one so
+// that slots claimed by both go to one of them
+fn handle_epoch_collision(state: &mut State, message: &Message) {
+    let myself = state.myself();
+    if myself.role != Role::Master
+        || myself.config_epoch != message.config_epoch
+        || message.sender <= myself.id
+    {
+        return;
+    }
+    state.current_epoch += 1;
+    let epoch = state.current_epoch;
+    state.myself_mut().config_epoch = epoch;
+    state.dirty = true;
+}
+
+// Records the failure reports of masters, and meets the nodes gossiped about that aren't known
+fn process_gossip(state: &mut State, sender: &str, gossip: &[Gossip], now: u64) {
+    let from_master = state.nodes[sender].role == Role::Master;
+    for gossip in gossip {
+        if gossip.id == state.myself {
+            continue;
+        }
+        if state.nodes.contains_key(&gossip.id) {
+            if from_master && gossip.id != sender {
+                let reports = state.failure_reports.entry(gossip.id.clone()).or_default();
+                if gossip.pfail || gossip.fail {
+                    reports.insert(sender.to_string(), now);
+                } else {
+                    reports.remove(sender);
+                }
+            }
+        } else if !state
+            .nodes
+            .values()
+            .any(|node| node.ip == gossip.ip && node.port == gossip.port)
+        {
+            start_handshake(state, &gossip.ip, gossip.port, gossip.cport);
+        }
+    }
+}
+
+// Whether to vote for the replica asking to replace its master, like Redis'
+// clusterSendFailoverAuthIfNeeded. Masters vote once per epoch, and for one replica of a failed
+// master in two node timeouts.
+fn vote(state: &mut State, message: &Message, now: u64, timeout: u64) -> bool {
+    let master = match &message.master {
+        Some(master) => master,
+        None => return false,
+    };
+    let myself = state.myself();
+    if myself.role != Role::Master
+        || !state.serves_slots(&myself.id)
+        || message.role != Role::Replica
+        || message.current_epoch < state.current_epoch
+        || state.last_vote_epoch == state.current_epoch
+        || !state.nodes.get(master).is_some_and(|node| node.fail)
+        || state
+            .voted_for
+            .get(master)
+            .is_some_and(|voted| now.saturating_sub(*voted) < 2 * timeout)
+    {
+        return false;
+    }
+    state.last_vote_epoch = state.current_epoch;
+    state.voted_for.insert(master.clone(), now);
+    state.dirty = true;
+    true
+}
+
+// Runs every 100 milliseconds, like Redis' clusterCron, returning the messages to send
+fn tick(state: &mut State, iteration: u64, timeout: u64) -> Vec<(String, Message)> {
+    let now = now_ms();
+    let mut messages = vec![];
+    let handshake_timeout = timeout.max(1000);
+    state
+        .nodes
+        .retain(|_, node| !node.handshake || now.saturating_sub(node.ctime) <= handshake_timeout);
+    let others: Vec<String> = state
+        .nodes
+        .keys()
+        .filter(|id| **id != state.myself)
+        .cloned()
+        .collect();
+
+    // Nodes in handshake are met every second, the others pinged when they haven't answered for
+    // half the timeout, again every second while the ping goes unanswered, and a random one every
+    // second to keep the gossip going
+    for id in &others {
+        let node = &state.nodes[id];
+        let kind = if node.handshake {
+            if now.saturating_sub(node.ping_sent) < 1000 {
+                continue;
+            }
+            Kind::Meet
+        } else if (node.ping_sent == 0 && now.saturating_sub(node.pong_received) > timeout / 2)
+            || (node.ping_sent != 0
+                && iteration.is_multiple_of(10)
+                && now.saturating_sub(node.ping_sent) > timeout / 2)
+        {
+            Kind::Ping
+        } else {
+            continue;
+        };
+        messages.extend(ping(state, id, kind, now));
+    }
+    if iteration.is_multiple_of(10) {
+        let idle: Vec<&String> = others
+            .iter()
+            .filter(|id| !state.nodes[*id].handshake && state.nodes[*id].ping_sent == 0)
+            .collect();
+        if !idle.is_empty() {
+            let id = idle[random_u64() as usize % idle.len()].clone();
+            messages.extend(ping(state, &id, Kind::Ping, now));
+        }
+    }
+
+    for id in &others {
+        let node = state.nodes.get_mut(id).expect("listed above");
+        if !node.handshake
+            && !node.pfail
+            && !node.fail
+            && node.ping_sent != 0
+            && now.saturating_sub(node.ping_sent) > timeout
+        {
+            node.pfail = true;
+            node.connected = false;
+        }
+    }
+
+    // Reports expire after two node timeouts, like in Redis
+    let nodes = &state.nodes;
+    state.failure_reports.retain(|id, reports| {
+        reports.retain(|reporter, time| {
+            nodes.contains_key(reporter) && now.saturating_sub(*time) <= 2 * timeout
+        });
+        nodes.contains_key(id) && !reports.is_empty()
+    });
+    messages.extend(mark_failing_nodes(state, now));
+    messages.extend(failover(state, now, timeout));
+    messages
+}
+
+// Flags the nodes that a majority of masters consider possibly failing as failing, telling the
+// other nodes so
+fn mark_failing_nodes(state: &mut State, now: u64) -> Vec<(String, Message)> {
+    let quorum = state.quorum();
+    let myself_reports = (state.myself().role == Role::Master) as usize;
+    let failing: Vec<String> = state
+        .nodes
+        .values()
+        .filter(|node| node.pfail)
+        .filter(|node| {
+            let reports = state.failure_reports.get(&node.id).map_or(0, |r| r.len());
+            reports + myself_reports >= quorum
+        })
+        .map(|node| node.id.clone())
+        .collect();
+    let mut messages = vec![];
+    for id in failing {
+        if let Some(node) = state.nodes.get_mut(&id) {
+            node.pfail = false;
+            node.fail = true;
+            node.connected = false;
+            node.fail_time = now;
+        }
+        state.dirty = true;
+        messages.extend(broadcast(state, Kind::Fail, Some(&id)));
+    }
+    messages
+}
+
+// Runs the election of a replica whose master failed, like Redis' clusterHandleSlaveFailover
+fn failover(state: &mut State, now: u64, timeout: u64) -> Vec<(String, Message)> {
+    let myself = state.myself().clone();
+    let master = match (myself.role, &myself.master) {
+        (Role::Replica, Some(master))
+            if state.nodes.get(master).is_some_and(|node| node.fail)
+                && state.serves_slots(master) =>
+        {
+            master.clone()
+        }
+        _ => {
+            state.election = None;
+            return vec![];
+        }
+    };
+    // Like Redis, a replica that never synced with its master, or whose link went down long before
+    // the master failed, doesn't have enough of its keys to take over
+    let max_data_age = timeout * REPLICA_VALIDITY_FACTOR + 1000;
+    if state
+        .link_down
+        .is_none_or(|down| down.saturating_sub(timeout) > max_data_age)
+    {
+        state.election = None;
+        return vec![];
+    }
+    let quorum = state.quorum();
+    let election = match &mut state.election {
+        Some(election) => election,
+        None => {
+            // Replicas ask for votes one after the other so that they don't split them, those
+            // that replicated the most first
+            let rank = state
+                .replicas(&master)
+                .iter()
+                .filter(|replica| {
+                    (replica.repl_offset, &myself.id) > (myself.repl_offset, &replica.id)
+                })
+                .count() as u64;
+            state.election = Some(Election {
+                start: now + 500 + random_u64() % 500 + rank * 1000,
+                epoch: 0,
+                votes: BTreeSet::new(),
+            });
+            return vec![];
+        }
+    };
+    if election.epoch == 0 {
+        if now < election.start {
+            return vec![];
+        }
+        state.current_epoch += 1;
+        election.epoch = state.current_epoch;
+        election.start = now;
+        state.dirty = true;
+        return broadcast(state, Kind::AuthRequest, None);
+    }
+    if election.votes.len() < quorum {
+        // Elections that didn't get enough votes in time start over
+        if now.saturating_sub(election.start) > (2 * timeout).max(2000) {
+            state.election = None;
+        }
+        return vec![];
+    }
+
+    // Elected, this replica takes over the slots of its master with the epoch of the election,
+    // newer than the config of the master
+    let epoch = election.epoch;
+    state.election = None;
+    for owner in state.slots.iter_mut() {
+        if owner.as_deref() == Some(master.as_str()) {
+            *owner = Some(myself.id.clone());
+        }
+    }
+    let node = state.myself_mut();
+    node.role = Role::Master;
+    node.master = None;
+    node.config_epoch = epoch;
+    state.dirty = true;
+    broadcast(state, Kind::Pong, None)
+}
+
+// Starts the bus of this node: receiving messages from the other nodes, sending them messages,
+// and checking on them every 100 milliseconds
+pub async fn start(
+    server: Arc<Server>,
+    databases: Databases,
+    functions: Functions,
+) -> Result<(), String> {
+    let cport = match &*server.cluster.state()? {
+        Some(state) => state.myself().cport,
+        None => return Ok(()),
+    };
+    let listener = TcpListener::bind(("0.0.0.0", cport)).await.map_err(|e| {
+        format!(
+            "Failed to listen for the cluster bus on port {}: {}",
+            cport, e
+        )
+    })?;
+    let (outbox, messages) = mpsc::unbounded_channel();
+    tokio::spawn(send_messages(messages));
+
+    let cron_server = Arc::clone(&server);
+    let cron_outbox = outbox.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        let mut iteration = 0;
+        loop {
+            interval.tick().await;
+            iteration += 1;
+            let result = cron(
+                &cron_server,
+                &databases,
+                &functions,
+                &cron_outbox,
+                iteration,
+            );
+            if let Err(e) = result {
+                eprintln!("{}", e);
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(receive_messages(
+                socket,
+                Arc::clone(&server),
+                outbox.clone(),
+            ));
+        }
+    });
+    Ok(())
+}
+
+fn cron(
+    server: &Arc<Server>,
+    databases: &Databases,
+    functions: &Functions,
+    outbox: &Outbox,
+    iteration: u64,
+) -> Result<(), String> {
+    let config = server.config()?;
+    let link_down = server.replication.synced()?;
+    let repl_offset = server.replication.repl_offset()?;
+    let (messages, master) = match &mut *server.cluster.state()? {
+        Some(state) => {
+            state.link_down = link_down.map(|down| down.as_millis() as u64);
+            state.myself_mut().repl_offset = repl_offset;
+            let messages = tick(state, iteration, config.cluster_node_timeout as u64);
+            (messages, replication_master(state))
+        }
+        None => return Ok(()),
+    };
+    for message in messages {
+        let _ = outbox.send(message);
+    }
+    // The replication link follows the role of this node, which the bus may change
+    match master {
+        Some((ip, port)) => {
+            replication::follow(&ip, port, databases, functions, server)?;
+        }
+        None => server.replication.stop_following()?,
+    }
+    server
+        .cluster
+        .save_if_dirty(Path::new(&config.cluster_config_file))
+}
+
+// The address of the master this node replicates, None when it's a master
+fn replication_master(state: &State) -> Option<(String, u16)> {
+    let myself = state.myself();
+    if myself.role != Role::Replica {
+        return None;
+    }
+    let master = state.nodes.get(myself.master.as_ref()?)?;
+    Some((master.ip.clone(), master.port)).filter(|_| !master.noaddr)
+}
+
+async fn receive_messages(mut socket: TcpStream, server: Arc<Server>, outbox: Outbox) {
+    let (peer_ip, local_ip) = match (socket.peer_addr(), socket.local_addr()) {
+        (Ok(peer), Ok(local)) => (peer.ip().to_string(), local.ip().to_string()),
+        _ => return,
+    };
+    let mut buffer = vec![];
+    let mut chunk = [0; 4096];
+    loop {
+        match socket.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+        // A read may end in the middle of a message, or hold several
+        loop {
+            let len = match Frame::check(&buffer) {
+                Ok(len) => len,
+                Err(ParseError::NotEnoughBytes) => break,
+                Err(_) => return,
+            };
+            let value = match RESPValue::parse(&buffer[..len]) {
+                Ok((value, _)) => value,
+                Err(_) => return,
+            };
+            buffer.drain(..len);
+            let message = match Message::from_resp(value) {
+                Some(message) => message,
+                None => continue,
+            };
+            let replies = server.config().and_then(|config| {
+                let timeout = config.cluster_node_timeout as u64;
+                Ok(match &mut *server.cluster.state()? {
+                    Some(state) => handle(state, message, &peer_ip, &local_ip, timeout),
+                    None => vec![],
+                })
+            });
+            match replies {
+                Ok(replies) => replies.into_iter().for_each(|reply| {
+                    let _ = outbox.send(reply);
+                }),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+}
+
+// Hands the messages to a task per node, so that a node that is down only delays its own
+async fn send_messages(mut messages: mpsc::UnboundedReceiver<(String, Message)>) {
+    let mut links: HashMap<String, mpsc::UnboundedSender<RESPValue>> = HashMap::new();
+    while let Some((addr, message)) = messages.recv().await {
+        let link = links.entry(addr.clone()).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(link(addr, receiver));
+            sender
+        });
+        let _ = link.send(message.to_resp());
+    }
+}
+
+// Sends messages to a node over a connection kept open. Messages that can't be sent are dropped,
+// the pings left unanswered are what tells that the node is failing.
+async fn link(addr: String, mut messages: mpsc::UnboundedReceiver<RESPValue>) {
+    let mut stream: Option<TcpStream> = None;
+    while let Some(message) = messages.recv().await {
+        if stream.is_none() {
+            stream = tokio::time::timeout(Duration::from_secs(1), TcpStream::connect(&addr))
+                .await
+                .ok()
+                .and_then(Result::ok);
+        }
+        let sent = match &mut stream {
+            Some(stream) => stream.write_all(&message.to_bytes()).await.is_ok(),
+            None => false,
+        };
+        if !sent {
+            stream = None;
+            while messages.try_recv().is_ok() {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cluster::SLOTS;
+
+    fn node(state: &mut State, id: &str, port: u16) {
+        state
+            .nodes
+            .insert(id.to_string(), Node::new(id, "127.0.0.1", port));
+    }
+
+    // Three masters serving a third of the slots each, and a replica of the first
+    fn cluster() -> State {
+        let mut state = State::new("127.0.0.1", 7000);
+        state.myself = "a".to_string();
+        state.nodes.clear();
+        node(&mut state, "a", 7000);
+        node(&mut state, "b", 7001);
+        node(&mut state, "c", 7002);
+        node(&mut state, "r", 7003);
+        let replica = state.nodes.get_mut("r").unwrap();
+        replica.role = Role::Replica;
+        replica.master = Some("a".to_string());
+        for (slot, owner) in state.slots.iter_mut().enumerate() {
+            *owner = Some(["a", "b", "c"][slot * 3 / SLOTS].to_string());
+        }
+        state
+    }
+
+    #[test]
+    fn test_message() {
+        let state = cluster();
+        let mut message = message(&state, Kind::Fail, Some("b"));
+        message.failing = Some("c".to_string());
+        assert_eq!(message.slots, vec![(0, 5461)]);
+        assert_eq!(
+            message
+                .gossip
+                .iter()
+                .map(|g| g.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["c", "r"]
+        );
+        assert_eq!(Message::from_resp(message.to_resp()), Some(message));
+        assert_eq!(Message::from_resp(RESPValue::Array(Some(vec![]))), None);
+    }
+
+    #[test]
+    fn test_failover() {
+        let timeout = 1000;
+        let mut state = cluster();
+        state.nodes.get_mut("b").unwrap().pfail = true;
+        // One report from another master is enough, with this one's, for two of three masters
+        let mut c = message(&state, Kind::Ping, None);
+        c.sender = "c".to_string();
+        c.slots = state.slot_ranges("c");
+        c.gossip[0].pfail = true;
+        handle(&mut state, c, "127.0.0.1", "127.0.0.1", timeout);
+        assert_eq!(state.failure_reports["b"].len(), 1);
+        let messages = tick(&mut state, 1, timeout);
+        assert!(state.nodes["b"].fail);
+        assert!(messages
+            .iter()
+            .any(|(_, m)| m.kind == Kind::Fail && m.failing.as_deref() == Some("b")));
+
+        // A replica of b asks for a vote, which is given once per epoch
+        let mut request = message(&state, Kind::AuthRequest, None);
+        request.sender = "r".to_string();
+        request.role = Role::Replica;
+        request.master = Some("b".to_string());
+        request.slots = vec![];
+        request.current_epoch = 1;
+        let replies = handle(
+            &mut state,
+            request.clone(),
+            "127.0.0.1",
+            "127.0.0.1",
+            timeout,
+        );
+        assert_eq!(replies[0].1.kind, Kind::AuthAck);
+        assert_eq!(state.last_vote_epoch, 1);
+        assert!(handle(&mut state, request, "127.0.0.1", "127.0.0.1", timeout).is_empty());
+
+        // Once elected, r serves the slots of b with a newer config
+        let mut pong = message(&state, Kind::Pong, None);
+        pong.sender = "r".to_string();
+        pong.config_epoch = 1;
+        pong.slots = state.slot_ranges("b");
+        handle(&mut state, pong, "127.0.0.1", "127.0.0.1", timeout);
+        assert!(!state.serves_slots("b"));
+        assert_eq!(state.slot_ranges("r"), vec![(5462, 10922)]);
+    }
+
+    #[test]
+    fn test_election() {
+        let timeout = 1000;
+        let mut state = cluster();
+        state.myself = "r".to_string();
+        state.nodes.get_mut("a").unwrap().fail = true;
+        // Without a full sync with its master, the replica has none of its keys
+        assert!(failover(&mut state, now_ms(), timeout).is_empty());
+        assert_eq!(state.election, None);
+        state.link_down = Some(timeout * (REPLICA_VALIDITY_FACTOR + 3));
+        failover(&mut state, now_ms(), timeout);
+        assert_eq!(state.election, None);
+
+        // Replicas that replicated more ask for votes first
+        state.link_down = Some(timeout);
+        node(&mut state, "q", 7004);
+        let other = state.nodes.get_mut("q").unwrap();
+        other.role = Role::Replica;
+        other.master = Some("a".to_string());
+        other.repl_offset = 100;
+        let now = now_ms();
+        failover(&mut state, now, timeout);
+        assert!(state.election.as_ref().unwrap().start >= now + 1500);
+        state.election = None;
+        state.myself_mut().repl_offset = 200;
+        failover(&mut state, now, timeout);
+        assert!(state.election.as_ref().unwrap().start < now + 1000);
+    }
+
+    #[test]
+    fn test_update_slots() {
+        let mut state = cluster();
+        state.myself_mut().config_epoch = 2;
+        update_slots(&mut state, "b", &[(0, 5461)], 1);
+        assert!(state.serves_slots("a"));
+        update_slots(&mut state, "b", &[(0, 5461)], 3);
+        assert!(!state.serves_slots("a"));
+        assert_eq!(state.myself().role, Role::Replica);
+        assert_eq!(state.myself().master.as_deref(), Some("b"));
+    }
+}
@@ -0,0 +1,556 @@
+// Registry of the connected clients and the CLIENT command
+use crate::acl;
+use crate::commands;
+use crate::info::REDIS_VERSION;
+use crate::pubsub::Pushes;
+use crate::strings::syntax_error;
+use crate::strings::wrong_number_of_arguments;
+use crate::tracking;
+use crate::value;
+use crate::Server;
+use crate::Session;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+// Commands that have subcommands, reported as `command|subcommand` like Redis does
+const CONTAINER_COMMANDS: &[&str] = &["CLIENT", "CONFIG", "FUNCTION", "MEMORY", "PUBSUB"];
+
+struct Client {
+    addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    name: Option<String>,
+    connected: Instant,
+    last_interaction: Instant,
+    db: usize,
+    last_command: String,
+    no_evict: bool,
+    monitor: bool,
+    // Channels and patterns the client is subscribed to
+    subscriptions: usize,
+    user: String,
+    // Whether the connection switched to RESP3 with HELLO
+    resp3: bool,
+    // Where replies the connection didn't ask for are sent
+    pushes: Pushes,
+    // Wakes the connection up to close it
+    kill: Arc<Notify>,
+}
+
+impl Client {
+    // The line describing the client in CLIENT LIST and CLIENT INFO
+    fn describe(&self, id: u64) -> String {
+        let mut flags = String::new();
+        if self.monitor {
+            flags.push('O');
+        }
+        if self.subscriptions > 0 {
+            flags.push('P');
+        }
+        if self.no_evict {
+            flags.push('e');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} cmd={} user={} resp={}\n",
+            id,
+            self.addr,
+            self.local_addr.map_or(String::new(), |a| a.to_string()),
+            self.name.as_deref().unwrap_or_default(),
+            self.connected.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            flags,
+            self.db,
+            self.last_command,
+            self.user,
+            if self.resp3 { 3 } else { 2 },
+        )
+    }
+
+    // The type CLIENT KILL TYPE filters on
+    fn kind(&self) -> &'static str {
+        if self.subscriptions > 0 {
+            "pubsub"
+        } else {
+            "normal"
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum PauseKind {
+    Write,
+    All,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    // Ordered by id, the order CLIENT LIST shows them in
+    clients: Mutex<BTreeMap<u64, Client>>,
+    last_id: AtomicU64,
+    pause: Mutex<Option<(Instant, PauseKind)>>,
+    unpaused: Notify,
+}
+
+impl Registry {
+    fn clients(&self) -> Result<MutexGuard<'_, BTreeMap<u64, Client>>, String> {
+        self.clients
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for clients {}", e))
+    }
+
+    // Adds a connection, returning its id and what gets notified when it's killed
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        local_addr: Option<SocketAddr>,
+        pushes: Pushes,
+    ) -> Result<(u64, Arc<Notify>), String> {
+        let id = self.last_id.fetch_add(1, Relaxed) + 1;
+        let kill = Arc::new(Notify::new());
+        let now = Instant::now();
+        self.clients()?.insert(
+            id,
+            Client {
+                addr,
+                local_addr,
+                name: None,
+                connected: now,
+                last_interaction: now,
+                db: 0,
+                last_command: "NULL".to_string(),
+                no_evict: false,
+                monitor: false,
+                subscriptions: 0,
+                user: acl::DEFAULT_USER.to_string(),
+                resp3: false,
+                pushes,
+                kill: kill.clone(),
+            },
+        );
+        Ok((id, kill))
+    }
+
+    pub fn unregister(&self, id: u64) -> Result<(), String> {
+        self.clients()?.remove(&id);
+        Ok(())
+    }
+
+    // Records the command a client is about to run
+    pub fn record_command(&self, session: &Session, args: &[&str]) -> Result<(), String> {
+        let mut clients = self.clients()?;
+        if let Some(client) = clients.get_mut(&session.client_id) {
+            let mut name = args.first().copied().unwrap_or_default().to_lowercase();
+            if let Some(subcommand) = args.get(1) {
+                if CONTAINER_COMMANDS.contains(&name.to_uppercase().as_str()) {
+                    name = format!("{}|{}", name, subcommand.to_lowercase());
+                }
+            }
+            client.last_command = name;
+            client.last_interaction = Instant::now();
+            client.db = session.db;
+        }
+        Ok(())
+    }
+
+    pub fn record_db(&self, session: &Session) -> Result<(), String> {
+        if let Some(client) = self.clients()?.get_mut(&session.client_id) {
+            client.db = session.db;
+        }
+        Ok(())
+    }
+
+    pub fn record_user(&self, session: &Session) -> Result<(), String> {
+        if let Some(client) = self.clients()?.get_mut(&session.client_id) {
+            client.user = session
+                .user
+                .clone()
+                .unwrap_or_else(|| acl::DEFAULT_USER.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn record_monitor(&self, session: &Session) -> Result<(), String> {
+        if let Some(client) = self.clients()?.get_mut(&session.client_id) {
+            client.monitor = true;
+        }
+        Ok(())
+    }
+
+    pub fn record_subscriptions(&self, session: &Session, count: usize) -> Result<(), String> {
+        if let Some(client) = self.clients()?.get_mut(&session.client_id) {
+            client.subscriptions = count;
+        }
+        Ok(())
+    }
+
+    // Where to send the replies the client didn't ask for, and whether it speaks RESP3
+    pub fn pushes(&self, id: u64) -> Result<Option<(Pushes, bool)>, String> {
+        Ok(self
+            .clients()?
+            .get(&id)
+            .map(|client| (client.pushes.clone(), client.resp3)))
+    }
+
+    // The address and name of the client, as logs show them
+    pub fn peer(&self, id: u64) -> Result<Option<(String, String)>, String> {
+        Ok(self.clients()?.get(&id).map(|client| {
+            (
+                client.addr.to_string(),
+                client.name.clone().unwrap_or_default(),
+            )
+        }))
+    }
+
+    // The line CLIENT INFO shows for the client
+    pub fn info(&self, id: u64) -> Result<Option<String>, String> {
+        Ok(self.clients()?.get(&id).map(|client| client.describe(id)))
+    }
+
+    // Disconnects the clients authenticated as the user, returning how many there were
+    pub fn kill_user(&self, user: &str) -> Result<usize, String> {
+        kill(self, |_, client| client.user == user)
+    }
+
+    // Time left before the command can run, None if it isn't paused
+    fn pause_remaining(&self, args: &[&str]) -> Option<Duration> {
+        let pause = *self.pause.lock().ok()?;
+        let (end, kind) = pause?;
+        let command = args.first()?.to_uppercase();
+        // CLIENT is never paused so that CLIENT UNPAUSE can always be sent
+        let affected = command != "CLIENT"
+            && (kind == PauseKind::All || commands::is_write(args) || command == "FCALL");
+        Some(end.saturating_duration_since(Instant::now())).filter(|d| affected && !d.is_zero())
+    }
+
+    // Waits for the end of a CLIENT PAUSE affecting the command
+    pub async fn wait_unpaused(&self, command: &[BulkString]) {
+        let args: Vec<&str> = command
+            .iter()
+            .take(2)
+            .map(|arg| arg.as_deref().unwrap_or_default())
+            .collect();
+        while let Some(remaining) = self.pause_remaining(&args) {
+            tokio::select! {
+                _ = tokio::time::sleep(remaining) => {}
+                _ = self.unpaused.notified() => {}
+            }
+        }
+    }
+}
+
+pub fn client_command(
+    args: &[BulkString],
+    session: &Session,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let mut args = Args::new("client", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    let registry = &server.clients;
+    let ok = || Ok(RESPValue::simple_string("OK".to_string()));
+    match (subcommand.as_str(), &args[..]) {
+        ("ID", []) => Ok(RESPValue::integer(session.client_id as i64)),
+        ("LIST", []) => Ok(RESPValue::bulk_string(Some(
+            registry
+                .clients()?
+                .iter()
+                .map(|(id, client)| client.describe(*id))
+                .collect(),
+        ))),
+        ("INFO", []) => Ok(RESPValue::bulk_string(registry.info(session.client_id)?)),
+        ("SETNAME", [name]) => {
+            if name.chars().any(|c| !('!'..='~').contains(&c)) {
+                return Ok(RESPValue::error(
+                    "ERR Client names cannot contain spaces, newlines or special characters."
+                        .to_string(),
+                ));
+            }
+            if let Some(client) = registry.clients()?.get_mut(&session.client_id) {
+                client.name = Some(name.to_string()).filter(|name| !name.is_empty());
+            }
+            ok()
+        }
+        ("GETNAME", []) => Ok(RESPValue::bulk_string(
+            registry
+                .clients()?
+                .get(&session.client_id)
+                .and_then(|client| client.name.clone()),
+        )),
+        ("KILL", [addr]) => {
+            // The old form, killing the client with the address
+            let killed = kill(registry, |_, client| client.addr.to_string() == *addr)?;
+            if killed == 0 {
+                Ok(RESPValue::error("ERR No such client".to_string()))
+            } else {
+                ok()
+            }
+        }
+        ("KILL", filters) if !filters.is_empty() => kill_filtered(filters, session, registry),
+        ("PAUSE", [timeout, options @ ..]) => {
+            let kind = match options {
+                [] => PauseKind::All,
+                [o] if o.eq_ignore_ascii_case("ALL") => PauseKind::All,
+                [o] if o.eq_ignore_ascii_case("WRITE") => PauseKind::Write,
+                _ => return Ok(syntax_error()),
+            };
+            let timeout = match value::parse_integer(timeout) {
+                Some(t) if t < 0 => {
+                    return Ok(RESPValue::error("ERR timeout is negative".to_string()))
+                }
+                Some(t) => Duration::from_millis(t as u64),
+                None => {
+                    return Ok(RESPValue::error(
+                        "ERR timeout is not an integer or out of range".to_string(),
+                    ))
+                }
+            };
+            let mut pause = registry
+                .pause
+                .lock()
+                .map_err(|e| format!("Failed to acquire lock for client pause {}", e))?;
+            // A pause in progress is only ever extended and made stricter
+            let (mut end, mut kind) = (Instant::now() + timeout, kind);
+            if let Some((current_end, current_kind)) = *pause {
+                if current_end > Instant::now() {
+                    end = end.max(current_end);
+                    kind = kind.max(current_kind);
+                }
+            }
+            *pause = Some((end, kind));
+            ok()
+        }
+        ("UNPAUSE", []) => {
+            *registry
+                .pause
+                .lock()
+                .map_err(|e| format!("Failed to acquire lock for client pause {}", e))? = None;
+            registry.unpaused.notify_waiters();
+            ok()
+        }
+        ("NO-EVICT", [mode]) => {
+            let no_evict = match mode.to_uppercase().as_str() {
+                "ON" => true,
+                "OFF" => false,
+                _ => return Ok(syntax_error()),
+            };
+            if let Some(client) = registry.clients()?.get_mut(&session.client_id) {
+                client.no_evict = no_evict;
+            }
+            ok()
+        }
+        ("TRACKING", args) => tracking::tracking(args, session, server),
+        ("CACHING", args) => tracking::caching(args, session, server),
+        ("GETREDIR", []) => tracking::getredir(session, server),
+        ("ID", _)
+        | ("GETREDIR", _)
+        | ("LIST", _)
+        | ("INFO", _)
+        | ("SETNAME", _)
+        | ("GETNAME", _)
+        | ("KILL", _)
+        | ("PAUSE", _)
+        | ("UNPAUSE", _)
+        | ("NO-EVICT", _) => Ok(wrong_number_of_arguments(&format!(
+            "client|{}",
+            subcommand.to_lowercase()
+        ))),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'. Try CLIENT HELP.",
+            s.to_lowercase()
+        ))),
+    }
+}
+
+// Kills the matching clients, returning how many there were
+fn kill(registry: &Registry, matches: impl Fn(u64, &Client) -> bool) -> Result<usize, String> {
+    let clients = registry.clients()?;
+    let mut killed = 0;
+    for (id, client) in clients.iter() {
+        if matches(*id, client) {
+            client.kill.notify_one();
+            killed += 1;
+        }
+    }
+    Ok(killed)
+}
+
+// HELLO [protover], switching the connection to RESP2 or RESP3 and describing the server
+pub fn hello(
+    args: &[BulkString],
+    session: &mut Session,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let args = match Args::new("hello", args).rest() {
+        Ok(args) => args,
+        Err(e) => return Ok(e.into()),
+    };
+    let resp3 = match args[..] {
+        [] => session.resp3,
+        [protover] => match value::parse_integer(protover) {
+            Some(2) => false,
+            Some(3) => true,
+            Some(_) => {
+                return Ok(RESPValue::error(
+                    "NOPROTO unsupported protocol version".to_string(),
+                ))
+            }
+            None => {
+                return Ok(RESPValue::error(
+                    "ERR Protocol version is not an integer or out of range".to_string(),
+                ))
+            }
+        },
+        _ => return Ok(syntax_error()),
+    };
+    session.resp3 = resp3;
+    if let Some(client) = server.clients.clients()?.get_mut(&session.client_id) {
+        client.resp3 = resp3;
+    }
+    let bulk = |s: &str| RESPValue::bulk_string(Some(s.to_string()));
+    Ok(RESPValue::Map(vec![
+        (bulk("server"), bulk("redis")),
+        (bulk("version"), bulk(REDIS_VERSION)),
+        (bulk("proto"), RESPValue::integer(if resp3 { 3 } else { 2 })),
+        (bulk("id"), RESPValue::integer(session.client_id as i64)),
+        (bulk("mode"), bulk("standalone")),
+        (bulk("role"), bulk("master")),
+        (bulk("modules"), RESPValue::Array(Some(vec![]))),
+    ]))
+}
+
+// CLIENT KILL <filter> <value> ..., every filter has to match
+fn kill_filtered(
+    filters: &[&str],
+    session: &Session,
+    registry: &Registry,
+) -> Result<RESPValue, String> {
+    if !filters.len().is_multiple_of(2) {
+        return Ok(syntax_error());
+    }
+    let (mut id, mut addr, mut local_addr, mut max_age) = (None, None, None, None);
+    let mut user = None;
+    let mut skip_me = true;
+    let mut kind = None;
+    for pair in filters.chunks(2) {
+        let value = pair[1];
+        match pair[0].to_uppercase().as_str() {
+            "ID" => match value::parse_integer(value) {
+                Some(i) if i > 0 => id = Some(i as u64),
+                _ => {
+                    return Ok(RESPValue::error(
+                        "ERR client-id should be greater than 0".to_string(),
+                    ))
+                }
+            },
+            "ADDR" => addr = Some(value),
+            "LADDR" => local_addr = Some(value),
+            "USER" => user = Some(value),
+            "TYPE" => match value.to_lowercase().as_str() {
+                "normal" => kind = Some("normal"),
+                "pubsub" => kind = Some("pubsub"),
+                // There are no replicas
+                "master" | "replica" | "slave" => kind = Some("replica"),
+                _ => {
+                    return Ok(RESPValue::error(format!(
+                        "ERR Unknown client type '{}'",
+                        value
+                    )))
+                }
+            },
+            "SKIPME" => match value.to_lowercase().as_str() {
+                "yes" => skip_me = true,
+                "no" => skip_me = false,
+                _ => return Ok(syntax_error()),
+            },
+            "MAXAGE" => match value::parse_integer(value) {
+                Some(age) if age >= 0 => max_age = Some(age as u64),
+                _ => return Ok(syntax_error()),
+            },
+            _ => return Ok(syntax_error()),
+        }
+    }
+    let killed = kill(registry, |client_id, client| {
+        kind.is_none_or(|kind| kind == client.kind())
+            && id.is_none_or(|id| id == client_id)
+            && addr.is_none_or(|addr| client.addr.to_string() == addr)
+            && local_addr
+                .is_none_or(|addr| client.local_addr.is_some_and(|a| a.to_string() == addr))
+            && user.is_none_or(|user| client.user == user)
+            && max_age.is_none_or(|age| client.connected.elapsed().as_secs() >= age)
+            && !(skip_me && client_id == session.client_id)
+    })?;
+    Ok(RESPValue::integer(killed as i64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_kill() {
+        let registry = Registry::default();
+        let (pushes, _pushed) = tokio::sync::mpsc::unbounded_channel();
+        let (first, _) = registry.register(addr(1000), None, pushes.clone()).unwrap();
+        let (second, _) = registry.register(addr(1001), None, pushes).unwrap();
+        assert_eq!((first, second), (1, 2));
+        let session = Session {
+            client_id: first,
+            ..Session::default()
+        };
+        let kill_filtered = |filters: &[&str]| kill_filtered(filters, &session, &registry);
+        assert_eq!(
+            kill_filtered(&["TYPE", "normal"]),
+            Ok(RESPValue::integer(1))
+        );
+        assert_eq!(
+            kill_filtered(&["TYPE", "normal", "SKIPME", "no"]),
+            Ok(RESPValue::integer(2))
+        );
+        assert_eq!(
+            kill_filtered(&["ADDR", "127.0.0.1:1001", "ID", "1"]),
+            Ok(RESPValue::integer(0))
+        );
+        assert_eq!(
+            kill_filtered(&["TYPE", "pubsub"]),
+            Ok(RESPValue::integer(0))
+        );
+        assert_eq!(
+            kill_filtered(&["ID", "0"]),
+            Ok(RESPValue::error(
+                "ERR client-id should be greater than 0".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_pause() {
+        let registry = Registry::default();
+        *registry.pause.lock().unwrap() =
+            Some((Instant::now() + Duration::from_secs(10), PauseKind::Write));
+        assert!(registry.pause_remaining(&["set", "a"]).is_some());
+        assert!(registry.pause_remaining(&["FUNCTION", "LOAD"]).is_some());
+        assert!(registry.pause_remaining(&["GET", "a"]).is_none());
+        assert!(registry.pause_remaining(&["CLIENT", "UNPAUSE"]).is_none());
+        *registry.pause.lock().unwrap() = Some((Instant::now(), PauseKind::All));
+        assert!(registry.pause_remaining(&["GET", "a"]).is_none());
+    }
+}
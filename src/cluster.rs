@@ -0,0 +1,1373 @@
+// Cluster mode: keys are spread over 16384 hash slots, each served by one master of the cluster.
+// Commands for keys in slots served elsewhere are redirected with MOVED, or with ASK while the
+// slot moves to another node. The nodes and who serves which slot are read from the cluster
+// config file, in the format of Redis' nodes.conf, and kept up to date by the cluster bus.
+use crate::bus;
+use crate::commands;
+use crate::get_live_entry;
+use crate::info;
+use crate::keyspace::is_expired;
+use crate::not_an_integer;
+use crate::rdb;
+use crate::replication;
+use crate::strings::syntax_error;
+use crate::strings::wrong_number_of_arguments;
+use crate::value;
+use crate::write_table;
+use crate::Databases;
+use crate::Server;
+use crate::Session;
+use crate::Table;
+use redis_starter_rust::bytes_to_string;
+use redis_starter_rust::string_to_bytes;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+pub const SLOTS: usize = 16384;
+
+// The cluster bus listens on the client port plus this
+pub const BUS_PORT_OFFSET: u16 = 10000;
+
+// CRC16-CCITT (XMODEM), the checksum Redis hashes keys with
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// The slot of the key. When the key has a non empty {hashtag}, only the tag is hashed so that
+// related keys can be kept in the same slot.
+pub fn key_hash_slot(key: &str) -> u16 {
+    let bytes = string_to_bytes(key);
+    let tag = bytes.iter().position(|&b| b == b'{').and_then(|start| {
+        let end = bytes[start + 1..].iter().position(|&b| b == b'}')?;
+        Some(&bytes[start + 1..start + 1 + end]).filter(|tag| !tag.is_empty())
+    });
+    crc16(tag.unwrap_or(&bytes)) % SLOTS as u16
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Master,
+    Replica,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub cport: u16,
+    pub role: Role,
+    // The master of a replica
+    pub master: Option<String>,
+    // Flags of nodes that may be or are down, or not known well yet
+    pub pfail: bool,
+    pub fail: bool,
+    pub handshake: bool,
+    pub noaddr: bool,
+    // Unix times in milliseconds, 0 when there is none
+    pub ping_sent: u64,
+    pub pong_received: u64,
+    pub config_epoch: u64,
+    pub connected: bool,
+    // Not saved: when the node was flagged as failing, and when the handshake with it started
+    pub fail_time: u64,
+    pub ctime: u64,
+    // Not saved: the replication offset the node last told
+    pub repl_offset: u64,
+}
+
+impl Node {
+    pub fn new(id: &str, ip: &str, port: u16) -> Self {
+        Self {
+            id: id.to_string(),
+            ip: ip.to_string(),
+            port,
+            cport: port.saturating_add(BUS_PORT_OFFSET),
+            role: Role::Master,
+            master: None,
+            pfail: false,
+            fail: false,
+            handshake: false,
+            noaddr: false,
+            ping_sent: 0,
+            pong_received: 0,
+            config_epoch: 0,
+            connected: true,
+            fail_time: 0,
+            ctime: 0,
+            repl_offset: 0,
+        }
+    }
+
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    fn flags(&self, myself: bool) -> String {
+        let mut flags = vec![];
+        if myself {
+            flags.push("myself");
+        }
+        flags.push(match self.role {
+            Role::Master => "master",
+            Role::Replica => "slave",
+        });
+        if self.pfail {
+            flags.push("fail?");
+        }
+        if self.fail {
+            flags.push("fail");
+        }
+        if self.handshake {
+            flags.push("handshake");
+        }
+        if self.noaddr {
+            flags.push("noaddr");
+        }
+        flags.join(",")
+    }
+
+    fn set_flags(&mut self, flags: &str) -> Result<bool, String> {
+        let mut myself = false;
+        for flag in flags.split(',') {
+            match flag {
+                "myself" => myself = true,
+                "master" => self.role = Role::Master,
+                "slave" => self.role = Role::Replica,
+                "fail?" => self.pfail = true,
+                "fail" => self.fail = true,
+                "handshake" => self.handshake = true,
+                "noaddr" => self.noaddr = true,
+                "noflags" | "nofailover" => {}
+                f => return Err(format!("Unknown flag '{}'", f)),
+            }
+        }
+        Ok(myself)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    pub myself: String,
+    pub current_epoch: u64,
+    pub last_vote_epoch: u64,
+    pub nodes: BTreeMap<String, Node>,
+    // The master serving each slot
+    pub slots: Vec<Option<String>>,
+    // Slots of this node moving to another one, and slots moving here from another one
+    pub migrating: BTreeMap<u16, String>,
+    pub importing: BTreeMap<u16, String>,
+    // Not saved: when masters reported each node as failing, by reporter
+    pub failure_reports: BTreeMap<String, BTreeMap<String, u64>>,
+    // The election this replica runs to replace its failed master
+    pub election: Option<bus::Election>,
+    // When this master last voted for a replica of each master
+    pub voted_for: BTreeMap<String, u64>,
+    // Not saved: for how long in milliseconds the replication link of this replica has been down,
+    // None until it completed a full sync with its master
+    pub link_down: Option<u64>,
+    // Whether the config file is out of date
+    pub dirty: bool,
+}
+
+impl State {
+    // A cluster of one node serving no slot, what a node starts as without a config file
+    pub fn new(ip: &str, port: u16) -> Self {
+        let myself = Node::new(&info::new_run_id(), ip, port);
+        Self {
+            myself: myself.id.clone(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            nodes: BTreeMap::from([(myself.id.clone(), myself)]),
+            slots: vec![None; SLOTS],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            failure_reports: BTreeMap::new(),
+            election: None,
+            voted_for: BTreeMap::new(),
+            link_down: None,
+            dirty: true,
+        }
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    pub fn myself_mut(&mut self) -> &mut Node {
+        self.nodes.get_mut(&self.myself).expect("myself is a node")
+    }
+
+    pub fn serves_slots(&self, id: &str) -> bool {
+        self.slots.iter().any(|owner| owner.as_deref() == Some(id))
+    }
+
+    // The number of masters serving slots that must agree for a node to be flagged as failing or
+    // for a replica to win an election
+    pub fn quorum(&self) -> usize {
+        let masters = self
+            .nodes
+            .values()
+            .filter(|node| node.role == Role::Master && self.serves_slots(&node.id))
+            .count();
+        masters / 2 + 1
+    }
+
+    // The ranges of slots the node serves
+    pub fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        for slot in (0..SLOTS).filter(|slot| self.slots[*slot].as_deref() == Some(id)) {
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    // The replicas of the master
+    pub fn replicas(&self, id: &str) -> Vec<&Node> {
+        self.nodes
+            .values()
+            .filter(|node| node.master.as_deref() == Some(id))
+            .collect()
+    }
+
+    // Whether every slot is served by a node that isn't failing
+    pub fn is_ok(&self) -> bool {
+        self.slots.iter().all(|owner| {
+            owner
+                .as_ref()
+                .and_then(|id| self.nodes.get(id))
+                .is_some_and(|node| !node.fail)
+        })
+    }
+
+    // The line of the node in CLUSTER NODES and the config file
+    pub fn describe(&self, node: &Node) -> String {
+        let myself = node.id == self.myself;
+        let mut line = format!(
+            "{} {}:{}@{} {} {} {} {} {} {}",
+            node.id,
+            node.ip,
+            node.port,
+            node.cport,
+            node.flags(myself),
+            node.master.as_deref().unwrap_or("-"),
+            node.ping_sent,
+            node.pong_received,
+            node.config_epoch,
+            if node.connected || myself {
+                "connected"
+            } else {
+                "disconnected"
+            },
+        );
+        for (start, end) in self.slot_ranges(&node.id) {
+            if start == end {
+                let _ = write!(line, " {}", start);
+            } else {
+                let _ = write!(line, " {}-{}", start, end);
+            }
+        }
+        if myself {
+            for (slot, id) in &self.migrating {
+                let _ = write!(line, " [{}->-{}]", slot, id);
+            }
+            for (slot, id) in &self.importing {
+                let _ = write!(line, " [{}-<-{}]", slot, id);
+            }
+        }
+        line
+    }
+
+    // The contents of the config file, nodes still in handshake are left out like Redis does
+    pub fn to_config(&self) -> String {
+        let mut config: String = self
+            .nodes
+            .values()
+            .filter(|node| !node.handshake)
+            .map(|node| format!("{}\n", self.describe(node)))
+            .collect();
+        let _ = writeln!(
+            config,
+            "vars currentEpoch {} lastVoteEpoch {}",
+            self.current_epoch, self.last_vote_epoch
+        );
+        config
+    }
+
+    pub fn from_config(config: &str) -> Result<Self, String> {
+        let mut state = Self {
+            myself: String::new(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            nodes: BTreeMap::new(),
+            slots: vec![None; SLOTS],
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            failure_reports: BTreeMap::new(),
+            election: None,
+            voted_for: BTreeMap::new(),
+            link_down: None,
+            dirty: false,
+        };
+        for (i, line) in config.lines().enumerate() {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let invalid = || format!("Invalid line {} of the cluster config: {}", i + 1, line);
+            match parts.as_slice() {
+                [] => continue,
+                ["vars", vars @ ..] => {
+                    for pair in vars.chunks(2) {
+                        let value = pair.get(1).and_then(|v| v.parse().ok());
+                        match (pair[0], value) {
+                            ("currentEpoch", Some(epoch)) => state.current_epoch = epoch,
+                            ("lastVoteEpoch", Some(epoch)) => state.last_vote_epoch = epoch,
+                            _ => return Err(invalid()),
+                        }
+                    }
+                }
+                [id, addr, flags, master, ping_sent, pong_received, epoch, link, slots @ ..] => {
+                    let (ip, port, cport) = parse_addr(addr).ok_or_else(invalid)?;
+                    let mut node = Node::new(id, ip, port);
+                    node.cport = cport;
+                    let myself = node
+                        .set_flags(flags)
+                        .map_err(|e| format!("{}: {}", invalid(), e))?;
+                    node.master = Some(master.to_string()).filter(|m| m != "-");
+                    node.ping_sent = ping_sent.parse().map_err(|_| invalid())?;
+                    node.pong_received = pong_received.parse().map_err(|_| invalid())?;
+                    node.config_epoch = epoch.parse().map_err(|_| invalid())?;
+                    node.connected = *link == "connected";
+                    if myself {
+                        state.myself = id.to_string();
+                    }
+                    for slots in slots {
+                        state.parse_slots(id, slots).ok_or_else(invalid)?;
+                    }
+                    state.nodes.insert(id.to_string(), node);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if !state.nodes.contains_key(&state.myself) {
+            return Err("The cluster config doesn't say which node is this one".to_string());
+        }
+        Ok(state)
+    }
+
+    // A slot, a range of slots or a slot being migrated like [93->-<id>] or imported like
+    // [93-<-<id>]
+    fn parse_slots(&mut self, id: &str, slots: &str) -> Option<()> {
+        if let Some(migration) = slots.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            if let Some((slot, target)) = migration.split_once("->-") {
+                self.migrating.insert(parse_slot(slot)?, target.to_string());
+            } else {
+                let (slot, source) = migration.split_once("-<-")?;
+                self.importing.insert(parse_slot(slot)?, source.to_string());
+            }
+            return Some(());
+        }
+        let (start, end) = match slots.split_once('-') {
+            Some((start, end)) => (parse_slot(start)?, parse_slot(end)?),
+            None => (parse_slot(slots)?, parse_slot(slots)?),
+        };
+        for slot in start..=end {
+            self.slots[slot as usize] = Some(id.to_string());
+        }
+        Some(())
+    }
+}
+
+fn parse_slot(slot: &str) -> Option<u16> {
+    slot.parse().ok().filter(|slot| (*slot as usize) < SLOTS)
+}
+
+// ip:port@cport, possibly followed by ,hostname
+fn parse_addr(addr: &str) -> Option<(&str, u16, u16)> {
+    let addr = addr.split(',').next()?;
+    let (addr, cport) = addr.split_once('@')?;
+    let (ip, port) = addr.rsplit_once(':')?;
+    Some((ip, port.parse().ok()?, cport.parse().ok()?))
+}
+
+// The cluster state, None unless cluster-enabled is set
+#[derive(Default)]
+pub struct Cluster {
+    state: Mutex<Option<State>>,
+}
+
+impl Cluster {
+    pub fn state(&self) -> Result<MutexGuard<'_, Option<State>>, String> {
+        self.state
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for cluster {}", e))
+    }
+
+    // Enables cluster mode with the nodes of the config file, or as a new node without one
+    pub fn load(&self, path: &Path, port: u16) -> Result<(), String> {
+        let state = match fs::read_to_string(path) {
+            Ok(config) => {
+                let mut state = State::from_config(&config)?;
+                // Like in Redis, the times saved only tell whether a ping or a pong was seen
+                let now = bus::now_ms();
+                for node in state.nodes.values_mut() {
+                    if node.ping_sent != 0 {
+                        node.ping_sent = now;
+                    }
+                    if node.pong_received != 0 {
+                        node.pong_received = now;
+                    }
+                }
+                state
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => State::new("127.0.0.1", port),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        *self.state()? = Some(state);
+        Ok(())
+    }
+
+    // Writes the config file if the state changed since it was last written
+    pub fn save_if_dirty(&self, path: &Path) -> Result<(), String> {
+        let config = match &mut *self.state()? {
+            Some(state) if state.dirty => {
+                state.dirty = false;
+                state.to_config()
+            }
+            _ => return Ok(()),
+        };
+        // Written to a temporary file first so that a failure can't leave a truncated file behind
+        let temporary = path.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&temporary, config)
+            .and_then(|_| fs::rename(&temporary, path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&temporary);
+                format!(
+                    "Failed to save the cluster config to '{}': {}",
+                    path.display(),
+                    e
+                )
+            })
+    }
+}
+
+// The redirection to reply with when the keys of the command aren't served here, None when the
+// command can run. Keys of slots being imported are served to clients that sent ASKING first.
+pub fn check(
+    args: &[&str],
+    databases: &Databases,
+    asking: bool,
+    server: &Server,
+) -> Result<Option<RESPValue>, String> {
+    let cluster = server.cluster.state()?;
+    let state = match &*cluster {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+    let keys = match commands::lookup(args) {
+        Some(command) => command.keys(args),
+        None => return Ok(None),
+    };
+    let slot = match keys.split_first() {
+        Some((key, others)) => {
+            let slot = key_hash_slot(key);
+            if others.iter().any(|key| key_hash_slot(key) != slot) {
+                return Ok(Some(RESPValue::error(
+                    "CROSSSLOT Keys in request don't hash to the same slot".to_string(),
+                )));
+            }
+            slot
+        }
+        None => return Ok(None),
+    };
+    let redirect = |kind: &str, id: &str| {
+        let addr = state
+            .nodes
+            .get(id)
+            .map_or(String::new(), |node| node.addr());
+        Ok(Some(RESPValue::error(format!(
+            "{} {} {}",
+            kind, slot, addr
+        ))))
+    };
+    // The number of keys that aren't here, while they move from one node to the other
+    let missing = || -> Result<usize, String> {
+        let t = databases[0]
+            .read()
+            .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+        Ok(keys
+            .iter()
+            .filter(|key| t.get(key).is_none_or(is_expired))
+            .count())
+    };
+    let try_again = || {
+        Ok(Some(RESPValue::error(
+            "TRYAGAIN Multiple keys request during rehashing of slot".to_string(),
+        )))
+    };
+    // Keys move with MIGRATE while their slot is open, some being here and others not yet
+    if args[0].eq_ignore_ascii_case("migrate")
+        && (state.migrating.contains_key(&slot) || state.importing.contains_key(&slot))
+    {
+        return Ok(None);
+    }
+    let asking = asking || args[0].eq_ignore_ascii_case("restore-asking");
+    if asking && state.importing.contains_key(&slot) {
+        return match missing()? {
+            n if n > 0 && keys.len() > 1 => try_again(),
+            _ => Ok(None),
+        };
+    }
+    match &state.slots[slot as usize] {
+        None => Ok(Some(RESPValue::error(
+            "CLUSTERDOWN Hash slot not served".to_string(),
+        ))),
+        Some(owner) if *owner != state.myself => redirect("MOVED", owner),
+        Some(_) => {
+            let target = match state.migrating.get(&slot) {
+                Some(target) => target,
+                None => return Ok(None),
+            };
+            // While the slot migrates, the keys that already moved are asked to the target
+            match missing()? {
+                0 => Ok(None),
+                n if n == keys.len() => redirect("ASK", target),
+                _ => try_again(),
+            }
+        }
+    }
+}
+
+pub fn asking(
+    args: &[BulkString],
+    session: &mut Session,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    if !args.is_empty() {
+        return Ok(wrong_number_of_arguments("asking"));
+    }
+    if server.cluster.state()?.is_none() {
+        return Ok(RESPValue::error(
+            "ERR This instance has cluster support disabled".to_string(),
+        ));
+    }
+    session.asking = true;
+    Ok(RESPValue::simple_string("OK".to_string()))
+}
+
+// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH password]
+// [AUTH2 username password] [KEYS key ...] restores the keys on another instance from their DUMP
+// payloads, then deletes the ones the target acknowledged unless COPY is given. Like in Redis,
+// the client waits for the target instance meanwhile, but other clients are served.
+// Runs alone like in Redis, where MIGRATE blocks the server, so that the keys can't change while
+// they are sent. The guard of replication is only held around their deletion, as PSYNC would
+// otherwise wait for the target instance too.
+pub fn migrate(
+    args: &[BulkString],
+    table: Table,
+    source_db: usize,
+    replication: &replication::Replication,
+) -> Result<RESPValue, String> {
+    let parsed = Args::new("migrate", args).parse(|args| {
+        Ok((
+            args.next_str()?,
+            args.next_str()?,
+            args.next_key()?,
+            args.next_str()?,
+            args.next_str()?,
+            args.rest()?,
+        ))
+    });
+    // Replicas delete the keys that were moved, rather than moving them again
+    replication::propagate_instead(vec![]);
+    let (host, port, key, db, timeout, options) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut copy = false;
+    let mut replace = false;
+    let mut auth = vec![];
+    let mut keys = vec![key];
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "AUTH" => match options.next() {
+                Some(password) => auth = vec![*password],
+                None => return Ok(syntax_error()),
+            },
+            "AUTH2" => match (options.next(), options.next()) {
+                (Some(username), Some(password)) => auth = vec![*username, *password],
+                _ => return Ok(syntax_error()),
+            },
+            "KEYS" => {
+                if !key.is_empty() {
+                    return Ok(RESPValue::error(
+                        "ERR When using MIGRATE KEYS option, the key argument must be set to \
+                         the empty string"
+                            .to_string(),
+                    ));
+                }
+                keys = options.by_ref().copied().collect();
+            }
+            _ => return Ok(syntax_error()),
+        }
+    }
+    let (port, db, timeout) = match (
+        port.parse::<u16>(),
+        value::parse_integer(db),
+        value::parse_integer(timeout),
+    ) {
+        (Ok(port), Some(db), Some(timeout)) => (port, db, timeout),
+        _ => return Ok(not_an_integer()),
+    };
+    let timeout = Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 });
+
+    let mut commands: Vec<Vec<String>> = vec![];
+    if !auth.is_empty() {
+        commands.push(
+            std::iter::once("AUTH")
+                .chain(auth)
+                .map(str::to_string)
+                .collect(),
+        );
+    }
+    commands.push(vec!["SELECT".to_string(), db.to_string()]);
+    let mut migrated = vec![];
+    {
+        let mut t = write_table(&table)?;
+        for key in keys {
+            let (value, expiry, _) = match get_live_entry(&mut t, key) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let ttl = expiry.map_or(0, |(inserted, duration)| {
+                duration
+                    .saturating_sub(inserted.elapsed())
+                    .as_millis()
+                    .max(1)
+            });
+            let mut payload = vec![];
+            rdb::write_value(&mut payload, value);
+            rdb::append_footer(&mut payload);
+            let mut restore = vec![
+                "RESTORE-ASKING".to_string(),
+                key.to_string(),
+                ttl.to_string(),
+                bytes_to_string(&payload),
+            ];
+            if replace {
+                restore.push("REPLACE".to_string());
+            }
+            commands.push(restore);
+            migrated.push((key, value.clone()));
+        }
+    }
+    if migrated.is_empty() {
+        return Ok(RESPValue::simple_string("NOKEY".to_string()));
+    }
+
+    let setup = commands.len() - migrated.len();
+    // The worker thread is handed over to the runtime's other tasks while this one waits
+    let replies = tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current()
+            .block_on(send_commands(host, port, timeout, &commands, setup))
+    });
+    let replies = match replies {
+        Ok(replies) => replies,
+        Err(e) => return Ok(RESPValue::error(format!("IOERR error or timeout {}", e))),
+    };
+    let target_error = |e: &str| {
+        Ok(RESPValue::error(format!(
+            "ERR Target instance replied with error: {}",
+            e
+        )))
+    };
+    // AUTH and SELECT have to succeed, while the keys that were restored are deleted even when
+    // others weren't. Clients waited meanwhile, so the keys can only have expired.
+    if let Some(RESPValue::Error(e)) = replies
+        .iter()
+        .take(setup)
+        .find(|r| matches!(r, RESPValue::Error(_)))
+    {
+        return target_error(e);
+    }
+    let restores = &replies[setup..];
+    let mut error = None;
+    let mut deleted = vec![];
+    let _writes = replication.lock_writes()?;
+    let mut t = write_table(&table)?;
+    for ((key, value), reply) in migrated.into_iter().zip(restores) {
+        match reply {
+            RESPValue::SimpleString(ok) if ok == "OK" => {
+                if !copy && t.get(key).is_some_and(|(current, _, _)| *current == value) {
+                    t.remove(key);
+                    deleted.push(key.to_string());
+                }
+            }
+            RESPValue::Error(e) => {
+                error.get_or_insert(e.clone());
+            }
+            reply => {
+                error.get_or_insert(format!("unexpected reply {:?}", reply));
+            }
+        }
+    }
+    if !deleted.is_empty() {
+        let unlink: Vec<&str> = std::iter::once("UNLINK")
+            .chain(deleted.iter().map(String::as_str))
+            .collect();
+        replication.propagate(Some(source_db), &unlink)?;
+    }
+    match error {
+        Some(e) => target_error(&e),
+        None => Ok(RESPValue::simple_string("OK".to_string())),
+    }
+}
+
+// Sends the commands one after the other, each once the previous one was answered, returning
+// the replies or what failed. It stops at the first error among the first `setup` commands.
+async fn send_commands(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    commands: &[Vec<String>],
+    setup: usize,
+) -> Result<Vec<RESPValue>, &'static str> {
+    let connecting = "connecting to the client";
+    let writing = "writing to target instance";
+    let reading = "reading to target instance";
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect((host, port)))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(connecting)?;
+    let mut buffer = vec![];
+    let mut replies = vec![];
+    for command in commands {
+        let command = RESPValue::Array(Some(
+            command
+                .iter()
+                .map(|arg| RESPValue::bulk_string(Some(arg.clone())))
+                .collect(),
+        ));
+        tokio::time::timeout(timeout, stream.write_all(&command.to_bytes()))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or(writing)?;
+        let reply =
+            tokio::time::timeout(timeout, replication::read_reply(&mut stream, &mut buffer))
+                .await
+                .ok()
+                .flatten()
+                .ok_or(reading)?;
+        let failed = matches!(reply, RESPValue::Error(_));
+        replies.push(reply);
+        if failed && replies.len() <= setup {
+            break;
+        }
+    }
+    Ok(replies)
+}
+
+// The keys of the slot in the only database of a cluster
+fn keys_in_slot(databases: &Databases, slot: u16) -> Result<Vec<String>, String> {
+    let t = databases[0]
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+    let mut keys: Vec<String> = t
+        .iter()
+        .filter(|(key, entry)| !is_expired(entry) && key_hash_slot(key) == slot)
+        .map(|(key, _)| key.clone())
+        .collect();
+    keys.sort();
+    Ok(keys)
+}
+
+fn bulk(s: &str) -> RESPValue {
+    RESPValue::bulk_string(Some(s.to_string()))
+}
+
+// A node as CLUSTER SLOTS lists it
+fn slots_node(node: &Node) -> RESPValue {
+    RESPValue::Array(Some(vec![
+        bulk(&node.ip),
+        RESPValue::integer(node.port as i64),
+        bulk(&node.id),
+        RESPValue::Map(vec![]),
+    ]))
+}
+
+// A node as CLUSTER SHARDS lists it
+fn shards_node(node: &Node) -> RESPValue {
+    let health = if node.fail || node.pfail {
+        "fail"
+    } else {
+        "online"
+    };
+    let role = match node.role {
+        Role::Master => "master",
+        Role::Replica => "replica",
+    };
+    RESPValue::Map(vec![
+        (bulk("id"), bulk(&node.id)),
+        (bulk("port"), RESPValue::integer(node.port as i64)),
+        (bulk("ip"), bulk(&node.ip)),
+        (bulk("endpoint"), bulk(&node.ip)),
+        (bulk("role"), bulk(role)),
+        (
+            bulk("replication-offset"),
+            RESPValue::integer(node.repl_offset as i64),
+        ),
+        (bulk("health"), bulk(health)),
+    ])
+}
+
+fn cluster_info(state: &State) -> String {
+    let assigned = state.slots.iter().flatten().count();
+    let failing = |pfail: bool| {
+        state
+            .slots
+            .iter()
+            .flatten()
+            .filter_map(|id| state.nodes.get(id))
+            .filter(|node| if pfail { node.pfail } else { node.fail })
+            .count()
+    };
+    let masters = state
+        .nodes
+        .values()
+        .filter(|node| !state.slot_ranges(&node.id).is_empty())
+        .count();
+    let fields = [
+        ("cluster_enabled", "1".to_string()),
+        (
+            "cluster_state",
+            if state.is_ok() { "ok" } else { "fail" }.to_string(),
+        ),
+        ("cluster_slots_assigned", assigned.to_string()),
+        (
+            "cluster_slots_ok",
+            (assigned - failing(true) - failing(false)).to_string(),
+        ),
+        ("cluster_slots_pfail", failing(true).to_string()),
+        ("cluster_slots_fail", failing(false).to_string()),
+        ("cluster_known_nodes", state.nodes.len().to_string()),
+        ("cluster_size", masters.to_string()),
+        ("cluster_current_epoch", state.current_epoch.to_string()),
+        ("cluster_my_epoch", state.myself().config_epoch.to_string()),
+    ];
+    fields
+        .iter()
+        .map(|(name, value)| format!("{}:{}\r\n", name, value))
+        .collect()
+}
+
+pub fn cluster_command(
+    args: &[BulkString],
+    databases: &Databases,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let mut args = Args::new("cluster", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    let mut cluster = server.cluster.state()?;
+    let state = match &mut *cluster {
+        Some(state) => state,
+        None => {
+            return Ok(RESPValue::error(
+                "ERR This instance has cluster support disabled".to_string(),
+            ))
+        }
+    };
+    let invalid_slot = || Ok(RESPValue::error("ERR Invalid slot".to_string()));
+    let unknown_node = |id: &str| Ok(RESPValue::error(format!("ERR Unknown node {}", id)));
+    let ok = || Ok(RESPValue::simple_string("OK".to_string()));
+    match (subcommand.as_str(), &args[..]) {
+        ("ADDSLOTS", [_, ..]) | ("DELSLOTS", [_, ..]) => {
+            let adding = subcommand == "ADDSLOTS";
+            let mut slots = BTreeSet::new();
+            for slot in args {
+                let slot = match parse_slot(slot) {
+                    Some(slot) => slot,
+                    None => {
+                        return Ok(RESPValue::error(
+                            "ERR Invalid or out of range slot".to_string(),
+                        ))
+                    }
+                };
+                let error = match (adding, &state.slots[slot as usize]) {
+                    (true, Some(_)) => Some("is already busy"),
+                    (false, None) => Some("is already unassigned"),
+                    _ if !slots.insert(slot) => Some("specified multiple times"),
+                    _ => None,
+                };
+                if let Some(error) = error {
+                    return Ok(RESPValue::error(format!("ERR Slot {} {}", slot, error)));
+                }
+            }
+            for slot in slots {
+                state.slots[slot as usize] = Some(state.myself.clone()).filter(|_| adding);
+                state.importing.remove(&slot);
+            }
+            state.dirty = true;
+            ok()
+        }
+        ("SETSLOT", [slot, action, node @ ..]) => {
+            let slot = match parse_slot(slot) {
+                Some(slot) => slot,
+                None => {
+                    return Ok(RESPValue::error(
+                        "ERR Invalid or out of range slot".to_string(),
+                    ))
+                }
+            };
+            let owned = state.slots[slot as usize].as_deref() == Some(state.myself.as_str());
+            let node = match (action.to_uppercase().as_str(), node) {
+                ("STABLE", []) => {
+                    state.migrating.remove(&slot);
+                    state.importing.remove(&slot);
+                    state.dirty = true;
+                    return ok();
+                }
+                (action @ ("MIGRATING" | "IMPORTING" | "NODE"), [id]) => {
+                    match (action, state.nodes.get(*id)) {
+                        (_, Some(node)) if node.role != Role::Master => {
+                            return Ok(RESPValue::error(
+                                "ERR Target node is not a master".to_string(),
+                            ))
+                        }
+                        ("NODE", None) => return unknown_node(id),
+                        (_, None) => {
+                            return Ok(RESPValue::error(format!(
+                                "ERR I don't know about node {}",
+                                id
+                            )))
+                        }
+                        (_, Some(node)) => node.id.clone(),
+                    }
+                }
+                _ => {
+                    return Ok(RESPValue::error(
+                        "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try \
+                         CLUSTER HELP"
+                            .to_string(),
+                    ))
+                }
+            };
+            match action.to_uppercase().as_str() {
+                "MIGRATING" if !owned => {
+                    return Ok(RESPValue::error(format!(
+                        "ERR I'm not the owner of hash slot {}",
+                        slot
+                    )))
+                }
+                "MIGRATING" => {
+                    state.migrating.insert(slot, node);
+                }
+                "IMPORTING" if owned => {
+                    return Ok(RESPValue::error(format!(
+                        "ERR I'm already the owner of hash slot {}",
+                        slot
+                    )))
+                }
+                "IMPORTING" => {
+                    state.importing.insert(slot, node);
+                }
+                _ => {
+                    let keys = keys_in_slot(databases, slot)?.len();
+                    if owned && node != state.myself && keys > 0 {
+                        return Ok(RESPValue::error(format!(
+                            "ERR Can't assign hashslot {} to a different node while I still \
+                             hold keys for this hash slot.",
+                            slot
+                        )));
+                    }
+                    if keys == 0 {
+                        state.migrating.remove(&slot);
+                    }
+                    // The node that imported the slot takes a new config epoch without asking
+                    // the others, so that its claim of the slot wins over the previous owner's
+                    if node == state.myself && state.importing.remove(&slot).is_some() {
+                        let max_epoch = state
+                            .nodes
+                            .values()
+                            .map(|node| node.config_epoch)
+                            .fold(state.current_epoch, u64::max);
+                        let epoch = state.myself().config_epoch;
+                        if epoch == 0 || epoch != max_epoch {
+                            state.current_epoch += 1;
+                            let epoch = state.current_epoch;
+                            state.myself_mut().config_epoch = epoch;
+                        }
+                    }
+                    state.slots[slot as usize] = Some(node);
+                }
+            }
+            state.dirty = true;
+            ok()
+        }
+        ("MEET", [ip, port]) | ("MEET", [ip, port, _]) => {
+            let cport = match args.get(2) {
+                Some(cport) => cport.parse().ok(),
+                None => port
+                    .parse::<u16>()
+                    .ok()
+                    .map(|p| p.wrapping_add(BUS_PORT_OFFSET)),
+            };
+            match (ip.parse::<IpAddr>(), port.parse(), cport) {
+                (Ok(_), Ok(port), Some(cport)) => {
+                    bus::start_handshake(state, ip, port, cport);
+                    Ok(RESPValue::simple_string("OK".to_string()))
+                }
+                _ => Ok(RESPValue::error(format!(
+                    "ERR Invalid node address specified: {}:{}",
+                    ip, port
+                ))),
+            }
+        }
+        ("REPLICATE", [id]) => {
+            let master = match state.nodes.get(*id) {
+                Some(master) => master,
+                None => return unknown_node(id),
+            };
+            if *id == state.myself {
+                return Ok(RESPValue::error("ERR Can't replicate myself".to_string()));
+            }
+            if master.role != Role::Master {
+                return Ok(RESPValue::error(
+                    "ERR I can only replicate a master, not a replica.".to_string(),
+                ));
+            }
+            if state.myself().role == Role::Master && state.serves_slots(&state.myself.clone()) {
+                return Ok(RESPValue::error(
+                    "ERR To set a master the node must be empty and without assigned slots."
+                        .to_string(),
+                ));
+            }
+            let myself = state.myself_mut();
+            myself.role = Role::Replica;
+            myself.master = Some(id.to_string());
+            state.dirty = true;
+            Ok(RESPValue::simple_string("OK".to_string()))
+        }
+        ("REPLICAS", [id]) | ("SLAVES", [id]) => match state.nodes.get(*id) {
+            Some(node) if node.role == Role::Master => Ok(RESPValue::Array(Some(
+                state
+                    .replicas(id)
+                    .into_iter()
+                    .map(|replica| bulk(&state.describe(replica)))
+                    .collect(),
+            ))),
+            Some(_) => Ok(RESPValue::error(
+                "ERR The specified node is not a master".to_string(),
+            )),
+            None => unknown_node(id),
+        },
+        ("COUNT-FAILURE-REPORTS", [id]) => match state.nodes.get(*id) {
+            Some(_) => Ok(RESPValue::integer(
+                state
+                    .failure_reports
+                    .get(*id)
+                    .map_or(0, |reports| reports.len()) as i64,
+            )),
+            None => unknown_node(id),
+        },
+        ("INFO", []) => Ok(RESPValue::bulk_string(Some(cluster_info(state)))),
+        ("MYID", []) => Ok(bulk(&state.myself)),
+        ("NODES", []) => Ok(RESPValue::bulk_string(Some(
+            state
+                .nodes
+                .values()
+                .map(|node| format!("{}\n", state.describe(node)))
+                .collect(),
+        ))),
+        ("SLOTS", []) => {
+            let mut slots = vec![];
+            for master in state.nodes.values() {
+                for (start, end) in state.slot_ranges(&master.id) {
+                    let mut range = vec![
+                        RESPValue::integer(start as i64),
+                        RESPValue::integer(end as i64),
+                        slots_node(master),
+                    ];
+                    range.extend(
+                        state
+                            .replicas(&master.id)
+                            .into_iter()
+                            .filter(|replica| !replica.fail)
+                            .map(slots_node),
+                    );
+                    slots.push((start, RESPValue::Array(Some(range))));
+                }
+            }
+            slots.sort_by_key(|(start, _)| *start);
+            Ok(RESPValue::Array(Some(
+                slots.into_iter().map(|(_, range)| range).collect(),
+            )))
+        }
+        ("SHARDS", []) => Ok(RESPValue::Array(Some(
+            state
+                .nodes
+                .values()
+                .filter(|node| node.role == Role::Master)
+                .map(|master| {
+                    let slots = state
+                        .slot_ranges(&master.id)
+                        .into_iter()
+                        .flat_map(|(start, end)| {
+                            [
+                                RESPValue::integer(start as i64),
+                                RESPValue::integer(end as i64),
+                            ]
+                        })
+                        .collect();
+                    let mut nodes = vec![shards_node(master)];
+                    nodes.extend(state.replicas(&master.id).into_iter().map(shards_node));
+                    RESPValue::Map(vec![
+                        (bulk("slots"), RESPValue::Array(Some(slots))),
+                        (bulk("nodes"), RESPValue::Array(Some(nodes))),
+                    ])
+                })
+                .collect(),
+        ))),
+        ("KEYSLOT", [key]) => Ok(RESPValue::integer(key_hash_slot(key) as i64)),
+        ("COUNTKEYSINSLOT", [slot]) => match value::parse_integer(slot) {
+            Some(slot) if (0..SLOTS as i64).contains(&slot) => Ok(RESPValue::integer(
+                keys_in_slot(databases, slot as u16)?.len() as i64,
+            )),
+            _ => invalid_slot(),
+        },
+        ("GETKEYSINSLOT", [slot, count]) => {
+            let count = match value::parse_integer(count) {
+                Some(count) if count >= 0 => count as usize,
+                _ => return Ok(RESPValue::error("ERR Invalid number of keys".to_string())),
+            };
+            match value::parse_integer(slot) {
+                Some(slot) if (0..SLOTS as i64).contains(&slot) => Ok(RESPValue::Array(Some(
+                    keys_in_slot(databases, slot as u16)?
+                        .iter()
+                        .take(count)
+                        .map(|key| bulk(key))
+                        .collect(),
+                ))),
+                _ => invalid_slot(),
+            }
+        }
+        ("ADDSLOTS", _)
+        | ("DELSLOTS", _)
+        | ("SETSLOT", _)
+        | ("MEET", _)
+        | ("REPLICATE", _)
+        | ("REPLICAS", _)
+        | ("SLAVES", _)
+        | ("COUNT-FAILURE-REPORTS", _)
+        | ("INFO", _)
+        | ("MYID", _)
+        | ("NODES", _)
+        | ("SLOTS", _)
+        | ("SHARDS", _)
+        | ("KEYSLOT", _)
+        | ("COUNTKEYSINSLOT", _)
+        | ("GETKEYSINSLOT", _) => Ok(wrong_number_of_arguments(&format!(
+            "cluster|{}",
+            subcommand.to_lowercase()
+        ))),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'. Try CLUSTER HELP.",
+            s.to_lowercase()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dict::Keyspace;
+    use crate::memory::Access;
+    use crate::value::Value;
+    use std::sync::Arc;
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_key_hash_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_hash_slot("foo"), 12182);
+        assert_eq!(
+            key_hash_slot("{user1000}.following"),
+            key_hash_slot("user1000")
+        );
+        assert_eq!(key_hash_slot("foo{bar}{zap}"), key_hash_slot("bar"));
+        assert_eq!(
+            key_hash_slot("foo{}{bar}"),
+            crc16(b"foo{}{bar}") % SLOTS as u16
+        );
+        assert_eq!(key_hash_slot("foo{{bar}}zap"), key_hash_slot("{bar"));
+    }
+
+    #[test]
+    fn test_config() {
+        let config = "\
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@40001 myself,master - 0 0 1 connected 0-5460 [93->-67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1]
+67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1 127.0.0.1:30002@40002,host2 master - 0 1426238316232 2 connected 5461-10922 16383
+07c37dfeb235213a872192d90877d0cd55635b91 127.0.0.1:30004@40004 slave,fail e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 0 1426238317239 1 disconnected
+vars currentEpoch 6 lastVoteEpoch 0
+";
+        let state = State::from_config(config).unwrap();
+        assert_eq!(state.myself, "e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca");
+        assert_eq!(state.current_epoch, 6);
+        assert_eq!(
+            state.slot_ranges("67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1"),
+            vec![(5461, 10922), (16383, 16383)]
+        );
+        assert_eq!(
+            state.migrating.get(&93).map(String::as_str),
+            Some("67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1")
+        );
+        let replica = &state.nodes["07c37dfeb235213a872192d90877d0cd55635b91"];
+        assert_eq!(replica.role, Role::Replica);
+        assert!(replica.fail && !replica.connected);
+        assert!(!state.is_ok());
+        assert_eq!(State::from_config(&state.to_config()).unwrap(), state);
+        assert!(State::from_config("vars currentEpoch 1").is_err());
+    }
+
+    fn args(args: &[&str]) -> Vec<BulkString> {
+        args.iter()
+            .map(|s| BulkString::from(s.to_string()))
+            .collect()
+    }
+
+    // Serves one connection like a target instance would, answering the commands with `replies`
+    // in turn and leaving the others unanswered, and returns the commands it received
+    async fn serve_target(
+        replies: Vec<&'static str>,
+    ) -> (u16, tokio::task::JoinHandle<Vec<RESPValue>>) {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = vec![];
+            let mut commands = vec![];
+            for reply in replies {
+                commands.push(
+                    replication::read_reply(&mut stream, &mut buffer)
+                        .await
+                        .unwrap(),
+                );
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+            commands
+        });
+        (port, handle)
+    }
+
+    fn table_with(entries: &[(&str, Value)]) -> Table {
+        let table: Table = Arc::new(RwLock::new(Keyspace::default()));
+        for (key, value) in entries {
+            table
+                .write()
+                .unwrap()
+                .insert(key.to_string(), (value.clone(), None, Access::new()));
+        }
+        table
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrate() {
+        let big = "x".repeat(10_000);
+        let table = table_with(&[("a", Value::Integer(1)), ("b", Value::String(big.clone()))]);
+        let (port, target) = serve_target(vec!["+OK\r\n", "+OK\r\n", "+OK\r\n"]).await;
+        let port = port.to_string();
+        assert_eq!(
+            migrate(
+                &args(&["127.0.0.1", &port, "", "0", "1000", "KEYS", "a", "b"]),
+                table.clone(),
+                0,
+                &replication::Replication::default()
+            ),
+            Ok(RESPValue::simple_string("OK".to_string()))
+        );
+        assert!(table.read().unwrap().is_empty());
+        let commands = target.await.unwrap();
+        let restore = |key: &str, value: &Value| {
+            let mut payload = vec![];
+            rdb::write_value(&mut payload, value);
+            rdb::append_footer(&mut payload);
+            RESPValue::Array(Some(
+                ["RESTORE-ASKING", key, "0", &bytes_to_string(&payload)]
+                    .iter()
+                    .map(|arg| RESPValue::bulk_string(Some(arg.to_string())))
+                    .collect(),
+            ))
+        };
+        assert_eq!(commands[1], restore("a", &Value::Integer(1)));
+        assert_eq!(commands[2], restore("b", &Value::String(big)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrate_errors() {
+        let table = table_with(&[("a", Value::Integer(1)), ("b", Value::Integer(2))]);
+        let (port, target) = serve_target(vec!["+OK\r\n", "+OK\r\n", "-BUSYKEY exists\r\n"]).await;
+        let port = port.to_string();
+        assert_eq!(
+            migrate(
+                &args(&["127.0.0.1", &port, "", "0", "1000", "KEYS", "a", "b"]),
+                table.clone(),
+                0,
+                &replication::Replication::default()
+            ),
+            Ok(RESPValue::error(
+                "ERR Target instance replied with error: BUSYKEY exists".to_string()
+            ))
+        );
+        target.await.unwrap();
+        assert!(!table.read().unwrap().contains_key("a"));
+        assert!(table.read().unwrap().contains_key("b"));
+
+        // The key is kept when the target instance fails to answer
+        let (port, target) = serve_target(vec!["+OK\r\n"]).await;
+        let port = port.to_string();
+        assert_eq!(
+            migrate(
+                &args(&["127.0.0.1", &port, "b", "0", "100"]),
+                table.clone(),
+                0,
+                &replication::Replication::default()
+            ),
+            Ok(RESPValue::error(
+                "IOERR error or timeout reading to target instance".to_string()
+            ))
+        );
+        target.await.unwrap();
+        assert!(table.read().unwrap().contains_key("b"));
+    }
+}
@@ -0,0 +1,422 @@
+// Table of the commands the server implements, with the ACL categories they belong to and where
+// their keys are among the arguments, like Redis' command table
+use crate::value;
+
+// Where the keys of a command are, counting its name as argument 0 like Redis' legacy key specs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Keys {
+    None,
+    // First and last key, a negative last one counts from the end, and the step between keys
+    Range(usize, i64, usize),
+    // The number of keys is the argument at the position, and the keys follow it
+    Counted(usize),
+    // The key at the position, or when it's empty the arguments after the keyword
+    KeyOrKeyword(usize, &'static str),
+}
+
+pub struct Command {
+    // Lowercase, subcommands with their own permissions are named `command|subcommand`
+    pub name: &'static str,
+    pub categories: &'static [&'static str],
+    pub keys: Keys,
+}
+
+// The categories in the order ACL CAT lists them
+pub const CATEGORIES: &[&str] = &[
+    "keyspace",
+    "read",
+    "write",
+    "set",
+    "sortedset",
+    "list",
+    "hash",
+    "string",
+    "bitmap",
+    "hyperloglog",
+    "geo",
+    "stream",
+    "pubsub",
+    "admin",
+    "fast",
+    "slow",
+    "blocking",
+    "dangerous",
+    "connection",
+    "transaction",
+    "scripting",
+];
+
+const fn command(name: &'static str, categories: &'static [&'static str], keys: Keys) -> Command {
+    Command {
+        name,
+        categories,
+        keys,
+    }
+}
+
+const KEY: Keys = Keys::Range(1, 1, 1);
+const TWO_KEYS: Keys = Keys::Range(1, 2, 1);
+const ALL_KEYS: Keys = Keys::Range(1, -1, 1);
+
+pub const COMMANDS: &[Command] = &[
+    command("echo", &["fast", "connection"], Keys::None),
+    command("ping", &["fast", "connection"], Keys::None),
+    command("hello", &["fast", "connection"], Keys::None),
+    command("auth", &["fast", "connection"], Keys::None),
+    command("select", &["fast", "connection"], Keys::None),
+    command("asking", &["fast", "connection"], Keys::None),
+    command("set", &["write", "string", "slow"], KEY),
+    command("get", &["read", "string", "fast"], KEY),
+    command("incr", &["write", "string", "fast"], KEY),
+    command("decr", &["write", "string", "fast"], KEY),
+    command("incrby", &["write", "string", "fast"], KEY),
+    command("decrby", &["write", "string", "fast"], KEY),
+    command("incrbyfloat", &["write", "string", "fast"], KEY),
+    command("append", &["write", "string", "fast"], KEY),
+    command("strlen", &["read", "string", "fast"], KEY),
+    command("getrange", &["read", "string", "slow"], KEY),
+    command("setrange", &["write", "string", "slow"], KEY),
+    command("getdel", &["write", "string", "fast"], KEY),
+    command("getex", &["write", "string", "fast"], KEY),
+    command("getset", &["write", "string", "fast"], KEY),
+    command("mget", &["read", "string", "fast"], ALL_KEYS),
+    command("mset", &["write", "string", "slow"], Keys::Range(1, -1, 2)),
+    command(
+        "msetnx",
+        &["write", "string", "slow"],
+        Keys::Range(1, -1, 2),
+    ),
+    command("setnx", &["write", "string", "fast"], KEY),
+    command("setex", &["write", "string", "slow"], KEY),
+    command("psetex", &["write", "string", "slow"], KEY),
+    command("lcs", &["read", "string", "slow"], TWO_KEYS),
+    command("setbit", &["write", "bitmap", "slow"], KEY),
+    command("getbit", &["read", "bitmap", "fast"], KEY),
+    command("bitcount", &["read", "bitmap", "slow"], KEY),
+    command("bitpos", &["read", "bitmap", "slow"], KEY),
+    command("bitop", &["write", "bitmap", "slow"], Keys::Range(2, -1, 1)),
+    command("bitfield", &["write", "bitmap", "slow"], KEY),
+    command("bitfield_ro", &["read", "bitmap", "fast"], KEY),
+    command("pfadd", &["write", "hyperloglog", "fast"], KEY),
+    command("pfcount", &["read", "hyperloglog", "slow"], ALL_KEYS),
+    command("pfmerge", &["write", "hyperloglog", "slow"], ALL_KEYS),
+    command("geoadd", &["write", "geo", "slow"], KEY),
+    command("geopos", &["read", "geo", "slow"], KEY),
+    command("geodist", &["read", "geo", "slow"], KEY),
+    command("geohash", &["read", "geo", "slow"], KEY),
+    command("geosearch", &["read", "geo", "slow"], KEY),
+    command("geosearchstore", &["write", "geo", "slow"], TWO_KEYS),
+    command("move", &["keyspace", "write", "fast"], KEY),
+    command(
+        "swapdb",
+        &["keyspace", "write", "fast", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "flushdb",
+        &["keyspace", "write", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "flushall",
+        &["keyspace", "write", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command("dbsize", &["keyspace", "read", "fast"], Keys::None),
+    command(
+        "keys",
+        &["keyspace", "read", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command("scan", &["keyspace", "read", "slow"], Keys::None),
+    command("type", &["keyspace", "read", "fast"], KEY),
+    command("randomkey", &["keyspace", "read", "slow"], Keys::None),
+    command("rename", &["keyspace", "write", "slow"], TWO_KEYS),
+    command("renamenx", &["keyspace", "write", "fast"], TWO_KEYS),
+    command("copy", &["keyspace", "write", "slow"], TWO_KEYS),
+    command("unlink", &["keyspace", "write", "fast"], ALL_KEYS),
+    command("touch", &["keyspace", "read", "fast"], ALL_KEYS),
+    command("dump", &["keyspace", "read", "slow"], KEY),
+    command("restore", &["keyspace", "write", "slow", "dangerous"], KEY),
+    command(
+        "restore-asking",
+        &["keyspace", "write", "slow", "dangerous"],
+        KEY,
+    ),
+    command(
+        "migrate",
+        &["keyspace", "write", "slow", "dangerous"],
+        Keys::KeyOrKeyword(3, "KEYS"),
+    ),
+    command("memory", &["slow"], Keys::None),
+    command("memory|usage", &["read", "slow"], Keys::Range(2, 2, 1)),
+    command("info", &["slow", "dangerous"], Keys::None),
+    command("save", &["admin", "slow", "dangerous"], Keys::None),
+    command("bgsave", &["admin", "slow", "dangerous"], Keys::None),
+    command("lastsave", &["admin", "fast", "dangerous"], Keys::None),
+    command("monitor", &["admin", "slow", "dangerous"], Keys::None),
+    command("slowlog", &["admin", "slow", "dangerous"], Keys::None),
+    command("latency", &["admin", "slow", "dangerous"], Keys::None),
+    command("subscribe", &["pubsub", "slow"], Keys::None),
+    command("unsubscribe", &["pubsub", "slow"], Keys::None),
+    command("psubscribe", &["pubsub", "slow"], Keys::None),
+    command("punsubscribe", &["pubsub", "slow"], Keys::None),
+    command("publish", &["pubsub", "fast"], Keys::None),
+    command("pubsub", &["slow"], Keys::None),
+    command("pubsub|channels", &["pubsub", "slow"], Keys::None),
+    command("pubsub|numsub", &["pubsub", "slow"], Keys::None),
+    command("pubsub|numpat", &["pubsub", "slow"], Keys::None),
+    command("replicaof", &["admin", "slow", "dangerous"], Keys::None),
+    command("slaveof", &["admin", "slow", "dangerous"], Keys::None),
+    command("replconf", &["admin", "slow", "dangerous"], Keys::None),
+    command("psync", &["admin", "slow", "dangerous"], Keys::None),
+    command("sync", &["admin", "slow", "dangerous"], Keys::None),
+    command("sentinel", &["admin", "slow", "dangerous"], Keys::None),
+    command("cluster", &["slow"], Keys::None),
+    command("cluster|info", &["slow"], Keys::None),
+    command("cluster|myid", &["slow"], Keys::None),
+    command("cluster|nodes", &["slow"], Keys::None),
+    command("cluster|slots", &["slow"], Keys::None),
+    command("cluster|shards", &["slow"], Keys::None),
+    command("cluster|keyslot", &["slow"], Keys::None),
+    command("cluster|countkeysinslot", &["slow"], Keys::None),
+    command("cluster|getkeysinslot", &["slow"], Keys::None),
+    command(
+        "cluster|addslots",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "cluster|delslots",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "cluster|setslot",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command("cluster|meet", &["admin", "slow", "dangerous"], Keys::None),
+    command(
+        "cluster|replicate",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "cluster|replicas",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "cluster|slaves",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "cluster|count-failure-reports",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command("config", &["slow"], Keys::None),
+    command("config|get", &["admin", "slow", "dangerous"], Keys::None),
+    command("config|set", &["admin", "slow", "dangerous"], Keys::None),
+    command(
+        "config|resetstat",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command(
+        "config|rewrite",
+        &["admin", "slow", "dangerous"],
+        Keys::None,
+    ),
+    command("client", &["slow"], Keys::None),
+    command("client|id", &["slow", "connection"], Keys::None),
+    command("client|info", &["slow", "connection"], Keys::None),
+    command("client|setname", &["slow", "connection"], Keys::None),
+    command("client|getname", &["slow", "connection"], Keys::None),
+    command("client|tracking", &["slow", "connection"], Keys::None),
+    command("client|caching", &["slow", "connection"], Keys::None),
+    command("client|getredir", &["slow", "connection"], Keys::None),
+    command(
+        "client|list",
+        &["admin", "slow", "dangerous", "connection"],
+        Keys::None,
+    ),
+    command(
+        "client|kill",
+        &["admin", "slow", "dangerous", "connection"],
+        Keys::None,
+    ),
+    command(
+        "client|pause",
+        &["admin", "slow", "dangerous", "connection"],
+        Keys::None,
+    ),
+    command(
+        "client|unpause",
+        &["admin", "slow", "dangerous", "connection"],
+        Keys::None,
+    ),
+    command(
+        "client|no-evict",
+        &["admin", "slow", "dangerous", "connection"],
+        Keys::None,
+    ),
+    command("acl", &["slow"], Keys::None),
+    command("acl|whoami", &["slow"], Keys::None),
+    command("acl|cat", &["slow"], Keys::None),
+    command("acl|setuser", &["admin", "slow", "dangerous"], Keys::None),
+    command("acl|getuser", &["admin", "slow", "dangerous"], Keys::None),
+    command("acl|deluser", &["admin", "slow", "dangerous"], Keys::None),
+    command("acl|list", &["admin", "slow", "dangerous"], Keys::None),
+    command("acl|users", &["admin", "slow", "dangerous"], Keys::None),
+    command("acl|log", &["admin", "slow", "dangerous"], Keys::None),
+    command("acl|load", &["admin", "slow", "dangerous"], Keys::None),
+    command("acl|save", &["admin", "slow", "dangerous"], Keys::None),
+    command("function", &["slow"], Keys::None),
+    command("function|list", &["slow", "scripting"], Keys::None),
+    command("function|dump", &["slow", "scripting"], Keys::None),
+    command("function|load", &["write", "slow", "scripting"], Keys::None),
+    command("function|kill", &["slow", "scripting"], Keys::None),
+    command(
+        "function|delete",
+        &["write", "slow", "scripting"],
+        Keys::None,
+    ),
+    command(
+        "function|flush",
+        &["write", "slow", "scripting"],
+        Keys::None,
+    ),
+    command(
+        "function|restore",
+        &["write", "slow", "dangerous", "scripting"],
+        Keys::None,
+    ),
+    command("fcall", &["slow", "scripting"], Keys::Counted(2)),
+    command("fcall_ro", &["slow", "scripting"], Keys::Counted(2)),
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+}
+
+// The entry for the command being called, which is the subcommand's when it has one
+pub fn lookup(args: &[&str]) -> Option<&'static Command> {
+    let name = args.first()?;
+    if let Some(subcommand) = args.get(1) {
+        if is_container(name) {
+            let command = find(&format!("{}|{}", name, subcommand));
+            if command.is_some() {
+                return command;
+            }
+        }
+    }
+    find(name)
+}
+
+// Whether the command has subcommands with their own entries
+pub fn is_container(name: &str) -> bool {
+    COMMANDS.iter().any(|c| {
+        c.name
+            .split_once('|')
+            .is_some_and(|(container, _)| container.eq_ignore_ascii_case(name))
+    })
+}
+
+// Commands that modify the keyspace, refused in read only scripts, when out of memory and during
+// CLIENT PAUSE WRITE. Subcommands like FUNCTION LOAD are looked up with their container.
+pub fn is_write(args: &[&str]) -> bool {
+    lookup(args).is_some_and(|c| c.has_category("write"))
+}
+
+impl Command {
+    pub fn has_category(&self, category: &str) -> bool {
+        self.categories.contains(&category)
+    }
+
+    // The keys among the arguments, which start with the command name
+    pub fn keys<'a>(&self, args: &[&'a str]) -> Vec<&'a str> {
+        match self.keys {
+            Keys::None => vec![],
+            Keys::Range(first, last, step) => {
+                let last = if last < 0 {
+                    args.len() as i64 + last
+                } else {
+                    last.min(args.len() as i64 - 1)
+                };
+                if last < first as i64 {
+                    return vec![];
+                }
+                args[first..=last as usize]
+                    .iter()
+                    .step_by(step)
+                    .copied()
+                    .collect()
+            }
+            Keys::Counted(position) => {
+                let numkeys = args
+                    .get(position)
+                    .and_then(|n| value::parse_integer(n))
+                    .filter(|n| *n >= 0)
+                    .map_or(0, |n| n as usize);
+                args.iter()
+                    .skip(position + 1)
+                    .take(numkeys)
+                    .copied()
+                    .collect()
+            }
+            Keys::KeyOrKeyword(position, keyword) => match args.get(position) {
+                Some(key) if !key.is_empty() => vec![*key],
+                Some(_) => args
+                    .iter()
+                    .skip(position + 1)
+                    .skip_while(|arg| !arg.eq_ignore_ascii_case(keyword))
+                    .skip(1)
+                    .copied()
+                    .collect(),
+                None => vec![],
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let keys = |args: &[&'static str]| lookup(args).unwrap().keys(args);
+        assert_eq!(keys(&["GET", "a"]), vec!["a"]);
+        assert_eq!(keys(&["MSET", "a", "1", "b", "2"]), vec!["a", "b"]);
+        assert_eq!(
+            keys(&["bitop", "AND", "dest", "a", "b"]),
+            vec!["dest", "a", "b"]
+        );
+        assert_eq!(keys(&["FCALL", "f", "1", "a", "arg"]), vec!["a"]);
+        assert_eq!(keys(&["memory", "usage", "a"]), vec!["a"]);
+        assert!(keys(&["memory", "usage"]).is_empty());
+        assert!(keys(&["MGET"]).is_empty());
+        assert_eq!(keys(&["MIGRATE", "h", "1", "a", "0", "10"]), vec!["a"]);
+        assert_eq!(
+            keys(&["MIGRATE", "h", "1", "", "0", "10", "COPY", "keys", "a", "b"]),
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup(&["CONFIG", "get", "x"]).unwrap().name, "config|get");
+        assert_eq!(lookup(&["config", "nope"]).unwrap().name, "config");
+        assert!(is_write(&["SET", "a", "1"]));
+        assert!(!is_write(&["GET", "a"]));
+        assert!(is_write(&["function", "LOAD", "code"]));
+        assert!(!is_write(&["FUNCTION", "LIST"]));
+        assert!(lookup(&["nope"]).is_none());
+        for command in COMMANDS {
+            assert!(command.categories.iter().all(|c| CATEGORIES.contains(c)));
+        }
+    }
+}
@@ -0,0 +1,660 @@
+// Server settings and the CONFIG command. Every setting is described by a typed parameter of the
+// registry, which is used to read the config file and the command line options at startup as well
+// as by CONFIG GET, SET and REWRITE.
+use crate::glob;
+use crate::info::STATS;
+use crate::memory;
+use crate::memory::Policy;
+use crate::notify;
+use crate::sentinel;
+use crate::strings::wrong_number_of_arguments;
+use crate::Server;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::fs;
+use std::path::Path;
+
+// Same marker as Redis, parameters missing from the file get appended after it
+pub const REWRITE_SIGNATURE: &str = "# Generated by CONFIG REWRITE";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub port: usize,
+    pub databases: usize,
+    // Seconds after which idle clients are disconnected, never when 0
+    pub timeout: usize,
+    pub memory: memory::Settings,
+    // Password of the default user, anyone can connect when it's empty
+    pub requirepass: String,
+    // File ACL LOAD and ACL SAVE use, none when empty
+    pub aclfile: String,
+    pub acllog_max_len: usize,
+    // Microseconds a command has to take to be logged, never when negative
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    // Milliseconds an event has to take to be recorded, the monitor is disabled when 0
+    pub latency_monitor_threshold: usize,
+    // Classes of keyspace notifications to publish, as notify::parse_flags reads them
+    pub notify_keyspace_events: u32,
+    pub cluster_enabled: bool,
+    // Where the nodes of the cluster and their slots are kept
+    pub cluster_config_file: String,
+    // Milliseconds a node can go without answering pings before it's considered failing
+    pub cluster_node_timeout: usize,
+    // Sentinels promote the replicas with the lowest priority first, and never those with 0
+    pub replica_priority: usize,
+    // Where SAVE and BGSAVE write the keys and functions, and where they're loaded from at startup
+    pub dir: String,
+    pub dbfilename: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 6379,
+            databases: 16,
+            timeout: 0,
+            memory: memory::Settings::default(),
+            requirepass: String::new(),
+            aclfile: String::new(),
+            acllog_max_len: 128,
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            latency_monitor_threshold: 0,
+            notify_keyspace_events: 0,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_string(),
+            cluster_node_timeout: 15000,
+            replica_priority: 100,
+            dir: "./".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+        }
+    }
+}
+
+enum Kind {
+    Integer {
+        min: usize,
+        max: usize,
+        field: fn(&mut Config) -> &mut usize,
+    },
+    Signed {
+        min: i64,
+        max: i64,
+        field: fn(&mut Config) -> &mut i64,
+    },
+    // Number of bytes, accepting units like 100mb
+    Memory(fn(&mut Config) -> &mut usize),
+    Policy(fn(&mut Config) -> &mut Policy),
+    String(fn(&mut Config) -> &mut String),
+    // yes or no
+    Bool(fn(&mut Config) -> &mut bool),
+    // Flags like KEA, see notify::parse_flags
+    KeyspaceEvents(fn(&mut Config) -> &mut u32),
+}
+
+// Brings the rest of the server in line with a new value set by CONFIG SET
+type Apply = fn(&Server, &Config) -> Result<(), String>;
+
+struct Param {
+    name: &'static str,
+    // Immutable parameters can only be set at startup
+    mutable: bool,
+    kind: Kind,
+    apply: Option<Apply>,
+}
+
+const PARAMS: &[Param] = &[
+    Param {
+        name: "port",
+        mutable: false,
+        kind: Kind::Integer {
+            min: 0,
+            max: 65535,
+            field: |c| &mut c.port,
+        },
+        apply: None,
+    },
+    Param {
+        name: "databases",
+        mutable: false,
+        kind: Kind::Integer {
+            min: 1,
+            max: i32::MAX as usize,
+            field: |c| &mut c.databases,
+        },
+        apply: None,
+    },
+    Param {
+        name: "timeout",
+        mutable: true,
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as usize,
+            field: |c| &mut c.timeout,
+        },
+        apply: None,
+    },
+    Param {
+        name: "maxmemory",
+        mutable: true,
+        kind: Kind::Memory(|c| &mut c.memory.maxmemory),
+        apply: None,
+    },
+    Param {
+        name: "maxmemory-policy",
+        mutable: true,
+        kind: Kind::Policy(|c| &mut c.memory.policy),
+        apply: None,
+    },
+    Param {
+        name: "maxmemory-samples",
+        mutable: true,
+        kind: Kind::Integer {
+            min: 1,
+            max: 64,
+            field: |c| &mut c.memory.samples,
+        },
+        apply: None,
+    },
+    Param {
+        name: "requirepass",
+        mutable: true,
+        kind: Kind::String(|c| &mut c.requirepass),
+        apply: Some(|server, config| server.acl.set_requirepass(&config.requirepass)),
+    },
+    Param {
+        name: "aclfile",
+        mutable: false,
+        kind: Kind::String(|c| &mut c.aclfile),
+        apply: None,
+    },
+    Param {
+        name: "acllog-max-len",
+        mutable: true,
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as usize,
+            field: |c| &mut c.acllog_max_len,
+        },
+        apply: None,
+    },
+    Param {
+        name: "slowlog-log-slower-than",
+        mutable: true,
+        kind: Kind::Signed {
+            min: -1,
+            max: i64::MAX,
+            field: |c| &mut c.slowlog_log_slower_than,
+        },
+        apply: None,
+    },
+    Param {
+        name: "slowlog-max-len",
+        mutable: true,
+        kind: Kind::Integer {
+            min: 0,
+            max: i64::MAX as usize,
+            field: |c| &mut c.slowlog_max_len,
+        },
+        apply: None,
+    },
+    Param {
+        name: "latency-monitor-threshold",
+        mutable: true,
+        kind: Kind::Integer {
+            min: 0,
+            max: i64::MAX as usize,
+            field: |c| &mut c.latency_monitor_threshold,
+        },
+        apply: None,
+    },
+    Param {
+        name: "notify-keyspace-events",
+        mutable: true,
+        kind: Kind::KeyspaceEvents(|c| &mut c.notify_keyspace_events),
+        apply: None,
+    },
+    Param {
+        name: "cluster-enabled",
+        mutable: false,
+        kind: Kind::Bool(|c| &mut c.cluster_enabled),
+        apply: None,
+    },
+    Param {
+        name: "cluster-config-file",
+        mutable: false,
+        kind: Kind::String(|c| &mut c.cluster_config_file),
+        apply: None,
+    },
+    Param {
+        name: "cluster-node-timeout",
+        mutable: true,
+        kind: Kind::Integer {
+            min: 0,
+            max: i64::MAX as usize,
+            field: |c| &mut c.cluster_node_timeout,
+        },
+        apply: None,
+    },
+    Param {
+        name: "replica-priority",
+        mutable: true,
+        kind: Kind::Integer {
+            min: 0,
+            max: i32::MAX as usize,
+            field: |c| &mut c.replica_priority,
+        },
+        apply: None,
+    },
+    // Like Redis' protected configs, where the RDB file goes can't be changed by clients, as SAVE
+    // would then write to any path the server can write to
+    Param {
+        name: "dir",
+        mutable: false,
+        kind: Kind::String(|c| &mut c.dir),
+        apply: None,
+    },
+    Param {
+        name: "dbfilename",
+        mutable: false,
+        kind: Kind::String(|c| &mut c.dbfilename),
+        apply: None,
+    },
+];
+
+fn find(name: &str) -> Option<&'static Param> {
+    PARAMS.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+impl Param {
+    // The value as CONFIG GET shows it
+    fn get(&self, config: &Config) -> String {
+        let mut config = config.clone();
+        match self.kind {
+            Kind::Integer { field, .. } | Kind::Memory(field) => field(&mut config).to_string(),
+            Kind::Signed { field, .. } => field(&mut config).to_string(),
+            Kind::Policy(field) => field(&mut config).name().to_string(),
+            Kind::String(field) => field(&mut config).clone(),
+            Kind::Bool(field) => if *field(&mut config) { "yes" } else { "no" }.to_string(),
+            Kind::KeyspaceEvents(field) => notify::flags_to_string(*field(&mut config)),
+        }
+    }
+
+    // The value as CONFIG REWRITE writes it, with units for memory
+    fn format(&self, config: &Config) -> String {
+        let mut config = config.clone();
+        match self.kind {
+            Kind::Memory(field) => format_memory(*field(&mut config)),
+            _ => self.get(&config),
+        }
+    }
+
+    // Parses and stores the value, the error describes what's wrong with it
+    fn set(&self, config: &mut Config, value: &str) -> Result<(), String> {
+        match self.kind {
+            Kind::Integer { min, max, field } => match value.parse::<i64>() {
+                Ok(n) if n >= min as i64 && n <= max as i64 => *field(config) = n as usize,
+                Ok(_) => {
+                    return Err(format!(
+                        "argument must be between {} and {} inclusive",
+                        min, max
+                    ))
+                }
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            Kind::Signed { min, max, field } => match value.parse::<i64>() {
+                Ok(n) if n >= min && n <= max => *field(config) = n,
+                Ok(_) => {
+                    return Err(format!(
+                        "argument must be between {} and {} inclusive",
+                        min, max
+                    ))
+                }
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            Kind::Memory(field) => match memory::parse_memory(value) {
+                Some(n) => *field(config) = n,
+                None => return Err("argument must be a memory value".to_string()),
+            },
+            Kind::Policy(field) => match Policy::parse(value) {
+                Some(policy) => *field(config) = policy,
+                None => {
+                    let names: Vec<&str> = memory::POLICIES.iter().map(|(_, n)| *n).collect();
+                    return Err(format!(
+                        "argument(s) must be one of the following: {}",
+                        names.join(", ")
+                    ));
+                }
+            },
+            Kind::String(field) => *field(config) = value.to_string(),
+            Kind::Bool(field) => match value.to_lowercase().as_str() {
+                "yes" => *field(config) = true,
+                "no" => *field(config) = false,
+                _ => return Err("argument must be 'yes' or 'no'".to_string()),
+            },
+            Kind::KeyspaceEvents(field) => match notify::parse_flags(value) {
+                Some(flags) => *field(config) = flags,
+                None => {
+                    return Err("Invalid event class character. Use 'Ag$lshzxetKEn'.".to_string())
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+// Formats bytes with the largest unit dividing them, like Redis' rewriteConfigFormatMemory
+fn format_memory(bytes: usize) -> String {
+    const UNITS: &[(usize, &str)] = &[
+        (1024 * 1024 * 1024, "gb"),
+        (1024 * 1024, "mb"),
+        (1024, "kb"),
+    ];
+    UNITS
+        .iter()
+        .find(|(size, _)| bytes != 0 && bytes.is_multiple_of(*size))
+        .map_or(bytes.to_string(), |(size, unit)| {
+            format!("{}{}", bytes / size, unit)
+        })
+}
+
+// Splits a config file line into the parameter name and its value, None for blank lines and
+// comments
+pub fn parse_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    Some((name.to_lowercase(), value.to_string()))
+}
+
+// Builds the configuration from the config file, if any, followed by the `--name value` options
+// that override it. Unknown parameters are ignored so that options meant for other tools can
+// be passed along. The `sentinel` lines and the --sentinel option are left to the sentinel module,
+// sentinels listen on 26379 by default.
+pub fn load(file: Option<&Path>, options: &[(String, String)]) -> Result<Config, String> {
+    let mut config = Config::default();
+    if options.iter().any(|(name, _)| name == "sentinel") {
+        config.port = sentinel::DEFAULT_PORT;
+    }
+    let contents = match file {
+        Some(file) => fs::read_to_string(file)
+            .map_err(|e| format!("Can't open config file '{}': {}", file.display(), e))?,
+        None => String::new(),
+    };
+    let settings = contents
+        .lines()
+        .filter_map(parse_line)
+        .chain(options.iter().cloned());
+    for (name, value) in settings {
+        match find(&name) {
+            Some(param) => param
+                .set(&mut config, &value)
+                .map_err(|e| format!("Invalid {} '{}': {}", name, value, e))?,
+            None if name == "sentinel" => {}
+            // Only RDB persistence is implemented, a server asked for an AOF mustn't start
+            // without one
+            None if name == "appendonly" && !value.eq_ignore_ascii_case("no") => {
+                return Err(format!(
+                    "Invalid appendonly '{}': AOF persistence isn't supported",
+                    value
+                ))
+            }
+            None => eprintln!("Ignoring unknown config parameter '{}'", name),
+        }
+    }
+    Ok(config)
+}
+
+// Returns the contents of the config file updated with the current configuration. Comments and
+// unknown lines are kept, the first line of each parameter gets its current value and later
+// duplicates are dropped. Parameters missing from the file are appended if they aren't at their
+// default.
+fn rewrite(old: &str, config: &Config) -> String {
+    let mut lines = vec![];
+    let mut written = vec![];
+    for line in old.lines() {
+        match parse_line(line).and_then(|(name, _)| find(&name)) {
+            Some(param) if written.contains(&param.name) => {}
+            Some(param) => {
+                lines.push(format!("{} {}", param.name, param.format(config)));
+                written.push(param.name);
+            }
+            None => lines.push(line.to_string()),
+        }
+    }
+
+    let default = Config::default();
+    let missing: Vec<&Param> = PARAMS
+        .iter()
+        .filter(|p| !written.contains(&p.name) && p.get(config) != p.get(&default))
+        .collect();
+    if !missing.is_empty() && !lines.iter().any(|line| line == REWRITE_SIGNATURE) {
+        lines.push(REWRITE_SIGNATURE.to_string());
+    }
+    for param in missing {
+        lines.push(format!("{} {}", param.name, param.format(config)));
+    }
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+pub fn config_command(args: &[BulkString], server: &Server) -> Result<RESPValue, String> {
+    let mut args = Args::new("config", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    match (subcommand.as_str(), &args[..]) {
+        ("GET", patterns) if !patterns.is_empty() => config_get(patterns, server),
+        ("SET", pairs) if !pairs.is_empty() && pairs.len().is_multiple_of(2) => {
+            config_set(pairs, server)
+        }
+        ("RESETSTAT", []) => {
+            STATS.reset();
+            Ok(RESPValue::simple_string("OK".to_string()))
+        }
+        ("REWRITE", []) => config_rewrite(server),
+        ("GET", _) | ("SET", _) | ("RESETSTAT", _) | ("REWRITE", _) => Ok(
+            wrong_number_of_arguments(&format!("config|{}", subcommand.to_lowercase())),
+        ),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'. Try CONFIG HELP.",
+            s.to_lowercase()
+        ))),
+    }
+}
+
+fn config_get(patterns: &[&str], server: &Server) -> Result<RESPValue, String> {
+    let config = server.config()?;
+    let mut reply = vec![];
+    for param in PARAMS {
+        if patterns
+            .iter()
+            .any(|pattern| glob::matches(pattern, param.name, true))
+        {
+            reply.push(RESPValue::bulk_string(Some(param.name.to_string())));
+            reply.push(RESPValue::bulk_string(Some(param.get(&config))));
+        }
+    }
+    Ok(RESPValue::Array(Some(reply)))
+}
+
+// Every value is checked before any is applied, so when one is rejected none of the parameters
+// change
+fn config_set(pairs: &[&str], server: &Server) -> Result<RESPValue, String> {
+    let mut config = server
+        .config
+        .write()
+        .map_err(|e| format!("Failed to acquire lock for config {}", e))?;
+    let (new_config, params) = match parse_set(&config, pairs) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e),
+    };
+    // When the server can't take a value, the parameters applied so far are put back
+    let mut applied: Vec<Apply> = vec![];
+    for param in params {
+        let apply = match param.apply {
+            Some(apply) => apply,
+            None => continue,
+        };
+        if let Err(e) = apply(server, &new_config) {
+            for apply in applied.into_iter().rev() {
+                apply(server, &config)?;
+            }
+            return Ok(set_failed(param.name, &e));
+        }
+        applied.push(apply);
+    }
+    *config = new_config;
+    Ok(RESPValue::simple_string("OK".to_string()))
+}
+
+fn set_failed(name: &str, reason: &str) -> RESPValue {
+    RESPValue::error(format!(
+        "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+        name, reason
+    ))
+}
+
+// The configuration with the values of CONFIG SET, and the parameters they set in the order of
+// the registry
+fn parse_set(config: &Config, pairs: &[&str]) -> Result<(Config, Vec<&'static Param>), RESPValue> {
+    let mut new_config = config.clone();
+    let mut seen = vec![];
+    for pair in pairs.chunks(2) {
+        let (name, value) = (pair[0], pair[1]);
+        let param = find(name).ok_or_else(|| {
+            RESPValue::error(format!(
+                "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            ))
+        })?;
+        if !param.mutable {
+            return Err(set_failed(name, "can't set immutable config"));
+        }
+        if seen.contains(&param.name) {
+            return Err(set_failed(name, "duplicate parameter"));
+        }
+        seen.push(param.name);
+        param
+            .set(&mut new_config, value)
+            .map_err(|e| set_failed(name, &e))?;
+    }
+    let params = PARAMS
+        .iter()
+        .filter(|param| seen.contains(&param.name))
+        .collect();
+    Ok((new_config, params))
+}
+
+fn config_rewrite(server: &Server) -> Result<RESPValue, String> {
+    let file = match &server.config_file {
+        Some(file) => file,
+        None => {
+            return Ok(RESPValue::error(
+                "ERR The server is running without a config file".to_string(),
+            ))
+        }
+    };
+    let old = fs::read_to_string(file).unwrap_or_default();
+    let new = rewrite(&old, &server.config()?);
+    // Written to a temporary file first so that a failure can't leave a truncated config behind
+    let temporary = file.with_extension(format!("tmp-{}", std::process::id()));
+    match fs::write(&temporary, new).and_then(|_| fs::rename(&temporary, file)) {
+        Ok(()) => Ok(RESPValue::simple_string("OK".to_string())),
+        Err(e) => {
+            let _ = fs::remove_file(&temporary);
+            Ok(RESPValue::error(format!(
+                "ERR Rewriting config file: {}",
+                e
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut config = Config::default();
+        let maxmemory = find("MAXMEMORY").unwrap();
+        assert_eq!(maxmemory.set(&mut config, "2mb"), Ok(()));
+        assert_eq!(maxmemory.get(&config), "2097152");
+        assert_eq!(maxmemory.format(&config), "2mb");
+        assert_eq!(
+            find("maxmemory-samples").unwrap().set(&mut config, "100"),
+            Err("argument must be between 1 and 64 inclusive".to_string())
+        );
+        assert!(find("maxmemory-policy")
+            .unwrap()
+            .set(&mut config, "lru")
+            .is_err());
+        assert_eq!(config.memory.samples, 5);
+    }
+
+    #[test]
+    fn test_parse_set() {
+        let config = Config::default();
+        let (new_config, params) =
+            parse_set(&config, &["timeout", "5", "maxmemory", "1mb"]).unwrap();
+        assert_eq!(new_config.timeout, 5);
+        assert_eq!(new_config.memory.maxmemory, 1024 * 1024);
+        let names: Vec<&str> = params.iter().map(|p| p.name).collect();
+        assert_eq!(names, ["timeout", "maxmemory"]);
+        assert_eq!(
+            parse_set(&config, &["timeout", "5", "nope", "1"]).err(),
+            Some(RESPValue::error(
+                "ERR Unknown option or number of arguments for CONFIG SET - 'nope'".to_string()
+            ))
+        );
+        assert!(parse_set(&config, &["port", "1"]).is_err());
+        assert!(parse_set(&config, &["dir", "/etc"]).is_err());
+        assert!(parse_set(&config, &["dbfilename", "passwd"]).is_err());
+        assert!(parse_set(&config, &["timeout", "1", "TIMEOUT", "2"]).is_err());
+        assert!(parse_set(&config, &["timeout", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let options = [
+            ("databases".to_string(), "4".to_string()),
+            ("dir".to_string(), "/tmp".to_string()),
+        ];
+        let config = load(None, &options).unwrap();
+        assert_eq!(config.databases, 4);
+        assert_eq!(config.dir, "/tmp");
+        assert!(load(None, &[("databases".to_string(), "0".to_string())]).is_err());
+        assert!(load(None, &[("appendonly".to_string(), "no".to_string())]).is_ok());
+        assert!(load(None, &[("appendonly".to_string(), "yes".to_string())]).is_err());
+        let config = load(None, &[("sentinel".to_string(), String::new())]).unwrap();
+        assert_eq!(config.port, 26379);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let mut config = Config {
+            timeout: 30,
+            ..Config::default()
+        };
+        config.memory.maxmemory = 100 * 1024 * 1024;
+        let old = "# Memory settings\nmaxmemory 1gb\nunknown-option yes\n\nmaxmemory 2gb\n";
+        assert_eq!(
+            rewrite(old, &config),
+            "# Memory settings\nmaxmemory 100mb\nunknown-option yes\n\n\
+             # Generated by CONFIG REWRITE\ntimeout 30\n"
+        );
+        // Rewriting again changes nothing
+        let new = rewrite(old, &config);
+        assert_eq!(rewrite(&new, &config), new);
+    }
+}
@@ -0,0 +1,238 @@
+// Commands working across the numbered databases
+use crate::dict::Keyspace;
+use crate::get_live_entry;
+use crate::strings::syntax_error;
+use crate::strings::wrong_number_of_arguments;
+use crate::value;
+use crate::write_table;
+use crate::Databases;
+use crate::Session;
+use crate::Table;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::sync::RwLockWriteGuard;
+
+fn ok() -> RESPValue {
+    RESPValue::simple_string("OK".to_string())
+}
+
+fn out_of_range() -> RESPValue {
+    RESPValue::error("ERR DB index is out of range".to_string())
+}
+
+// Parses a database index, replying with `invalid` if it isn't an integer
+pub fn parse_index(databases: &Databases, index: &str, invalid: &str) -> Result<usize, RESPValue> {
+    match value::parse_integer(index) {
+        Some(i) if i >= 0 && (i as usize) < databases.len() => Ok(i as usize),
+        Some(_) => Err(out_of_range()),
+        None => Err(RESPValue::error(invalid.to_string())),
+    }
+}
+
+type TableGuard<'a> = RwLockWriteGuard<'a, Keyspace>;
+
+// Locks two different databases, always the lower index first so that concurrent commands
+// locking the same pair can't deadlock. The guards are returned in the order of the arguments.
+pub fn lock_pair(
+    databases: &Databases,
+    first: usize,
+    second: usize,
+) -> Result<(TableGuard<'_>, TableGuard<'_>), String> {
+    if first < second {
+        let first = write_table(&databases[first])?;
+        Ok((first, write_table(&databases[second])?))
+    } else {
+        let second = write_table(&databases[second])?;
+        Ok((write_table(&databases[first])?, second))
+    }
+}
+
+// Checks the optional ASYNC|SYNC argument of FLUSHDB and FLUSHALL.
+// Flushing is always done synchronously.
+fn check_flush_mode(args: &[BulkString], command: &str) -> Result<(), RESPValue> {
+    let mode = Args::new(command, args).parse(Args::optional_str)?;
+    match mode {
+        None => Ok(()),
+        Some(mode) if mode.eq_ignore_ascii_case("ASYNC") || mode.eq_ignore_ascii_case("SYNC") => {
+            Ok(())
+        }
+        Some(_) => Err(syntax_error()),
+    }
+}
+
+pub fn select(
+    args: &[BulkString],
+    databases: &Databases,
+    session: &mut Session,
+    cluster_enabled: bool,
+) -> Result<RESPValue, String> {
+    let index = match Args::new("select", args).parse(Args::next_str) {
+        Ok(index) => index,
+        Err(e) => return Ok(e.into()),
+    };
+    // A cluster only has the first database
+    if cluster_enabled && index != "0" {
+        return Ok(RESPValue::error(
+            "ERR SELECT is not allowed in cluster mode".to_string(),
+        ));
+    }
+    match parse_index(databases, index, "ERR invalid DB index") {
+        Ok(index) => {
+            session.db = index;
+            Ok(ok())
+        }
+        Err(e) => Ok(e),
+    }
+}
+
+pub fn move_key(
+    args: &[BulkString],
+    databases: &Databases,
+    session: &Session,
+) -> Result<RESPValue, String> {
+    let parsed = Args::new("move", args).parse(|args| Ok((args.next_key()?, args.next_str()?)));
+    let (key, index) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let index = match parse_index(
+        databases,
+        index,
+        "ERR value is not an integer or out of range",
+    ) {
+        Ok(index) => index,
+        Err(e) => return Ok(e),
+    };
+    if index == session.db {
+        return Ok(RESPValue::error(
+            "ERR source and destination objects are the same".to_string(),
+        ));
+    }
+
+    let (mut source, mut target) = lock_pair(databases, session.db, index)?;
+    if get_live_entry(&mut source, key).is_none() || get_live_entry(&mut target, key).is_some() {
+        return Ok(RESPValue::integer(0));
+    }
+    if let Some(entry) = source.remove(key) {
+        target.insert(key.to_string(), entry);
+    }
+    Ok(RESPValue::integer(1))
+}
+
+pub fn swapdb(args: &[BulkString], databases: &Databases) -> Result<RESPValue, String> {
+    let parsed = Args::new("swapdb", args).parse(|args| Ok((args.next_str()?, args.next_str()?)));
+    let (first, second) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let indexes = parse_index(databases, first, "ERR invalid first DB index").and_then(|first| {
+        parse_index(databases, second, "ERR invalid second DB index").map(|second| (first, second))
+    });
+    let (first, second) = match indexes {
+        Ok((first, second)) if first == second => return Ok(ok()),
+        Ok((first, second)) => (first, second),
+        Err(e) => return Ok(e),
+    };
+    let (mut first, mut second) = lock_pair(databases, first, second)?;
+    std::mem::swap(&mut *first, &mut *second);
+    Ok(ok())
+}
+
+pub fn flushdb(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    if let Err(e) = check_flush_mode(args, "flushdb") {
+        return Ok(e);
+    }
+    write_table(&table)?.clear();
+    Ok(ok())
+}
+
+pub fn flushall(args: &[BulkString], databases: &Databases) -> Result<RESPValue, String> {
+    if let Err(e) = check_flush_mode(args, "flushall") {
+        return Ok(e);
+    }
+    for table in databases.iter() {
+        write_table(table)?.clear();
+    }
+    Ok(ok())
+}
+
+pub fn dbsize(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    if !args.is_empty() {
+        return Ok(wrong_number_of_arguments("dbsize"));
+    }
+    let t = table
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+    Ok(RESPValue::integer(t.len() as i64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::strings::get_string;
+    use crate::strings::set_string;
+    use std::sync::Arc;
+    use std::sync::RwLock;
+
+    fn databases(n: usize) -> Databases {
+        Arc::new(
+            (0..n)
+                .map(|_| Arc::new(RwLock::new(Keyspace::default())))
+                .collect(),
+        )
+    }
+
+    fn args(args: &[&str]) -> Vec<BulkString> {
+        args.iter()
+            .map(|s| BulkString::from(s.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let databases = databases(2);
+        let mut session = Session::default();
+        assert_eq!(
+            select(&args(&["1"]), &databases, &mut session, false),
+            Ok(ok())
+        );
+        assert_eq!(session.db, 1);
+        assert_eq!(
+            select(&args(&["2"]), &databases, &mut session, false),
+            Ok(out_of_range())
+        );
+        assert_eq!(session.db, 1);
+    }
+
+    #[test]
+    fn test_move_and_swap() {
+        let databases = databases(2);
+        let session = Session::default();
+        set_string(
+            &mut databases[0].write().unwrap(),
+            "k",
+            "v".to_string(),
+            None,
+        );
+        assert_eq!(
+            move_key(&args(&["k", "1"]), &databases, &session),
+            Ok(RESPValue::integer(1))
+        );
+        assert_eq!(
+            move_key(&args(&["k", "1"]), &databases, &session),
+            Ok(RESPValue::integer(0))
+        );
+        assert_eq!(
+            get_string(&mut databases[1].write().unwrap(), "k"),
+            Ok(Some("v".to_string()))
+        );
+
+        assert_eq!(swapdb(&args(&["1", "0"]), &databases), Ok(ok()));
+        assert_eq!(
+            get_string(&mut databases[0].write().unwrap(), "k"),
+            Ok(Some("v".to_string()))
+        );
+        assert!(databases[1].read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+// The keys of a database, kept like Redis' db->dict and db->expires: hash tables whose size is a
+// power of two, so that SCAN can walk their buckets with a reverse binary cursor and random keys
+// can be drawn from random buckets, both in time that doesn't depend on the number of keys.
+use crate::random_u64;
+use crate::Entry;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::iter::FromIterator;
+use std::ops::Index;
+use std::time::Duration;
+use std::time::Instant;
+
+// Same as Redis' DICT_HT_INITIAL_SIZE
+const MIN_BUCKETS: usize = 4;
+// The table shrinks once less than one bucket in this many would be used, so that random buckets
+// hold a key often enough
+const MIN_FILL: usize = 8;
+// Buckets looked at for every key asked for when sampling, like Redis' dictGetSomeKeys
+const SAMPLE_STEPS_PER_KEY: usize = 10;
+
+// A hash table of string keys with chained buckets. It's rehashed at once when it grows or
+// shrinks, which keeps every key in the bucket its hash selects and so SCAN's guarantees intact.
+pub struct Dict<V> {
+    buckets: Vec<Vec<(String, V)>>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Self {
+            buckets: vec![],
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V> Dict<V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn bucket(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize & (self.buckets.len() - 1)
+    }
+
+    fn position(&self, key: &str) -> Option<(usize, usize)> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let bucket = self.bucket(key);
+        let i = self.buckets[bucket].iter().position(|(k, _)| k == key)?;
+        Some((bucket, i))
+    }
+
+    pub fn get_key_value(&self, key: &str) -> Option<(&String, &V)> {
+        let (bucket, i) = self.position(key)?;
+        let (key, value) = &self.buckets[bucket][i];
+        Some((key, value))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    // The expiry of the entry is changed with set_expiry, which keeps track of it
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let (bucket, i) = self.position(key)?;
+        Some(&mut self.buckets[bucket][i].1)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.position(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        if let Some(current) = self.get_mut(&key) {
+            return Some(std::mem::replace(current, value));
+        }
+        if self.len >= self.buckets.len() {
+            self.resize((self.len + 1).next_power_of_two().max(MIN_BUCKETS));
+        }
+        let bucket = self.bucket(&key);
+        self.buckets[bucket].push((key, value));
+        self.len += 1;
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let (bucket, i) = self.position(key)?;
+        let (_, value) = self.buckets[bucket].swap_remove(i);
+        self.len -= 1;
+        self.shrink();
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets = vec![];
+        self.len = 0;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &V)> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key, value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    fn shrink(&mut self) {
+        if self.buckets.len() > MIN_BUCKETS && self.len * MIN_FILL < self.buckets.len() {
+            self.resize(self.len.next_power_of_two().max(MIN_BUCKETS));
+        }
+    }
+
+    fn resize(&mut self, size: usize) {
+        let old = std::mem::replace(&mut self.buckets, (0..size).map(|_| vec![]).collect());
+        for (key, value) in old.into_iter().flatten() {
+            let bucket = self.bucket(&key);
+            self.buckets[bucket].push((key, value));
+        }
+    }
+
+    // Visits the keys of the bucket the cursor points to and returns the cursor of the next one,
+    // 0 once they were all visited. The cursor counts with its bits reversed, like Redis'
+    // dictScan, so that the buckets a bucket splits into when the table grows, or merges with
+    // when it shrinks, are the ones visited next: every key present for the whole scan is
+    // returned, some may be returned twice.
+    pub fn scan<'a>(&'a self, cursor: u64, mut visit: impl FnMut(&'a String, &'a V)) -> u64 {
+        if self.buckets.is_empty() {
+            return 0;
+        }
+        let mask = self.buckets.len() as u64 - 1;
+        for (key, value) in &self.buckets[(cursor & mask) as usize] {
+            visit(key, value);
+        }
+        // Sets the bits past the mask so that incrementing the reversed cursor carries over them
+        (cursor | !mask)
+            .reverse_bits()
+            .wrapping_add(1)
+            .reverse_bits()
+    }
+
+    // A key from a random bucket. As in Redis, keys sharing their bucket with others are a bit
+    // less likely to be picked.
+    pub fn random(&self) -> Option<(&String, &V)> {
+        if self.is_empty() {
+            return None;
+        }
+        loop {
+            let bucket = &self.buckets[random_u64() as usize & (self.buckets.len() - 1)];
+            if !bucket.is_empty() {
+                let (key, value) = &bucket[random_u64() as usize % bucket.len()];
+                return Some((key, value));
+            }
+        }
+    }
+
+    // Up to count keys from the buckets following a random one, like Redis' dictGetSomeKeys
+    pub fn sample(&self, count: usize) -> Vec<(&String, &V)> {
+        let mut sampled = vec![];
+        if self.is_empty() {
+            return sampled;
+        }
+        let count = count.min(self.len);
+        let mask = self.buckets.len() - 1;
+        let start = random_u64() as usize;
+        for step in 0..count * SAMPLE_STEPS_PER_KEY {
+            let bucket = &self.buckets[start.wrapping_add(step) & mask];
+            sampled.extend(bucket.iter().map(|(key, value)| (key, value)));
+            if sampled.len() >= count {
+                break;
+            }
+        }
+        sampled.truncate(count);
+        sampled
+    }
+}
+
+// The keys of a database with their entries, and those with an expiry again so that they can be
+// sampled without going through the others
+#[derive(Default)]
+pub struct Keyspace {
+    keys: Dict<Entry>,
+    expires: Dict<()>,
+}
+
+impl Keyspace {
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.keys.get(key)
+    }
+
+    // The expiry of the entry is changed with set_expiry, which keeps track of it
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.keys.get_mut(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: String, entry: Entry) -> Option<Entry> {
+        if entry.1.is_some() {
+            self.expires.insert(key.clone(), ());
+        } else {
+            self.expires.remove(&key);
+        }
+        self.keys.insert(key, entry)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        self.expires.remove(key);
+        self.keys.remove(key)
+    }
+
+    pub fn set_expiry(&mut self, key: &str, expiry: Option<(Instant, Duration)>) {
+        if let Some(entry) = self.keys.get_mut(key) {
+            entry.1 = expiry;
+            if expiry.is_some() {
+                self.expires.insert(key.to_string(), ());
+            } else {
+                self.expires.remove(key);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+        self.expires.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.keys.iter()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Entry> {
+        self.keys.values()
+    }
+
+    pub fn scan<'a>(&'a self, cursor: u64, visit: impl FnMut(&'a String, &'a Entry)) -> u64 {
+        self.keys.scan(cursor, visit)
+    }
+
+    pub fn random(&self) -> Option<(&String, &Entry)> {
+        self.keys.random()
+    }
+
+    // Up to count keys starting from a random one, only among those with an expiry if
+    // volatile_only
+    pub fn sample(&self, count: usize, volatile_only: bool) -> Vec<(&String, &Entry)> {
+        if !volatile_only {
+            return self.keys.sample(count);
+        }
+        self.expires
+            .sample(count)
+            .into_iter()
+            .filter_map(|(key, _)| self.keys.get_key_value(key))
+            .collect()
+    }
+}
+
+impl FromIterator<(String, Entry)> for Keyspace {
+    fn from_iter<I: IntoIterator<Item = (String, Entry)>>(entries: I) -> Self {
+        let mut t = Self::default();
+        t.extend(entries);
+        t
+    }
+}
+
+impl Extend<(String, Entry)> for Keyspace {
+    fn extend<I: IntoIterator<Item = (String, Entry)>>(&mut self, entries: I) {
+        for (key, entry) in entries {
+            self.insert(key, entry);
+        }
+    }
+}
+
+impl Index<&str> for Keyspace {
+    type Output = Entry;
+
+    fn index(&self, key: &str) -> &Entry {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::Access;
+    use crate::value::Value;
+    use std::collections::HashSet;
+
+    fn dict(n: usize) -> Dict<usize> {
+        let mut dict = Dict::default();
+        for i in 0..n {
+            dict.insert(format!("key:{}", i), i);
+        }
+        dict
+    }
+
+    #[test]
+    fn test_dict() {
+        let mut dict = dict(1000);
+        assert_eq!(dict.len(), 1000);
+        assert_eq!(dict.buckets.len(), 1024);
+        assert_eq!(dict.get("key:10"), Some(&10));
+        assert_eq!(dict.insert("key:10".to_string(), 11), Some(10));
+        assert_eq!(dict.len(), 1000);
+        assert_eq!(dict.remove("key:10"), Some(11));
+        assert_eq!(dict.remove("key:10"), None);
+        for i in 0..100 {
+            dict.remove(&format!("key:{}", i));
+        }
+        assert_eq!(dict.len(), 900);
+        assert_eq!(dict.iter().count(), 900);
+        for i in 100..990 {
+            dict.remove(&format!("key:{}", i));
+        }
+        // Shrunk so that random buckets keep finding keys
+        assert_eq!(dict.len(), 10);
+        assert_eq!(dict.buckets.len(), 16);
+        assert!(dict.random().is_some_and(|(key, _)| dict.contains_key(key)));
+        dict.clear();
+        assert!(dict.random().is_none());
+        assert_eq!(dict.scan(0, |_, _| {}), 0);
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut dict = dict(100);
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            cursor = dict.scan(cursor, |key, _| {
+                seen.insert(key.clone());
+            });
+            calls += 1;
+            // Growing and shrinking midway, the keys there from start to end are still all seen
+            if calls == 20 {
+                for i in 100..1000 {
+                    dict.insert(format!("key:{}", i), i);
+                }
+            }
+            if calls == 200 {
+                for i in 100..1000 {
+                    dict.remove(&format!("key:{}", i));
+                }
+            }
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!((0..100).all(|i| seen.contains(&format!("key:{}", i))));
+    }
+
+    #[test]
+    fn test_sample() {
+        let dict = dict(1000);
+        let sampled = dict.sample(5);
+        assert_eq!(sampled.len(), 5);
+        let keys: HashSet<&String> = sampled.iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys.len(), 5);
+        assert_eq!(Dict::<()>::default().sample(5).len(), 0);
+        assert_eq!(dict.sample(2000).len(), 1000);
+    }
+
+    #[test]
+    fn test_keyspace_expires() {
+        let mut t = Keyspace::default();
+        let ttl = Some((Instant::now(), Duration::from_secs(100)));
+        for i in 0..100 {
+            let expiry = if i < 3 { ttl } else { None };
+            t.insert(format!("{}", i), (Value::Integer(i), expiry, Access::new()));
+        }
+        let volatile = |t: &Keyspace| {
+            let mut keys: Vec<String> = t
+                .sample(10, true)
+                .into_iter()
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(volatile(&t), ["0", "1", "2"]);
+        t.remove("0");
+        t.set_expiry("1", None);
+        t.set_expiry("50", ttl);
+        t.insert("2".to_string(), (Value::Integer(2), None, Access::new()));
+        assert_eq!(volatile(&t), ["50"]);
+        t.clear();
+        assert!(volatile(&t).is_empty());
+    }
+}
@@ -0,0 +1,491 @@
+// Function libraries loaded with FUNCTION LOAD and invoked with FCALL/FCALL_RO
+use crate::glob;
+use crate::lua;
+use crate::rdb;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+const ENGINE: &str = "LUA";
+// Same as Redis, the top level of a library runs when it's loaded and must not take longer
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+// Redis' busy-reply-threshold. The commands of other clients wait for the function running, so
+// one running past it is stopped rather than blocking them for good.
+pub const TIME_LIMIT: Duration = Duration::from_secs(5);
+const KNOWN_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+pub struct Function {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<String>,
+    callback: Arc<lua::Closure>,
+}
+
+impl Function {
+    pub fn is_read_only(&self) -> bool {
+        self.flags.iter().any(|f| f == "no-writes")
+    }
+
+    pub fn call(
+        &self,
+        keys: Vec<String>,
+        args: Vec<String>,
+        host: &mut dyn lua::Host,
+    ) -> RESPValue {
+        match lua::call(&self.callback, keys, args, host) {
+            Ok(reply) => reply,
+            // Errors raised by redis.call already carry an error code
+            Err(e) if e.starts_with(|c: char| c.is_ascii_uppercase()) => RESPValue::error(e),
+            Err(e) => RESPValue::error(format!("ERR user_script: {}", e)),
+        }
+    }
+}
+
+pub struct Library {
+    pub name: String,
+    pub code: String,
+    pub functions: BTreeMap<String, Arc<Function>>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    libraries: BTreeMap<String, Library>,
+}
+
+// Collects the functions a library registers while its code is loaded
+struct LoadHost {
+    functions: BTreeMap<String, Arc<Function>>,
+    started: Instant,
+}
+
+impl lua::Host for LoadHost {
+    fn call(&mut self, _args: Vec<String>) -> RESPValue {
+        RESPValue::error("ERR redis.call can not be used while loading a library".to_string())
+    }
+
+    fn register_function(&mut self, registration: lua::Registration) -> Result<(), String> {
+        let name = registration.name;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+        }
+        if self.functions.contains_key(&name) {
+            return Err("Function already exists in the library".to_string());
+        }
+        if let Some(flag) = registration
+            .flags
+            .iter()
+            .find(|f| !KNOWN_FLAGS.contains(&f.as_str()))
+        {
+            return Err(format!("unknown flag given: {}", flag));
+        }
+        self.functions.insert(
+            name.clone(),
+            Arc::new(Function {
+                name,
+                description: registration.description,
+                flags: registration.flags,
+                callback: registration.callback,
+            }),
+        );
+        Ok(())
+    }
+
+    fn check_running(&mut self) -> Result<(), String> {
+        if self.started.elapsed() > LOAD_TIMEOUT {
+            return Err("FUNCTION LOAD timeout".to_string());
+        }
+        Ok(())
+    }
+}
+
+// A function being run, which FUNCTION KILL stops unless it already wrote to the dataset
+#[derive(Debug)]
+pub struct Script {
+    started: Instant,
+    killed: AtomicBool,
+    wrote: AtomicBool,
+}
+
+impl Script {
+    pub fn record_write(&self) {
+        self.wrote.store(true, Relaxed);
+    }
+
+    // Tells the script whether it should stop
+    pub fn check_running(&self) -> Result<(), String> {
+        if self.killed.load(Relaxed) {
+            return Err("ERR Script killed by user with FUNCTION KILL...".to_string());
+        }
+        if self.started.elapsed() > TIME_LIMIT {
+            return Err(format!(
+                "ERR Script timed out after {} seconds",
+                TIME_LIMIT.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+// The functions being run, across every connection
+#[derive(Default)]
+pub struct Running {
+    scripts: Mutex<Vec<Arc<Script>>>,
+}
+
+impl Running {
+    fn scripts(&self) -> Result<MutexGuard<'_, Vec<Arc<Script>>>, String> {
+        self.scripts
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for running scripts {}", e))
+    }
+
+    pub fn start(&self) -> Result<Arc<Script>, String> {
+        let script = Arc::new(Script {
+            started: Instant::now(),
+            killed: AtomicBool::new(false),
+            wrote: AtomicBool::new(false),
+        });
+        self.scripts()?.push(Arc::clone(&script));
+        Ok(script)
+    }
+
+    pub fn finish(&self, script: &Arc<Script>) -> Result<(), String> {
+        self.scripts()?.retain(|s| !Arc::ptr_eq(s, script));
+        Ok(())
+    }
+
+    // FUNCTION KILL, which stops every running function that didn't write yet
+    pub fn kill(&self) -> Result<RESPValue, String> {
+        let scripts = self.scripts()?;
+        if scripts.is_empty() {
+            return Ok(RESPValue::error(
+                "NOTBUSY No scripts in execution right now.".to_string(),
+            ));
+        }
+        let killable: Vec<&Arc<Script>> =
+            scripts.iter().filter(|s| !s.wrote.load(Relaxed)).collect();
+        if killable.is_empty() {
+            return Ok(RESPValue::error("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.".to_string()));
+        }
+        for script in killable {
+            script.killed.store(true, Relaxed);
+        }
+        Ok(RESPValue::simple_string("OK".to_string()))
+    }
+}
+
+// Parses the "#!lua name=<library>" header, returns the library name
+fn parse_metadata(code: &str) -> Result<String, String> {
+    let header = code
+        .lines()
+        .next()
+        .and_then(|l| l.strip_prefix("#!"))
+        .ok_or_else(|| "ERR Missing library metadata".to_string())?;
+    let mut parts = header.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case(ENGINE) {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(n) => name = Some(n.to_string()),
+            None => return Err(format!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+    name.ok_or_else(|| "ERR Library name was not given".to_string())
+}
+
+fn compile(code: &str) -> Result<Library, String> {
+    let name = parse_metadata(code)?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string());
+    }
+    let mut host = LoadHost {
+        functions: BTreeMap::new(),
+        started: Instant::now(),
+    };
+    lua::load(code, &mut host).map_err(|e| format!("ERR Error compiling function: {}", e))?;
+    if host.functions.is_empty() {
+        return Err("ERR No functions registered".to_string());
+    }
+    Ok(Library {
+        name,
+        code: code.to_string(),
+        functions: host.functions,
+    })
+}
+
+impl Registry {
+    pub fn find(&self, function: &str) -> Option<Arc<Function>> {
+        self.libraries
+            .values()
+            .find_map(|l| l.functions.get(function))
+            .map(Arc::clone)
+    }
+
+    // Adds the libraries, replacing existing ones with the same name if `replace` is set.
+    // Nothing is added if any of them fails.
+    fn add(&mut self, libraries: Vec<Library>, replace: bool) -> Result<(), String> {
+        let mut candidate: BTreeMap<String, &Library> =
+            self.libraries.iter().map(|(k, v)| (k.clone(), v)).collect();
+        for library in &libraries {
+            if candidate.contains_key(&library.name) && !replace {
+                return Err(format!("ERR Library '{}' already exists", library.name));
+            }
+            candidate.insert(library.name.clone(), library);
+        }
+        let mut seen = BTreeMap::new();
+        for library in candidate.values() {
+            for function in library.functions.keys() {
+                if let Some(other) = seen.insert(function, &library.name) {
+                    return Err(format!(
+                        "ERR Function {} already exists (in library '{}')",
+                        function, other
+                    ));
+                }
+            }
+        }
+        for library in libraries {
+            self.libraries.insert(library.name.clone(), library);
+        }
+        Ok(())
+    }
+
+    pub fn load(&mut self, code: &str, replace: bool) -> Result<String, String> {
+        let library = compile(code)?;
+        let name = library.name.clone();
+        self.add(vec![library], replace)?;
+        Ok(name)
+    }
+
+    // The code of every library, which is what RDB files keep of them
+    pub fn codes(&self) -> Vec<String> {
+        self.libraries.values().map(|l| l.code.clone()).collect()
+    }
+
+    // Replaces every library with those of an RDB file, the registry is left as is on errors
+    pub fn replace_all(&mut self, codes: &[String]) -> Result<(), String> {
+        let libraries = codes
+            .iter()
+            .map(|code| compile(code))
+            .collect::<Result<Vec<Library>, String>>()?;
+        let mut registry = Registry::default();
+        registry.add(libraries, false)?;
+        *self = registry;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, library: &str) -> bool {
+        self.libraries.remove(library).is_some()
+    }
+
+    pub fn flush(&mut self) {
+        self.libraries.clear();
+    }
+
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> RESPValue {
+        let bulk = |s: &str| RESPValue::bulk_string(Some(s.to_string()));
+        let libraries = self
+            .libraries
+            .values()
+            .filter(|l| pattern.is_none_or(|p| glob::matches(p, &l.name, true)))
+            .map(|library| {
+                let functions = library
+                    .functions
+                    .values()
+                    .map(|f| {
+                        RESPValue::Array(Some(vec![
+                            bulk("name"),
+                            bulk(&f.name),
+                            bulk("description"),
+                            RESPValue::bulk_string(f.description.clone()),
+                            bulk("flags"),
+                            RESPValue::Array(Some(f.flags.iter().map(|s| bulk(s)).collect())),
+                        ]))
+                    })
+                    .collect();
+                let mut fields = vec![
+                    bulk("library_name"),
+                    bulk(&library.name),
+                    bulk("engine"),
+                    bulk(ENGINE),
+                    bulk("functions"),
+                    RESPValue::Array(Some(functions)),
+                ];
+                if with_code {
+                    fields.push(bulk("library_code"));
+                    fields.push(bulk(&library.code));
+                }
+                RESPValue::Array(Some(fields))
+            })
+            .collect();
+        RESPValue::Array(Some(libraries))
+    }
+
+    pub fn dump(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        for library in self.libraries.values() {
+            payload.push(rdb::RDB_OPCODE_FUNCTION2);
+            rdb::write_string(
+                &mut payload,
+                &redis_starter_rust::string_to_bytes(&library.code),
+            );
+        }
+        rdb::append_footer(&mut payload);
+        payload
+    }
+
+    pub fn restore(&mut self, payload: &[u8], policy: RestorePolicy) -> Result<(), String> {
+        let mut body = rdb::verify_footer(payload)
+            .ok_or_else(|| "ERR payload version or checksum are wrong".to_string())?;
+        let mut libraries = vec![];
+        while let Some((&opcode, rest)) = body.split_first() {
+            if opcode != rdb::RDB_OPCODE_FUNCTION2 {
+                return Err("ERR given type is not a function".to_string());
+            }
+            let (code, rest) = rdb::read_string(rest)
+                .ok_or_else(|| "ERR can not read data from given payload".to_string())?;
+            libraries.push(compile(&redis_starter_rust::bytes_to_string(&code))?);
+            body = rest;
+        }
+
+        match policy {
+            RestorePolicy::Flush => {
+                let previous = std::mem::take(&mut self.libraries);
+                let result = self.add(libraries, false);
+                if result.is_err() {
+                    self.libraries = previous;
+                }
+                result
+            }
+            RestorePolicy::Append => self.add(libraries, false),
+            RestorePolicy::Replace => self.add(libraries, true),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RestorePolicy {
+    Flush,
+    Append,
+    Replace,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LIB: &str = "#!lua name=mylib\nredis.register_function('myfunc', function(keys, args) return args[1] end)";
+
+    #[test]
+    fn test_load_and_find() {
+        let mut registry = Registry::default();
+        assert_eq!(registry.load(LIB, false), Ok("mylib".to_string()));
+        assert!(registry.find("myfunc").is_some());
+        assert_eq!(
+            registry.load(LIB, false),
+            Err("ERR Library 'mylib' already exists".to_string())
+        );
+        assert_eq!(registry.load(LIB, true), Ok("mylib".to_string()));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let mut registry = Registry::default();
+        assert_eq!(
+            registry.load("return 1", false),
+            Err("ERR Missing library metadata".to_string())
+        );
+        assert_eq!(
+            registry.load("#!js name=lib\n", false),
+            Err("ERR Engine 'js' not found".to_string())
+        );
+        assert_eq!(
+            registry.load("#!lua\n", false),
+            Err("ERR Library name was not given".to_string())
+        );
+        assert_eq!(
+            registry.load("#!lua name=lib\nlocal x = 1", false),
+            Err("ERR No functions registered".to_string())
+        );
+        // Function names are unique across libraries
+        registry.load(LIB, false).unwrap();
+        assert!(registry
+            .load(&LIB.replace("mylib", "otherlib"), false)
+            .unwrap_err()
+            .starts_with("ERR Function myfunc already exists"));
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let mut registry = Registry::default();
+        registry.load(LIB, false).unwrap();
+        let payload = registry.dump();
+
+        let mut other = Registry::default();
+        other.restore(&payload, RestorePolicy::Append).unwrap();
+        assert!(other.find("myfunc").is_some());
+        assert!(other.restore(&payload, RestorePolicy::Append).is_err());
+        other.restore(&payload, RestorePolicy::Replace).unwrap();
+        other.restore(&payload, RestorePolicy::Flush).unwrap();
+
+        let mut corrupted = payload.clone();
+        corrupted[3] ^= 1;
+        assert_eq!(
+            other.restore(&corrupted, RestorePolicy::Flush),
+            Err("ERR payload version or checksum are wrong".to_string())
+        );
+        assert!(other.find("myfunc").is_some());
+    }
+
+    #[test]
+    fn test_kill() {
+        let running = Running::default();
+        assert_eq!(
+            running.kill(),
+            Ok(RESPValue::error(
+                "NOTBUSY No scripts in execution right now.".to_string()
+            ))
+        );
+        let writer = running.start().unwrap();
+        writer.record_write();
+        assert!(matches!(running.kill(), Ok(RESPValue::Error(e)) if e.starts_with("UNKILLABLE")));
+        assert_eq!(writer.check_running(), Ok(()));
+
+        let reader = running.start().unwrap();
+        assert_eq!(
+            running.kill(),
+            Ok(RESPValue::simple_string("OK".to_string()))
+        );
+        assert_eq!(
+            reader.check_running(),
+            Err("ERR Script killed by user with FUNCTION KILL...".to_string())
+        );
+        assert_eq!(writer.check_running(), Ok(()));
+        running.finish(&reader).unwrap();
+        running.finish(&writer).unwrap();
+        assert!(matches!(running.kill(), Ok(RESPValue::Error(e)) if e.starts_with("NOTBUSY")));
+
+        let mut host = LoadHost {
+            functions: BTreeMap::new(),
+            started: Instant::now() - LOAD_TIMEOUT * 2,
+        };
+        assert_eq!(
+            lua::Host::check_running(&mut host),
+            Err("FUNCTION LOAD timeout".to_string())
+        );
+    }
+}
@@ -0,0 +1,938 @@
+// Geospatial commands. Positions are stored in sorted sets, scored by their 52 bit geohash
+// (26 bits of latitude interleaved with 26 bits of longitude), using the same projection
+// limits as Redis so that scores and search results match.
+use crate::memory::Access;
+use crate::not_an_integer;
+use crate::sorted_set::get_or_create_sorted_set;
+use crate::sorted_set::get_sorted_set;
+use crate::sorted_set::SortedSet;
+use crate::strings::parse_float;
+use crate::strings::syntax_error;
+use crate::strings::wrong_number_of_arguments;
+use crate::value;
+use crate::value::Value;
+use crate::write_table;
+use crate::Table;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+
+const GEO_STEP_MAX: u8 = 26;
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const D_R: f64 = std::f64::consts::PI / 180.0;
+const GEO_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Range {
+    min: f64,
+    max: f64,
+}
+
+const LONG_RANGE: Range = Range {
+    min: GEO_LONG_MIN,
+    max: GEO_LONG_MAX,
+};
+const LAT_RANGE: Range = Range {
+    min: GEO_LAT_MIN,
+    max: GEO_LAT_MAX,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HashBits {
+    bits: u64,
+    step: u8,
+}
+
+impl HashBits {
+    const ZERO: HashBits = HashBits { bits: 0, step: 0 };
+
+    // Range of 52 bit scores covered by this box, the maximum being exclusive
+    fn score_range(&self) -> (u64, u64) {
+        let shift = 52 - self.step as u32 * 2;
+        (self.bits << shift, (self.bits + 1) << shift)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Area {
+    longitude: Range,
+    latitude: Range,
+}
+
+fn deg_rad(angle: f64) -> f64 {
+    angle * D_R
+}
+
+fn rad_deg(angle: f64) -> f64 {
+    angle / D_R
+}
+
+// Spreads the bits of `x` over the even bits of the result
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000_ffff_0000_ffff;
+    x = (x | (x << 8)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+// Inverse of spread
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0f0f_0f0f_0f0f_0f0f;
+    x = (x | (x >> 4)) & 0x00ff_00ff_00ff_00ff;
+    x = (x | (x >> 8)) & 0x0000_ffff_0000_ffff;
+    ((x | (x >> 16)) & 0x0000_0000_ffff_ffff) as u32
+}
+
+fn encode(
+    long_range: Range,
+    lat_range: Range,
+    longitude: f64,
+    latitude: f64,
+    step: u8,
+) -> Option<HashBits> {
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+        || !(lat_range.min..=lat_range.max).contains(&latitude)
+        || !(long_range.min..=long_range.max).contains(&longitude)
+    {
+        return None;
+    }
+    let scale = (1u64 << step) as f64;
+    let lat_offset = (latitude - lat_range.min) / (lat_range.max - lat_range.min) * scale;
+    let long_offset = (longitude - long_range.min) / (long_range.max - long_range.min) * scale;
+    Some(HashBits {
+        bits: spread(lat_offset as u32) | (spread(long_offset as u32) << 1),
+        step,
+    })
+}
+
+fn decode(hash: HashBits) -> Area {
+    let lat = squash(hash.bits);
+    let long = squash(hash.bits >> 1);
+    let scale = (1u64 << hash.step) as f64;
+    let lat_scale = LAT_RANGE.max - LAT_RANGE.min;
+    let long_scale = LONG_RANGE.max - LONG_RANGE.min;
+    Area {
+        latitude: Range {
+            min: LAT_RANGE.min + (lat as f64 / scale) * lat_scale,
+            max: LAT_RANGE.min + ((lat + 1) as f64 / scale) * lat_scale,
+        },
+        longitude: Range {
+            min: LONG_RANGE.min + (long as f64 / scale) * long_scale,
+            max: LONG_RANGE.min + ((long + 1) as f64 / scale) * long_scale,
+        },
+    }
+}
+
+// Returns the (longitude, latitude) at the center of the box of a score
+fn decode_score(score: f64) -> (f64, f64) {
+    let area = decode(HashBits {
+        bits: score as u64,
+        step: GEO_STEP_MAX,
+    });
+    let longitude =
+        ((area.longitude.min + area.longitude.max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let latitude = ((area.latitude.min + area.latitude.max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (longitude, latitude)
+}
+
+// Moves the box east (d > 0) or west (d < 0)
+fn move_x(hash: &mut HashBits, d: i8) {
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0x5555_5555_5555_5555u64 >> (64 - hash.step as u32 * 2);
+    let x = if d > 0 {
+        x.wrapping_add(zz + 1)
+    } else {
+        (x | zz).wrapping_sub(zz + 1)
+    };
+    hash.bits = (x & (0xaaaa_aaaa_aaaa_aaaau64 >> (64 - hash.step as u32 * 2))) | y;
+}
+
+// Moves the box north (d > 0) or south (d < 0)
+fn move_y(hash: &mut HashBits, d: i8) {
+    let x = hash.bits & 0xaaaa_aaaa_aaaa_aaaa;
+    let y = hash.bits & 0x5555_5555_5555_5555;
+    let zz = 0xaaaa_aaaa_aaaa_aaaau64 >> (64 - hash.step as u32 * 2);
+    let y = if d > 0 {
+        y.wrapping_add(zz + 1)
+    } else {
+        (y | zz).wrapping_sub(zz + 1)
+    };
+    hash.bits = x | (y & (0x5555_5555_5555_5555u64 >> (64 - hash.step as u32 * 2)));
+}
+
+fn neighbor(hash: HashBits, dx: i8, dy: i8) -> HashBits {
+    let mut hash = hash;
+    if dx != 0 {
+        move_x(&mut hash, dx);
+    }
+    if dy != 0 {
+        move_y(&mut hash, dy);
+    }
+    hash
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2) - deg_rad(lat1)).abs()
+}
+
+// Haversine distance in meters
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let v = ((deg_rad(lon2) - deg_rad(lon1)) / 2.0).sin();
+    // Same longitude, avoid the expensive math
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+    let lat1 = deg_rad(lat1);
+    let lat2 = deg_rad(lat2);
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ShapeKind {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Shape {
+    longitude: f64,
+    latitude: f64,
+    // Meters per unit of the dimensions
+    conversion: f64,
+    kind: ShapeKind,
+}
+
+impl Shape {
+    // Returns the distance to the point in meters if it's inside the shape
+    fn distance_if_within(&self, longitude: f64, latitude: f64) -> Option<f64> {
+        match self.kind {
+            ShapeKind::Radius(radius) => {
+                let d = distance(self.longitude, self.latitude, longitude, latitude);
+                (d <= radius * self.conversion).then_some(d)
+            }
+            ShapeKind::Box { width, height } => {
+                // The latitude distance is cheaper, check it first
+                if lat_distance(latitude, self.latitude) > height * self.conversion / 2.0 {
+                    return None;
+                }
+                if distance(longitude, latitude, self.longitude, latitude)
+                    > width * self.conversion / 2.0
+                {
+                    return None;
+                }
+                Some(distance(self.longitude, self.latitude, longitude, latitude))
+            }
+        }
+    }
+
+    // Returns (min longitude, min latitude, max longitude, max latitude)
+    fn bounding_box(&self) -> (f64, f64, f64, f64) {
+        let (width, height) = match self.kind {
+            ShapeKind::Radius(radius) => (radius, radius),
+            ShapeKind::Box { width, height } => (width / 2.0, height / 2.0),
+        };
+        let (width, height) = (width * self.conversion, height * self.conversion);
+        let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+        let long_delta_top =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.latitude + lat_delta).cos());
+        let long_delta_bottom =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.latitude - lat_delta).cos());
+        // The box is widest on the side closest to the equator
+        let long_delta = if self.latitude < 0.0 {
+            long_delta_bottom
+        } else {
+            long_delta_top
+        };
+        (
+            self.longitude - long_delta,
+            self.latitude - lat_delta,
+            self.longitude + long_delta,
+            self.latitude + lat_delta,
+        )
+    }
+
+    // Distance from the center to the furthest point of the shape, in meters
+    fn radius_meters(&self) -> f64 {
+        let radius = match self.kind {
+            ShapeKind::Radius(radius) => radius,
+            ShapeKind::Box { width, height } => {
+                ((width / 2.0) * (width / 2.0) + (height / 2.0) * (height / 2.0)).sqrt()
+            }
+        };
+        radius * self.conversion
+    }
+}
+
+fn estimate_steps_by_radius(mut range_meters: f64, latitude: f64) -> u8 {
+    if range_meters == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    // Make sure the range is included in most of the base cases
+    step -= 2;
+    // Wider range towards the poles
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u8
+}
+
+// Returns the box containing the center of the shape followed by its 8 neighbors, in the order
+// Redis scans them. Neighbors that can't contain any point of the shape are zeroed.
+fn areas_to_search(shape: &Shape) -> [HashBits; 9] {
+    let (min_lon, min_lat, max_lon, max_lat) = shape.bounding_box();
+    let mut steps = estimate_steps_by_radius(shape.radius_meters(), shape.latitude);
+    // The center is validated when parsed
+    let encode_center = |steps| {
+        encode(
+            LONG_RANGE,
+            LAT_RANGE,
+            shape.longitude,
+            shape.latitude,
+            steps,
+        )
+        .unwrap_or(HashBits::ZERO)
+    };
+    let mut hash = encode_center(steps);
+
+    // The estimated step may be too big when the shape is near the edge of the box
+    let decrease_step = decode(neighbor(hash, 0, 1)).latitude.max < max_lat
+        || decode(neighbor(hash, 0, -1)).latitude.min > min_lat
+        || decode(neighbor(hash, 1, 0)).longitude.max < max_lon
+        || decode(neighbor(hash, -1, 0)).longitude.min > min_lon;
+    if steps > 1 && decrease_step {
+        steps -= 1;
+        hash = encode_center(steps);
+    }
+    let area = decode(hash);
+
+    // north, south, east, west, north east, north west, south east, south west
+    let directions = [
+        (0, 1),
+        (0, -1),
+        (1, 0),
+        (-1, 0),
+        (1, 1),
+        (-1, 1),
+        (1, -1),
+        (-1, -1),
+    ];
+    let mut areas = [hash; 9];
+    for (area, &(dx, dy)) in areas[1..].iter_mut().zip(directions.iter()) {
+        *area = neighbor(hash, dx, dy);
+    }
+    if steps >= 2 {
+        for (i, &(dx, dy)) in directions.iter().enumerate() {
+            let useless = (dy < 0 && area.latitude.min < min_lat)
+                || (dy > 0 && area.latitude.max > max_lat)
+                || (dx < 0 && area.longitude.min < min_lon)
+                || (dx > 0 && area.longitude.max > max_lon);
+            if useless {
+                areas[i + 1] = HashBits::ZERO;
+            }
+        }
+    }
+    areas
+}
+
+struct Point {
+    member: String,
+    // In meters until the reply is built
+    distance: f64,
+    score: f64,
+    longitude: f64,
+    latitude: f64,
+}
+
+// Collects the members inside the shape. Stops after `limit` matches if it isn't 0.
+fn search(set: &SortedSet, shape: &Shape, limit: usize) -> Vec<Point> {
+    let areas = areas_to_search(shape);
+    let mut points = vec![];
+    let mut last_processed = 0;
+    for (i, area) in areas.iter().enumerate() {
+        if *area == HashBits::ZERO {
+            continue;
+        }
+        // Neighbors can be the same box when the radius is huge
+        if last_processed != 0 && *area == areas[last_processed] {
+            continue;
+        }
+        if limit != 0 && points.len() >= limit {
+            break;
+        }
+        let (min, max) = area.score_range();
+        for (member, score) in set.range_by_score(min as f64, max as f64) {
+            let (longitude, latitude) = decode_score(score);
+            if let Some(distance) = shape.distance_if_within(longitude, latitude) {
+                points.push(Point {
+                    member: member.to_string(),
+                    distance,
+                    score,
+                    longitude,
+                    latitude,
+                });
+                if limit != 0 && points.len() >= limit {
+                    break;
+                }
+            }
+        }
+        last_processed = i;
+    }
+    points
+}
+
+fn not_a_float() -> RESPValue {
+    RESPValue::error("ERR value is not a valid float".to_string())
+}
+
+fn parse_long_lat(longitude: &str, latitude: &str) -> Result<(f64, f64), RESPValue> {
+    let longitude = parse_float(longitude).ok_or_else(not_a_float)?;
+    let latitude = parse_float(latitude).ok_or_else(not_a_float)?;
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+    {
+        return Err(RESPValue::error(format!(
+            "ERR invalid longitude,latitude pair {:.6},{:.6}",
+            longitude, latitude
+        )));
+    }
+    Ok((longitude, latitude))
+}
+
+// Returns the number of meters in the unit
+fn parse_unit(unit: &str) -> Result<f64, RESPValue> {
+    match unit.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(RESPValue::error(
+            "ERR unsupported unit provided. please use M, KM, FT, MI".to_string(),
+        )),
+    }
+}
+
+// Formats distances with 4 decimals, rounding like Redis does
+fn format_distance(d: f64) -> String {
+    let scaled = (d * 10000.0).round_ties_even() as i64;
+    format!("{}.{:04}", scaled / 10000, scaled % 10000)
+}
+
+// Formats coordinates with up to 17 decimals, without trailing zeros
+fn format_coordinate(c: f64) -> String {
+    let s = format!("{:.17}", c);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+fn position_reply(longitude: f64, latitude: f64) -> RESPValue {
+    RESPValue::Array(Some(vec![
+        RESPValue::bulk_string(Some(format_coordinate(longitude))),
+        RESPValue::bulk_string(Some(format_coordinate(latitude))),
+    ]))
+}
+
+pub fn geoadd(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let args = match Args::new("geoadd", args).rest() {
+        Ok(args) if args.len() >= 4 => args,
+        Ok(_) => return Ok(wrong_number_of_arguments("geoadd")),
+        Err(e) => return Ok(e.into()),
+    };
+    let key = args[0];
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut first = 1;
+    while let Some(option) = args.get(first) {
+        match option.to_uppercase().as_str() {
+            "NX" => nx = true,
+            "XX" => xx = true,
+            "CH" => ch = true,
+            _ => break,
+        }
+        first += 1;
+    }
+    if !(args.len() - first).is_multiple_of(3) || (nx && xx) {
+        return Ok(syntax_error());
+    }
+    let mut points = vec![];
+    for triplet in args[first..].chunks(3) {
+        let (longitude, latitude) = match parse_long_lat(triplet[0], triplet[1]) {
+            Ok(position) => position,
+            Err(e) => return Ok(e),
+        };
+        let hash = encode(LONG_RANGE, LAT_RANGE, longitude, latitude, GEO_STEP_MAX)
+            .unwrap_or(HashBits::ZERO);
+        points.push((triplet[2], hash.bits as f64));
+    }
+
+    let mut t = write_table(&table)?;
+    match get_sorted_set(&mut t, key) {
+        Ok(None) if xx => return Ok(RESPValue::integer(0)),
+        Err(e) => return Ok(e),
+        _ => {}
+    }
+    let set = match get_or_create_sorted_set(&mut t, key) {
+        Ok(set) => set,
+        Err(e) => return Ok(e),
+    };
+    let (mut added, mut changed) = (0, 0);
+    for (member, score) in points {
+        match set.score(member) {
+            Some(_) if nx => {}
+            Some(old_score) => {
+                if old_score != score {
+                    set.insert(member.to_string(), score);
+                    changed += 1;
+                }
+            }
+            None if xx => {}
+            None => {
+                set.insert(member.to_string(), score);
+                added += 1;
+            }
+        }
+    }
+    Ok(RESPValue::integer(if ch { added + changed } else { added }))
+}
+
+pub fn geopos(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("geopos", args);
+    let (key, members) = match args.next_key().and_then(|key| Ok((key, args.rest()?))) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let set = match get_sorted_set(&mut t, key) {
+        Ok(set) => set,
+        Err(e) => return Ok(e),
+    };
+    Ok(RESPValue::Array(Some(
+        members
+            .iter()
+            .map(
+                |member| match set.as_ref().and_then(|set| set.score(member)) {
+                    Some(score) => {
+                        let (longitude, latitude) = decode_score(score);
+                        position_reply(longitude, latitude)
+                    }
+                    None => RESPValue::Array(None),
+                },
+            )
+            .collect(),
+    )))
+}
+
+pub fn geodist(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("geodist", args);
+    let parsed = args
+        .next_key()
+        .and_then(|key| Ok((key, args.next_str()?, args.next_str()?, args.rest()?)));
+    let (key, member1, member2, options) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let to_meters = match options[..] {
+        [] => 1.0,
+        [unit] => match parse_unit(unit) {
+            Ok(to_meters) => to_meters,
+            Err(e) => return Ok(e),
+        },
+        _ => return Ok(syntax_error()),
+    };
+    let mut t = write_table(&table)?;
+    let set = match get_sorted_set(&mut t, key) {
+        Ok(Some(set)) => set,
+        Ok(None) => return Ok(RESPValue::bulk_string(None)),
+        Err(e) => return Ok(e),
+    };
+    match (set.score(member1), set.score(member2)) {
+        (Some(score1), Some(score2)) => {
+            let (lon1, lat1) = decode_score(score1);
+            let (lon2, lat2) = decode_score(score2);
+            let d = distance(lon1, lat1, lon2, lat2) / to_meters;
+            Ok(RESPValue::bulk_string(Some(format_distance(d))))
+        }
+        _ => Ok(RESPValue::bulk_string(None)),
+    }
+}
+
+pub fn geohash(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("geohash", args);
+    let (key, members) = match args.next_key().and_then(|key| Ok((key, args.rest()?))) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let set = match get_sorted_set(&mut t, key) {
+        Ok(set) => set,
+        Err(e) => return Ok(e),
+    };
+    // Standard geohashes use the full latitude range rather than the Mercator limits
+    let lat_range = Range {
+        min: -90.0,
+        max: 90.0,
+    };
+    Ok(RESPValue::Array(Some(
+        members
+            .iter()
+            .map(|member| {
+                let score = set.as_ref().and_then(|set| set.score(member))?;
+                let (longitude, latitude) = decode_score(score);
+                let hash = encode(LONG_RANGE, lat_range, longitude, latitude, GEO_STEP_MAX)?;
+                // There are only 52 bits, the 11th character is always 0
+                let s = (0..11)
+                    .map(|i| match i {
+                        10 => GEO_ALPHABET[0] as char,
+                        _ => {
+                            GEO_ALPHABET[((hash.bits >> (52 - (i + 1) * 5)) & 0x1f) as usize]
+                                as char
+                        }
+                    })
+                    .collect();
+                Some(s)
+            })
+            .map(RESPValue::bulk_string)
+            .collect(),
+    )))
+}
+
+enum Origin<'a> {
+    Member(&'a str),
+    Position(f64, f64),
+}
+
+#[derive(PartialEq)]
+enum Sort {
+    None,
+    Asc,
+    Desc,
+}
+
+// Handles GEOSEARCH and GEOSEARCHSTORE (`store` true, the destination being the first argument)
+pub fn geosearch(args: &[BulkString], table: Table, store: bool) -> Result<RESPValue, String> {
+    let command = if store { "geosearchstore" } else { "geosearch" };
+    let args = match Args::new(command, args).rest() {
+        Ok(args) => args,
+        Err(e) => return Ok(e.into()),
+    };
+    let (destination, key, options) = match (store, &args[..]) {
+        (true, [destination, key, options @ ..]) if options.len() >= 5 => {
+            (Some(*destination), *key, options)
+        }
+        (false, [key, options @ ..]) if options.len() >= 5 => (None, *key, options),
+        _ => return Ok(wrong_number_of_arguments(command)),
+    };
+
+    let mut origin = None;
+    let mut kind = None;
+    let mut conversion = 1.0;
+    let (mut with_dist, mut with_hash, mut with_coord) = (false, false, false);
+    let mut any = false;
+    let mut sort = Sort::None;
+    let mut count = 0;
+    let mut store_dist = false;
+    let mut i = 0;
+    while i < options.len() {
+        let remaining = options.len() - i - 1;
+        match options[i].to_uppercase().as_str() {
+            "WITHDIST" => with_dist = true,
+            "WITHHASH" => with_hash = true,
+            "WITHCOORD" => with_coord = true,
+            "ANY" => any = true,
+            "ASC" => sort = Sort::Asc,
+            "DESC" => sort = Sort::Desc,
+            "STOREDIST" if store => store_dist = true,
+            "COUNT" if remaining >= 1 => {
+                count = match value::parse_integer(options[i + 1]) {
+                    Some(n) if n <= 0 => {
+                        return Ok(RESPValue::error("ERR COUNT must be > 0".to_string()))
+                    }
+                    Some(n) => n as usize,
+                    None => return Ok(not_an_integer()),
+                };
+                i += 1;
+            }
+            "FROMMEMBER" if remaining >= 1 && origin.is_none() => {
+                origin = Some(Origin::Member(options[i + 1]));
+                i += 1;
+            }
+            "FROMLONLAT" if remaining >= 2 && origin.is_none() => {
+                origin = match parse_long_lat(options[i + 1], options[i + 2]) {
+                    Ok((longitude, latitude)) => Some(Origin::Position(longitude, latitude)),
+                    Err(e) => return Ok(e),
+                };
+                i += 2;
+            }
+            "BYRADIUS" if remaining >= 2 && kind.is_none() => {
+                let radius = match parse_float(options[i + 1]) {
+                    Some(r) if r < 0.0 => {
+                        return Ok(RESPValue::error(
+                            "ERR radius cannot be negative".to_string(),
+                        ))
+                    }
+                    Some(r) => r,
+                    None => return Ok(not_a_float()),
+                };
+                conversion = match parse_unit(options[i + 2]) {
+                    Ok(c) => c,
+                    Err(e) => return Ok(e),
+                };
+                kind = Some(ShapeKind::Radius(radius));
+                i += 2;
+            }
+            "BYBOX" if remaining >= 3 && kind.is_none() => {
+                let (width, height) =
+                    match (parse_float(options[i + 1]), parse_float(options[i + 2])) {
+                        (Some(w), Some(h)) if w < 0.0 || h < 0.0 => {
+                            return Ok(RESPValue::error(
+                                "ERR height or width cannot be negative".to_string(),
+                            ))
+                        }
+                        (Some(w), Some(h)) => (w, h),
+                        _ => return Ok(not_a_float()),
+                    };
+                conversion = match parse_unit(options[i + 3]) {
+                    Ok(c) => c,
+                    Err(e) => return Ok(e),
+                };
+                kind = Some(ShapeKind::Box { width, height });
+                i += 3;
+            }
+            _ => return Ok(syntax_error()),
+        }
+        i += 1;
+    }
+
+    if store && (with_dist || with_hash || with_coord) {
+        return Ok(RESPValue::error(
+            "ERR GEOSEARCHSTORE is not compatible with WITHDIST, WITHHASH and WITHCOORD options"
+                .to_string(),
+        ));
+    }
+    let origin = match origin {
+        Some(origin) => origin,
+        None => {
+            return Ok(RESPValue::error(format!(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                command
+            )))
+        }
+    };
+    let kind = match kind {
+        Some(kind) => kind,
+        None => {
+            return Ok(RESPValue::error(format!(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for {}",
+                command
+            )))
+        }
+    };
+    if any && count == 0 {
+        return Ok(RESPValue::error(
+            "ERR the ANY argument requires COUNT argument".to_string(),
+        ));
+    }
+    // The closest matches are the ones that make sense to return with COUNT
+    if count != 0 && sort == Sort::None && !any {
+        sort = Sort::Asc;
+    }
+
+    let mut t = write_table(&table)?;
+    let set = match get_sorted_set(&mut t, key) {
+        Ok(Some(set)) => set,
+        Ok(None) => {
+            return Ok(match destination {
+                Some(destination) => {
+                    t.remove(destination);
+                    RESPValue::integer(0)
+                }
+                None => RESPValue::Array(Some(vec![])),
+            })
+        }
+        Err(e) => return Ok(e),
+    };
+    let (longitude, latitude) = match origin {
+        Origin::Position(longitude, latitude) => (longitude, latitude),
+        Origin::Member(member) => match set.score(member) {
+            Some(score) => decode_score(score),
+            None => {
+                return Ok(RESPValue::error(
+                    "ERR could not decode requested zset member".to_string(),
+                ))
+            }
+        },
+    };
+    let shape = Shape {
+        longitude,
+        latitude,
+        conversion,
+        kind,
+    };
+    let mut points = search(set, &shape, if any { count } else { 0 });
+    match sort {
+        Sort::Asc => points.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+        Sort::Desc => points.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+        Sort::None => {}
+    }
+    if count != 0 {
+        points.truncate(count);
+    }
+    for point in &mut points {
+        point.distance /= conversion;
+    }
+
+    let destination = match destination {
+        Some(destination) => destination,
+        None => {
+            return Ok(RESPValue::Array(Some(
+                points
+                    .into_iter()
+                    .map(|point| {
+                        let member = RESPValue::bulk_string(Some(point.member));
+                        if !(with_dist || with_hash || with_coord) {
+                            return member;
+                        }
+                        let mut reply = vec![member];
+                        if with_dist {
+                            reply.push(RESPValue::bulk_string(Some(format_distance(
+                                point.distance,
+                            ))));
+                        }
+                        if with_hash {
+                            reply.push(RESPValue::integer(point.score as i64));
+                        }
+                        if with_coord {
+                            reply.push(position_reply(point.longitude, point.latitude));
+                        }
+                        RESPValue::Array(Some(reply))
+                    })
+                    .collect(),
+            )))
+        }
+    };
+    let stored = points.len();
+    if points.is_empty() {
+        t.remove(destination);
+    } else {
+        let mut result = SortedSet::default();
+        for point in points {
+            let score = if store_dist {
+                point.distance
+            } else {
+                point.score
+            };
+            result.insert(point.member, score);
+        }
+        t.insert(
+            destination.to_string(),
+            (Value::SortedSet(result), None, Access::new()),
+        );
+    }
+    Ok(RESPValue::integer(stored as i64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn score(longitude: f64, latitude: f64) -> f64 {
+        encode(LONG_RANGE, LAT_RANGE, longitude, latitude, GEO_STEP_MAX)
+            .unwrap()
+            .bits as f64
+    }
+
+    #[test]
+    fn test_encode() {
+        // Scores stored by Redis for the GEOADD Sicily examples
+        assert_eq!(score(13.361389, 38.115556), 3479099956230698.0);
+        assert_eq!(score(15.087269, 37.502669), 3479447370796909.0);
+        assert_eq!(encode(LONG_RANGE, LAT_RANGE, 0.0, 86.0, GEO_STEP_MAX), None);
+    }
+
+    #[test]
+    fn test_decode_score() {
+        let (longitude, latitude) = decode_score(3479099956230698.0);
+        assert_eq!(format_coordinate(longitude), "13.36138933897018433");
+        assert_eq!(format_coordinate(latitude), "38.11555639549629859");
+    }
+
+    #[test]
+    fn test_distance() {
+        let (lon1, lat1) = decode_score(3479099956230698.0);
+        let (lon2, lat2) = decode_score(3479447370796909.0);
+        assert_eq!(
+            format_distance(distance(lon1, lat1, lon2, lat2)),
+            "166274.1516"
+        );
+        assert_eq!(
+            format_distance(distance(lon1, lat1, lon2, lat2) / 1000.0),
+            "166.2742"
+        );
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let hash = HashBits {
+            bits: 0b1100,
+            step: 2,
+        };
+        let east = neighbor(hash, 1, 0);
+        let west = neighbor(hash, -1, 0);
+        assert_eq!(neighbor(east, -1, 0), hash);
+        assert_eq!(neighbor(west, 1, 0), hash);
+        assert_eq!(neighbor(neighbor(hash, 0, 1), 0, -1), hash);
+    }
+
+    #[test]
+    fn test_search() {
+        let mut set = SortedSet::default();
+        set.insert("Palermo".to_string(), 3479099956230698.0);
+        set.insert("Catania".to_string(), 3479447370796909.0);
+        set.insert("edge1".to_string(), score(12.758489, 38.788135));
+        set.insert("edge2".to_string(), score(17.241510, 38.788135));
+        let shape = Shape {
+            longitude: 15.0,
+            latitude: 37.0,
+            conversion: 1000.0,
+            kind: ShapeKind::Radius(200.0),
+        };
+        let mut members = search(&set, &shape, 0)
+            .into_iter()
+            .map(|p| p.member)
+            .collect::<Vec<_>>();
+        members.sort();
+        assert_eq!(members, ["Catania", "Palermo"]);
+
+        let shape = Shape {
+            kind: ShapeKind::Box {
+                width: 400.0,
+                height: 400.0,
+            },
+            ..shape
+        };
+        assert_eq!(search(&set, &shape, 0).len(), 4);
+    }
+}
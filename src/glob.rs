@@ -0,0 +1,172 @@
+// Glob-style pattern matching used by KEYS, SCAN and FUNCTION LIST, following Redis' stringmatchlen:
+// `*` matches any sequence, `?` any character, `[abc]`, `[a-z]` and `[^x]` character classes,
+// and `\` escapes the next character.
+use redis_starter_rust::string_to_bytes;
+
+// Patterns nesting more `*` than this never match, to protect against abusive patterns
+const MAX_NESTING: usize = 1000;
+
+pub fn matches(pattern: &str, string: &str, nocase: bool) -> bool {
+    let pattern = string_to_bytes(pattern);
+    let string = string_to_bytes(string);
+    let mut skip_longer_matches = false;
+    match_bytes(&pattern, &string, nocase, &mut skip_longer_matches, 0)
+}
+
+fn eq(a: u8, b: u8, nocase: bool) -> bool {
+    if nocase {
+        a.eq_ignore_ascii_case(&b)
+    } else {
+        a == b
+    }
+}
+
+fn match_bytes(
+    mut pattern: &[u8],
+    mut string: &[u8],
+    nocase: bool,
+    skip_longer_matches: &mut bool,
+    nesting: usize,
+) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+
+    while !pattern.is_empty() && !string.is_empty() {
+        match pattern[0] {
+            b'*' => {
+                while pattern.get(1) == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {
+                    return true;
+                }
+                while !string.is_empty() {
+                    if match_bytes(
+                        &pattern[1..],
+                        string,
+                        nocase,
+                        skip_longer_matches,
+                        nesting + 1,
+                    ) {
+                        return true;
+                    }
+                    if *skip_longer_matches {
+                        return false;
+                    }
+                    string = &string[1..];
+                }
+                // The rest of the pattern doesn't match anywhere in the rest of the string, so
+                // matching earlier stars against longer substrings can't help either
+                *skip_longer_matches = true;
+                return false;
+            }
+            b'?' => string = &string[1..],
+            b'[' => {
+                pattern = &pattern[1..];
+                let negate = pattern.first() == Some(&b'^');
+                if negate {
+                    pattern = &pattern[1..];
+                }
+                let c = string[0];
+                let mut matched = false;
+                loop {
+                    match pattern {
+                        [b'\\', escaped, ..] => {
+                            pattern = &pattern[1..];
+                            matched |= *escaped == c;
+                        }
+                        [b']', ..] => break,
+                        // Unterminated class, the closing bracket is implied
+                        [] => break,
+                        [start, b'-', end, ..] => {
+                            let (mut start, mut end) = (*start.min(end), *start.max(end));
+                            let mut c = c;
+                            if nocase {
+                                start = start.to_ascii_lowercase();
+                                end = end.to_ascii_lowercase();
+                                c = c.to_ascii_lowercase();
+                            }
+                            pattern = &pattern[2..];
+                            matched |= (start..=end).contains(&c);
+                        }
+                        [p, ..] => matched |= eq(*p, c, nocase),
+                    }
+                    pattern = &pattern[1..];
+                }
+                if matched == negate {
+                    return false;
+                }
+                string = &string[1..];
+                if pattern.is_empty() {
+                    // Skip the implied closing bracket
+                    continue;
+                }
+            }
+            _ => {
+                if pattern[0] == b'\\' && pattern.len() >= 2 {
+                    pattern = &pattern[1..];
+                }
+                if !eq(pattern[0], string[0], nocase) {
+                    return false;
+                }
+                string = &string[1..];
+            }
+        }
+        pattern = &pattern[1..];
+        if string.is_empty() {
+            while pattern.first() == Some(&b'*') {
+                pattern = &pattern[1..];
+            }
+            break;
+        }
+    }
+    pattern.is_empty() && string.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("my*", "mylib", false));
+        assert!(matches("*lib", "mylib", false));
+        assert!(matches("m?lib", "mylib", false));
+        assert!(!matches("other*", "mylib", false));
+        // Like Redis, nothing matches the empty string
+        assert!(!matches("*", "", false));
+        assert!(matches("a**b*", "ab", false));
+        assert!(!matches("?", "", false));
+        assert!(!matches("a*b", "a", false));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("h[ae]llo", "hello", false));
+        assert!(!matches("h[ae]llo", "hillo", false));
+        assert!(matches("h[^e]llo", "hallo", false));
+        assert!(!matches("h[^e]llo", "hello", false));
+        assert!(matches("h[a-b]llo", "hbllo", false));
+        assert!(matches("h[b-a]llo", "hbllo", false));
+        assert!(!matches("h[a-b]llo", "hcllo", false));
+        assert!(matches("[\\]]", "]", false));
+        // An unterminated class ends the pattern
+        assert!(matches("a[bc", "ab", false));
+    }
+
+    #[test]
+    fn test_escapes_and_case() {
+        assert!(matches("a\\*", "a*", false));
+        assert!(!matches("a\\*", "ab", false));
+        assert!(matches("HELLO", "hello", true));
+        assert!(!matches("HELLO", "hello", false));
+        assert!(matches("[A-C]x", "bx", true));
+    }
+
+    #[test]
+    fn test_abusive_pattern() {
+        let pattern = "a*".repeat(2000) + "b";
+        assert!(!matches(&pattern, &"a".repeat(100), false));
+    }
+}
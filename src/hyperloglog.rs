@@ -0,0 +1,673 @@
+// HyperLogLog cardinality estimation, stored in string values using the same
+// sparse and dense encodings as Redis so that payloads are interchangeable.
+//
+// Layout: "HYLL" magic, 1 byte encoding, 3 unused bytes, 8 byte little endian
+// cached cardinality (most significant bit set when the cache is stale), then
+// the registers.
+use crate::dict::Keyspace;
+use crate::get_live_entry;
+use crate::strings::set_string;
+use crate::value::Value;
+use crate::write_table;
+use crate::Table;
+use redis_starter_rust::bytes_to_string;
+use redis_starter_rust::string_to_bytes;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_P_MASK: u64 = HLL_REGISTERS as u64 - 1;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const HLL_DENSE: u8 = 0;
+const HLL_SPARSE: u8 = 1;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const HLL_SEED: u64 = 0xadc8_3b19;
+
+// Same as Redis' default hll-sparse-max-bytes
+const HLL_SPARSE_MAX_BYTES: usize = 3000;
+
+// Sparse opcodes: ZERO 00xxxxxx, XZERO 01xxxxxx yyyyyyyy, VAL 1vvvvvxx
+const HLL_SPARSE_XZERO_BIT: u8 = 0x40;
+const HLL_SPARSE_VAL_BIT: u8 = 0x80;
+const HLL_SPARSE_VAL_MAX_VALUE: u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN: usize = 4;
+const HLL_SPARSE_ZERO_MAX_LEN: usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+#[derive(Debug, PartialEq)]
+pub enum HllError {
+    // Not a HyperLogLog at all
+    WrongType,
+    // Looks like a HyperLogLog but the registers can't be decoded
+    Corrupted,
+}
+
+impl HllError {
+    fn reply(&self) -> RESPValue {
+        RESPValue::error(match self {
+            HllError::WrongType => {
+                "WRONGTYPE Key is not a valid HyperLogLog string value.".to_string()
+            }
+            HllError::Corrupted => "INVALIDOBJ Corrupted HLL object detected".to_string(),
+        })
+    }
+}
+
+type HllResult<T> = Result<T, HllError>;
+
+pub fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes([
+            chunk[0], chunk[1], chunk[2], chunk[3], chunk[4], chunk[5], chunk[6], chunk[7],
+        ]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// Returns the register an element maps to and the length of the "000..1" pattern of its hash
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, HLL_SEED);
+    let index = (hash & HLL_P_MASK) as usize;
+    // Setting bit Q makes sure the count is at most Q + 1
+    let hash = (hash >> HLL_P) | (1 << HLL_Q);
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(registers: &[u8], index: usize) -> u8 {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let b0 = registers[byte] as u16;
+    let b1 = registers.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> fb) | (b1 << (8 - fb))) & HLL_REGISTER_MAX as u16) as u8
+}
+
+fn dense_set(registers: &mut [u8], index: usize, value: u8) {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let value = value as u16;
+    registers[byte] &= !((HLL_REGISTER_MAX as u16) << fb) as u8;
+    registers[byte] |= (value << fb) as u8;
+    if let Some(next) = registers.get_mut(byte + 1) {
+        *next &= !((HLL_REGISTER_MAX as u16) >> (8 - fb)) as u8;
+        *next |= (value >> (8 - fb)) as u8;
+    }
+}
+
+fn is_zero(op: u8) -> bool {
+    op & 0xc0 == 0
+}
+
+fn is_xzero(op: u8) -> bool {
+    op & 0xc0 == HLL_SPARSE_XZERO_BIT
+}
+
+fn is_val(op: u8) -> bool {
+    op & HLL_SPARSE_VAL_BIT != 0
+}
+
+fn zero_len(op: u8) -> usize {
+    (op & 0x3f) as usize + 1
+}
+
+fn xzero_len(op: u8, next: u8) -> usize {
+    ((((op & 0x3f) as usize) << 8) | next as usize) + 1
+}
+
+fn val_value(op: u8) -> u8 {
+    ((op >> 2) & 0x1f) + 1
+}
+
+fn val_len(op: u8) -> usize {
+    (op & 0x3) as usize + 1
+}
+
+fn val_op(value: u8, len: usize) -> u8 {
+    (((value - 1) << 2) | (len as u8 - 1)) | HLL_SPARSE_VAL_BIT
+}
+
+fn push_zeros(seq: &mut Vec<u8>, len: usize) {
+    if len > HLL_SPARSE_ZERO_MAX_LEN {
+        let len = len - 1;
+        seq.push((len >> 8) as u8 | HLL_SPARSE_XZERO_BIT);
+        seq.push((len & 0xff) as u8);
+    } else {
+        seq.push(len as u8 - 1);
+    }
+}
+
+// Iterates over the sparse opcodes as (position, opcode length in bytes, registers covered)
+fn sparse_ops(sparse: &[u8]) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+    let mut p = 0;
+    std::iter::from_fn(move || {
+        let op = *sparse.get(p)?;
+        let (oplen, span) = if is_zero(op) {
+            (1, zero_len(op))
+        } else if is_xzero(op) {
+            (2, xzero_len(op, *sparse.get(p + 1)?))
+        } else {
+            (1, val_len(op))
+        };
+        let item = (p, oplen, span);
+        p += oplen;
+        Some(item)
+    })
+}
+
+pub fn new_sparse() -> Vec<u8> {
+    let mut hll = vec![0; HLL_HDR_SIZE];
+    hll[..4].copy_from_slice(b"HYLL");
+    hll[4] = HLL_SPARSE;
+    let mut remaining = HLL_REGISTERS;
+    while remaining > 0 {
+        let len = remaining.min(HLL_SPARSE_XZERO_MAX_LEN);
+        push_zeros(&mut hll, len);
+        remaining -= len;
+    }
+    hll
+}
+
+pub fn validate(hll: &[u8]) -> HllResult<()> {
+    if hll.len() < HLL_HDR_SIZE
+        || &hll[..4] != b"HYLL"
+        || hll[4] > HLL_SPARSE
+        || (hll[4] == HLL_DENSE && hll.len() != HLL_DENSE_SIZE)
+    {
+        return Err(HllError::WrongType);
+    }
+    Ok(())
+}
+
+fn invalidate_cache(hll: &mut [u8]) {
+    hll[15] |= 1 << 7;
+}
+
+fn cached_cardinality(hll: &[u8]) -> Option<u64> {
+    if hll[15] & (1 << 7) != 0 {
+        return None;
+    }
+    let mut card = [0; 8];
+    card.copy_from_slice(&hll[8..16]);
+    Some(u64::from_le_bytes(card))
+}
+
+fn sparse_to_dense(hll: &mut Vec<u8>) -> HllResult<()> {
+    if hll[4] == HLL_DENSE {
+        return Ok(());
+    }
+    let mut dense = vec![0; HLL_DENSE_SIZE];
+    // Keeps the magic and the cached cardinality
+    dense[..HLL_HDR_SIZE].copy_from_slice(&hll[..HLL_HDR_SIZE]);
+    dense[4] = HLL_DENSE;
+
+    let sparse = &hll[HLL_HDR_SIZE..];
+    let mut index = 0;
+    for (p, _, span) in sparse_ops(sparse) {
+        if is_val(sparse[p]) {
+            if index + span > HLL_REGISTERS {
+                break;
+            }
+            for i in index..index + span {
+                dense_set(&mut dense[HLL_HDR_SIZE..], i, val_value(sparse[p]));
+            }
+        }
+        index += span;
+    }
+    if index != HLL_REGISTERS {
+        return Err(HllError::Corrupted);
+    }
+    *hll = dense;
+    Ok(())
+}
+
+fn dense_update(hll: &mut [u8], index: usize, count: u8) -> bool {
+    let registers = &mut hll[HLL_HDR_SIZE..];
+    if count > dense_get(registers, index) {
+        dense_set(registers, index, count);
+        true
+    } else {
+        false
+    }
+}
+
+// Sets register `index` to `count` if it's greater than the current value, splitting and
+// merging opcodes the same way Redis does. Promotes the representation to dense if the
+// value doesn't fit a VAL opcode or the sparse representation grows too big.
+fn sparse_update(hll: &mut Vec<u8>, index: usize, count: u8) -> HllResult<bool> {
+    if count > HLL_SPARSE_VAL_MAX_VALUE {
+        return promote_and_update(hll, index, count);
+    }
+
+    // Step 1: find the opcode covering the register
+    let mut first = 0;
+    let mut prev = None;
+    let mut found = None;
+    for (p, oplen, span) in sparse_ops(&hll[HLL_HDR_SIZE..]) {
+        if index < first + span {
+            found = Some((HLL_HDR_SIZE + p, oplen, span));
+            break;
+        }
+        prev = Some(HLL_HDR_SIZE + p);
+        first += span;
+    }
+    let (p, oplen, span) = found.ok_or(HllError::Corrupted)?;
+    let op = hll[p];
+
+    // Step 2: update in place when possible, otherwise build the replacement sequence
+    let mut updated_in_place = false;
+    if is_val(op) {
+        if val_value(op) >= count {
+            return Ok(false);
+        }
+        if span == 1 {
+            hll[p] = val_op(count, 1);
+            updated_in_place = true;
+        }
+    } else if is_zero(op) && span == 1 {
+        hll[p] = val_op(count, 1);
+        updated_in_place = true;
+    }
+
+    if !updated_in_place {
+        let last = first + span - 1;
+        let mut seq = Vec::with_capacity(5);
+        if is_val(op) {
+            let current = val_value(op);
+            if index != first {
+                seq.push(val_op(current, index - first));
+            }
+            seq.push(val_op(count, 1));
+            if index != last {
+                seq.push(val_op(current, last - index));
+            }
+        } else {
+            if index != first {
+                push_zeros(&mut seq, index - first);
+            }
+            seq.push(val_op(count, 1));
+            if index != last {
+                push_zeros(&mut seq, last - index);
+            }
+        }
+
+        // Step 3: substitute the old opcode
+        if seq.len() > oplen && hll.len() + seq.len() - oplen > HLL_SPARSE_MAX_BYTES {
+            return promote_and_update(hll, index, count);
+        }
+        hll.splice(p..p + oplen, seq);
+    }
+
+    // Step 4: merge adjacent VAL opcodes with the same value, scanning up to 5 opcodes from `prev`
+    let mut p = prev.unwrap_or(HLL_HDR_SIZE);
+    let mut scan = 5;
+    while p < hll.len() && scan > 0 {
+        scan -= 1;
+        let op = hll[p];
+        if is_xzero(op) {
+            p += 2;
+            continue;
+        }
+        if is_zero(op) {
+            p += 1;
+            continue;
+        }
+        if let Some(&next) = hll.get(p + 1) {
+            if is_val(next) && val_value(op) == val_value(next) {
+                let len = val_len(op) + val_len(next);
+                if len <= HLL_SPARSE_VAL_MAX_LEN {
+                    hll[p + 1] = val_op(val_value(op), len);
+                    hll.remove(p);
+                    // Try to merge the merged opcode with the one on its right
+                    continue;
+                }
+            }
+        }
+        p += 1;
+    }
+
+    invalidate_cache(hll);
+    Ok(true)
+}
+
+fn promote_and_update(hll: &mut Vec<u8>, index: usize, count: u8) -> HllResult<bool> {
+    sparse_to_dense(hll)?;
+    Ok(dense_update(hll, index, count))
+}
+
+fn set_register(hll: &mut Vec<u8>, index: usize, count: u8) -> HllResult<bool> {
+    match hll[4] {
+        HLL_DENSE => Ok(dense_update(hll, index, count)),
+        _ => sparse_update(hll, index, count),
+    }
+}
+
+pub fn add(hll: &mut Vec<u8>, element: &[u8]) -> HllResult<bool> {
+    let (index, count) = pattern_len(element);
+    set_register(hll, index, count)
+}
+
+// Folds the registers of `hll` into `max`, keeping the largest value of each register
+pub fn merge_into(max: &mut [u8], hll: &[u8]) -> HllResult<()> {
+    let registers = &hll[HLL_HDR_SIZE..];
+    if hll[4] == HLL_DENSE {
+        for (i, m) in max.iter_mut().enumerate() {
+            *m = (*m).max(dense_get(registers, i));
+        }
+        return Ok(());
+    }
+
+    let mut index = 0;
+    for (p, _, span) in sparse_ops(registers) {
+        if index + span > HLL_REGISTERS {
+            return Err(HllError::Corrupted);
+        }
+        if is_val(registers[p]) {
+            let value = val_value(registers[p]);
+            for m in &mut max[index..index + span] {
+                *m = (*m).max(value);
+            }
+        }
+        index += span;
+    }
+    if index != HLL_REGISTERS {
+        return Err(HllError::Corrupted);
+    }
+    Ok(())
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+// Estimates the cardinality from the register histogram, see "New cardinality estimation
+// algorithms for HyperLogLog sketches" by Otmar Ertl
+fn estimate(histogram: &[u32; 64]) -> u64 {
+    let m = HLL_REGISTERS as f64;
+    let q = HLL_Q as usize;
+    let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+    for j in (1..=q).rev() {
+        z += histogram[j] as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (HLL_ALPHA_INF * m * m / z).round() as u64
+}
+
+fn registers_estimate(registers: &[u8]) -> u64 {
+    let mut histogram = [0u32; 64];
+    for &r in registers {
+        histogram[r as usize] += 1;
+    }
+    estimate(&histogram)
+}
+
+pub fn count(hll: &[u8]) -> HllResult<u64> {
+    let mut registers = vec![0; HLL_REGISTERS];
+    merge_into(&mut registers, hll)?;
+    Ok(registers_estimate(&registers))
+}
+
+fn get_hll(t: &mut Keyspace, key: &str) -> HllResult<Option<Vec<u8>>> {
+    match get_live_entry(t, key) {
+        Some((value, _, _)) => {
+            let hll = string_to_bytes(&value.to_string());
+            validate(&hll)?;
+            Ok(Some(hll))
+        }
+        None => Ok(None),
+    }
+}
+
+// Stores the HyperLogLog, keeping the expiry of the key if it exists
+fn put_hll(t: &mut Keyspace, key: &str, hll: &[u8]) {
+    match get_live_entry(t, key) {
+        Some((value, _, _)) => *value = Value::String(bytes_to_string(hll)),
+        None => set_string(t, key, bytes_to_string(hll), None),
+    }
+}
+
+// Raises the registers of `hll` to the values in `max`
+fn set_registers(hll: &mut Vec<u8>, max: &[u8], use_dense: bool) -> HllResult<()> {
+    if use_dense {
+        sparse_to_dense(hll)?;
+    }
+    for (i, &value) in max.iter().enumerate() {
+        if value != 0 {
+            set_register(hll, i, value)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn pfadd(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("pfadd", args);
+    let (key, elements) = match args.next_key().and_then(|key| Ok((key, args.rest()?))) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let (mut hll, mut updated) = match get_hll(&mut t, key) {
+        Ok(Some(hll)) => (hll, false),
+        // Creating the key counts as an update even without elements
+        Ok(None) => (new_sparse(), true),
+        Err(e) => return Ok(e.reply()),
+    };
+    for element in elements {
+        match add(&mut hll, &string_to_bytes(element)) {
+            Ok(changed) => updated |= changed,
+            Err(e) => return Ok(e.reply()),
+        }
+    }
+    if updated {
+        invalidate_cache(&mut hll);
+        put_hll(&mut t, key, &hll);
+    }
+    Ok(RESPValue::integer(updated as i64))
+}
+
+pub fn pfcount(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let keys = match Args::new("pfcount", args).rest_non_empty() {
+        Ok(keys) => keys,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    match keys[..] {
+        [key] => {
+            let mut hll = match get_hll(&mut t, key) {
+                Ok(Some(hll)) => hll,
+                Ok(None) => return Ok(RESPValue::integer(0)),
+                Err(e) => return Ok(e.reply()),
+            };
+            if let Some(card) = cached_cardinality(&hll) {
+                return Ok(RESPValue::integer(card as i64));
+            }
+            let card = match count(&hll) {
+                Ok(card) => card,
+                Err(e) => return Ok(e.reply()),
+            };
+            hll[8..16].copy_from_slice(&card.to_le_bytes());
+            put_hll(&mut t, key, &hll);
+            Ok(RESPValue::integer(card as i64))
+        }
+        _ => {
+            // The union of several keys is computed on the fly and never cached
+            let mut max = vec![0; HLL_REGISTERS];
+            for key in keys {
+                let merged = get_hll(&mut t, key)
+                    .and_then(|hll| hll.map_or(Ok(()), |hll| merge_into(&mut max, &hll)));
+                if let Err(e) = merged {
+                    return Ok(e.reply());
+                }
+            }
+            Ok(RESPValue::integer(registers_estimate(&max) as i64))
+        }
+    }
+}
+
+pub fn pfmerge(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let keys = match Args::new("pfmerge", args).rest_non_empty() {
+        Ok(keys) => keys,
+        Err(e) => return Ok(e.into()),
+    };
+    let dest = keys[0];
+    let mut t = write_table(&table)?;
+    let mut max = vec![0; HLL_REGISTERS];
+    let mut use_dense = false;
+    // The destination is one of the sources
+    for key in &keys {
+        let hll = match get_hll(&mut t, key) {
+            Ok(Some(hll)) => hll,
+            Ok(None) => continue,
+            Err(e) => return Ok(e.reply()),
+        };
+        use_dense |= hll[4] == HLL_DENSE;
+        if let Err(e) = merge_into(&mut max, &hll) {
+            return Ok(e.reply());
+        }
+    }
+
+    let mut hll = match get_hll(&mut t, dest) {
+        Ok(Some(hll)) => hll,
+        _ => new_sparse(),
+    };
+    if let Err(e) = set_registers(&mut hll, &max, use_dense) {
+        return Ok(e.reply());
+    }
+    invalidate_cache(&mut hll);
+    put_hll(&mut t, dest, &hll);
+    Ok(RESPValue::simple_string("OK".to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_new_sparse() {
+        let hll = new_sparse();
+        assert_eq!(&hll[..5], b"HYLL\x01");
+        assert_eq!(&hll[HLL_HDR_SIZE..], &[0x7f, 0xff]);
+        assert_eq!(count(&hll), Ok(0));
+    }
+
+    #[test]
+    fn test_dense_registers() {
+        let mut registers = vec![0; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+        for i in [0, 1, 2, 3, 100, HLL_REGISTERS - 1] {
+            dense_set(&mut registers, i, (i % 64) as u8);
+        }
+        for i in [0, 1, 2, 3, 100, HLL_REGISTERS - 1] {
+            assert_eq!(dense_get(&registers, i), (i % 64) as u8);
+        }
+        assert_eq!(dense_get(&registers, 4), 0);
+    }
+
+    #[test]
+    fn test_sparse_update_splits_and_merges() {
+        let mut hll = new_sparse();
+        assert_eq!(sparse_update(&mut hll, 5, 3), Ok(true));
+        // ZERO(5) VAL(3,1) XZERO(16378)
+        assert_eq!(&hll[HLL_HDR_SIZE..], &[0x04, 0x88, 0x7f, 0xf9]);
+        assert_eq!(sparse_update(&mut hll, 5, 2), Ok(false));
+        assert_eq!(sparse_update(&mut hll, 6, 3), Ok(true));
+        // The two VAL(3,1) opcodes are merged into VAL(3,2)
+        assert_eq!(&hll[HLL_HDR_SIZE..], &[0x04, 0x89, 0x7f, 0xf8]);
+    }
+
+    #[test]
+    fn test_sparse_and_dense_agree() {
+        let mut sparse = new_sparse();
+        let mut dense = new_sparse();
+        sparse_to_dense(&mut dense).unwrap();
+        for i in 0..1000 {
+            let element = format!("element:{}", i);
+            add(&mut sparse, element.as_bytes()).unwrap();
+            add(&mut dense, element.as_bytes()).unwrap();
+        }
+        assert_eq!(sparse[4], HLL_SPARSE);
+        assert_eq!(count(&sparse), count(&dense));
+
+        // Converting keeps every register
+        sparse_to_dense(&mut sparse).unwrap();
+        assert_eq!(&sparse[HLL_HDR_SIZE..], &dense[HLL_HDR_SIZE..]);
+    }
+
+    #[test]
+    fn test_estimate_accuracy() {
+        let mut hll = new_sparse();
+        for i in 0..100_000 {
+            add(&mut hll, format!("{}", i).as_bytes()).unwrap();
+        }
+        // Large enough to have been promoted
+        assert_eq!(hll[4], HLL_DENSE);
+        let estimate = count(&hll).unwrap() as f64;
+        assert!((estimate - 100_000.0).abs() / 100_000.0 < 0.02);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(b"hello"), Err(HllError::WrongType));
+        assert_eq!(validate(&new_sparse()), Ok(()));
+        let mut truncated = new_sparse();
+        sparse_to_dense(&mut truncated).unwrap();
+        truncated.pop();
+        assert_eq!(validate(&truncated), Err(HllError::WrongType));
+    }
+}
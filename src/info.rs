@@ -0,0 +1,321 @@
+// Server statistics and the INFO command
+use crate::memory;
+use crate::random_u64;
+use crate::Databases;
+use crate::Server;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Version of Redis whose behavior the server follows, matching the RDB version of DUMP payloads
+pub const REDIS_VERSION: &str = "7.2.0";
+// Same as Redis' STATS_METRIC_SAMPLES
+const OPS_SAMPLES: usize = 16;
+
+const DEFAULT_SECTIONS: &[&str] = &[
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "cluster",
+    "keyspace",
+];
+
+// Sentinels have no keys to report on, and report on the masters they monitor instead
+const SENTINEL_SECTIONS: &[&str] = &["server", "clients", "stats", "sentinel"];
+
+// Commands per second measured over the last few periods, like Redis' instantaneous metrics
+struct OpsSamples {
+    last: Option<(Instant, u64)>,
+    samples: [u64; OPS_SAMPLES],
+    index: usize,
+}
+
+// Counters reported by INFO. They're global so that lookups deep inside the commands can count
+// hits, misses and expirations.
+pub struct Stats {
+    pub connected_clients: AtomicU64,
+    pub total_connections_received: AtomicU64,
+    pub total_commands_processed: AtomicU64,
+    pub keyspace_hits: AtomicU64,
+    pub keyspace_misses: AtomicU64,
+    pub expired_keys: AtomicU64,
+    pub evicted_keys: AtomicU64,
+    ops: Mutex<OpsSamples>,
+}
+
+pub static STATS: Stats = Stats {
+    connected_clients: AtomicU64::new(0),
+    total_connections_received: AtomicU64::new(0),
+    total_commands_processed: AtomicU64::new(0),
+    keyspace_hits: AtomicU64::new(0),
+    keyspace_misses: AtomicU64::new(0),
+    expired_keys: AtomicU64::new(0),
+    evicted_keys: AtomicU64::new(0),
+    ops: Mutex::new(OpsSamples {
+        last: None,
+        samples: [0; OPS_SAMPLES],
+        index: 0,
+    }),
+};
+
+impl Stats {
+    // Records the rate of commands since the previous call, meant to be called periodically
+    pub fn sample_ops(&self) {
+        let commands = self.total_commands_processed.load(Relaxed);
+        let now = Instant::now();
+        let mut ops = match self.ops.lock() {
+            Ok(ops) => ops,
+            Err(_) => return,
+        };
+        if let Some((last_time, last_commands)) = ops.last {
+            let millis = now.duration_since(last_time).as_millis().max(1) as u64;
+            let index = ops.index;
+            ops.samples[index] = commands.saturating_sub(last_commands) * 1000 / millis;
+            ops.index = (index + 1) % OPS_SAMPLES;
+        }
+        ops.last = Some((now, commands));
+    }
+
+    // Clears the counters for CONFIG RESETSTAT, connected_clients is a gauge and stays
+    pub fn reset(&self) {
+        for counter in [
+            &self.total_connections_received,
+            &self.total_commands_processed,
+            &self.keyspace_hits,
+            &self.keyspace_misses,
+            &self.expired_keys,
+            &self.evicted_keys,
+        ] {
+            counter.store(0, Relaxed);
+        }
+        if let Ok(mut ops) = self.ops.lock() {
+            ops.samples = [0; OPS_SAMPLES];
+        }
+    }
+
+    fn instantaneous_ops_per_sec(&self) -> u64 {
+        self.ops.lock().map_or(0, |ops| {
+            ops.samples.iter().sum::<u64>() / OPS_SAMPLES as u64
+        })
+    }
+}
+
+// Identifies this run of the server, as 40 hex characters
+pub fn new_run_id() -> String {
+    (0..3)
+        .map(|_| format!("{:016x}", random_u64()))
+        .collect::<String>()[..40]
+        .to_string()
+}
+
+// Formats a number of bytes like Redis' bytesToHuman
+fn bytes_to_human(n: usize) -> String {
+    const UNITS: &[(f64, &str)] = &[
+        (1024.0 * 1024.0 * 1024.0 * 1024.0, "T"),
+        (1024.0 * 1024.0 * 1024.0, "G"),
+        (1024.0 * 1024.0, "M"),
+        (1024.0, "K"),
+    ];
+    UNITS
+        .iter()
+        .find(|(size, _)| n as f64 >= *size)
+        .map_or(format!("{}B", n), |(size, unit)| {
+            format!("{:.2}{}", n as f64 / size, unit)
+        })
+}
+
+pub fn info(
+    args: &[BulkString],
+    databases: &Databases,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let requested = match Args::new("info", args).rest() {
+        Ok(requested) => requested,
+        Err(e) => return Ok(e.into()),
+    };
+    let requested: Vec<String> = requested.iter().map(|s| s.to_lowercase()).collect();
+    let available = if server.sentinel.is_enabled()? {
+        SENTINEL_SECTIONS
+    } else {
+        DEFAULT_SECTIONS
+    };
+    let sections: Vec<&str> = if requested.is_empty()
+        || requested
+            .iter()
+            .any(|s| ["default", "all", "everything"].contains(&s.as_str()))
+    {
+        available.to_vec()
+    } else {
+        available
+            .iter()
+            .copied()
+            .filter(|section| requested.iter().any(|s| s == section))
+            .collect()
+    };
+
+    let mut out = String::new();
+    for section in sections {
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        let _ = write!(
+            out,
+            "# {}{}\r\n",
+            section[..1].to_uppercase(),
+            &section[1..]
+        );
+        if section == "keyspace" {
+            for line in keyspace_lines(databases)? {
+                let _ = write!(out, "{}\r\n", line);
+            }
+            continue;
+        }
+        if section == "sentinel" {
+            for line in server.sentinel.info_lines()? {
+                let _ = write!(out, "{}\r\n", line);
+            }
+            continue;
+        }
+        if section == "replication" {
+            let priority = server.config()?.replica_priority;
+            for line in server.replication.info_lines(&server.run_id, priority)? {
+                let _ = write!(out, "{}\r\n", line);
+            }
+            continue;
+        }
+        let fields = match section {
+            "server" => server_section(server)?,
+            "clients" => vec![
+                (
+                    "connected_clients",
+                    STATS.connected_clients.load(Relaxed).to_string(),
+                ),
+                ("blocked_clients", "0".to_string()),
+            ],
+            "memory" => memory_section(server)?,
+            "persistence" => server.persistence.info_fields(),
+            "stats" => stats_section(),
+            "cluster" => vec![(
+                "cluster_enabled",
+                (server.cluster.state()?.is_some() as u8).to_string(),
+            )],
+            _ => vec![],
+        };
+        for (name, value) in fields {
+            let _ = write!(out, "{}:{}\r\n", name, value);
+        }
+    }
+    Ok(RESPValue::bulk_string(Some(out)))
+}
+
+fn server_section(server: &Server) -> Result<Vec<(&'static str, String)>, String> {
+    let uptime = server.started.elapsed().as_secs();
+    let mode = if server.sentinel.is_enabled()? {
+        "sentinel"
+    } else if server.cluster.state()?.is_some() {
+        "cluster"
+    } else {
+        "standalone"
+    };
+    Ok(vec![
+        ("redis_version", REDIS_VERSION.to_string()),
+        ("redis_mode", mode.to_string()),
+        ("os", std::env::consts::OS.to_string()),
+        ("arch_bits", (usize::BITS).to_string()),
+        ("process_id", std::process::id().to_string()),
+        ("run_id", server.run_id.clone()),
+        ("tcp_port", server.config()?.port.to_string()),
+        ("uptime_in_seconds", uptime.to_string()),
+        ("uptime_in_days", (uptime / 86400).to_string()),
+    ])
+}
+
+fn memory_section(server: &Server) -> Result<Vec<(&'static str, String)>, String> {
+    let used = memory::used_memory();
+    let settings = server.config()?.memory;
+    Ok(vec![
+        ("used_memory", used.to_string()),
+        ("used_memory_human", bytes_to_human(used)),
+        ("maxmemory", settings.maxmemory.to_string()),
+        ("maxmemory_human", bytes_to_human(settings.maxmemory)),
+        ("maxmemory_policy", settings.policy.name().to_string()),
+    ])
+}
+
+fn stats_section() -> Vec<(&'static str, String)> {
+    let counter = |c: &AtomicU64| c.load(Relaxed).to_string();
+    vec![
+        (
+            "total_connections_received",
+            counter(&STATS.total_connections_received),
+        ),
+        (
+            "total_commands_processed",
+            counter(&STATS.total_commands_processed),
+        ),
+        (
+            "instantaneous_ops_per_sec",
+            STATS.instantaneous_ops_per_sec().to_string(),
+        ),
+        ("expired_keys", counter(&STATS.expired_keys)),
+        ("evicted_keys", counter(&STATS.evicted_keys)),
+        ("keyspace_hits", counter(&STATS.keyspace_hits)),
+        ("keyspace_misses", counter(&STATS.keyspace_misses)),
+    ]
+}
+
+// Databases without keys are left out, like Redis does
+fn keyspace_lines(databases: &Databases) -> Result<Vec<String>, String> {
+    let mut lines = vec![];
+    for (i, table) in databases.iter().enumerate() {
+        let t = table
+            .read()
+            .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+        if t.is_empty() {
+            continue;
+        }
+        let ttls: Vec<u128> = t
+            .values()
+            .filter_map(|(_, expiry, _)| *expiry)
+            .map(|(t_insert, duration)| duration.saturating_sub(t_insert.elapsed()).as_millis())
+            .collect();
+        let avg_ttl = ttls.iter().sum::<u128>() / ttls.len().max(1) as u128;
+        lines.push(format!(
+            "db{}:keys={},expires={},avg_ttl={}",
+            i,
+            t.len(),
+            ttls.len(),
+            avg_ttl
+        ));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bytes_to_human() {
+        assert_eq!(bytes_to_human(0), "0B");
+        assert_eq!(bytes_to_human(1023), "1023B");
+        assert_eq!(bytes_to_human(1024), "1.00K");
+        assert_eq!(bytes_to_human(1536 * 1024), "1.50M");
+        assert_eq!(bytes_to_human(3 * 1024 * 1024 * 1024), "3.00G");
+    }
+
+    #[test]
+    fn test_run_id() {
+        let id = new_run_id();
+        assert_eq!(id.len(), 40);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
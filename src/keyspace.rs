@@ -0,0 +1,636 @@
+// Commands operating on keys regardless of the type of their values
+use crate::databases;
+use crate::dict::Keyspace;
+use crate::get_live_entry;
+use crate::glob;
+use crate::info::STATS;
+use crate::memory::Access;
+use crate::not_an_integer;
+use crate::notify;
+use crate::notify::Event;
+use crate::rdb;
+use crate::strings::syntax_error;
+use crate::strings::wrong_number_of_arguments;
+use crate::value;
+use crate::value::Value;
+use crate::write_table;
+use crate::Databases;
+use crate::Entry;
+use crate::Session;
+use crate::Table;
+use redis_starter_rust::bytes_to_string;
+use redis_starter_rust::string_to_bytes;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::sync::atomic::Ordering::Relaxed;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const SCAN_DEFAULT_COUNT: usize = 10;
+// Same as Redis' LAZYFREE_THRESHOLD, values made of more allocations are freed in the background
+const LAZYFREE_THRESHOLD: usize = 64;
+const TYPE_NAMES: &[&str] = &["string", "list", "set", "zset", "hash", "stream"];
+// Same as Redis' ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+// Another round of sampling starts while more than this percentage of the keys had expired
+const ACTIVE_EXPIRE_STALE_PERCENT: usize = 10;
+// A quarter of the 100ms between cycles, like Redis' ACTIVE_EXPIRE_CYCLE_SLOW_TIME_PERC
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+pub fn is_expired((_, expiry, _): &Entry) -> bool {
+    expiry.is_some_and(|(t_insert, duration): (Instant, _)| t_insert.elapsed() > duration)
+}
+
+// Deletes the expired keys nobody looks up anymore, the way Redis' activeExpireCycle does: keys
+// with a TTL are sampled in every database until few of them turn out to be expired or the time
+// is up. Returns the number of deleted keys.
+pub fn active_expire_cycle(databases: &Databases) -> Result<usize, String> {
+    let started = Instant::now();
+    let mut deleted = 0;
+    for (db, table) in databases.iter().enumerate() {
+        loop {
+            let mut t = write_table(table)?;
+            let sampled = t.sample(ACTIVE_EXPIRE_KEYS_PER_LOOP, true);
+            let count = sampled.len();
+            let expired: Vec<String> = sampled
+                .into_iter()
+                .filter(|(_, entry)| is_expired(entry))
+                .map(|(key, _)| key.clone())
+                .collect();
+            let values = expired
+                .iter()
+                .filter_map(|key| t.remove(key))
+                .map(|(value, _, _)| value)
+                .collect();
+            free(values);
+            STATS.expired_keys.fetch_add(expired.len() as u64, Relaxed);
+            for key in &expired {
+                notify::raise(Event::new(notify::EXPIRED, "expired", key, Some(db)));
+            }
+            deleted += expired.len();
+            if expired.len() * 100 <= count * ACTIVE_EXPIRE_STALE_PERCENT
+                || started.elapsed() > ACTIVE_EXPIRE_TIME_LIMIT
+            {
+                break;
+            }
+        }
+        if started.elapsed() > ACTIVE_EXPIRE_TIME_LIMIT {
+            break;
+        }
+    }
+    Ok(deleted)
+}
+
+// Returns the keys of the buckets visited from `cursor` until at least `count` were found, and
+// the cursor to continue from (0 once every bucket has been visited). As in Redis, a call gives
+// up after visiting ten times `count` buckets so that a sparse table doesn't make it walk all of
+// them.
+fn scan_keys(t: &Keyspace, mut cursor: u64, count: usize) -> (Vec<&String>, u64) {
+    let mut keys = vec![];
+    let mut buckets = count.saturating_mul(10);
+    loop {
+        cursor = t.scan(cursor, |key, _| keys.push(key));
+        buckets -= 1;
+        if cursor == 0 || keys.len() >= count || buckets == 0 {
+            return (keys, cursor);
+        }
+    }
+}
+
+pub fn keys(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let pattern = match Args::new("keys", args).parse(Args::next_str) {
+        Ok(pattern) => pattern,
+        Err(e) => return Ok(e.into()),
+    };
+    let t = table
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+    Ok(RESPValue::Array(Some(
+        t.iter()
+            .filter(|(key, entry)| {
+                !is_expired(entry) && (pattern == "*" || glob::matches(pattern, key, false))
+            })
+            .map(|(key, _)| RESPValue::bulk_string(Some(key.clone())))
+            .collect(),
+    )))
+}
+
+pub fn scan(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("scan", args);
+    let (cursor, options) = match args.next_str().and_then(|c| Ok((c, args.rest()?))) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let cursor = match cursor.parse::<u64>() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(RESPValue::error("ERR invalid cursor".to_string())),
+    };
+    let mut count = SCAN_DEFAULT_COUNT;
+    let mut pattern = None;
+    let mut type_name = None;
+    for pair in options.chunks(2) {
+        match (pair[0].to_uppercase().as_str(), pair.get(1)) {
+            ("COUNT", Some(n)) => {
+                count = match value::parse_integer(n) {
+                    Some(n) if n < 1 => return Ok(syntax_error()),
+                    Some(n) => n as usize,
+                    None => return Ok(not_an_integer()),
+                }
+            }
+            // Everything matches `*`, skip matching altogether
+            ("MATCH", Some(p)) => pattern = Some(*p).filter(|p| *p != "*"),
+            ("TYPE", Some(t)) => {
+                let t = t.to_lowercase();
+                if !TYPE_NAMES.contains(&t.as_str()) {
+                    return Ok(RESPValue::error(format!("ERR unknown type name '{}'", t)));
+                }
+                type_name = Some(t);
+            }
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let t = table
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+    let (keys, next_cursor) = scan_keys(&t, cursor, count);
+    // Like Redis, filtering happens after the keys are picked, so fewer than COUNT may be returned
+    let keys = keys
+        .into_iter()
+        .filter(|key| {
+            let entry = &t[*key];
+            !is_expired(entry)
+                && pattern.is_none_or(|p| glob::matches(p, key, false))
+                && type_name
+                    .as_ref()
+                    .is_none_or(|name| entry.0.type_name() == name)
+        })
+        .map(|key| RESPValue::bulk_string(Some(key.clone())))
+        .collect();
+    Ok(RESPValue::Array(Some(vec![
+        RESPValue::bulk_string(Some(next_cursor.to_string())),
+        RESPValue::Array(Some(keys)),
+    ])))
+}
+
+pub fn key_type(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let key = match Args::new("type", args).parse(Args::next_key) {
+        Ok(key) => key,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let name = get_live_entry(&mut t, key).map_or("none", |(value, _, _)| value.type_name());
+    Ok(RESPValue::simple_string(name.to_string()))
+}
+
+pub fn randomkey(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    if !args.is_empty() {
+        return Ok(wrong_number_of_arguments("randomkey"));
+    }
+    let mut t = write_table(&table)?;
+    // Expired keys that get picked are removed and another key is tried
+    while let Some((key, entry)) = t.random() {
+        let key = key.clone();
+        if !is_expired(entry) {
+            return Ok(RESPValue::bulk_string(Some(key)));
+        }
+        t.remove(&key);
+        notify::raise(Event::new(notify::EXPIRED, "expired", &key, None));
+    }
+    Ok(RESPValue::bulk_string(None))
+}
+
+fn ok() -> RESPValue {
+    RESPValue::simple_string("OK".to_string())
+}
+
+// Handles RENAME and RENAMENX (`only_if_new` true). The key keeps its expiry.
+pub fn rename(args: &[BulkString], table: Table, only_if_new: bool) -> Result<RESPValue, String> {
+    let command = if only_if_new { "renamenx" } else { "rename" };
+    let parsed = Args::new(command, args).parse(|args| Ok((args.next_key()?, args.next_key()?)));
+    let (key, new_key) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    if get_live_entry(&mut t, key).is_none() {
+        return Ok(RESPValue::error("ERR no such key".to_string()));
+    }
+    if key == new_key {
+        return Ok(if only_if_new {
+            RESPValue::integer(0)
+        } else {
+            ok()
+        });
+    }
+    if only_if_new && get_live_entry(&mut t, new_key).is_some() {
+        return Ok(RESPValue::integer(0));
+    }
+    if let Some(entry) = t.remove(key) {
+        if let Some((old_value, _, _)) = t.insert(new_key.to_string(), entry) {
+            free(vec![old_value]);
+        }
+    }
+    Ok(if only_if_new {
+        RESPValue::integer(1)
+    } else {
+        ok()
+    })
+}
+
+pub fn copy(
+    args: &[BulkString],
+    databases: &Databases,
+    session: &Session,
+) -> Result<RESPValue, String> {
+    let mut args = Args::new("copy", args);
+    let parsed = args
+        .next_key()
+        .and_then(|source| Ok((source, args.next_key()?, args.rest()?)));
+    let (source, destination, options) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut db = session.db;
+    let mut replace = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.to_uppercase().as_str(), options.len()) {
+            ("REPLACE", _) => replace = true,
+            ("DB", n) if n >= 1 => {
+                let index = options.next().copied().unwrap_or_default();
+                db = match databases::parse_index(
+                    databases,
+                    index,
+                    "ERR value is not an integer or out of range",
+                ) {
+                    Ok(db) => db,
+                    Err(e) => return Ok(e),
+                }
+            }
+            _ => return Ok(syntax_error()),
+        }
+    }
+    if source == destination && db == session.db {
+        return Ok(RESPValue::error(
+            "ERR source and destination objects are the same".to_string(),
+        ));
+    }
+
+    // Without a destination table, the entry is copied within the source one
+    let copy_entry = |from: &mut Keyspace, to: Option<&mut Keyspace>| {
+        let entry = match get_live_entry(from, source) {
+            Some(entry) => entry.clone(),
+            None => return 0,
+        };
+        let to = match to {
+            Some(to) => to,
+            None => from,
+        };
+        if !replace && get_live_entry(to, destination).is_some() {
+            return 0;
+        }
+        if let Some((old_value, _, _)) = to.insert(destination.to_string(), entry) {
+            free(vec![old_value]);
+        }
+        1
+    };
+    let copied = if db == session.db {
+        copy_entry(&mut *write_table(&databases[db])?, None)
+    } else {
+        let (mut from, mut to) = databases::lock_pair(databases, session.db, db)?;
+        copy_entry(&mut from, Some(&mut to))
+    };
+    Ok(RESPValue::integer(copied))
+}
+
+// Number of allocations freeing the value takes, roughly
+fn free_effort(value: &Value) -> usize {
+    match value {
+        Value::SortedSet(set) => set.len(),
+        Value::String(_) | Value::Integer(_) => 1,
+    }
+}
+
+// Drops the values, on a blocking thread of the runtime if that takes a lot of work so that
+// the connection isn't stalled
+fn free(values: Vec<Value>) {
+    if values.iter().map(free_effort).sum::<usize>() <= LAZYFREE_THRESHOLD {
+        return;
+    }
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        runtime.spawn_blocking(move || drop(values));
+    }
+}
+
+pub fn unlink(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let keys = match Args::new("unlink", args).rest_non_empty() {
+        Ok(keys) => keys,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let mut removed = vec![];
+    for key in keys {
+        if get_live_entry(&mut t, key).is_some() {
+            if let Some((value, _, _)) = t.remove(key) {
+                removed.push(value);
+            }
+        }
+    }
+    drop(t);
+    let count = removed.len();
+    free(removed);
+    Ok(RESPValue::integer(count as i64))
+}
+
+pub fn touch(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let keys = match Args::new("touch", args).rest_non_empty() {
+        Ok(keys) => keys,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let count = keys
+        .into_iter()
+        .filter(|key| get_live_entry(&mut t, key).is_some())
+        .count();
+    Ok(RESPValue::integer(count as i64))
+}
+
+pub fn dump(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let key = match Args::new("dump", args).parse(Args::next_key) {
+        Ok(key) => key,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let mut payload = vec![];
+    match get_live_entry(&mut t, key) {
+        Some((value, _, _)) => rdb::write_value(&mut payload, value),
+        None => return Ok(RESPValue::bulk_string(None)),
+    }
+    rdb::append_footer(&mut payload);
+    Ok(RESPValue::bulk_string(Some(bytes_to_string(&payload))))
+}
+
+pub fn restore(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("restore", args);
+    let parsed = args
+        .next_key()
+        .and_then(|key| Ok((key, args.next_str()?, args.next_str()?, args.rest()?)));
+    let (key, ttl, payload, options) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut replace = false;
+    let mut absolute_ttl = false;
+    let (mut idle_time, mut frequency) = (None, None);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.to_uppercase().as_str(), options.len()) {
+            ("REPLACE", _) => replace = true,
+            ("ABSTTL", _) => absolute_ttl = true,
+            ("IDLETIME", n) if n >= 1 && frequency.is_none() => {
+                idle_time = match options.next().and_then(|s| value::parse_integer(s)) {
+                    Some(t) if t < 0 => {
+                        return Ok(RESPValue::error(
+                            "ERR Invalid IDLETIME value, must be >= 0".to_string(),
+                        ))
+                    }
+                    Some(t) => Some(Duration::from_secs(t as u64)),
+                    None => return Ok(not_an_integer()),
+                }
+            }
+            ("FREQ", n) if n >= 1 && idle_time.is_none() => {
+                frequency = match options.next().and_then(|s| value::parse_integer(s)) {
+                    Some(f) if !(0..=255).contains(&f) => {
+                        return Ok(RESPValue::error(
+                            "ERR Invalid FREQ value, must be >= 0 and <= 255".to_string(),
+                        ))
+                    }
+                    Some(f) => Some(f as u8),
+                    None => return Ok(not_an_integer()),
+                }
+            }
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let mut t = write_table(&table)?;
+    if !replace && get_live_entry(&mut t, key).is_some() {
+        return Ok(RESPValue::error(
+            "BUSYKEY Target key name already exists.".to_string(),
+        ));
+    }
+    let ttl = match value::parse_integer(ttl) {
+        Some(ttl) if ttl < 0 => {
+            return Ok(RESPValue::error(
+                "ERR Invalid TTL value, must be >= 0".to_string(),
+            ))
+        }
+        Some(ttl) => ttl as u64,
+        None => return Ok(not_an_integer()),
+    };
+    let payload = string_to_bytes(payload);
+    let body = match rdb::verify_footer(&payload) {
+        Some(body) => body,
+        None => {
+            return Ok(RESPValue::error(
+                "ERR DUMP payload version or checksum are wrong".to_string(),
+            ))
+        }
+    };
+    let value = match rdb::read_value(body) {
+        Some((value, _)) => value,
+        None => return Ok(RESPValue::error("ERR Bad data format".to_string())),
+    };
+
+    if replace {
+        if let Some((old_value, _, _)) = t.remove(key) {
+            free(vec![old_value]);
+        }
+    }
+    // A TTL of 0 means no expiry
+    let expiry = match (ttl, absolute_ttl) {
+        (0, _) => None,
+        (ttl, false) => Some(Duration::from_millis(ttl)),
+        (ttl, true) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            match Duration::from_millis(ttl).checked_sub(now) {
+                Some(ttl) => Some(ttl),
+                // Already expired, the key isn't created
+                None => return Ok(ok()),
+            }
+        }
+    };
+    t.insert(
+        key.to_string(),
+        (
+            value,
+            expiry.map(|d| (Instant::now(), d)),
+            Access::restored(idle_time, frequency),
+        ),
+    );
+    Ok(ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::RwLock;
+
+    fn table(n: usize) -> Keyspace {
+        (0..n)
+            .map(|i| {
+                (
+                    format!("key:{}", i),
+                    (Value::Integer(i as i64), None, Access::new()),
+                )
+            })
+            .collect()
+    }
+
+    fn scan_all(t: &Keyspace, count: usize) -> Vec<String> {
+        let mut cursor = 0;
+        let mut keys = vec![];
+        loop {
+            let (batch, next_cursor) = scan_keys(t, cursor, count);
+            keys.extend(batch.into_iter().cloned());
+            if next_cursor == 0 {
+                return keys;
+            }
+            cursor = next_cursor;
+        }
+    }
+
+    #[test]
+    fn test_scan_returns_every_key_once() {
+        let t = table(100);
+        let mut keys = scan_all(&t, 7);
+        keys.sort();
+        let mut expected: Vec<String> = t.iter().map(|(key, _)| key.clone()).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_scan_batches_hold_count_keys() {
+        let t = table(100);
+        let mut cursor = 0;
+        loop {
+            let (batch, next_cursor) = scan_keys(&t, cursor, 10);
+            if next_cursor == 0 {
+                break;
+            }
+            assert!(batch.len() >= 10);
+            cursor = next_cursor;
+        }
+        let (batch, cursor) = scan_keys(&t, 0, 1000);
+        assert_eq!((batch.len(), cursor), (100, 0));
+    }
+
+    #[test]
+    fn test_scan_survives_resizing() {
+        let mut t = table(50);
+        let (first, mut cursor) = scan_keys(&t, 0, 10);
+        let mut seen: Vec<String> = first.into_iter().cloned().collect();
+        // Grow the table well past its capacity between calls
+        for i in 50..1000 {
+            t.insert(
+                format!("key:{}", i),
+                (Value::Integer(i), None, Access::new()),
+            );
+        }
+        while cursor != 0 {
+            let (batch, next_cursor) = scan_keys(&t, cursor, 10);
+            seen.extend(batch.into_iter().cloned());
+            cursor = next_cursor;
+        }
+        for i in 0..50 {
+            assert!(seen.contains(&format!("key:{}", i)));
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<BulkString> {
+        args.iter()
+            .map(|s| BulkString::from(s.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let table: Table = Arc::new(RwLock::new(table(1)));
+        let payload = match dump(&args(&["key:0"]), table.clone()) {
+            Ok(RESPValue::BulkString(Some(payload))) => payload,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        // The integer encoding of 0 followed by the version and checksum
+        assert_eq!(&string_to_bytes(&payload)[..2], &[0x00, 0xc0]);
+
+        assert_eq!(
+            restore(&args(&["key:0", "0", &payload]), table.clone()),
+            Ok(RESPValue::error(
+                "BUSYKEY Target key name already exists.".to_string()
+            ))
+        );
+        assert_eq!(
+            restore(&args(&["copy", "0", &payload]), table.clone()),
+            Ok(ok())
+        );
+        assert_eq!(table.read().unwrap()["copy"].0, Value::Integer(0));
+
+        let mut corrupted = payload.clone();
+        corrupted.push('x');
+        assert_eq!(
+            restore(&args(&["other", "0", &corrupted]), table),
+            Ok(RESPValue::error(
+                "ERR DUMP payload version or checksum are wrong".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_rename_keeps_expiry() {
+        let table: Table = Arc::new(RwLock::new(Keyspace::default()));
+        let expiry = Some((Instant::now(), Duration::from_secs(100)));
+        table
+            .write()
+            .unwrap()
+            .insert("a".to_string(), (Value::Integer(1), expiry, Access::new()));
+        assert_eq!(rename(&args(&["a", "b"]), table.clone(), false), Ok(ok()));
+        let (value, renamed_expiry, _) = table.read().unwrap()["b"].clone();
+        assert_eq!((value, renamed_expiry), (Value::Integer(1), expiry));
+        assert_eq!(
+            rename(&args(&["a", "b"]), table, false),
+            Ok(RESPValue::error("ERR no such key".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_copy() {
+        let databases: Databases =
+            Arc::new((0..2).map(|_| Arc::new(RwLock::new(table(2)))).collect());
+        let session = Session::default();
+        let copy = |command: &[&str]| copy(&args(command), &databases, &session);
+        assert_eq!(copy(&["key:0", "key:1"]), Ok(RESPValue::integer(0)));
+        assert_eq!(databases[0].read().unwrap()["key:1"].0, Value::Integer(1));
+        assert_eq!(
+            copy(&["key:0", "key:1", "REPLACE"]),
+            Ok(RESPValue::integer(1))
+        );
+        assert_eq!(databases[0].read().unwrap()["key:1"].0, Value::Integer(0));
+        assert_eq!(
+            copy(&["key:1", "key:0", "DB", "1"]),
+            Ok(RESPValue::integer(0))
+        );
+        assert_eq!(
+            copy(&["key:1", "new", "DB", "1"]),
+            Ok(RESPValue::integer(1))
+        );
+        assert_eq!(databases[1].read().unwrap()["new"].0, Value::Integer(0));
+    }
+}
@@ -0,0 +1,273 @@
+// The latency monitor, recording the events that took longer than latency-monitor-threshold, and
+// the LATENCY command. Like Redis, each event keeps a short history with one sample per second.
+use crate::strings::wrong_number_of_arguments;
+use crate::Server;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Same as Redis' LATENCY_TS_LEN
+const HISTORY_LEN: usize = 160;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sample {
+    // Unix time in seconds
+    time: u64,
+    millis: u64,
+}
+
+#[derive(Default)]
+struct Series {
+    // Oldest first
+    samples: VecDeque<Sample>,
+    max: u64,
+}
+
+#[derive(Default)]
+pub struct Latency {
+    // By event name
+    events: Mutex<BTreeMap<&'static str, Series>>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Latency {
+    fn events(&self) -> Result<MutexGuard<'_, BTreeMap<&'static str, Series>>, String> {
+        self.events
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for latency events {}", e))
+    }
+
+    // Records the event if it took at least the threshold, which disables the monitor when 0
+    pub fn record(
+        &self,
+        event: &'static str,
+        duration: Duration,
+        threshold_millis: usize,
+    ) -> Result<(), String> {
+        let millis = duration.as_millis() as u64;
+        if threshold_millis == 0 || millis < threshold_millis as u64 {
+            return Ok(());
+        }
+        self.add_sample(
+            event,
+            Sample {
+                time: now_secs(),
+                millis,
+            },
+        )
+    }
+
+    fn add_sample(&self, event: &'static str, sample: Sample) -> Result<(), String> {
+        let mut events = self.events()?;
+        let series = events.entry(event).or_default();
+        series.max = series.max.max(sample.millis);
+        // Events within the same second share a sample, the worst one
+        match series.samples.back_mut() {
+            Some(last) if last.time == sample.time => last.millis = last.millis.max(sample.millis),
+            _ => {
+                series.samples.push_back(sample);
+                if series.samples.len() > HISTORY_LEN {
+                    series.samples.pop_front();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn latency_command(args: &[BulkString], server: &Server) -> Result<RESPValue, String> {
+    let mut args = Args::new("latency", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    let mut events = server.latency.events()?;
+    let integer = |n: u64| RESPValue::integer(n as i64);
+    match (subcommand.as_str(), &args[..]) {
+        ("LATEST", []) => Ok(RESPValue::Array(Some(
+            events
+                .iter()
+                .filter_map(|(event, series)| {
+                    let last = series.samples.back()?;
+                    Some(RESPValue::Array(Some(vec![
+                        RESPValue::bulk_string(Some(event.to_string())),
+                        integer(last.time),
+                        integer(last.millis),
+                        integer(series.max),
+                    ])))
+                })
+                .collect(),
+        ))),
+        ("HISTORY", [event]) => Ok(RESPValue::Array(Some(
+            events
+                .get(event.to_lowercase().as_str())
+                .map_or(vec![], |series| {
+                    series
+                        .samples
+                        .iter()
+                        .map(|s| RESPValue::Array(Some(vec![integer(s.time), integer(s.millis)])))
+                        .collect()
+                }),
+        ))),
+        ("RESET", names) => {
+            let before = events.len();
+            if names.is_empty() {
+                events.clear();
+            } else {
+                events.retain(|event, _| !names.iter().any(|n| n.eq_ignore_ascii_case(event)));
+            }
+            Ok(RESPValue::integer((before - events.len()) as i64))
+        }
+        ("DOCTOR", []) => Ok(RESPValue::bulk_string(Some(doctor(&events)))),
+        ("LATEST", _) | ("HISTORY", _) | ("DOCTOR", _) => Ok(wrong_number_of_arguments(&format!(
+            "latency|{}",
+            subcommand.to_lowercase()
+        ))),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'. Try LATENCY HELP.",
+            s.to_lowercase()
+        ))),
+    }
+}
+
+// A human readable analysis of the events, in the spirit of Redis' createLatencyReport
+fn doctor(events: &BTreeMap<&'static str, Series>) -> String {
+    if events.values().all(|series| series.samples.is_empty()) {
+        return "Dave, no latency spike was observed during the lifetime of this Redis instance, \
+                not in the slightest bit. I honestly think you ought to sleep tonight.\n"
+            .to_string();
+    }
+    let mut report = String::from(
+        "Dave, I have observed latency spikes in this Redis instance. \
+         You don't mind talking about it, do you Dave?\n\n",
+    );
+    for (i, (event, series)) in events.iter().enumerate() {
+        let samples = &series.samples;
+        let count = samples.len() as u64;
+        let average = samples.iter().map(|s| s.millis).sum::<u64>() / count.max(1);
+        let deviation = samples
+            .iter()
+            .map(|s| s.millis.abs_diff(average))
+            .sum::<u64>()
+            / count.max(1);
+        let period = match (samples.front(), samples.back()) {
+            (Some(first), Some(last)) if count > 1 => {
+                (last.time - first.time) as f64 / (count - 1) as f64
+            }
+            _ => 0.0,
+        };
+        let _ = writeln!(
+            report,
+            "{}. {}: {} latency spikes (average {}ms, mean deviation {}ms, period {:.2} sec). \
+             Worst all time event {}ms.",
+            i + 1,
+            event,
+            count,
+            average,
+            deviation,
+            period,
+            series.max
+        );
+    }
+    report.push_str("\nI have a few advices for you:\n\n");
+    if events.contains_key("command") || events.contains_key("fast-command") {
+        report.push_str(
+            "- Check your Slow Log to understand what are the commands you are running which \
+             are too slow to execute. Please check https://redis.io/commands/slowlog for more \
+             information.\n",
+        );
+    }
+    if events.contains_key("expire-cycle") {
+        report.push_str(
+            "- Deleting, expiring or evicting (because of maxmemory policy) large objects is a \
+             blocking operation. If you have very large objects that are often deleted, expired, \
+             or evicted, try to fragment those objects into multiple smaller objects.\n",
+        );
+    }
+    if events.contains_key("eviction-cycle") {
+        report.push_str(
+            "- Evicting keys can take a while when maxmemory is far below the memory in use. \
+             Consider raising maxmemory or lowering the amount of data written.\n",
+        );
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let latency = Latency::default();
+        let millis = Duration::from_millis;
+        latency.record("command", millis(500), 0).unwrap();
+        latency.record("command", millis(5), 10).unwrap();
+        assert!(latency.events().unwrap().is_empty());
+
+        latency
+            .add_sample(
+                "command",
+                Sample {
+                    time: 100,
+                    millis: 20,
+                },
+            )
+            .unwrap();
+        latency
+            .add_sample(
+                "command",
+                Sample {
+                    time: 100,
+                    millis: 30,
+                },
+            )
+            .unwrap();
+        latency
+            .add_sample(
+                "command",
+                Sample {
+                    time: 101,
+                    millis: 10,
+                },
+            )
+            .unwrap();
+        for time in 200..400 {
+            latency
+                .add_sample("expire-cycle", Sample { time, millis: 1 })
+                .unwrap();
+        }
+        let events = latency.events().unwrap();
+        let command: Vec<Sample> = events["command"].samples.iter().copied().collect();
+        assert_eq!(
+            command,
+            vec![
+                Sample {
+                    time: 100,
+                    millis: 30
+                },
+                Sample {
+                    time: 101,
+                    millis: 10
+                }
+            ]
+        );
+        assert_eq!(events["command"].max, 30);
+        assert_eq!(events["expire-cycle"].samples.len(), HISTORY_LEN);
+        assert!(doctor(&events).contains("1. command: 2 latency spikes (average 20ms"));
+    }
+}
@@ -1,5 +1,7 @@
+use bytes::{BufMut, Bytes, BytesMut};
 use std::{
     convert::TryFrom,
+    io::Write,
     ops::{Deref, DerefMut},
 };
 
@@ -10,6 +12,9 @@ pub enum RESPDataType {
     Integer,
     BulkString,
     Array,
+    // RESP3
+    Push,
+    Map,
 }
 
 impl RESPDataType {
@@ -32,24 +37,28 @@ impl TryFrom<u8> for RESPDataType {
             b':' => Ok(Self::Integer),
             b'$' => Ok(Self::BulkString),
             b'*' => Ok(Self::Array),
+            b'>' => Ok(Self::Push),
+            b'%' => Ok(Self::Map),
             c => Err(ParseError::UnknownDataType(c as char)),
         }
     }
 }
 
-impl Into<u8> for RESPDataType {
-    fn into(self) -> u8 {
-        match self {
-            Self::SimpleString => b'+',
-            Self::Error => b'-',
-            Self::Integer => b':',
-            Self::BulkString => b'$',
-            Self::Array => b'*',
+impl From<RESPDataType> for u8 {
+    fn from(data_type: RESPDataType) -> u8 {
+        match data_type {
+            RESPDataType::SimpleString => b'+',
+            RESPDataType::Error => b'-',
+            RESPDataType::Integer => b':',
+            RESPDataType::BulkString => b'$',
+            RESPDataType::Array => b'*',
+            RESPDataType::Push => b'>',
+            RESPDataType::Map => b'%',
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ParseError {
     UnknownDataType(char),
     // (expected, actual)
@@ -59,11 +68,15 @@ pub enum ParseError {
     UnexpectedNonNumericCharacter(char),
     MissingCLRF,
     NegativeValueLength,
+    // an integer that overflows, or a length over the limits
+    ValueTooLarge,
+    // an integer line without digits, like "" or "-"
+    MissingDigits,
 }
 
 type ParseResult<T> = Result<T, ParseError>;
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum RESPValue {
     Integer(i64),
     // TODO: deal with null strings
@@ -71,6 +84,10 @@ pub enum RESPValue {
     SimpleString(String),
     Error(String),
     Array(Option<Vec<RESPValue>>),
+    // Sent by the server outside of the command/reply flow, to RESP3 connections only
+    Push(Vec<RESPValue>),
+    // A RESP3 map, replied as a flat array of keys and values to RESP2 connections
+    Map(Vec<(RESPValue, RESPValue)>),
 }
 
 const ESCAPED_CLRF: &str = "\\r\\n";
@@ -105,7 +122,7 @@ impl RESPValue {
         Self::Integer(i)
     }
 
-    pub fn parse<'a>(bytes: &'a (impl AsRef<[u8]> + ?Sized)) -> ParseResult<(Self, &'a [u8])> {
+    pub fn parse(bytes: &(impl AsRef<[u8]> + ?Sized)) -> ParseResult<(Self, &[u8])> {
         let bytes = bytes.as_ref();
         let (data_type, bytes) = RESPDataType::from_bytes(bytes)?;
         match data_type {
@@ -129,18 +146,44 @@ impl RESPValue {
                 let (len, bytes) = parse_array_len(bytes)?;
                 let (values, bytes) = len
                     .map(|len| {
-                        (0..len)
-                            .try_fold((vec![], bytes), |(mut vec, bytes), _| {
-                                let (value, bytes) = RESPValue::parse(bytes)?;
-                                vec.push(value);
-                                Ok((vec, bytes))
-                            })
-                            .map(|(values, bytes)| (Some(values), bytes))
+                        parse_values(len, bytes).map(|(values, bytes)| (Some(values), bytes))
                     })
                     .unwrap_or_else(|| Ok((None, bytes)))?;
 
                 Ok((Self::Array(values), bytes))
             }
+            RESPDataType::Push => {
+                let (len, bytes) = parse_array_len(bytes)?;
+                let (values, bytes) = parse_values(len.unwrap_or_default(), bytes)?;
+                Ok((Self::Push(values), bytes))
+            }
+            RESPDataType::Map => {
+                let (len, bytes) = parse_array_len(bytes)?;
+                let (values, bytes) = parse_values(len.unwrap_or_default() * 2, bytes)?;
+                let mut values = values.into_iter();
+                let mut pairs = vec![];
+                while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                    pairs.push((key, value));
+                }
+                Ok((Self::Map(pairs), bytes))
+            }
+        }
+    }
+
+    // The value as a RESP2 connection receives it, where maps are flat arrays
+    pub fn into_resp2(self) -> Self {
+        match self {
+            Self::Map(pairs) => Self::Array(Some(
+                pairs
+                    .into_iter()
+                    .flat_map(|(key, value)| [key.into_resp2(), value.into_resp2()])
+                    .collect(),
+            )),
+            Self::Array(Some(values)) => {
+                Self::Array(Some(values.into_iter().map(Self::into_resp2).collect()))
+            }
+            Self::Push(values) => Self::Array(Some(values)).into_resp2(),
+            value => value,
         }
     }
 
@@ -151,6 +194,85 @@ impl RESPValue {
             RESPValue::SimpleString(_) => RESPDataType::SimpleString,
             RESPValue::Error(_) => RESPDataType::Error,
             RESPValue::Array(_) => RESPDataType::Array,
+            RESPValue::Push(_) => RESPDataType::Push,
+            RESPValue::Map(_) => RESPDataType::Map,
+        }
+    }
+
+    /// Serializes the value for the wire. Bulk strings hold one byte per char
+    /// (see `parse_bulk_string_contents`), so they are written back out the same way.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode(&mut buf);
+        buf
+    }
+
+    /// Appends the serialized value to the buffer, growing it once
+    pub fn encode_into(&self, buf: &mut BytesMut) {
+        buf.reserve(self.encoded_len());
+        self.encode(buf);
+    }
+
+    /// Writes the serialized value straight to the writer, without buffering it first
+    pub fn write_to(&self, w: impl Write) -> std::io::Result<()> {
+        let mut writer = Writer {
+            inner: w,
+            result: Ok(()),
+        };
+        self.encode(&mut writer);
+        writer.result
+    }
+
+    /// The number of bytes the serialized value takes
+    pub fn encoded_len(&self) -> usize {
+        let header = |n: usize| 1 + decimal_len(n as i64) + CLRF.len();
+        match self {
+            Self::Integer(i) => 1 + decimal_len(*i) + CLRF.len(),
+            Self::BulkString(Some(s)) => {
+                let len = s.chars().count();
+                header(len) + len + CLRF.len()
+            }
+            Self::BulkString(None) | Self::Array(None) => 1 + decimal_len(-1) + CLRF.len(),
+            Self::Error(s) | Self::SimpleString(s) => 1 + s.chars().count() + CLRF.len(),
+            Self::Array(Some(values)) | Self::Push(values) => {
+                header(values.len()) + values.iter().map(Self::encoded_len).sum::<usize>()
+            }
+            Self::Map(pairs) => {
+                header(pairs.len())
+                    + pairs
+                        .iter()
+                        .map(|(key, value)| key.encoded_len() + value.encoded_len())
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    fn encode(&self, buf: &mut impl Sink) {
+        match self {
+            Self::Integer(i) => put_header(buf, RESPDataType::Integer, *i),
+            Self::BulkString(Some(s)) => {
+                put_header(buf, RESPDataType::BulkString, s.chars().count() as i64);
+                put_chars(buf, s);
+                buf.put_slice(CLRF.as_bytes());
+            }
+            Self::BulkString(None) => put_header(buf, RESPDataType::BulkString, -1),
+            Self::Error(s) | Self::SimpleString(s) => {
+                buf.put_u8(self.data_type().into());
+                put_chars(buf, s);
+                buf.put_slice(CLRF.as_bytes());
+            }
+            Self::Array(Some(values)) | Self::Push(values) => {
+                put_header(buf, self.data_type(), values.len() as i64);
+                values.iter().for_each(|v| v.encode(buf));
+            }
+            Self::Array(None) => put_header(buf, RESPDataType::Array, -1),
+            Self::Map(pairs) => {
+                put_header(buf, RESPDataType::Map, pairs.len() as i64);
+                pairs.iter().for_each(|(key, value)| {
+                    key.encode(buf);
+                    value.encode(buf);
+                });
+            }
         }
     }
 
@@ -160,7 +282,7 @@ impl RESPValue {
                 write!(f, ":{}", i)?;
             }
             Self::BulkString(Some(s)) => {
-                write!(f, "${}{}{}", s.len(), clrf, s)?;
+                write!(f, "${}{}{}", s.chars().count(), clrf, s)?;
             }
             Self::BulkString(None) => {
                 write!(f, "$-1")?;
@@ -173,19 +295,254 @@ impl RESPValue {
             }
             Self::Array(Some(values)) => {
                 write!(f, "*{}{}", values.len(), clrf)?;
-                for v in values {
-                    v.format(f, clrf)?;
-                }
+                // Every element is already terminated
+                return values.iter().try_for_each(|v| v.format(f, clrf));
             }
             Self::Array(None) => {
                 write!(f, "*-1")?;
             }
+            Self::Push(values) => {
+                write!(f, ">{}{}", values.len(), clrf)?;
+                return values.iter().try_for_each(|v| v.format(f, clrf));
+            }
+            Self::Map(pairs) => {
+                write!(f, "%{}{}", pairs.len(), clrf)?;
+                return pairs.iter().try_for_each(|(key, value)| {
+                    key.format(f, clrf)?;
+                    value.format(f, clrf)
+                });
+            }
         }
 
         write!(f, "{}", clrf)
     }
 }
 
+/// A RESP value whose strings are slices of the buffer it was parsed from, so that parsing
+/// doesn't copy them
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Integer(i64),
+    BulkString(Option<Bytes>),
+    SimpleString(Bytes),
+    Error(Bytes),
+    Array(Option<Vec<Frame>>),
+    Push(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+}
+
+impl Frame {
+    /// Checks that the buffer starts with a complete frame, without copying or slicing anything,
+    /// and returns the number of bytes it takes. `NotEnoughBytes` means the rest of the frame
+    /// hasn't been read yet.
+    pub fn check(bytes: &[u8]) -> ParseResult<usize> {
+        let rest = Self::skip(bytes)?;
+        Ok(bytes.len() - rest.len())
+    }
+
+    // Returns the bytes after the frame at the start of bytes
+    fn skip(bytes: &[u8]) -> ParseResult<&[u8]> {
+        let (data_type, bytes) = RESPDataType::from_bytes(bytes)?;
+        let (line, bytes) = take_line(bytes)?;
+        match data_type {
+            RESPDataType::Integer => line_integer(line).map(|_| bytes),
+            RESPDataType::SimpleString | RESPDataType::Error => Ok(bytes),
+            RESPDataType::BulkString => match line_len(line)? {
+                Some(len) => take_bulk(bytes, len).map(|(_, bytes)| bytes),
+                None => Ok(bytes),
+            },
+            RESPDataType::Array | RESPDataType::Push | RESPDataType::Map => {
+                (0..element_count(data_type, line)?).try_fold(bytes, |bytes, _| Self::skip(bytes))
+            }
+        }
+    }
+
+    /// Parses the frame at the start of the buffer, returns it with the number of bytes it takes
+    pub fn parse(buf: &Bytes) -> ParseResult<(Self, usize)> {
+        let (frame, rest) = Self::parse_suffix(buf, buf)?;
+        Ok((frame, buf.len() - rest.len()))
+    }
+
+    // Parses the frame at the start of bytes, which ends the buffer
+    fn parse_suffix<'a>(buf: &Bytes, bytes: &'a [u8]) -> ParseResult<(Self, &'a [u8])> {
+        // The part of the buffer that s is
+        let slice = |s: &[u8]| {
+            let start = s.as_ptr() as usize - buf.as_ptr() as usize;
+            buf.slice(start..start + s.len())
+        };
+        let (data_type, bytes) = RESPDataType::from_bytes(bytes)?;
+        let (line, bytes) = take_line(bytes)?;
+        match data_type {
+            RESPDataType::Integer => Ok((Self::Integer(line_integer(line)?), bytes)),
+            RESPDataType::SimpleString => Ok((Self::SimpleString(slice(line)), bytes)),
+            RESPDataType::Error => Ok((Self::Error(slice(line)), bytes)),
+            RESPDataType::BulkString => match line_len(line)? {
+                Some(len) => {
+                    let (s, bytes) = take_bulk(bytes, len)?;
+                    Ok((Self::BulkString(Some(slice(s))), bytes))
+                }
+                None => Ok((Self::BulkString(None), bytes)),
+            },
+            RESPDataType::Array | RESPDataType::Push | RESPDataType::Map => {
+                let count = element_count(data_type, line)?;
+                let mut frames = Vec::with_capacity(count.min(MAX_PREALLOCATED_ELEMENTS));
+                let bytes = (0..count).try_fold(bytes, |bytes, _| {
+                    let (frame, bytes) = Self::parse_suffix(buf, bytes)?;
+                    frames.push(frame);
+                    Ok(bytes)
+                })?;
+                let frame = match data_type {
+                    RESPDataType::Array if line_len(line)?.is_none() => Self::Array(None),
+                    RESPDataType::Array => Self::Array(Some(frames)),
+                    RESPDataType::Push => Self::Push(frames),
+                    _ => {
+                        let mut frames = frames.into_iter();
+                        let mut pairs = vec![];
+                        while let (Some(key), Some(value)) = (frames.next(), frames.next()) {
+                            pairs.push((key, value));
+                        }
+                        Self::Map(pairs)
+                    }
+                };
+                Ok((frame, bytes))
+            }
+        }
+    }
+}
+
+/// Parses the command at the start of the buffer, an array of bulk strings, in a single pass that
+/// copies each argument out once. Returns it with the number of bytes it takes, None for a null
+/// array. `NotEnoughBytes` means the rest of the command hasn't been read yet.
+pub fn parse_command(bytes: &[u8]) -> ParseResult<(Option<Vec<BulkString>>, usize)> {
+    let start = bytes.len();
+    let (data_type, bytes) = RESPDataType::from_bytes(bytes)?;
+    if data_type != RESPDataType::Array {
+        return Err(ParseError::UnexpectedDataType(
+            RESPDataType::Array,
+            data_type,
+        ));
+    }
+    let (line, mut bytes) = take_line(bytes)?;
+    let count = match line_len(line)? {
+        Some(_) => element_count(data_type, line)?,
+        None => return Ok((None, start - bytes.len())),
+    };
+    let mut args = Vec::with_capacity(count.min(MAX_PREALLOCATED_ELEMENTS));
+    for _ in 0..count {
+        let (data_type, rest) = RESPDataType::from_bytes(bytes)?;
+        if data_type != RESPDataType::BulkString {
+            return Err(ParseError::UnexpectedDataType(
+                RESPDataType::BulkString,
+                data_type,
+            ));
+        }
+        let (line, rest) = take_line(rest)?;
+        bytes = match line_len(line)? {
+            Some(len) => {
+                let (s, rest) = take_bulk(rest, len)?;
+                args.push(BulkString(Some(bytes_to_string(s))));
+                rest
+            }
+            None => {
+                args.push(BulkString(None));
+                rest
+            }
+        };
+    }
+    Ok((Some(args), start - bytes.len()))
+}
+
+// Longest line accepted while its CRLF hasn't arrived, like Redis' limit on inline commands
+const MAX_LINE_LEN: usize = 64 * 1024;
+// Longest bulk string accepted, Redis' default proto-max-bulk-len
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// Most elements in an aggregate, like Redis' limit on multibulk lengths
+const MAX_ELEMENTS: usize = i32::MAX as usize;
+// Elements allocated ahead for an aggregate, whatever count the peer sends
+const MAX_PREALLOCATED_ELEMENTS: usize = 1024;
+
+// Splits off the line ending at the first CRLF
+fn take_line(bytes: &[u8]) -> ParseResult<(&[u8], &[u8])> {
+    match bytes.windows(2).position(|w| w == CLRF.as_bytes()) {
+        Some(end) => Ok((&bytes[..end], &bytes[end + CLRF.len()..])),
+        None if bytes.len() > MAX_LINE_LEN => Err(ParseError::MissingCLRF),
+        None => Err(ParseError::NotEnoughBytes),
+    }
+}
+
+fn line_integer(line: &[u8]) -> ParseResult<i64> {
+    let (is_negative, digits) = match line {
+        [b'-', digits @ ..] => (true, digits),
+        digits => (false, digits),
+    };
+    if digits.is_empty() {
+        return Err(ParseError::MissingDigits);
+    }
+    let num = digits.iter().try_fold(0i64, |num, &digit| {
+        if !digit.is_ascii_digit() {
+            return Err(ParseError::UnexpectedNonNumericCharacter(digit as char));
+        }
+        num.checked_mul(10)
+            .and_then(|num| num.checked_add((digit - b'0') as i64))
+            .ok_or(ParseError::ValueTooLarge)
+    })?;
+    Ok(if is_negative { -num } else { num })
+}
+
+// The length of a bulk string or aggregate, None for null ones
+fn line_len(line: &[u8]) -> ParseResult<Option<usize>> {
+    match line_integer(line)? {
+        len @ 0.. => Ok(Some(len as usize)),
+        -1 => Ok(None),
+        _ => Err(ParseError::NegativeValueLength),
+    }
+}
+
+// The number of frames an aggregate holds, maps hold two per entry
+fn element_count(data_type: RESPDataType, line: &[u8]) -> ParseResult<usize> {
+    let len = line_len(line)?.unwrap_or_default();
+    if len > MAX_ELEMENTS {
+        return Err(ParseError::ValueTooLarge);
+    }
+    match data_type {
+        RESPDataType::Map => Ok(len * 2),
+        _ => Ok(len),
+    }
+}
+
+// Splits off a bulk string of len bytes and its CRLF
+fn take_bulk(bytes: &[u8], len: usize) -> ParseResult<(&[u8], &[u8])> {
+    if len > MAX_BULK_LEN {
+        return Err(ParseError::ValueTooLarge);
+    }
+    if bytes.len() < len + CLRF.len() {
+        return Err(ParseError::NotEnoughBytes);
+    }
+    Ok((&bytes[..len], validate_clrf(&bytes[len..])?))
+}
+
+/// Copies the strings out of the buffer, one char per byte like `RESPValue::parse`
+impl From<Frame> for RESPValue {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Integer(i) => Self::Integer(i),
+            Frame::BulkString(s) => Self::BulkString(s.map(|s| bytes_to_string(&s))),
+            Frame::SimpleString(s) => Self::SimpleString(bytes_to_string(&s)),
+            Frame::Error(s) => Self::Error(bytes_to_string(&s)),
+            Frame::Array(frames) => {
+                Self::Array(frames.map(|frames| frames.into_iter().map(Self::from).collect()))
+            }
+            Frame::Push(frames) => Self::Push(frames.into_iter().map(Self::from).collect()),
+            Frame::Map(pairs) => Self::Map(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (key.into(), value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum RESPValueConversionError {
     // (expected, actual)
@@ -210,9 +567,15 @@ impl TryFrom<RESPValue> for i64 {
 #[derive(Debug, PartialEq)]
 pub struct BulkString(Option<String>);
 
-impl Into<Option<String>> for BulkString {
-    fn into(self) -> Option<String> {
-        self.0
+impl From<BulkString> for Option<String> {
+    fn from(s: BulkString) -> Option<String> {
+        s.0
+    }
+}
+
+impl From<String> for BulkString {
+    fn from(s: String) -> Self {
+        Self(Some(s))
     }
 }
 
@@ -246,11 +609,11 @@ impl TryFrom<RESPValue> for BulkString {
 
 // Introduce this wrapper type so that we can safely convert to string without losing the type information
 #[derive(Debug, PartialEq)]
-struct SimpleString(String);
+pub struct SimpleString(String);
 
-impl Into<String> for SimpleString {
-    fn into(self) -> String {
-        self.0
+impl From<SimpleString> for String {
+    fn from(s: SimpleString) -> String {
+        s.0
     }
 }
 
@@ -284,11 +647,11 @@ impl TryFrom<RESPValue> for SimpleString {
 
 // Introduce this wrapper type so that we can safely convert to string without losing the type information
 #[derive(Debug, PartialEq)]
-struct RESPError(String);
+pub struct RESPError(String);
 
-impl Into<String> for RESPError {
-    fn into(self) -> String {
-        self.0
+impl From<RESPError> for String {
+    fn from(s: RESPError) -> String {
+        s.0
     }
 }
 
@@ -341,6 +704,254 @@ where
     }
 }
 
+/// Why the arguments of a command couldn't be read, replied to the client as an error
+#[derive(Debug, PartialEq)]
+pub enum ArgError {
+    // Holds the command name
+    WrongNumberOfArguments(String),
+    NotAnInteger,
+    NotAFloat,
+    // Holds the command name
+    InvalidExpireTime(String),
+    Syntax,
+    // A null bulk string where a string was expected
+    NullArgument,
+}
+
+pub type ArgResult<T> = Result<T, ArgError>;
+
+impl From<ArgError> for RESPValue {
+    fn from(e: ArgError) -> Self {
+        Self::Error(match e {
+            ArgError::WrongNumberOfArguments(command) => {
+                format!("ERR wrong number of arguments for '{}' command", command)
+            }
+            ArgError::NotAnInteger => "ERR value is not an integer or out of range".to_string(),
+            ArgError::NotAFloat => "ERR value is not a valid float".to_string(),
+            ArgError::InvalidExpireTime(command) => {
+                format!("ERR invalid expire time in '{}' command", command)
+            }
+            ArgError::Syntax => "ERR syntax error".to_string(),
+            ArgError::NullArgument => "ERR Protocol error: invalid bulk length".to_string(),
+        })
+    }
+}
+
+/// Reads the arguments of a command one at a time as typed values. Missing arguments are
+/// reported as the wrong number of arguments for the command, and null ones as protocol errors.
+pub struct Args<'a> {
+    command: String,
+    args: &'a [BulkString],
+}
+
+impl<'a> Args<'a> {
+    pub fn new(command: &str, args: &'a [BulkString]) -> Self {
+        Self {
+            command: command.to_lowercase(),
+            args,
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    /// Reads all the arguments with `read`, checking that none are left over
+    pub fn parse<T>(mut self, read: impl FnOnce(&mut Self) -> ArgResult<T>) -> ArgResult<T> {
+        let parsed = read(&mut self)?;
+        self.end()?;
+        Ok(parsed)
+    }
+
+    fn peek(&self) -> ArgResult<Option<&'a str>> {
+        match self.args.first() {
+            Some(arg) => arg.as_deref().map(Some).ok_or(ArgError::NullArgument),
+            None => Ok(None),
+        }
+    }
+
+    pub fn next_str(&mut self) -> ArgResult<&'a str> {
+        let arg = self
+            .peek()?
+            .ok_or_else(|| ArgError::WrongNumberOfArguments(self.command.clone()))?;
+        self.args = &self.args[1..];
+        Ok(arg)
+    }
+
+    /// Reads the next argument, if any
+    pub fn optional_str(&mut self) -> ArgResult<Option<&'a str>> {
+        if self.args.is_empty() {
+            return Ok(None);
+        }
+        self.next_str().map(Some)
+    }
+
+    pub fn next_key(&mut self) -> ArgResult<&'a str> {
+        self.next_str()
+    }
+
+    pub fn next_i64(&mut self) -> ArgResult<i64> {
+        parse_integer(self.next_str()?).ok_or(ArgError::NotAnInteger)
+    }
+
+    pub fn next_f64(&mut self) -> ArgResult<f64> {
+        parse_float(self.next_str()?).ok_or(ArgError::NotAFloat)
+    }
+
+    /// Consumes the next argument if it's the flag, in any case
+    pub fn optional_flag(&mut self, flag: &str) -> bool {
+        let found = matches!(self.peek(), Ok(Some(a)) if a.eq_ignore_ascii_case(flag));
+        if found {
+            self.args = &self.args[1..];
+        }
+        found
+    }
+
+    /// Consumes the next argument if it's the keyword, in any case, and returns the value
+    /// following it. A keyword without a value is a syntax error.
+    pub fn keyword_with_value(&mut self, keyword: &str) -> ArgResult<Option<&'a str>> {
+        if !self.optional_flag(keyword) {
+            return Ok(None);
+        }
+        self.next_str().map(Some).map_err(|_| ArgError::Syntax)
+    }
+
+    /// Consumes the remaining arguments
+    pub fn rest(&mut self) -> ArgResult<Vec<&'a str>> {
+        let rest = self
+            .args
+            .iter()
+            .map(|s| s.as_deref().ok_or(ArgError::NullArgument))
+            .collect::<ArgResult<_>>()?;
+        self.args = &[];
+        Ok(rest)
+    }
+
+    /// Consumes the remaining arguments, of which there must be at least one
+    pub fn rest_non_empty(&mut self) -> ArgResult<Vec<&'a str>> {
+        match self.rest()? {
+            rest if rest.is_empty() => Err(ArgError::WrongNumberOfArguments(self.command.clone())),
+            rest => Ok(rest),
+        }
+    }
+
+    /// Checks that every argument was read
+    pub fn end(&self) -> ArgResult<()> {
+        match self.args {
+            [] => Ok(()),
+            _ => Err(ArgError::WrongNumberOfArguments(self.command.clone())),
+        }
+    }
+}
+
+/// Parses integers the way Redis does: no sign prefix, whitespace or leading zeros
+pub fn parse_integer(s: &str) -> Option<i64> {
+    s.parse::<i64>().ok().filter(|i| i.to_string() == s)
+}
+
+/// Parses floats the way Redis does, rejecting surrounding whitespace, NaN and infinities
+pub fn parse_float(s: &str) -> Option<f64> {
+    if s.trim() != s {
+        return None;
+    }
+    s.parse::<f64>().ok().filter(|f| f.is_finite())
+}
+
+fn decimal_len(n: i64) -> usize {
+    let sign = if n < 0 { 1 } else { 0 };
+    let mut n = n.unsigned_abs();
+    let mut digits = 1;
+    while n >= 10 {
+        n /= 10;
+        digits += 1;
+    }
+    sign + digits
+}
+
+// The type, followed by the integer, length or count
+fn put_header(buf: &mut impl Sink, data_type: RESPDataType, n: i64) {
+    buf.put_u8(data_type.into());
+    put_decimal(buf, n);
+    buf.put_slice(CLRF.as_bytes());
+}
+
+// Writes the number without going through a String
+fn put_decimal(buf: &mut impl Sink, n: i64) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    let mut rest = n.unsigned_abs();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        start -= 1;
+        digits[start] = b'-';
+    }
+    buf.put_slice(&digits[start..]);
+}
+
+// Writes one byte per char, see `bytes_to_string`
+fn put_chars(buf: &mut impl Sink, s: &str) {
+    if s.is_ascii() {
+        return buf.put_slice(s.as_bytes());
+    }
+    let mut chunk = [0u8; 64];
+    let mut len = 0;
+    for c in s.chars() {
+        chunk[len] = c as u8;
+        len += 1;
+        if len == chunk.len() {
+            buf.put_slice(&chunk);
+            len = 0;
+        }
+    }
+    buf.put_slice(&chunk[..len]);
+}
+
+// Where values are encoded to
+trait Sink {
+    fn put_slice(&mut self, bytes: &[u8]);
+
+    fn put_u8(&mut self, b: u8) {
+        self.put_slice(&[b]);
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn put_slice(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+impl Sink for BytesMut {
+    fn put_slice(&mut self, bytes: &[u8]) {
+        BufMut::put_slice(self, bytes);
+    }
+}
+
+// Writes through to the writer, keeping the first error
+struct Writer<W> {
+    inner: W,
+    result: std::io::Result<()>,
+}
+
+impl<W: Write> Sink for Writer<W> {
+    fn put_slice(&mut self, bytes: &[u8]) {
+        if self.result.is_ok() {
+            self.result = self.inner.write_all(bytes);
+        }
+    }
+}
+
 /// Takes in a stream of bytes that represent a RESP message
 /// and turns it into a printable debug string that escapes all the special characters
 pub fn resp_to_debug_str(bytes: impl IntoIterator<Item = u8>) -> String {
@@ -355,6 +966,16 @@ pub fn resp_to_debug_str(bytes: impl IntoIterator<Item = u8>) -> String {
     out
 }
 
+/// Maps raw bytes to a string holding one char per byte, the way bulk strings are parsed
+pub fn bytes_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Inverse of `bytes_to_string`
+pub fn string_to_bytes(s: &str) -> Vec<u8> {
+    s.chars().map(|c| c as u8).collect()
+}
+
 // Checks that bytes starts with a CLRF, returns the remaining bytes
 fn validate_clrf(bytes: &[u8]) -> ParseResult<&[u8]> {
     match bytes {
@@ -367,6 +988,10 @@ fn validate_clrf(bytes: &[u8]) -> ParseResult<&[u8]> {
 fn parse_integer_value(mut bytes: &[u8]) -> ParseResult<(i64, &[u8])> {
     let mut num: i64 = 0;
 
+    if bytes.is_empty() {
+        return Err(ParseError::NotEnoughBytes);
+    }
+
     // Deal with negative numbers
     let is_negative = bytes[0] == b'-';
     if is_negative {
@@ -375,10 +1000,13 @@ fn parse_integer_value(mut bytes: &[u8]) -> ParseResult<(i64, &[u8])> {
 
     while !bytes.is_empty() && bytes[0] != b'\r' {
         let digit = bytes[0];
-        if digit < b'0' || digit > b'9' {
+        if !digit.is_ascii_digit() {
             return Err(ParseError::UnexpectedNonNumericCharacter(bytes[0] as char));
         }
-        num = num * 10 + (digit - b'0') as i64;
+        num = num
+            .checked_mul(10)
+            .and_then(|num| num.checked_add((digit - b'0') as i64))
+            .ok_or(ParseError::ValueTooLarge)?;
         bytes = &bytes[1..];
     }
 
@@ -391,7 +1019,7 @@ fn parse_integer_value(mut bytes: &[u8]) -> ParseResult<(i64, &[u8])> {
 
 fn parse_simple_string_contents(mut bytes: &[u8]) -> ParseResult<(String, &[u8])> {
     let mut s = String::new();
-    while let Err(_) = validate_clrf(bytes) {
+    while !bytes.is_empty() && validate_clrf(bytes).is_err() {
         s.push(bytes[0] as char);
         bytes = &bytes[1..];
 
@@ -404,7 +1032,7 @@ fn parse_simple_string_contents(mut bytes: &[u8]) -> ParseResult<(String, &[u8])
 }
 
 fn parse_bulk_string_contents(bytes: &[u8]) -> ParseResult<(Option<String>, &[u8])> {
-    match parse_array_len(&bytes)? {
+    match parse_array_len(bytes)? {
         (Some(len), bytes) => {
             if bytes.len() <= len {
                 return Err(ParseError::NotEnoughBytes);
@@ -416,8 +1044,16 @@ fn parse_bulk_string_contents(bytes: &[u8]) -> ParseResult<(Option<String>, &[u8
     }
 }
 
+fn parse_values(len: usize, bytes: &[u8]) -> ParseResult<(Vec<RESPValue>, &[u8])> {
+    (0..len).try_fold((vec![], bytes), |(mut vec, bytes), _| {
+        let (value, bytes) = RESPValue::parse(bytes)?;
+        vec.push(value);
+        Ok((vec, bytes))
+    })
+}
+
 fn parse_array_len(bytes: &[u8]) -> ParseResult<(Option<usize>, &[u8])> {
-    let (len, bytes) = parse_integer_value(&bytes)?;
+    let (len, bytes) = parse_integer_value(bytes)?;
     match len {
         0.. => Ok((Some(len as usize), bytes)),
         -1 => Ok((None, bytes)),
@@ -450,6 +1086,58 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_bulk_string_binary_round_trip() {
+        let bytes = [b'$', b'2', b'\r', b'\n', 0xff, 0x00, b'\r', b'\n'];
+        let (value, _) = RESPValue::parse(&bytes[..]).unwrap();
+        assert_eq!(value.to_bytes(), bytes.to_vec());
+    }
+
+    #[test]
+    fn test_format_array() {
+        let value = RESPValue::Array(Some(vec![
+            RESPValue::BulkString(Some("hello".to_string())),
+            RESPValue::Array(Some(vec![])),
+            RESPValue::Integer(1),
+        ]));
+        assert_eq!(format!("{}", value), "*3\r\n$5\r\nhello\r\n*0\r\n:1\r\n");
+    }
+
+    #[test]
+    fn test_encode() {
+        let values = [
+            RESPValue::Integer(0),
+            RESPValue::Integer(-42),
+            RESPValue::Integer(i64::MIN),
+            RESPValue::BulkString(Some("\u{ff}\u{0}hello".to_string())),
+            RESPValue::BulkString(Some(String::new())),
+            RESPValue::SimpleString("\u{e9}".repeat(150)),
+            RESPValue::BulkString(None),
+            RESPValue::Error("ERR bad".to_string()),
+            RESPValue::Array(None),
+            RESPValue::Array(Some(vec![
+                RESPValue::SimpleString("OK".to_string()),
+                RESPValue::Array(Some(vec![RESPValue::Integer(1); 12])),
+            ])),
+            RESPValue::Push(vec![RESPValue::BulkString(None)]),
+            RESPValue::Map(vec![(RESPValue::Integer(1), RESPValue::Array(None))]),
+        ];
+        let mut buf = BytesMut::new();
+        for value in &values {
+            let expected = string_to_bytes(&value.to_string());
+            assert_eq!(value.to_bytes(), expected);
+            assert_eq!(value.encoded_len(), expected.len());
+
+            let mut written = vec![];
+            value.write_to(&mut written).unwrap();
+            assert_eq!(written, expected);
+
+            let start = buf.len();
+            value.encode_into(&mut buf);
+            assert_eq!(&buf[start..], &expected[..]);
+        }
+    }
+
     #[test]
     fn test_parse_null_bulk_string() {
         assert_eq!(
@@ -570,6 +1258,188 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_resp3() {
+        let push = RESPValue::Push(vec![
+            RESPValue::BulkString(Some("invalidate".to_string())),
+            RESPValue::Array(None),
+        ]);
+        assert_eq!(push.to_string(), ">2\r\n$10\r\ninvalidate\r\n*-1\r\n");
+        assert_eq!(
+            RESPValue::parse(&push.to_bytes()),
+            Ok((push, "".as_bytes()))
+        );
+
+        let map = RESPValue::Map(vec![(
+            RESPValue::BulkString(Some("proto".to_string())),
+            RESPValue::Integer(3),
+        )]);
+        assert_eq!(map.to_string(), "%1\r\n$5\r\nproto\r\n:3\r\n");
+        assert_eq!(
+            RESPValue::parse(&map.to_bytes()),
+            Ok((map.clone(), "".as_bytes()))
+        );
+        assert_eq!(
+            map.into_resp2(),
+            RESPValue::Array(Some(vec![
+                RESPValue::BulkString(Some("proto".to_string())),
+                RESPValue::Integer(3),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_args() {
+        let args: Vec<BulkString> = ["key", "12", "1.5", "nx", "PX", "100", "a", "b"]
+            .iter()
+            .map(|s| BulkString::from(s.to_string()))
+            .collect();
+        let mut args = Args::new("SET", &args);
+        assert_eq!(args.next_key(), Ok("key"));
+        assert_eq!(args.next_i64(), Ok(12));
+        assert_eq!(args.next_f64(), Ok(1.5));
+        assert!(!args.optional_flag("XX"));
+        assert!(args.optional_flag("NX"));
+        assert_eq!(args.keyword_with_value("EX"), Ok(None));
+        assert_eq!(args.keyword_with_value("px"), Ok(Some("100")));
+        assert_eq!(
+            args.end(),
+            Err(ArgError::WrongNumberOfArguments("set".to_string()))
+        );
+        assert_eq!(args.rest(), Ok(vec!["a", "b"]));
+        assert_eq!(args.end(), Ok(()));
+        assert_eq!(
+            RESPValue::from(args.next_str().unwrap_err()),
+            RESPValue::Error("ERR wrong number of arguments for 'set' command".to_string())
+        );
+    }
+
+    #[test]
+    fn test_args_errors() {
+        let args: Vec<BulkString> = ["01", "nan", "PX"]
+            .iter()
+            .map(|s| BulkString::from(s.to_string()))
+            .collect();
+        let mut args = Args::new("incr", &args);
+        assert_eq!(args.next_i64(), Err(ArgError::NotAnInteger));
+        assert_eq!(args.next_f64(), Err(ArgError::NotAFloat));
+        assert_eq!(args.keyword_with_value("PX"), Err(ArgError::Syntax));
+
+        let args = vec![BulkString::from("GET".to_string()), BulkString(None)];
+        assert_eq!(Args::new("", &args).rest(), Err(ArgError::NullArgument));
+        let parsed = Args::new("get", &args[1..]).parse(|args| args.next_key());
+        assert_eq!(parsed, Err(ArgError::NullArgument));
+        let parsed = Args::new("get", &args[..1]).parse(|_| Ok(()));
+        assert_eq!(
+            parsed,
+            Err(ArgError::WrongNumberOfArguments("get".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        let buf = Bytes::from_static(
+            b"*4\r\n$5\r\nhello\r\n:123\r\n-ERR\rBAD\r\n$-1\r\n%1\r\n+a\r\n*-1\r\nrest",
+        );
+        let (frame, len) = Frame::parse(&buf).unwrap();
+        assert_eq!(
+            frame,
+            Frame::Array(Some(vec![
+                Frame::BulkString(Some(Bytes::from_static(b"hello"))),
+                Frame::Integer(123),
+                Frame::Error(Bytes::from_static(b"ERR\rBAD")),
+                Frame::BulkString(None),
+            ]))
+        );
+        assert_eq!(Frame::check(&buf), Ok(len));
+        // Until the whole frame has been read, the rest of it is waited for
+        for end in 0..len {
+            assert_eq!(Frame::check(&buf[..end]), Err(ParseError::NotEnoughBytes));
+            assert_eq!(
+                Frame::parse(&buf.slice(..end)),
+                Err(ParseError::NotEnoughBytes)
+            );
+        }
+        let (map, map_len) = Frame::parse(&buf.slice(len..)).unwrap();
+        assert_eq!(&buf[len + map_len..], b"rest");
+        assert_eq!(
+            RESPValue::from(map),
+            RESPValue::Map(vec![(
+                RESPValue::SimpleString("a".to_string()),
+                RESPValue::Array(None)
+            )])
+        );
+
+        // The strings point into the buffer rather than being copied
+        if let Frame::Array(Some(frames)) = &frame {
+            if let Frame::BulkString(Some(s)) = &frames[0] {
+                assert_eq!(s.as_ptr(), buf[8..].as_ptr());
+            }
+        }
+
+        let binary = [b'$', b'2', b'\r', b'\n', 0xff, 0x00, b'\r', b'\n'];
+        let (frame, _) = Frame::parse(&Bytes::copy_from_slice(&binary)).unwrap();
+        assert_eq!(
+            RESPValue::from(frame),
+            RESPValue::parse(&binary[..]).unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_parse_frame_errors() {
+        for (input, error) in [
+            ("$5\r\nhell", ParseError::NotEnoughBytes),
+            ("$5\r\nhelloooo\r\n", ParseError::MissingCLRF),
+            ("$5\r\nhello\r", ParseError::NotEnoughBytes),
+            ("+OK", ParseError::NotEnoughBytes),
+            ("*", ParseError::NotEnoughBytes),
+            ("*3\r\n$3", ParseError::NotEnoughBytes),
+            ("*99999999999999999999\r\n", ParseError::ValueTooLarge),
+            ("%2147483648\r\n", ParseError::ValueTooLarge),
+            ("$536870913\r\n", ParseError::ValueTooLarge),
+            ("$-2\r\n", ParseError::NegativeValueLength),
+            (":1x\r\n", ParseError::UnexpectedNonNumericCharacter('x')),
+            ("*2\r\n$5\r\nhello\r\n", ParseError::NotEnoughBytes),
+            ("x", ParseError::UnknownDataType('x')),
+            (":\r\n", ParseError::MissingDigits),
+            ("*-\r\n", ParseError::MissingDigits),
+            ("$\r\n\r\n", ParseError::MissingDigits),
+        ] {
+            assert_eq!(Frame::check(input.as_bytes()), Err(error));
+            assert_eq!(Frame::parse(&Bytes::from(input)), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$2\r\n\xff\n\r\n*-1\r\n";
+        let args = ["SET", "k", "\u{ff}\n"]
+            .iter()
+            .map(|a| BulkString::from(a.to_string()))
+            .collect();
+        let (command, len) = parse_command(buf).unwrap();
+        assert_eq!(command, Some(args));
+        assert_eq!(parse_command(&buf[len..]), Ok((None, 5)));
+        for end in 0..len {
+            assert_eq!(parse_command(&buf[..end]), Err(ParseError::NotEnoughBytes));
+        }
+        assert_eq!(
+            parse_command(b"*1\r\n:1\r\n"),
+            Err(ParseError::UnexpectedDataType(
+                RESPDataType::BulkString,
+                RESPDataType::Integer
+            ))
+        );
+        assert_eq!(
+            parse_command(b"+PING\r\n"),
+            Err(ParseError::UnexpectedDataType(
+                RESPDataType::Array,
+                RESPDataType::SimpleString
+            ))
+        );
+        assert_eq!(parse_command(b"*\r\n"), Err(ParseError::MissingDigits));
+    }
+
     #[test]
     fn test_parse_array_negative_len() {
         assert_eq!(
@@ -594,6 +1464,16 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_truncated() {
+        assert_eq!(RESPValue::parse("*"), Err(ParseError::NotEnoughBytes));
+        assert_eq!(RESPValue::parse("+"), Err(ParseError::MissingCLRF));
+        assert_eq!(
+            RESPValue::parse(":99999999999999999999\r\n"),
+            Err(ParseError::ValueTooLarge)
+        );
+    }
+
     #[test]
     fn test_parse_data_type() {
         assert_eq!(RESPDataType::try_from(b'+'), Ok(RESPDataType::SimpleString));
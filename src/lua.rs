@@ -0,0 +1,3242 @@
+// An interpreter for the Lua used by function libraries, following Lua 5.1 which Redis embeds.
+//
+// We can't pull in a real Lua engine, so this implements the language itself: locals, multiple
+// assignment, tables, closures, varargs, method calls, every loop and the usual operators, along
+// with the base, string, table and math libraries and the `redis.*` helpers. Metatables,
+// coroutines, loading code at runtime and Redis' extra libraries (cjson, cmsgpack, bit, struct)
+// aren't supported.
+use crate::lua_patterns;
+use redis_starter_rust::RESPValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::PoisonError;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Table(Arc<Mutex<Table>>),
+    Function(Arc<Closure>),
+    Builtin(Builtin),
+    // The iterator string.gmatch returns
+    Matches(Arc<Mutex<Matches>>),
+}
+
+// How a value is found as a table key. Tables, functions and iterators are keys by identity.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Boolean(bool),
+    Number(u64),
+    String(String),
+    Reference(usize),
+    Builtin(Builtin),
+}
+
+impl Key {
+    fn new(value: &Value) -> Result<Key, String> {
+        Ok(match value {
+            Value::Nil => return Err("table index is nil".to_string()),
+            Value::Number(n) if n.is_nan() => return Err("table index is NaN".to_string()),
+            Value::Boolean(b) => Key::Boolean(*b),
+            // Adding 0 turns -0 into 0, which is the same key
+            Value::Number(n) => Key::Number((n + 0.0).to_bits()),
+            Value::String(s) => Key::String(s.clone()),
+            Value::Table(t) => Key::Reference(Arc::as_ptr(t) as usize),
+            Value::Function(f) => Key::Reference(Arc::as_ptr(f) as usize),
+            Value::Matches(m) => Key::Reference(Arc::as_ptr(m) as usize),
+            Value::Builtin(b) => Key::Builtin(*b),
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Table {
+    // The values at the keys 1 to n, so that arrays, the common case, stay cheap. It never ends
+    // with a nil, and the key n + 1 is never among the entries, so n is the table's length.
+    array: Vec<Value>,
+    // Every other key in insertion order, which next follows. A removed key keeps its place with
+    // a nil value so that a traversal clearing fields can go on, until new keys compact them.
+    entries: Vec<(Value, Value)>,
+    positions: HashMap<Key, usize>,
+    removed: usize,
+}
+
+// The position in the array of a key that could be in it
+fn array_index(key: &Value) -> Option<usize> {
+    match key {
+        Value::Number(n) if *n >= 1.0 && n.fract() == 0.0 && *n <= usize::MAX as f64 => {
+            Some(*n as usize - 1)
+        }
+        _ => None,
+    }
+}
+
+impl Table {
+    fn get(&self, key: &Value) -> Value {
+        if let Some(value) = array_index(key).and_then(|i| self.array.get(i)) {
+            return value.clone();
+        }
+        Key::new(key)
+            .ok()
+            .and_then(|k| self.positions.get(&k))
+            .map(|&p| self.entries[p].1.clone())
+            .unwrap_or(Value::Nil)
+    }
+
+    fn field(&self, name: &str) -> Value {
+        self.get(&Value::String(name.to_string()))
+    }
+
+    fn len(&self) -> usize {
+        self.array.len()
+    }
+
+    fn set(&mut self, key: Value, value: Value) -> Result<(), String> {
+        if let Some(i) = array_index(&key) {
+            if i < self.array.len() {
+                self.array[i] = value;
+                self.trim();
+                return Ok(());
+            }
+            if i == self.array.len() && !matches!(value, Value::Nil) {
+                self.array.push(value);
+                self.migrate();
+                return Ok(());
+            }
+        }
+        let k = Key::new(&key)?;
+        self.set_entry(k, key, value);
+        Ok(())
+    }
+
+    fn set_field(&mut self, name: &str, value: Value) {
+        let key = Value::String(name.to_string());
+        self.set_entry(Key::String(name.to_string()), key, value);
+    }
+
+    fn set_entry(&mut self, k: Key, key: Value, value: Value) {
+        match self.positions.get(&k) {
+            Some(&p) => {
+                let was_nil = matches!(self.entries[p].1, Value::Nil);
+                match (was_nil, matches!(value, Value::Nil)) {
+                    (false, true) => self.removed += 1,
+                    (true, false) => self.removed -= 1,
+                    _ => {}
+                }
+                self.entries[p].1 = value;
+            }
+            None if matches!(value, Value::Nil) => {}
+            None => {
+                // Only adding keys compacts, which a traversal isn't allowed to do
+                if self.removed > self.entries.len() / 2 {
+                    self.compact();
+                }
+                self.positions.insert(k, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    fn trim(&mut self) {
+        while matches!(self.array.last(), Some(Value::Nil)) {
+            self.array.pop();
+        }
+    }
+
+    // Moves the keys following the array from the entries into it once the array reaches them
+    fn migrate(&mut self) {
+        loop {
+            let next = Key::Number(((self.array.len() + 1) as f64).to_bits());
+            match self.positions.get(&next) {
+                Some(&p) if !matches!(self.entries[p].1, Value::Nil) => {
+                    let value = std::mem::replace(&mut self.entries[p].1, Value::Nil);
+                    self.removed += 1;
+                    self.array.push(value);
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, v)| !matches!(v, Value::Nil));
+        self.positions = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(p, (key, _))| Some((Key::new(key).ok()?, p)))
+            .collect();
+        self.removed = 0;
+    }
+
+    // The entry after `key` in a traversal, the array first
+    fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, String> {
+        let mut position = match key {
+            Value::Nil => 0,
+            _ => match array_index(key) {
+                Some(i) if i < self.array.len() => i + 1,
+                _ => match Key::new(key).ok().and_then(|k| self.positions.get(&k)) {
+                    Some(&p) => self.array.len() + p + 1,
+                    // Clearing the last elements of the array shrinks it, what followed them
+                    // were nils, so the traversal goes on with the entries
+                    None if array_index(key).is_some() => self.array.len(),
+                    None => return Err("invalid key to 'next'".to_string()),
+                },
+            },
+        };
+        while position < self.array.len() + self.entries.len() {
+            let (key, value) = match self.array.get(position) {
+                Some(value) => (Value::Number((position + 1) as f64), value),
+                None => {
+                    let (key, value) = &self.entries[position - self.array.len()];
+                    (key.clone(), value)
+                }
+            };
+            if !matches!(value, Value::Nil) {
+                return Ok(Some((key, value.clone())));
+            }
+            position += 1;
+        }
+        Ok(None)
+    }
+}
+
+// Tables can hold themselves
+impl fmt::Debug for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Table")
+            .field("len", &self.array.len())
+            .field("entries", &(self.entries.len() - self.removed))
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Matches {
+    s: Vec<char>,
+    pattern: Vec<char>,
+    position: usize,
+}
+
+// The locals of a block. Closures share the scopes they were defined in rather than copying
+// them, so they see later assignments, including the local a recursive function is stored in.
+type Scope = Arc<Mutex<HashMap<String, Value>>>;
+
+fn new_scope() -> Scope {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+// A panic while a scope or a table is locked can't leave it half updated, the lock is just
+// taken over. Nothing calls back into the script while holding one.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub struct Closure {
+    def: Arc<FunctionDef>,
+    captured: Vec<Scope>,
+}
+
+// The captured scopes may hold the closure itself
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("params", &self.def.params)
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Builtin {
+    Call,
+    PCall,
+    RegisterFunction,
+    ErrorReply,
+    StatusReply,
+    Log,
+    Assert,
+    Error,
+    IPairs,
+    IPairsNext,
+    Next,
+    Pairs,
+    ProtectedCall,
+    Select,
+    ToNumber,
+    ToString,
+    Type,
+    Unpack,
+    StringByte,
+    StringChar,
+    StringFind,
+    StringFormat,
+    StringGmatch,
+    StringGsub,
+    StringLen,
+    StringLower,
+    StringMatch,
+    StringRep,
+    StringReverse,
+    StringSub,
+    StringUpper,
+    TableConcat,
+    TableGetn,
+    TableInsert,
+    TableRemove,
+    TableSort,
+    MathAbs,
+    MathCeil,
+    MathFloor,
+    MathFmod,
+    MathMax,
+    MathMin,
+    MathSqrt,
+}
+
+const REDIS_LIBRARY: &[(&str, Builtin)] = &[
+    ("call", Builtin::Call),
+    ("pcall", Builtin::PCall),
+    ("register_function", Builtin::RegisterFunction),
+    ("error_reply", Builtin::ErrorReply),
+    ("status_reply", Builtin::StatusReply),
+    ("log", Builtin::Log),
+];
+
+const BASE_LIBRARY: &[(&str, Builtin)] = &[
+    ("assert", Builtin::Assert),
+    ("error", Builtin::Error),
+    ("ipairs", Builtin::IPairs),
+    ("next", Builtin::Next),
+    ("pairs", Builtin::Pairs),
+    ("pcall", Builtin::ProtectedCall),
+    ("select", Builtin::Select),
+    ("tonumber", Builtin::ToNumber),
+    ("tostring", Builtin::ToString),
+    ("type", Builtin::Type),
+    ("unpack", Builtin::Unpack),
+];
+
+const STRING_LIBRARY: &[(&str, Builtin)] = &[
+    ("byte", Builtin::StringByte),
+    ("char", Builtin::StringChar),
+    ("find", Builtin::StringFind),
+    ("format", Builtin::StringFormat),
+    ("gmatch", Builtin::StringGmatch),
+    ("gsub", Builtin::StringGsub),
+    ("len", Builtin::StringLen),
+    ("lower", Builtin::StringLower),
+    ("match", Builtin::StringMatch),
+    ("rep", Builtin::StringRep),
+    ("reverse", Builtin::StringReverse),
+    ("sub", Builtin::StringSub),
+    ("upper", Builtin::StringUpper),
+];
+
+const TABLE_LIBRARY: &[(&str, Builtin)] = &[
+    ("concat", Builtin::TableConcat),
+    ("getn", Builtin::TableGetn),
+    ("insert", Builtin::TableInsert),
+    ("remove", Builtin::TableRemove),
+    ("sort", Builtin::TableSort),
+];
+
+const MATH_LIBRARY: &[(&str, Builtin)] = &[
+    ("abs", Builtin::MathAbs),
+    ("ceil", Builtin::MathCeil),
+    ("floor", Builtin::MathFloor),
+    ("fmod", Builtin::MathFmod),
+    ("max", Builtin::MathMax),
+    ("min", Builtin::MathMin),
+    ("sqrt", Builtin::MathSqrt),
+];
+
+// The levels of redis.log, as the LOG_* constants
+const LOG_LEVELS: &[&str] = &["LOG_DEBUG", "LOG_VERBOSE", "LOG_NOTICE", "LOG_WARNING"];
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) | Value::Builtin(_) | Value::Matches(_) => "function",
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::String(s) => parse_number(s),
+            _ => None,
+        }
+    }
+
+    fn as_string(&self) -> Option<String> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(format_number(*n)),
+            _ => None,
+        }
+    }
+
+    fn table(array: Vec<Value>, fields: Vec<(&str, Value)>) -> Self {
+        let mut table = Table {
+            array,
+            ..Table::default()
+        };
+        table.trim();
+        for (name, value) in fields {
+            table.set_field(name, value);
+        }
+        Value::Table(Arc::new(Mutex::new(table)))
+    }
+}
+
+// Lua's luaO_str2d: decimal numbers with or without an exponent, and hexadecimal integers
+fn parse_number(s: &str) -> Option<f64> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().map(|n| n as f64),
+        // Rust would also take "inf" and "nan"
+        None if s.contains(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E') => None,
+        None => s.parse().ok(),
+    }
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        // Lua's LUA_NUMBER_FMT
+        format_general(n, 14, false)
+    }
+}
+
+// C's %e, with at least two digits of exponent
+fn format_exponent(n: f64, precision: usize) -> String {
+    let formatted = format!("{:.*e}", precision, n);
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap_or(0));
+    let exponent: i32 = exponent[1..].parse().unwrap_or(0);
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+}
+
+// C's %g: %e or %f depending on the exponent, without trailing zeros unless `alternate`
+fn format_general(n: f64, precision: usize, alternate: bool) -> String {
+    if !n.is_finite() {
+        return to_display_special(n);
+    }
+    let precision = precision.max(1);
+    let exponent = if n == 0.0 {
+        0
+    } else {
+        let formatted = format!("{:.*e}", precision - 1, n);
+        formatted[formatted.find('e').unwrap_or(0) + 1..]
+            .parse()
+            .unwrap_or(0)
+    };
+    let formatted = if exponent < -4 || exponent >= precision as i32 {
+        format_exponent(n, precision - 1)
+    } else {
+        format!("{:.*}", (precision as i32 - 1 - exponent) as usize, n)
+    };
+    if alternate || !formatted.contains('.') {
+        return formatted;
+    }
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap_or(formatted.len()));
+    format!(
+        "{}{}",
+        mantissa.trim_end_matches('0').trim_end_matches('.'),
+        exponent
+    )
+}
+
+fn to_display_special(n: f64) -> String {
+    match n {
+        n if n.is_nan() => "nan".to_string(),
+        n if n > 0.0 => "inf".to_string(),
+        _ => "-inf".to_string(),
+    }
+}
+
+// What tostring gives
+fn to_display(value: &Value) -> String {
+    match value {
+        Value::Nil => "nil".to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Number(n) => format_number(*n),
+        Value::String(s) => s.clone(),
+        Value::Table(t) => format!("table: {:p}", Arc::as_ptr(t)),
+        Value::Function(f) => format!("function: {:p}", Arc::as_ptr(f)),
+        Value::Matches(m) => format!("function: {:p}", Arc::as_ptr(m)),
+        Value::Builtin(b) => format!("function: builtin: {:?}", b),
+    }
+}
+
+// The message of an error raised with `value`: a string, or the `err` field of a table like
+// the ones redis.call raises
+fn error_message(value: &Value) -> String {
+    if let Value::Table(t) = value {
+        if let Value::String(e) = lock(t).field("err") {
+            return e;
+        }
+    }
+    value
+        .as_string()
+        .unwrap_or_else(|| format!("(error object is a {} value)", value.type_name()))
+}
+
+/// A function registered from a library through `redis.register_function`
+pub struct Registration {
+    pub name: String,
+    pub callback: Arc<Closure>,
+    pub flags: Vec<String>,
+    pub description: Option<String>,
+}
+
+/// The interpreter's view of the server
+pub trait Host {
+    /// Runs a command on behalf of the script. Command errors come back as `RESPValue::Error`.
+    fn call(&mut self, args: Vec<String>) -> RESPValue;
+    fn register_function(&mut self, registration: Registration) -> Result<(), String>;
+    /// Checked every so many steps of the script, an error stops it
+    fn check_running(&mut self) -> Result<(), String>;
+}
+
+/// Parses and runs the top level of a library
+pub fn load(code: &str, host: &mut dyn Host) -> Result<(), String> {
+    let block = Parser::new(tokenize(code)?).parse_chunk()?;
+    let mut interpreter = Interpreter::new(host);
+    interpreter.exec_block(&block)?;
+    Ok(())
+}
+
+/// Invokes a registered function callback and converts its return value to a reply
+pub fn call(
+    closure: &Closure,
+    keys: Vec<String>,
+    args: Vec<String>,
+    host: &mut dyn Host,
+) -> Result<RESPValue, String> {
+    let to_table =
+        |v: Vec<String>| Value::table(v.into_iter().map(Value::String).collect(), vec![]);
+    let mut interpreter = Interpreter::new(host);
+    let results = interpreter.call_closure(closure, vec![to_table(keys), to_table(args)])?;
+    Ok(to_resp(
+        results.first().unwrap_or(&Value::Nil),
+        MAX_CALL_DEPTH,
+    ))
+}
+
+// Tables nest at most `depth` more levels, they can hold themselves
+fn to_resp(value: &Value, depth: usize) -> RESPValue {
+    match value {
+        Value::Number(n) => RESPValue::integer(*n as i64),
+        Value::String(s) => RESPValue::bulk_string(Some(s.clone())),
+        Value::Boolean(true) => RESPValue::integer(1),
+        Value::Table(_) if depth == 0 => {
+            RESPValue::error("ERR reached lua stack limit".to_string())
+        }
+        Value::Table(t) => {
+            let (err, ok, array) = {
+                let t = lock(t);
+                (t.field("err"), t.field("ok"), t.array.clone())
+            };
+            match (err, ok) {
+                (Value::String(e), _) => RESPValue::error(e),
+                (_, Value::String(s)) => RESPValue::simple_string(s),
+                // Like Redis, the array stops at the first nil
+                _ => RESPValue::Array(Some(
+                    array
+                        .iter()
+                        .take_while(|v| !matches!(v, Value::Nil))
+                        .map(|v| to_resp(v, depth - 1))
+                        .collect(),
+                )),
+            }
+        }
+        _ => RESPValue::bulk_string(None),
+    }
+}
+
+fn from_resp(value: RESPValue) -> Value {
+    match value {
+        RESPValue::Integer(i) => Value::Number(i as f64),
+        RESPValue::BulkString(Some(s)) => Value::String(s),
+        RESPValue::BulkString(None) | RESPValue::Array(None) => Value::Boolean(false),
+        RESPValue::SimpleString(s) => Value::table(vec![], vec![("ok", Value::String(s))]),
+        RESPValue::Error(e) => Value::table(vec![], vec![("err", Value::String(e))]),
+        RESPValue::Array(Some(values)) | RESPValue::Push(values) => {
+            Value::table(values.into_iter().map(from_resp).collect(), vec![])
+        }
+        map @ RESPValue::Map(_) => from_resp(map.into_resp2()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Name(String),
+    String(String),
+    Number(f64),
+    Symbol(&'static str),
+    Eof,
+}
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in", "local",
+    "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+// How many steps a script takes between checks of whether it should stop
+const CHECK_INTERVAL: u64 = 1000;
+// Lua's LUAI_MAXCCALLS, which also keeps deep recursion from overflowing the thread's stack.
+// It bounds nested calls as well as nested syntax.
+const MAX_CALL_DEPTH: usize = 200;
+// Lua's LUAI_MAXCSTACK, the most values unpack can return
+const MAX_RESULTS: i64 = 8000;
+// Redis' proto-max-bulk-len, the longest string string.rep builds
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+// Longest symbols first so that e.g. ".." wins over "."
+const SYMBOLS: &[&str] = &[
+    "...", "..", "==", "~=", "<=", ">=", "(", ")", "{", "}", "[", "]", ",", ";", ":", ".", "=",
+    "<", ">", "+", "-", "*", "/", "%", "^", "#",
+];
+
+// The level of the long bracket opening at `i`, like `[[` or `[==[`
+fn long_bracket(chars: &[char], i: usize) -> Option<usize> {
+    if chars.get(i) != Some(&'[') {
+        return None;
+    }
+    let level = chars[i + 1..].iter().take_while(|&&c| c == '=').count();
+    if chars.get(i + 1 + level) == Some(&'[') {
+        Some(level)
+    } else {
+        None
+    }
+}
+
+// Reads the text of the long bracket of `level` opening at `i`, returning it and where it ends
+fn read_long_bracket(chars: &[char], i: usize, level: usize) -> Option<(String, usize)> {
+    let mut start = i + level + 2;
+    // A newline right after the opening bracket isn't part of the text
+    if chars.get(start) == Some(&'\n') {
+        start += 1;
+    }
+    let mut closing = vec![']'];
+    closing.extend(std::iter::repeat_n('=', level));
+    closing.push(']');
+    let end = (start..chars.len()).find(|&end| chars[end..].starts_with(&closing))?;
+    Some((chars[start..end].iter().collect(), end + closing.len()))
+}
+
+fn tokenize(code: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = code.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    // Skip a shebang line, libraries start with one
+    if chars.starts_with(&['#', '!']) {
+        while i < chars.len() && chars[i] != '\n' {
+            i += 1;
+        }
+    }
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && chars.get(i + 1) == Some(&'-') {
+            i += 2;
+            if let Some(level) = long_bracket(&chars, i) {
+                let (_, end) = read_long_bracket(&chars, i, level)
+                    .ok_or_else(|| "unfinished long comment".to_string())?;
+                i = end;
+            } else {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+        } else if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None | Some('\n') => return Err("unfinished string".to_string()),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        s.push(match chars.get(i) {
+                            Some('a') => '\x07',
+                            Some('b') => '\x08',
+                            Some('f') => '\x0c',
+                            Some('n') => '\n',
+                            Some('r') => '\r',
+                            Some('t') => '\t',
+                            Some('v') => '\x0b',
+                            // Up to three decimal digits give the byte
+                            Some(d) if d.is_ascii_digit() => {
+                                let digits = chars[i..]
+                                    .iter()
+                                    .take(3)
+                                    .take_while(|d| d.is_ascii_digit())
+                                    .count();
+                                let code: u32 = chars[i..i + digits]
+                                    .iter()
+                                    .fold(0, |code, d| code * 10 + d.to_digit(10).unwrap_or(0));
+                                i += digits - 1;
+                                if code > 255 {
+                                    return Err("escape sequence too large".to_string());
+                                }
+                                char::from(code as u8)
+                            }
+                            Some(&other) => other,
+                            None => return Err("unfinished string".to_string()),
+                        });
+                    }
+                    Some(&other) => s.push(other),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::String(s));
+        } else if let Some(level) = long_bracket(&chars, i) {
+            let (s, end) = read_long_bracket(&chars, i, level)
+                .ok_or_else(|| "unfinished long string".to_string())?;
+            tokens.push(Token::String(s));
+            i = end;
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        {
+            // Like Lua's read_numeral, take everything that could belong to the number and
+            // let the conversion reject what doesn't
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                i += 1;
+                if i < chars.len() && (chars[i] == '+' || chars[i] == '-') {
+                    i += 1;
+                }
+            }
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            tokens
+                .push(Token::Number(parse_number(&literal).ok_or_else(|| {
+                    format!("malformed number near '{}'", literal)
+                })?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Name(chars[start..i].iter().collect()));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| {
+                    s.chars()
+                        .enumerate()
+                        .all(|(j, sc)| chars.get(i + j) == Some(&sc))
+                })
+                .ok_or_else(|| format!("unexpected symbol near '{}'", c))?;
+            i += symbol.len();
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+
+    tokens.push(Token::Eof);
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+pub struct FunctionDef {
+    params: Vec<String>,
+    // Whether the parameters end with `...`
+    variadic: bool,
+    body: Block,
+}
+
+type Block = Vec<Statement>;
+
+#[derive(Debug)]
+enum Statement {
+    Local(Vec<String>, Vec<Expr>),
+    // The targets are names or indexes
+    Assign(Vec<Expr>, Vec<Expr>),
+    Call(Expr),
+    Do(Block),
+    If(Vec<(Expr, Block)>, Option<Block>),
+    NumericFor(String, Expr, Expr, Option<Expr>, Block),
+    GenericFor(Vec<String>, Vec<Expr>, Block),
+    While(Expr, Block),
+    Repeat(Block, Expr),
+    Break,
+    Return(Vec<Expr>),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(Value),
+    Name(String),
+    VarArgs,
+    // Parentheses keep only the first value of a call or `...`
+    Paren(Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Method(Box<Expr>, String, Vec<Expr>),
+    Function(Arc<FunctionDef>),
+    Table(Vec<Field>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Length(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug)]
+enum Field {
+    Positional(Expr),
+    Keyed(Expr, Expr),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // How many loops enclose the current statement in the current function, for `break`
+    loops: usize,
+    // Whether the current function can use `...`
+    variadic: bool,
+    depth: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self {
+            tokens,
+            pos: 0,
+            loops: 0,
+            // The main chunk is variadic, like in Lua
+            variadic: true,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn check_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Token::Symbol(s) if *s == symbol)
+    }
+
+    fn check_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Name(n) if n == keyword)
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = self.check_symbol(symbol);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.check_keyword(keyword);
+        if found {
+            self.advance();
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(format!("'{}' expected near {}", symbol, self.describe()))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(format!("'{}' expected near {}", keyword, self.describe()))
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Token::Name(n) if !KEYWORDS.contains(&n.as_str()) => {
+                let n = n.clone();
+                self.advance();
+                Ok(n)
+            }
+            _ => Err(format!("<name> expected near {}", self.describe())),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            Token::Name(n) => format!("'{}'", n),
+            Token::String(s) => format!("'{}'", s),
+            Token::Number(n) => format!("'{}'", format_number(*n)),
+            Token::Symbol(s) => format!("'{}'", s),
+            Token::Eof => "<eof>".to_string(),
+        }
+    }
+
+    // Enters one more level of nested syntax, which the interpreter recurses into
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_CALL_DEPTH {
+            return Err(format!(
+                "chunk has too many syntax levels near {}",
+                self.describe()
+            ));
+        }
+        Ok(())
+    }
+
+    fn parse_chunk(&mut self) -> Result<Block, String> {
+        let block = self.parse_block()?;
+        if *self.peek() != Token::Eof {
+            return Err(format!("'<eof>' expected near {}", self.describe()));
+        }
+        Ok(block)
+    }
+
+    fn at_block_end(&self) -> bool {
+        *self.peek() == Token::Eof
+            || ["end", "else", "elseif", "until"]
+                .iter()
+                .any(|k| self.check_keyword(k))
+    }
+
+    fn parse_block(&mut self) -> Result<Block, String> {
+        self.enter()?;
+        let mut block = vec![];
+        while !self.at_block_end() {
+            if self.eat_symbol(";") {
+                continue;
+            }
+            let statement = if self.eat_keyword("return") {
+                let values = if self.at_block_end() || self.check_symbol(";") {
+                    vec![]
+                } else {
+                    self.parse_expr_list()?
+                };
+                Statement::Return(values)
+            } else {
+                self.parse_statement()?
+            };
+            // Like in Lua 5.1, nothing can follow a return or a break in its block
+            if let Statement::Return(_) | Statement::Break = statement {
+                block.push(statement);
+                self.eat_symbol(";");
+                if !self.at_block_end() {
+                    return Err(format!("'end' expected near {}", self.describe()));
+                }
+                break;
+            }
+            block.push(statement);
+        }
+        self.depth -= 1;
+        Ok(block)
+    }
+
+    fn parse_loop_block(&mut self) -> Result<Block, String> {
+        self.loops += 1;
+        let block = self.parse_block()?;
+        self.loops -= 1;
+        Ok(block)
+    }
+
+    fn parse_statement(&mut self) -> Result<Statement, String> {
+        if self.eat_keyword("local") {
+            if self.eat_keyword("function") {
+                let name = self.expect_name()?;
+                let def = self.parse_function_body(false)?;
+                return Ok(Statement::Local(vec![name], vec![Expr::Function(def)]));
+            }
+            let mut names = vec![self.expect_name()?];
+            while self.eat_symbol(",") {
+                names.push(self.expect_name()?);
+            }
+            let values = if self.eat_symbol("=") {
+                self.parse_expr_list()?
+            } else {
+                vec![]
+            };
+            return Ok(Statement::Local(names, values));
+        }
+        if self.eat_keyword("function") {
+            // `function a.b:c()` assigns to a field, a method gets `self` as its first parameter
+            let mut target = Expr::Name(self.expect_name()?);
+            let mut method = false;
+            while !method && (self.check_symbol(".") || self.check_symbol(":")) {
+                method = self.eat_symbol(":");
+                if !method {
+                    self.advance();
+                }
+                let field = self.expect_name()?;
+                target = Expr::Index(
+                    Box::new(target),
+                    Box::new(Expr::Literal(Value::String(field))),
+                );
+            }
+            let def = self.parse_function_body(method)?;
+            return Ok(Statement::Assign(vec![target], vec![Expr::Function(def)]));
+        }
+        if self.eat_keyword("do") {
+            let body = self.parse_block()?;
+            self.expect_keyword("end")?;
+            return Ok(Statement::Do(body));
+        }
+        if self.eat_keyword("if") {
+            let mut branches = vec![];
+            let condition = self.parse_expr()?;
+            self.expect_keyword("then")?;
+            branches.push((condition, self.parse_block()?));
+            let mut otherwise = None;
+            loop {
+                if self.eat_keyword("elseif") {
+                    let condition = self.parse_expr()?;
+                    self.expect_keyword("then")?;
+                    branches.push((condition, self.parse_block()?));
+                } else if self.eat_keyword("else") {
+                    otherwise = Some(self.parse_block()?);
+                    self.expect_keyword("end")?;
+                    break;
+                } else {
+                    self.expect_keyword("end")?;
+                    break;
+                }
+            }
+            return Ok(Statement::If(branches, otherwise));
+        }
+        if self.eat_keyword("for") {
+            let name = self.expect_name()?;
+            if self.eat_symbol("=") {
+                let start = self.parse_expr()?;
+                self.expect_symbol(",")?;
+                let stop = self.parse_expr()?;
+                let step = if self.eat_symbol(",") {
+                    Some(self.parse_expr()?)
+                } else {
+                    None
+                };
+                self.expect_keyword("do")?;
+                let body = self.parse_loop_block()?;
+                self.expect_keyword("end")?;
+                return Ok(Statement::NumericFor(name, start, stop, step, body));
+            }
+            let mut names = vec![name];
+            while self.eat_symbol(",") {
+                names.push(self.expect_name()?);
+            }
+            self.expect_keyword("in")?;
+            let values = self.parse_expr_list()?;
+            self.expect_keyword("do")?;
+            let body = self.parse_loop_block()?;
+            self.expect_keyword("end")?;
+            return Ok(Statement::GenericFor(names, values, body));
+        }
+        if self.eat_keyword("while") {
+            let condition = self.parse_expr()?;
+            self.expect_keyword("do")?;
+            let body = self.parse_loop_block()?;
+            self.expect_keyword("end")?;
+            return Ok(Statement::While(condition, body));
+        }
+        if self.eat_keyword("repeat") {
+            let body = self.parse_loop_block()?;
+            self.expect_keyword("until")?;
+            let condition = self.parse_expr()?;
+            return Ok(Statement::Repeat(body, condition));
+        }
+        if self.check_keyword("break") {
+            if self.loops == 0 {
+                return Err(format!("no loop to break near {}", self.describe()));
+            }
+            self.advance();
+            return Ok(Statement::Break);
+        }
+
+        let expr = self.parse_suffixed()?;
+        if self.check_symbol("=") || self.check_symbol(",") {
+            let mut targets = vec![expr];
+            while self.eat_symbol(",") {
+                targets.push(self.parse_suffixed()?);
+            }
+            if !targets
+                .iter()
+                .all(|t| matches!(t, Expr::Name(_) | Expr::Index(..)))
+            {
+                return Err(format!("syntax error near {}", self.describe()));
+            }
+            self.expect_symbol("=")?;
+            let values = self.parse_expr_list()?;
+            return Ok(Statement::Assign(targets, values));
+        }
+        match expr {
+            Expr::Call(..) | Expr::Method(..) => Ok(Statement::Call(expr)),
+            _ => Err(format!("syntax error near {}", self.describe())),
+        }
+    }
+
+    fn parse_function_body(&mut self, method: bool) -> Result<Arc<FunctionDef>, String> {
+        self.expect_symbol("(")?;
+        let mut params = vec![];
+        if method {
+            params.push("self".to_string());
+        }
+        let mut variadic = false;
+        if !self.check_symbol(")") {
+            loop {
+                if self.eat_symbol("...") {
+                    variadic = true;
+                    break;
+                }
+                params.push(self.expect_name()?);
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        self.expect_symbol(")")?;
+        // Loops and `...` of the enclosing function don't carry over into this one
+        let loops = std::mem::replace(&mut self.loops, 0);
+        let enclosing_variadic = std::mem::replace(&mut self.variadic, variadic);
+        let body = self.parse_block()?;
+        self.loops = loops;
+        self.variadic = enclosing_variadic;
+        self.expect_keyword("end")?;
+        Ok(Arc::new(FunctionDef {
+            params,
+            variadic,
+            body,
+        }))
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, String> {
+        self.parse_binary(0)
+    }
+
+    fn parse_expr_list(&mut self) -> Result<Vec<Expr>, String> {
+        let mut exprs = vec![self.parse_expr()?];
+        while self.eat_symbol(",") {
+            exprs.push(self.parse_expr()?);
+        }
+        Ok(exprs)
+    }
+
+    // Binary operator precedence, lowest first. ".." and "^" are right associative.
+    fn binary_precedence(&self) -> Option<(&'static str, u8)> {
+        let op = match self.peek() {
+            Token::Name(n) if n == "or" => "or",
+            Token::Name(n) if n == "and" => "and",
+            Token::Symbol(s) => s,
+            _ => return None,
+        };
+        let precedence = match op {
+            "or" => 1,
+            "and" => 2,
+            "<" | ">" | "<=" | ">=" | "~=" | "==" => 3,
+            ".." => 4,
+            "+" | "-" => 5,
+            "*" | "/" | "%" => 6,
+            "^" => 8,
+            _ => return None,
+        };
+        Some((op, precedence))
+    }
+
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        self.enter()?;
+        let mut levels = 1;
+        let mut lhs = self.parse_unary()?;
+        while let Some((op, precedence)) = self.binary_precedence() {
+            if precedence <= min_precedence {
+                break;
+            }
+            self.advance();
+            // Each operator nests what came before it one level deeper
+            self.enter()?;
+            levels += 1;
+            let next_min = if op == ".." || op == "^" {
+                precedence - 1
+            } else {
+                precedence
+            };
+            let rhs = self.parse_binary(next_min)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        self.depth -= levels;
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        // Unary operators bind tighter than every binary operator but "^"
+        const UNARY_PRECEDENCE: u8 = 7;
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_binary(UNARY_PRECEDENCE)?)));
+        }
+        if self.eat_symbol("-") {
+            return Ok(Expr::Negate(Box::new(self.parse_binary(UNARY_PRECEDENCE)?)));
+        }
+        if self.eat_symbol("#") {
+            return Ok(Expr::Length(Box::new(self.parse_binary(UNARY_PRECEDENCE)?)));
+        }
+        self.parse_simple()
+    }
+
+    fn parse_simple(&mut self) -> Result<Expr, String> {
+        match self.peek().clone() {
+            Token::Number(n) => {
+                self.advance();
+                Ok(Expr::Literal(Value::Number(n)))
+            }
+            Token::String(s) => {
+                self.advance();
+                Ok(Expr::Literal(Value::String(s)))
+            }
+            Token::Name(n) if n == "nil" => {
+                self.advance();
+                Ok(Expr::Literal(Value::Nil))
+            }
+            Token::Name(n) if n == "true" || n == "false" => {
+                self.advance();
+                Ok(Expr::Literal(Value::Boolean(n == "true")))
+            }
+            Token::Name(n) if n == "function" => {
+                self.advance();
+                Ok(Expr::Function(self.parse_function_body(false)?))
+            }
+            Token::Symbol("...") if !self.variadic => Err(format!(
+                "cannot use '...' outside a vararg function near {}",
+                self.describe()
+            )),
+            Token::Symbol("...") => {
+                self.advance();
+                Ok(Expr::VarArgs)
+            }
+            Token::Symbol("{") => self.parse_table(),
+            _ => self.parse_suffixed(),
+        }
+    }
+
+    fn parse_table(&mut self) -> Result<Expr, String> {
+        self.expect_symbol("{")?;
+        let mut fields = vec![];
+        while !self.check_symbol("}") {
+            let is_named_field = matches!(self.peek(), Token::Name(_))
+                && self.tokens.get(self.pos + 1) == Some(&Token::Symbol("="));
+            if self.eat_symbol("[") {
+                let key = self.parse_expr()?;
+                self.expect_symbol("]")?;
+                self.expect_symbol("=")?;
+                fields.push(Field::Keyed(key, self.parse_expr()?));
+            } else if is_named_field {
+                let name = self.expect_name()?;
+                self.expect_symbol("=")?;
+                fields.push(Field::Keyed(
+                    Expr::Literal(Value::String(name)),
+                    self.parse_expr()?,
+                ));
+            } else {
+                fields.push(Field::Positional(self.parse_expr()?));
+            }
+            if !self.eat_symbol(",") && !self.eat_symbol(";") {
+                break;
+            }
+        }
+        self.expect_symbol("}")?;
+        Ok(Expr::Table(fields))
+    }
+
+    fn parse_suffixed(&mut self) -> Result<Expr, String> {
+        let mut expr = if self.eat_symbol("(") {
+            let inner = self.parse_expr()?;
+            self.expect_symbol(")")?;
+            Expr::Paren(Box::new(inner))
+        } else {
+            Expr::Name(self.expect_name()?)
+        };
+
+        let mut levels = 0;
+        loop {
+            if self.eat_symbol(".") {
+                let field = self.expect_name()?;
+                expr = Expr::Index(
+                    Box::new(expr),
+                    Box::new(Expr::Literal(Value::String(field))),
+                );
+            } else if self.eat_symbol("[") {
+                let index = self.parse_expr()?;
+                self.expect_symbol("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.eat_symbol(":") {
+                let name = self.expect_name()?;
+                let args = self.parse_args()?;
+                expr = Expr::Method(Box::new(expr), name, args);
+            } else if self.check_symbol("(")
+                || self.check_symbol("{")
+                || matches!(self.peek(), Token::String(_))
+            {
+                let args = self.parse_args()?;
+                expr = Expr::Call(Box::new(expr), args);
+            } else {
+                self.depth -= levels;
+                return Ok(expr);
+            }
+            // Each suffix nests what came before it one level deeper
+            self.enter()?;
+            levels += 1;
+        }
+    }
+
+    fn parse_args(&mut self) -> Result<Vec<Expr>, String> {
+        if self.check_symbol("{") {
+            return Ok(vec![self.parse_table()?]);
+        }
+        if let Token::String(s) = self.peek().clone() {
+            self.advance();
+            return Ok(vec![Expr::Literal(Value::String(s))]);
+        }
+        self.expect_symbol("(")?;
+        let args = if self.check_symbol(")") {
+            vec![]
+        } else {
+            self.parse_expr_list()?
+        };
+        self.expect_symbol(")")?;
+        Ok(args)
+    }
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+}
+
+struct Interpreter<'a> {
+    host: &'a mut dyn Host,
+    scopes: Vec<Scope>,
+    globals: HashMap<String, Value>,
+    // What strings index, like through their metatable in Lua
+    string_library: Value,
+    // The arguments of the running function that `...` stands for
+    varargs: Vec<Value>,
+    // The value of the error being raised, for pcall, when it isn't just its message
+    error: Option<Value>,
+    // Whether the host stopped the script, which pcall can't catch
+    killed: bool,
+    steps: u64,
+    depth: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(host: &'a mut dyn Host) -> Self {
+        let library = |functions: &[(&'static str, Builtin)]| -> Vec<(&'static str, Value)> {
+            functions
+                .iter()
+                .map(|&(name, builtin)| (name, Value::Builtin(builtin)))
+                .collect()
+        };
+        let mut redis = library(REDIS_LIBRARY);
+        for (level, name) in LOG_LEVELS.iter().enumerate() {
+            redis.push((name, Value::Number(level as f64)));
+        }
+        let mut math = library(MATH_LIBRARY);
+        math.push(("huge", Value::Number(f64::INFINITY)));
+        math.push(("pi", Value::Number(std::f64::consts::PI)));
+        let string_library = Value::table(vec![], library(STRING_LIBRARY));
+
+        let mut globals: HashMap<String, Value> = library(BASE_LIBRARY)
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        globals.insert("redis".to_string(), Value::table(vec![], redis));
+        globals.insert("string".to_string(), string_library.clone());
+        globals.insert(
+            "table".to_string(),
+            Value::table(vec![], library(TABLE_LIBRARY)),
+        );
+        globals.insert("math".to_string(), Value::table(vec![], math));
+        Self {
+            host,
+            scopes: vec![new_scope()],
+            globals,
+            string_library,
+            varargs: vec![],
+            error: None,
+            killed: false,
+            steps: 0,
+            depth: 0,
+        }
+    }
+
+    // Counts a statement or a loop iteration, asking the host every so often whether to go on
+    fn step(&mut self) -> Result<(), String> {
+        self.steps += 1;
+        if self.steps.is_multiple_of(CHECK_INTERVAL) {
+            if let Err(e) = self.host.check_running() {
+                self.killed = true;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Value {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| lock(scope).get(name).cloned())
+            .or_else(|| self.globals.get(name).cloned())
+            .unwrap_or(Value::Nil)
+    }
+
+    fn assign(&mut self, name: String, value: Value) {
+        match self
+            .scopes
+            .iter()
+            .rev()
+            .find(|s| lock(s).contains_key(&name))
+        {
+            Some(scope) => {
+                lock(scope).insert(name, value);
+            }
+            None => {
+                self.globals.insert(name, value);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: String, value: Value) {
+        if let Some(scope) = self.scopes.last() {
+            lock(scope).insert(name, value);
+        }
+    }
+
+    fn index(&self, target: &Value, key: &Value) -> Result<Value, String> {
+        match target {
+            Value::Table(t) => Ok(lock(t).get(key)),
+            Value::String(_) => self.index(&self.string_library, key),
+            _ => Err(format!("attempt to index a {} value", target.type_name())),
+        }
+    }
+
+    fn exec_block(&mut self, block: &[Statement]) -> Result<Flow, String> {
+        self.scopes.push(new_scope());
+        let result = self.exec_statements(block);
+        self.scopes.pop();
+        result
+    }
+
+    fn exec_statements(&mut self, block: &[Statement]) -> Result<Flow, String> {
+        for statement in block {
+            self.step()?;
+            match self.exec_statement(statement)? {
+                Flow::Normal => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_statement(&mut self, statement: &Statement) -> Result<Flow, String> {
+        match statement {
+            Statement::Local(names, values) => {
+                let mut values = self.eval_list(values)?.into_iter();
+                for name in names {
+                    self.declare(name.clone(), values.next().unwrap_or(Value::Nil));
+                }
+            }
+            Statement::Assign(targets, values) => self.exec_assign(targets, values)?,
+            Statement::Call(e) => {
+                self.eval_multi(e)?;
+            }
+            Statement::Do(body) => return self.exec_block(body),
+            Statement::If(branches, otherwise) => {
+                for (condition, body) in branches {
+                    if self.eval(condition)?.is_truthy() {
+                        return self.exec_block(body);
+                    }
+                }
+                if let Some(body) = otherwise {
+                    return self.exec_block(body);
+                }
+            }
+            Statement::NumericFor(name, start, stop, step, body) => {
+                return self.exec_numeric_for(name, start, stop, step.as_ref(), body)
+            }
+            Statement::GenericFor(names, values, body) => {
+                return self.exec_generic_for(names, values, body)
+            }
+            Statement::While(condition, body) => {
+                while self.eval(condition)?.is_truthy() {
+                    self.step()?;
+                    match self.exec_block(body)? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            Statement::Repeat(body, condition) => loop {
+                self.step()?;
+                // The condition sees the locals of the body
+                self.scopes.push(new_scope());
+                let flow = self.exec_statements(body).and_then(|flow| match flow {
+                    Flow::Normal if self.eval(condition)?.is_truthy() => Ok(Flow::Break),
+                    flow => Ok(flow),
+                });
+                self.scopes.pop();
+                match flow? {
+                    Flow::Normal => {}
+                    Flow::Break => break,
+                    flow => return Ok(flow),
+                }
+            },
+            Statement::Break => return Ok(Flow::Break),
+            Statement::Return(values) => return Ok(Flow::Return(self.eval_list(values)?)),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_assign(&mut self, targets: &[Expr], values: &[Expr]) -> Result<(), String> {
+        // Like in Lua, every table and key is evaluated before anything is assigned
+        let mut places = Vec::with_capacity(targets.len());
+        for target in targets {
+            places.push(match target {
+                Expr::Index(table, key) => Some((self.eval(table)?, self.eval(key)?)),
+                _ => None,
+            });
+        }
+        let mut values = self.eval_list(values)?.into_iter();
+        for (target, place) in targets.iter().zip(places) {
+            let value = values.next().unwrap_or(Value::Nil);
+            match (target, place) {
+                (_, Some((Value::Table(t), key))) => lock(&t).set(key, value)?,
+                (_, Some((other, _))) => {
+                    return Err(format!("attempt to index a {} value", other.type_name()))
+                }
+                (Expr::Name(name), None) => self.assign(name.clone(), value),
+                _ => return Err("cannot assign to an expression".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    fn exec_numeric_for(
+        &mut self,
+        name: &str,
+        start: &Expr,
+        stop: &Expr,
+        step: Option<&Expr>,
+        body: &[Statement],
+    ) -> Result<Flow, String> {
+        let number = |v: Value, what: &str| {
+            v.as_number()
+                .ok_or_else(|| format!("'for' {} must be a number", what))
+        };
+        let mut i = number(self.eval(start)?, "initial value")?;
+        let stop = number(self.eval(stop)?, "limit")?;
+        let step = match step {
+            Some(e) => number(self.eval(e)?, "step")?,
+            None => 1.0,
+        };
+        if step == 0.0 {
+            return Err("'for' step is zero".to_string());
+        }
+        while (step > 0.0 && i <= stop) || (step < 0.0 && i >= stop) {
+            self.step()?;
+            self.scopes.push(new_scope());
+            self.declare(name.to_string(), Value::Number(i));
+            let flow = self.exec_block(body);
+            self.scopes.pop();
+            match flow? {
+                Flow::Normal => {}
+                Flow::Break => break,
+                flow => return Ok(flow),
+            }
+            i += step;
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_generic_for(
+        &mut self,
+        names: &[String],
+        values: &[Expr],
+        body: &[Statement],
+    ) -> Result<Flow, String> {
+        let mut values = self.eval_list(values)?.into_iter();
+        let iterator = values.next().unwrap_or(Value::Nil);
+        let state = values.next().unwrap_or(Value::Nil);
+        let mut control = values.next().unwrap_or(Value::Nil);
+        loop {
+            self.step()?;
+            let mut results = self
+                .call_value(&iterator, vec![state.clone(), control.clone()])?
+                .into_iter();
+            control = results.next().unwrap_or(Value::Nil);
+            if let Value::Nil = control {
+                return Ok(Flow::Normal);
+            }
+            self.scopes.push(new_scope());
+            self.declare(names[0].clone(), control.clone());
+            for name in &names[1..] {
+                self.declare(name.clone(), results.next().unwrap_or(Value::Nil));
+            }
+            let flow = self.exec_block(body);
+            self.scopes.pop();
+            match flow? {
+                Flow::Normal => {}
+                Flow::Break => return Ok(Flow::Normal),
+                flow => return Ok(flow),
+            }
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        Ok(match expr {
+            Expr::Literal(v) => v.clone(),
+            Expr::Name(n) => self.lookup(n),
+            Expr::VarArgs | Expr::Call(..) | Expr::Method(..) => self
+                .eval_multi(expr)?
+                .into_iter()
+                .next()
+                .unwrap_or(Value::Nil),
+            Expr::Paren(e) => self.eval(e)?,
+            Expr::Index(target, key) => {
+                let target = self.eval(target)?;
+                let key = self.eval(key)?;
+                self.index(&target, &key)?
+            }
+            Expr::Function(def) => Value::Function(Arc::new(Closure {
+                def: Arc::clone(def),
+                captured: self.scopes.clone(),
+            })),
+            Expr::Table(fields) => self.eval_table(fields)?,
+            Expr::Not(e) => Value::Boolean(!self.eval(e)?.is_truthy()),
+            Expr::Negate(e) => {
+                let v = self.eval(e)?;
+                Value::Number(-v.as_number().ok_or_else(|| {
+                    format!("attempt to perform arithmetic on a {} value", v.type_name())
+                })?)
+            }
+            Expr::Length(e) => match self.eval(e)? {
+                Value::String(s) => Value::Number(s.chars().count() as f64),
+                Value::Table(t) => Value::Number(lock(&t).len() as f64),
+                v => {
+                    return Err(format!(
+                        "attempt to get length of a {} value",
+                        v.type_name()
+                    ))
+                }
+            },
+            Expr::Binary("and", lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                if lhs.is_truthy() {
+                    self.eval(rhs)?
+                } else {
+                    lhs
+                }
+            }
+            Expr::Binary("or", lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                if lhs.is_truthy() {
+                    lhs
+                } else {
+                    self.eval(rhs)?
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs)?;
+                let rhs = self.eval(rhs)?;
+                binary_op(op, lhs, rhs)?
+            }
+        })
+    }
+
+    fn eval_table(&mut self, fields: &[Field]) -> Result<Value, String> {
+        let mut table = Table::default();
+        let mut position = 1;
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                // A call or `...` last in the constructor adds all its values
+                Field::Positional(e) if i == fields.len() - 1 => {
+                    for value in self.eval_multi(e)? {
+                        table.set(Value::Number(position as f64), value)?;
+                        position += 1;
+                    }
+                }
+                Field::Positional(e) => {
+                    let value = self.eval(e)?;
+                    table.set(Value::Number(position as f64), value)?;
+                    position += 1;
+                }
+                Field::Keyed(key, e) => {
+                    let key = self.eval(key)?;
+                    let value = self.eval(e)?;
+                    table.set(key, value)?;
+                }
+            }
+        }
+        Ok(Value::Table(Arc::new(Mutex::new(table))))
+    }
+
+    // Evaluates an expression that can have any number of values
+    fn eval_multi(&mut self, expr: &Expr) -> Result<Vec<Value>, String> {
+        match expr {
+            Expr::Call(function, args) => {
+                let function = self.eval(function)?;
+                let args = self.eval_list(args)?;
+                self.call_value(&function, args)
+            }
+            Expr::Method(object, name, args) => {
+                let object = self.eval(object)?;
+                let function = self.index(&object, &Value::String(name.clone()))?;
+                let mut values = vec![object];
+                values.extend(self.eval_list(args)?);
+                self.call_value(&function, values)
+            }
+            Expr::VarArgs => Ok(self.varargs.clone()),
+            _ => Ok(vec![self.eval(expr)?]),
+        }
+    }
+
+    // Evaluates a list of expressions, where only the last one can have more or less than one value
+    fn eval_list(&mut self, exprs: &[Expr]) -> Result<Vec<Value>, String> {
+        let mut values = Vec::with_capacity(exprs.len());
+        for (i, expr) in exprs.iter().enumerate() {
+            if i == exprs.len() - 1 {
+                values.extend(self.eval_multi(expr)?);
+            } else {
+                values.push(self.eval(expr)?);
+            }
+        }
+        Ok(values)
+    }
+
+    fn call_value(&mut self, function: &Value, args: Vec<Value>) -> Result<Vec<Value>, String> {
+        match function {
+            Value::Function(closure) => self.call_closure(closure, args),
+            Value::Builtin(builtin) => self.call_builtin(*builtin, args),
+            Value::Matches(matches) => self.next_match(matches),
+            other => Err(format!("attempt to call a {} value", other.type_name())),
+        }
+    }
+
+    fn call_closure(&mut self, closure: &Closure, args: Vec<Value>) -> Result<Vec<Value>, String> {
+        if self.depth == MAX_CALL_DEPTH {
+            return Err("stack overflow".to_string());
+        }
+        let mut scope = HashMap::new();
+        let mut args = args.into_iter();
+        for param in &closure.def.params {
+            scope.insert(param.clone(), args.next().unwrap_or(Value::Nil));
+        }
+        let varargs = if closure.def.variadic {
+            args.collect()
+        } else {
+            vec![]
+        };
+        // A function only sees what it captured, not the caller's locals
+        let mut scopes = closure.captured.clone();
+        scopes.push(Arc::new(Mutex::new(scope)));
+        let saved_scopes = std::mem::replace(&mut self.scopes, scopes);
+        let saved_varargs = std::mem::replace(&mut self.varargs, varargs);
+        self.depth += 1;
+        let flow = self.exec_block(&closure.def.body);
+        self.depth -= 1;
+        self.scopes = saved_scopes;
+        self.varargs = saved_varargs;
+        Ok(match flow? {
+            Flow::Return(values) => values,
+            _ => vec![],
+        })
+    }
+
+    fn call_builtin(&mut self, builtin: Builtin, args: Vec<Value>) -> Result<Vec<Value>, String> {
+        let first = args.first().cloned().unwrap_or(Value::Nil);
+        let value = match builtin {
+            Builtin::Call | Builtin::PCall => {
+                if args.is_empty() {
+                    return Err(
+                        "Please specify at least one argument for this redis lib call".to_string(),
+                    );
+                }
+                let args = args
+                    .iter()
+                    .map(Value::as_string)
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| {
+                        "Lua redis lib command arguments must be strings or integers".to_string()
+                    })?;
+                match self.host.call(args) {
+                    // The error is raised as the table redis.pcall would have returned
+                    RESPValue::Error(e) if builtin == Builtin::Call => {
+                        self.error = Some(Value::table(
+                            vec![],
+                            vec![("err", Value::String(e.clone()))],
+                        ));
+                        return Err(e);
+                    }
+                    reply => from_resp(reply),
+                }
+            }
+            Builtin::RegisterFunction => {
+                let registration = match (&first, args.get(1)) {
+                    (Value::String(name), Some(Value::Function(callback))) => Registration {
+                        name: name.clone(),
+                        callback: Arc::clone(callback),
+                        flags: vec![],
+                        description: None,
+                    },
+                    (Value::Table(t), None) => parse_registration_table(t)?,
+                    _ => return Err("wrong arguments given to redis.register_function".to_string()),
+                };
+                self.host.register_function(registration)?;
+                return Ok(vec![]);
+            }
+            Builtin::ErrorReply | Builtin::StatusReply => {
+                let field = if builtin == Builtin::ErrorReply {
+                    "err"
+                } else {
+                    "ok"
+                };
+                match first {
+                    Value::String(s) => Value::table(vec![], vec![(field, Value::String(s))]),
+                    _ => {
+                        return Err(format!(
+                            "wrong number or type of arguments to redis.{}_reply",
+                            field
+                        ))
+                    }
+                }
+            }
+            Builtin::Log => {
+                let message: Vec<String> =
+                    args.iter().skip(1).filter_map(Value::as_string).collect();
+                eprintln!("Script log: {}", message.join(" "));
+                return Ok(vec![]);
+            }
+            Builtin::Assert => {
+                if first.is_truthy() {
+                    return Ok(args);
+                }
+                return Err(args
+                    .get(1)
+                    .and_then(Value::as_string)
+                    .unwrap_or_else(|| "assertion failed!".to_string()));
+            }
+            Builtin::Error => {
+                let message = error_message(&first);
+                self.error = Some(first);
+                return Err(message);
+            }
+            Builtin::IPairs => {
+                let table = check_table(&args, 0, "ipairs")?;
+                return Ok(vec![
+                    Value::Builtin(Builtin::IPairsNext),
+                    Value::Table(table),
+                    Value::Number(0.0),
+                ]);
+            }
+            Builtin::IPairsNext => {
+                let table = check_table(&args, 0, "ipairs")?;
+                let i = check_number(&args, 1, "ipairs")? + 1.0;
+                let value = lock(&table).get(&Value::Number(i));
+                return Ok(match value {
+                    Value::Nil => vec![Value::Nil],
+                    value => vec![Value::Number(i), value],
+                });
+            }
+            Builtin::Next => {
+                let table = check_table(&args, 0, "next")?;
+                let key = args.get(1).cloned().unwrap_or(Value::Nil);
+                let entry = lock(&table).next(&key)?;
+                return Ok(match entry {
+                    Some((key, value)) => vec![key, value],
+                    None => vec![Value::Nil],
+                });
+            }
+            Builtin::Pairs => {
+                let table = check_table(&args, 0, "pairs")?;
+                return Ok(vec![
+                    Value::Builtin(Builtin::Next),
+                    Value::Table(table),
+                    Value::Nil,
+                ]);
+            }
+            Builtin::ProtectedCall => {
+                let mut args = args.into_iter();
+                let function = args
+                    .next()
+                    .ok_or("bad argument #1 to 'pcall' (value expected)")?;
+                self.error = None;
+                return match self.call_value(&function, args.collect()) {
+                    Ok(results) => Ok(std::iter::once(Value::Boolean(true))
+                        .chain(results)
+                        .collect()),
+                    Err(e) if self.killed => Err(e),
+                    Err(e) => Ok(vec![
+                        Value::Boolean(false),
+                        self.error.take().unwrap_or(Value::String(e)),
+                    ]),
+                };
+            }
+            Builtin::Select => {
+                let count = args.len().saturating_sub(1) as i64;
+                if let Value::String(s) = &first {
+                    if s == "#" {
+                        return Ok(vec![Value::Number(count as f64)]);
+                    }
+                }
+                let n = check_number(&args, 0, "select")? as i64;
+                let skip = if n < 0 { count + n } else { n - 1 };
+                if n == 0 || skip < 0 {
+                    return Err("bad argument #1 to 'select' (index out of range)".to_string());
+                }
+                return Ok(args.into_iter().skip(1 + skip as usize).collect());
+            }
+            Builtin::ToNumber => match args.get(1) {
+                None | Some(Value::Nil) => first.as_number().map_or(Value::Nil, Value::Number),
+                Some(_) => {
+                    let base = check_number(&args, 1, "tonumber")? as u32;
+                    if !(2..=36).contains(&base) {
+                        return Err("bad argument #2 to 'tonumber' (base out of range)".to_string());
+                    }
+                    let s = check_string(&args, 0, "tonumber")?;
+                    i64::from_str_radix(s.trim(), base)
+                        .map_or(Value::Nil, |n| Value::Number(n as f64))
+                }
+            },
+            Builtin::ToString => Value::String(to_display(&first)),
+            Builtin::Type => match args.first() {
+                Some(value) => Value::String(value.type_name().to_string()),
+                None => return Err("bad argument #1 to 'type' (value expected)".to_string()),
+            },
+            Builtin::Unpack => {
+                let table = check_table(&args, 0, "unpack")?;
+                let table = lock(&table);
+                let start = opt_number(&args, 1, "unpack", 1.0)? as i64;
+                let end = opt_number(&args, 2, "unpack", table.len() as f64)? as i64;
+                if start > end {
+                    return Ok(vec![]);
+                }
+                if end - start >= MAX_RESULTS {
+                    return Err("too many results to unpack".to_string());
+                }
+                return Ok((start..=end)
+                    .map(|i| table.get(&Value::Number(i as f64)))
+                    .collect());
+            }
+            Builtin::StringByte => {
+                let s: Vec<char> = check_string(&args, 0, "byte")?.chars().collect();
+                let i = opt_number(&args, 1, "byte", 1.0)?;
+                let start = relative(i, s.len()).max(1);
+                let end = relative(opt_number(&args, 2, "byte", i)?, s.len()).min(s.len() as i64);
+                return Ok((start..=end)
+                    .map(|i| Value::Number(s[i as usize - 1] as u32 as f64))
+                    .collect());
+            }
+            Builtin::StringChar => Value::String(
+                (0..args.len())
+                    .map(|i| match check_number(&args, i, "char")? as i64 {
+                        c @ 0..=255 => Ok(char::from(c as u8)),
+                        _ => Err(format!("bad argument #{} to 'char' (invalid value)", i + 1)),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            Builtin::StringFind => return self.find(&args, true),
+            Builtin::StringFormat => Value::String(format_string(&args)?),
+            Builtin::StringGmatch => {
+                let s = check_string(&args, 0, "gmatch")?;
+                let pattern = check_string(&args, 1, "gmatch")?;
+                Value::Matches(Arc::new(Mutex::new(Matches {
+                    s: s.chars().collect(),
+                    pattern: pattern.chars().collect(),
+                    position: 0,
+                })))
+            }
+            Builtin::StringGsub => return self.gsub(&args),
+            Builtin::StringLen => {
+                Value::Number(check_string(&args, 0, "len")?.chars().count() as f64)
+            }
+            Builtin::StringLower => {
+                Value::String(check_string(&args, 0, "lower")?.to_ascii_lowercase())
+            }
+            Builtin::StringMatch => return self.find(&args, false),
+            Builtin::StringRep => {
+                let s = check_string(&args, 0, "rep")?;
+                let n = check_number(&args, 1, "rep")?.max(0.0) as usize;
+                if s.chars().count().saturating_mul(n) > MAX_STRING_LEN {
+                    return Err("resulting string too large".to_string());
+                }
+                Value::String(s.repeat(n))
+            }
+            Builtin::StringReverse => {
+                Value::String(check_string(&args, 0, "reverse")?.chars().rev().collect())
+            }
+            Builtin::StringSub => {
+                let s: Vec<char> = check_string(&args, 0, "sub")?.chars().collect();
+                let start = relative(check_number(&args, 1, "sub")?, s.len()).max(1);
+                let end = relative(opt_number(&args, 2, "sub", -1.0)?, s.len()).min(s.len() as i64);
+                Value::String(if start <= end {
+                    s[start as usize - 1..end as usize].iter().collect()
+                } else {
+                    String::new()
+                })
+            }
+            Builtin::StringUpper => {
+                Value::String(check_string(&args, 0, "upper")?.to_ascii_uppercase())
+            }
+            Builtin::TableConcat => {
+                let table = check_table(&args, 0, "concat")?;
+                let table = lock(&table);
+                let separator = match args.get(1) {
+                    None | Some(Value::Nil) => String::new(),
+                    Some(_) => check_string(&args, 1, "concat")?,
+                };
+                let start = opt_number(&args, 2, "concat", 1.0)? as i64;
+                let end = opt_number(&args, 3, "concat", table.len() as f64)? as i64;
+                let parts = (start..=end)
+                    .map(|i| {
+                        table
+                            .get(&Value::Number(i as f64))
+                            .as_string()
+                            .ok_or_else(|| {
+                                format!("invalid value (at index {}) in table for 'concat'", i)
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Value::String(parts.join(&separator))
+            }
+            Builtin::TableGetn => {
+                let table = check_table(&args, 0, "getn")?;
+                let len = lock(&table).len();
+                Value::Number(len as f64)
+            }
+            Builtin::TableInsert => {
+                let table = check_table(&args, 0, "insert")?;
+                let mut table = lock(&table);
+                let len = table.len() as i64;
+                let (position, value) = match args.len() {
+                    2 => (len + 1, args[1].clone()),
+                    3 => (check_number(&args, 1, "insert")? as i64, args[2].clone()),
+                    _ => return Err("wrong number of arguments to 'insert'".to_string()),
+                };
+                if position < 1 || position > len + 1 {
+                    return Err("bad argument #2 to 'insert' (position out of bounds)".to_string());
+                }
+                if position == len + 1 {
+                    table.set(Value::Number(position as f64), value)?;
+                } else {
+                    table.array.insert(position as usize - 1, value);
+                    table.migrate();
+                }
+                return Ok(vec![]);
+            }
+            Builtin::TableRemove => {
+                let table = check_table(&args, 0, "remove")?;
+                let mut table = lock(&table);
+                let len = table.len();
+                let position = opt_number(&args, 1, "remove", len as f64)? as i64;
+                if position < 1 || position > len as i64 {
+                    return Ok(vec![]);
+                }
+                let value = table.array.remove(position as usize - 1);
+                table.trim();
+                value
+            }
+            Builtin::TableSort => return self.sort(&args),
+            Builtin::MathAbs => Value::Number(check_number(&args, 0, "abs")?.abs()),
+            Builtin::MathCeil => Value::Number(check_number(&args, 0, "ceil")?.ceil()),
+            Builtin::MathFloor => Value::Number(check_number(&args, 0, "floor")?.floor()),
+            Builtin::MathFmod => {
+                Value::Number(check_number(&args, 0, "fmod")? % check_number(&args, 1, "fmod")?)
+            }
+            Builtin::MathMax | Builtin::MathMin => {
+                let name = if builtin == Builtin::MathMax {
+                    "max"
+                } else {
+                    "min"
+                };
+                let mut best = check_number(&args, 0, name)?;
+                for i in 1..args.len() {
+                    let n = check_number(&args, i, name)?;
+                    if (builtin == Builtin::MathMax && n > best)
+                        || (builtin == Builtin::MathMin && n < best)
+                    {
+                        best = n;
+                    }
+                }
+                Value::Number(best)
+            }
+            Builtin::MathSqrt => Value::Number(check_number(&args, 0, "sqrt")?.sqrt()),
+        };
+        Ok(vec![value])
+    }
+
+    // string.find, or string.match which gives the captures without the positions
+    fn find(&mut self, args: &[Value], find: bool) -> Result<Vec<Value>, String> {
+        let function = if find { "find" } else { "match" };
+        let s: Vec<char> = check_string(args, 0, function)?.chars().collect();
+        let pattern: Vec<char> = check_string(args, 1, function)?.chars().collect();
+        let init = relative(opt_number(args, 2, function, 1.0)?, s.len()).max(1) as usize - 1;
+        if init > s.len() {
+            return Ok(vec![Value::Nil]);
+        }
+        let plain = args.get(3).is_some_and(Value::is_truthy);
+        if find && (plain || lua_patterns::is_plain(&pattern)) {
+            let start = (init..=s.len()).find(|&i| s[i..].starts_with(&pattern));
+            return Ok(match start {
+                Some(start) => vec![
+                    Value::Number((start + 1) as f64),
+                    Value::Number((start + pattern.len()) as f64),
+                ],
+                None => vec![Value::Nil],
+            });
+        }
+        let (anchored, pattern) = match pattern.split_first() {
+            Some(('^', rest)) => (true, rest),
+            _ => (false, &pattern[..]),
+        };
+        let mut start = init;
+        loop {
+            self.step()?;
+            if let Some(m) = lua_patterns::match_at(&s, pattern, start)? {
+                let captures = capture_values(&s, &m);
+                return Ok(if find {
+                    let mut values = vec![
+                        Value::Number((start + 1) as f64),
+                        Value::Number(m.end as f64),
+                    ];
+                    values.extend(captures);
+                    values
+                } else if captures.is_empty() {
+                    vec![Value::String(s[start..m.end].iter().collect())]
+                } else {
+                    captures
+                });
+            }
+            start += 1;
+            if anchored || start > s.len() {
+                return Ok(vec![Value::Nil]);
+            }
+        }
+    }
+
+    // The next match of a string.gmatch iterator
+    fn next_match(&mut self, matches: &Mutex<Matches>) -> Result<Vec<Value>, String> {
+        let mut matches = lock(matches);
+        while matches.position <= matches.s.len() {
+            self.step()?;
+            let start = matches.position;
+            if let Some(m) = lua_patterns::match_at(&matches.s, &matches.pattern, start)? {
+                // An empty match moves on by one, or the next call would find it again
+                matches.position = if m.end == start { start + 1 } else { m.end };
+                let captures = capture_values(&matches.s, &m);
+                return Ok(if captures.is_empty() {
+                    vec![Value::String(matches.s[start..m.end].iter().collect())]
+                } else {
+                    captures
+                });
+            }
+            matches.position += 1;
+        }
+        Ok(vec![Value::Nil])
+    }
+
+    fn gsub(&mut self, args: &[Value]) -> Result<Vec<Value>, String> {
+        let s: Vec<char> = check_string(args, 0, "gsub")?.chars().collect();
+        let pattern: Vec<char> = check_string(args, 1, "gsub")?.chars().collect();
+        let replacement = args.get(2).cloned().unwrap_or(Value::Nil);
+        if let Value::Nil | Value::Boolean(_) = replacement {
+            return Err(bad_argument(args, 2, "gsub", "string/function/table"));
+        }
+        let max = match args.get(3) {
+            None | Some(Value::Nil) => i64::MAX,
+            Some(_) => check_number(args, 3, "gsub")? as i64,
+        };
+        let (anchored, pattern) = match pattern.split_first() {
+            Some(('^', rest)) => (true, rest),
+            _ => (false, &pattern[..]),
+        };
+        let mut result = String::new();
+        let mut start = 0;
+        let mut count = 0;
+        while count < max {
+            self.step()?;
+            let found = lua_patterns::match_at(&s, pattern, start)?;
+            if let Some(m) = &found {
+                count += 1;
+                let whole: String = s[start..m.end].iter().collect();
+                let captures = capture_values(&s, m);
+                let value = match &replacement {
+                    Value::String(_) | Value::Number(_) => Value::String(expand_replacement(
+                        &to_display(&replacement),
+                        &whole,
+                        &captures,
+                    )?),
+                    Value::Table(t) => {
+                        let key = captures
+                            .first()
+                            .cloned()
+                            .unwrap_or_else(|| Value::String(whole.clone()));
+                        lock(t).get(&key)
+                    }
+                    function => {
+                        let args = if captures.is_empty() {
+                            vec![Value::String(whole.clone())]
+                        } else {
+                            captures
+                        };
+                        self.call_value(function, args)?
+                            .into_iter()
+                            .next()
+                            .unwrap_or(Value::Nil)
+                    }
+                };
+                // False or nil keeps the original text
+                match value {
+                    Value::Nil | Value::Boolean(false) => result.push_str(&whole),
+                    value => result.push_str(&value.as_string().ok_or_else(|| {
+                        format!("invalid replacement value (a {})", value.type_name())
+                    })?),
+                }
+            }
+            match found {
+                Some(m) if m.end > start => start = m.end,
+                _ if start < s.len() => {
+                    result.push(s[start]);
+                    start += 1;
+                }
+                _ => break,
+            }
+            if anchored {
+                break;
+            }
+        }
+        result.extend(&s[start..]);
+        Ok(vec![Value::String(result), Value::Number(count as f64)])
+    }
+
+    fn sort(&mut self, args: &[Value]) -> Result<Vec<Value>, String> {
+        let table = check_table(args, 0, "sort")?;
+        let comparator = match args.get(1) {
+            None | Some(Value::Nil) => None,
+            Some(f @ (Value::Function(_) | Value::Builtin(_))) => Some(f.clone()),
+            Some(_) => return Err(bad_argument(args, 1, "sort", "function")),
+        };
+        // A merge sort on a copy, as the comparator runs script code which could change the
+        // table, and a bad comparator can only give a wrong order
+        let mut values = lock(&table).array.clone();
+        let mut width = 1;
+        while width < values.len() {
+            let mut merged = Vec::with_capacity(values.len());
+            for chunk in values.chunks(2 * width) {
+                let (left, right) = chunk.split_at(width.min(chunk.len()));
+                let (mut i, mut j) = (0, 0);
+                while i < left.len() && j < right.len() {
+                    if self.less_than(comparator.as_ref(), &right[j], &left[i])? {
+                        merged.push(right[j].clone());
+                        j += 1;
+                    } else {
+                        merged.push(left[i].clone());
+                        i += 1;
+                    }
+                }
+                merged.extend_from_slice(&left[i..]);
+                merged.extend_from_slice(&right[j..]);
+            }
+            values = merged;
+            width *= 2;
+        }
+        let mut table = lock(&table);
+        for (i, value) in values.into_iter().enumerate() {
+            table.set(Value::Number((i + 1) as f64), value)?;
+        }
+        Ok(vec![])
+    }
+
+    fn less_than(
+        &mut self,
+        comparator: Option<&Value>,
+        a: &Value,
+        b: &Value,
+    ) -> Result<bool, String> {
+        self.step()?;
+        Ok(match comparator {
+            Some(function) => self
+                .call_value(function, vec![a.clone(), b.clone()])?
+                .first()
+                .is_some_and(Value::is_truthy),
+            None => binary_op("<", a.clone(), b.clone())?.is_truthy(),
+        })
+    }
+}
+
+// Where a possibly negative position of string.sub and the like points, counting from 1
+fn relative(position: f64, len: usize) -> i64 {
+    let position = position as i64;
+    if position < 0 {
+        (len as i64 + position + 1).max(0)
+    } else {
+        position
+    }
+}
+
+// The values of the captures of a match
+fn capture_values(s: &[char], m: &lua_patterns::Match) -> Vec<Value> {
+    m.captures
+        .iter()
+        .map(|capture| match *capture {
+            lua_patterns::Capture::Text(start, end) => {
+                Value::String(s[start..end].iter().collect())
+            }
+            lua_patterns::Capture::Position(position) => Value::Number((position + 1) as f64),
+        })
+        .collect()
+}
+
+// A string replacement of string.gsub, where `%1` to `%9` stand for the captures, `%0` for the
+// whole match and `%%` for a `%`
+fn expand_replacement(template: &str, whole: &str, captures: &[Value]) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => result.push_str(whole),
+            Some(d @ '1'..='9') => {
+                let index = d as usize - '1' as usize;
+                match captures.get(index) {
+                    Some(capture) => result.push_str(&to_display(capture)),
+                    // Without captures, the whole match is the first
+                    None if index == 0 && captures.is_empty() => result.push_str(whole),
+                    None => return Err("invalid capture index".to_string()),
+                }
+            }
+            Some(other) => result.push(other),
+            None => result.push('%'),
+        }
+    }
+    Ok(result)
+}
+
+fn bad_argument(args: &[Value], index: usize, function: &str, expected: &str) -> String {
+    let got = args.get(index).map_or("no value", Value::type_name);
+    format!(
+        "bad argument #{} to '{}' ({} expected, got {})",
+        index + 1,
+        function,
+        expected,
+        got
+    )
+}
+
+// A string argument, which can be given as a number
+fn check_string(args: &[Value], index: usize, function: &str) -> Result<String, String> {
+    args.get(index)
+        .and_then(Value::as_string)
+        .ok_or_else(|| bad_argument(args, index, function, "string"))
+}
+
+// A number argument, which can be given as a string
+fn check_number(args: &[Value], index: usize, function: &str) -> Result<f64, String> {
+    args.get(index)
+        .and_then(Value::as_number)
+        .ok_or_else(|| bad_argument(args, index, function, "number"))
+}
+
+fn opt_number(args: &[Value], index: usize, function: &str, default: f64) -> Result<f64, String> {
+    match args.get(index) {
+        None | Some(Value::Nil) => Ok(default),
+        Some(_) => check_number(args, index, function),
+    }
+}
+
+fn check_table(args: &[Value], index: usize, function: &str) -> Result<Arc<Mutex<Table>>, String> {
+    match args.get(index) {
+        Some(Value::Table(t)) => Ok(Arc::clone(t)),
+        _ => Err(bad_argument(args, index, function, "table")),
+    }
+}
+
+// string.format, with the conversions of C's printf that Lua 5.1 supports
+fn format_string(args: &[Value]) -> Result<String, String> {
+    let template: Vec<char> = check_string(args, 0, "format")?.chars().collect();
+    let mut result = String::new();
+    let mut arg = 0;
+    let mut i = 0;
+    while i < template.len() {
+        let c = template[i];
+        i += 1;
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        if template.get(i) == Some(&'%') {
+            result.push('%');
+            i += 1;
+            continue;
+        }
+        let flags_start = i;
+        while i < template.len() && "-+ #0".contains(template[i]) {
+            i += 1;
+        }
+        let flags: String = template[flags_start..i].iter().collect();
+        let width = read_format_digits(&template, &mut i)?.unwrap_or(0);
+        let precision = if template.get(i) == Some(&'.') {
+            i += 1;
+            Some(read_format_digits(&template, &mut i)?.unwrap_or(0))
+        } else {
+            None
+        };
+        let conversion = template.get(i).copied();
+        i += 1;
+        arg += 1;
+        if conversion.is_some() && arg >= args.len() {
+            return Err(format!("bad argument #{} to 'format' (no value)", arg + 1));
+        }
+        let number = || check_number(args, arg, "format");
+        // The sign or prefix, the digits or text, and whether zeros can pad between them
+        let (prefix, body, zero_padded) = match conversion {
+            Some('d') | Some('i') => {
+                let n = number()? as i64;
+                (
+                    sign(n < 0, &flags),
+                    with_precision(n.unsigned_abs().to_string(), precision),
+                    precision.is_none(),
+                )
+            }
+            Some(c @ 'o') | Some(c @ 'u') | Some(c @ 'x') | Some(c @ 'X') => {
+                let n = number()? as i64 as u64;
+                let digits = match c {
+                    'o' => format!("{:o}", n),
+                    'u' => n.to_string(),
+                    'x' => format!("{:x}", n),
+                    _ => format!("{:X}", n),
+                };
+                let prefix = match c {
+                    'o' if flags.contains('#') && n != 0 => "0",
+                    'x' if flags.contains('#') && n != 0 => "0x",
+                    'X' if flags.contains('#') && n != 0 => "0X",
+                    _ => "",
+                };
+                (
+                    prefix.to_string(),
+                    with_precision(digits, precision),
+                    precision.is_none(),
+                )
+            }
+            Some('c') => (
+                String::new(),
+                char::from(number()? as i64 as u8).to_string(),
+                false,
+            ),
+            Some(c @ 'e') | Some(c @ 'E') | Some(c @ 'f') | Some(c @ 'g') | Some(c @ 'G') => {
+                let n = number()?;
+                let precision = precision.unwrap_or(6);
+                let body = match c {
+                    _ if !n.is_finite() => to_display_special(n.abs()),
+                    'f' => format!("{:.*}", precision, n.abs()),
+                    'e' | 'E' => format_exponent(n.abs(), precision),
+                    _ => format_general(n.abs(), precision, flags.contains('#')),
+                };
+                let body = if c.is_ascii_uppercase() {
+                    body.to_ascii_uppercase()
+                } else {
+                    body
+                };
+                (
+                    sign(n.is_sign_negative() && !n.is_nan(), &flags),
+                    body,
+                    n.is_finite(),
+                )
+            }
+            Some('q') => (
+                String::new(),
+                quote(&check_string(args, arg, "format")?),
+                false,
+            ),
+            Some('s') => {
+                let s = check_string(args, arg, "format")?;
+                let s = match precision {
+                    Some(precision) => s.chars().take(precision).collect(),
+                    None => s,
+                };
+                (String::new(), s, false)
+            }
+            other => {
+                return Err(format!(
+                    "invalid option '%{}' to 'format'",
+                    other.map(String::from).unwrap_or_default()
+                ))
+            }
+        };
+        let padding = width.saturating_sub(prefix.chars().count() + body.chars().count());
+        if flags.contains('-') {
+            result.push_str(&prefix);
+            result.push_str(&body);
+            result.extend(std::iter::repeat_n(' ', padding));
+        } else if zero_padded && flags.contains('0') {
+            result.push_str(&prefix);
+            result.extend(std::iter::repeat_n('0', padding));
+            result.push_str(&body);
+        } else {
+            result.extend(std::iter::repeat_n(' ', padding));
+            result.push_str(&prefix);
+            result.push_str(&body);
+        }
+    }
+    Ok(result)
+}
+
+// A width or precision of a format, at most two digits like in Lua
+fn read_format_digits(template: &[char], i: &mut usize) -> Result<Option<usize>, String> {
+    let digits = template[*i..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if digits > 2 {
+        return Err("invalid format (width or precision too long)".to_string());
+    }
+    let value = template[*i..*i + digits]
+        .iter()
+        .fold(0, |n, d| n * 10 + d.to_digit(10).unwrap_or(0) as usize);
+    *i += digits;
+    Ok(if digits == 0 { None } else { Some(value) })
+}
+
+fn sign(negative: bool, flags: &str) -> String {
+    let sign = if negative {
+        "-"
+    } else if flags.contains('+') {
+        "+"
+    } else if flags.contains(' ') {
+        " "
+    } else {
+        ""
+    };
+    sign.to_string()
+}
+
+// The digits of an integer conversion with at least `precision` of them
+fn with_precision(digits: String, precision: Option<usize>) -> String {
+    match precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(precision) if digits.len() < precision => {
+            "0".repeat(precision - digits.len()) + &digits
+        }
+        _ => digits,
+    }
+}
+
+// The %q conversion, a string Lua can read back
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '\n' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\000"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn parse_registration_table(t: &Mutex<Table>) -> Result<Registration, String> {
+    // Read the fields before looking into them, the flags could be the table itself
+    let (name, callback, flags, description) = {
+        let t = lock(t);
+        let mut key = Value::Nil;
+        while let Some((next, _)) = t.next(&key)? {
+            match &next {
+                Value::String(s)
+                    if ["function_name", "callback", "flags", "description"]
+                        .contains(&s.as_str()) => {}
+                other => {
+                    return Err(format!(
+                        "unknown argument given to redis.register_function: {}",
+                        to_display(other)
+                    ))
+                }
+            }
+            key = next;
+        }
+        (
+            t.field("function_name"),
+            t.field("callback"),
+            t.field("flags"),
+            t.field("description"),
+        )
+    };
+    let name = match name {
+        Value::String(s) => s,
+        _ => {
+            return Err(
+                "function_name argument given to redis.register_function must be a string"
+                    .to_string(),
+            )
+        }
+    };
+    let callback = match callback {
+        Value::Function(f) => f,
+        _ => {
+            return Err(
+                "callback argument given to redis.register_function must be a function".to_string(),
+            )
+        }
+    };
+    let flags = match flags {
+        Value::Nil => vec![],
+        Value::Table(flags) => lock(&flags)
+            .array
+            .iter()
+            .map(|f| match f {
+                Value::String(s) => Ok(s.clone()),
+                _ => Err("unknown flag given".to_string()),
+            })
+            .collect::<Result<_, _>>()?,
+        _ => return Err(
+            "flags argument to redis.register_function must be a table representing function flags"
+                .to_string(),
+        ),
+    };
+    let description = match description {
+        Value::Nil => None,
+        Value::String(s) => Some(s),
+        _ => {
+            return Err(
+                "description argument given to redis.register_function must be a string"
+                    .to_string(),
+            )
+        }
+    };
+    Ok(Registration {
+        name,
+        callback,
+        flags,
+        description,
+    })
+}
+
+fn binary_op(op: &str, lhs: Value, rhs: Value) -> Result<Value, String> {
+    match op {
+        "==" => return Ok(Value::Boolean(values_equal(&lhs, &rhs))),
+        "~=" => return Ok(Value::Boolean(!values_equal(&lhs, &rhs))),
+        ".." => {
+            return match (lhs.as_string(), rhs.as_string()) {
+                (Some(l), Some(r)) => Ok(Value::String(l + &r)),
+                _ => {
+                    let bad = if lhs.as_string().is_none() {
+                        &lhs
+                    } else {
+                        &rhs
+                    };
+                    Err(format!(
+                        "attempt to concatenate a {} value",
+                        bad.type_name()
+                    ))
+                }
+            }
+        }
+        "<" | ">" | "<=" | ">=" => {
+            let ordering = match (&lhs, &rhs) {
+                (Value::Number(l), Value::Number(r)) => l.partial_cmp(r),
+                (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+                _ => {
+                    return Err(format!(
+                        "attempt to compare {} with {}",
+                        lhs.type_name(),
+                        rhs.type_name()
+                    ))
+                }
+            };
+            let result = match ordering {
+                Some(o) => match op {
+                    "<" => o.is_lt(),
+                    ">" => o.is_gt(),
+                    "<=" => o.is_le(),
+                    _ => o.is_ge(),
+                },
+                None => false,
+            };
+            return Ok(Value::Boolean(result));
+        }
+        _ => {}
+    }
+
+    let (l, r) = match (lhs.as_number(), rhs.as_number()) {
+        (Some(l), Some(r)) => (l, r),
+        _ => {
+            let bad = if lhs.as_number().is_none() {
+                &lhs
+            } else {
+                &rhs
+            };
+            return Err(format!(
+                "attempt to perform arithmetic on a {} value",
+                bad.type_name()
+            ));
+        }
+    };
+    Ok(Value::Number(match op {
+        "+" => l + r,
+        "-" => l - r,
+        "*" => l * r,
+        "/" => l / r,
+        "^" => l.powf(r),
+        // Lua's modulo takes the sign of the divisor
+        _ => l - (l / r).floor() * r,
+    }))
+}
+
+fn values_equal(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Boolean(l), Value::Boolean(r)) => l == r,
+        (Value::Number(l), Value::Number(r)) => l == r,
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Table(l), Value::Table(r)) => Arc::ptr_eq(l, r),
+        (Value::Function(l), Value::Function(r)) => Arc::ptr_eq(l, r),
+        (Value::Matches(l), Value::Matches(r)) => Arc::ptr_eq(l, r),
+        (Value::Builtin(l), Value::Builtin(r)) => l == r,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct TestHost {
+        calls: Vec<Vec<String>>,
+        registered: Vec<Registration>,
+        checks: usize,
+    }
+
+    impl Host for TestHost {
+        fn call(&mut self, args: Vec<String>) -> RESPValue {
+            self.calls.push(args.clone());
+            match args[0].as_str() {
+                "GET" => RESPValue::bulk_string(Some("41".to_string())),
+                "FAIL" => RESPValue::error("ERR failed".to_string()),
+                "INCRBY" => RESPValue::integer(args[2].parse().unwrap_or(0)),
+                "RPUSH" => RESPValue::integer(args.len() as i64 - 2),
+                "LLEN" => RESPValue::integer(0),
+                "HSET" => RESPValue::integer((args.len() as i64 - 2) / 2),
+                "TIME" => RESPValue::Array(Some(vec![
+                    RESPValue::bulk_string(Some("1700000000".to_string())),
+                    RESPValue::bulk_string(Some("0".to_string())),
+                ])),
+                _ => RESPValue::simple_string("OK".to_string()),
+            }
+        }
+
+        fn register_function(&mut self, registration: Registration) -> Result<(), String> {
+            self.registered.push(registration);
+            Ok(())
+        }
+
+        fn check_running(&mut self) -> Result<(), String> {
+            self.checks += 1;
+            match self.checks {
+                100 => Err("ERR Script killed".to_string()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn load_and_call(
+        code: &str,
+        keys: &[&str],
+        args: &[&str],
+    ) -> (Result<RESPValue, String>, TestHost) {
+        let mut host = TestHost::default();
+        load(code, &mut host).unwrap();
+        let callback = Arc::clone(&host.registered[0].callback);
+        let to_vec = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        let result = call(&callback, to_vec(keys), to_vec(args), &mut host);
+        (result, host)
+    }
+
+    #[test]
+    fn test_register_function_forms() {
+        let mut host = TestHost::default();
+        load(
+            "#!lua name=lib\n\
+             local function f(keys, args) return 1 end\n\
+             redis.register_function('f', f)\n\
+             redis.register_function{function_name='g', callback=function() end, flags={'no-writes'}, description='desc'}",
+            &mut host,
+        )
+        .unwrap();
+        assert_eq!(host.registered.len(), 2);
+        assert_eq!(host.registered[1].name, "g");
+        assert_eq!(host.registered[1].flags, vec!["no-writes".to_string()]);
+        assert_eq!(host.registered[1].description.as_deref(), Some("desc"));
+    }
+
+    #[test]
+    fn test_call_with_keys_and_args() {
+        let (result, host) = load_and_call(
+            "redis.register_function('f', function(keys, args) return redis.call('SET', keys[1], args[1]) end)",
+            &["k"],
+            &["v"],
+        );
+        assert_eq!(result, Ok(RESPValue::simple_string("OK".to_string())));
+        assert_eq!(
+            host.calls,
+            vec![vec!["SET".to_string(), "k".to_string(), "v".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_and_control_flow() {
+        let (result, _) = load_and_call(
+            "redis.register_function('f', function(keys, args)\n\
+               local current = tonumber(redis.call('GET', keys[1]))\n\
+               local total = 0\n\
+               for i = 1, #args do total = total + tonumber(args[i]) end\n\
+               if current + total > 100 then return redis.error_reply('too big') end\n\
+               return current + total .. ''\n\
+             end)",
+            &["counter"],
+            &["1", "2"],
+        );
+        assert_eq!(result, Ok(RESPValue::bulk_string(Some("44".to_string()))));
+    }
+
+    #[test]
+    fn test_closures_share_scopes() {
+        let (result, _) = load_and_call(
+            "local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end\n\
+             redis.register_function('f', function(keys, args) return fib(tonumber(args[1])) end)",
+            &[],
+            &["10"],
+        );
+        assert_eq!(result, Ok(RESPValue::integer(55)));
+
+        // Assignments after the closure was created are seen by it, and its own are seen outside
+        let (result, _) = load_and_call(
+            "redis.register_function('f', function()\n\
+               local count = 0\n\
+               local function bump() count = count + 1 end\n\
+               bump() bump()\n\
+               count = count * 10\n\
+               bump()\n\
+               return count\n\
+             end)",
+            &[],
+            &[],
+        );
+        assert_eq!(result, Ok(RESPValue::integer(21)));
+    }
+
+    #[test]
+    fn test_scripts_are_stopped() {
+        let (result, _) = load_and_call(
+            "redis.register_function('f', function()\n\
+               local i = 0\n\
+               while i < 10 do i = i + 1 end\n\
+               return i\n\
+             end)",
+            &[],
+            &[],
+        );
+        assert_eq!(result, Ok(RESPValue::integer(10)));
+
+        let (result, host) = load_and_call(
+            "redis.register_function('f', function() while true do end end)",
+            &[],
+            &[],
+        );
+        assert_eq!(result, Err("ERR Script killed".to_string()));
+        assert_eq!(host.checks, 100);
+
+        let (result, _) = load_and_call(
+            "local function f(n) return f(n + 1) end\n\
+             redis.register_function('f', function() return f(1) end)",
+            &[],
+            &[],
+        );
+        assert_eq!(result, Err("stack overflow".to_string()));
+    }
+
+    #[test]
+    fn test_return_conversions() {
+        let (result, _) = load_and_call(
+            "redis.register_function('f', function() return {1, 'two', {ok='fine'}, false, nil, 5} end)",
+            &[],
+            &[],
+        );
+        assert_eq!(
+            result,
+            Ok(RESPValue::Array(Some(vec![
+                RESPValue::integer(1),
+                RESPValue::bulk_string(Some("two".to_string())),
+                RESPValue::simple_string("fine".to_string()),
+                RESPValue::bulk_string(None),
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_call_error_propagates_and_pcall_catches() {
+        let (result, _) = load_and_call(
+            "redis.register_function('f', function() return redis.call('FAIL') end)",
+            &[],
+            &[],
+        );
+        assert_eq!(result, Err("ERR failed".to_string()));
+
+        let (result, _) = load_and_call(
+            "redis.register_function('f', function() return redis.pcall('FAIL') end)",
+            &[],
+            &[],
+        );
+        assert_eq!(result, Ok(RESPValue::error("ERR failed".to_string())));
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let mut host = TestHost::default();
+        assert!(load(
+            "redis.register_function('f', function() return end end)",
+            &mut host
+        )
+        .is_err());
+        assert!(load("local = 1", &mut host).is_err());
+    }
+
+    // Runs the body of a function taking no keys or arguments
+    fn run(body: &str) -> Result<RESPValue, String> {
+        let code = format!(
+            "redis.register_function('f', function(keys, args)\n{}\nend)",
+            body
+        );
+        load_and_call(&code, &[], &[]).0
+    }
+
+    fn bulk(s: &str) -> RESPValue {
+        RESPValue::bulk_string(Some(s.to_string()))
+    }
+
+    #[test]
+    fn test_tables_are_mutable() {
+        assert_eq!(
+            run("local t = {}\n\
+                 for i = 1, 3 do t[#t + 1] = i * 10 end\n\
+                 t.name = 'x'\n\
+                 t[3] = nil\n\
+                 return {#t, t[1], t[2], t.name}"),
+            Ok(RESPValue::Array(Some(vec![
+                RESPValue::integer(2),
+                RESPValue::integer(10),
+                RESPValue::integer(20),
+                bulk("x"),
+            ])))
+        );
+        assert_eq!(
+            run("local a, b = 1, 2\n\
+                 a, b = b, a\n\
+                 local t = {}\n\
+                 t.x, t.y = a, b\n\
+                 return {t.x, t.y}"),
+            Ok(RESPValue::Array(Some(vec![
+                RESPValue::integer(2),
+                RESPValue::integer(1),
+            ])))
+        );
+        assert_eq!(
+            run("local ok, err = pcall(error, {err = 'bad'})\n\
+                 return {tostring(ok), err.err}"),
+            Ok(RESPValue::Array(Some(vec![bulk("false"), bulk("bad")])))
+        );
+    }
+
+    #[test]
+    fn test_generic_for() {
+        assert_eq!(
+            run("local t = {10, 20, 30, a = 1, b = 2}\n\
+                 local keys = {}\n\
+                 for k, v in pairs(t) do keys[#keys + 1] = k .. '=' .. v end\n\
+                 local sum = 0\n\
+                 for i, v in ipairs(t) do\n\
+                   if i == 3 then break end\n\
+                   sum = sum + v\n\
+                 end\n\
+                 keys[#keys + 1] = sum\n\
+                 return keys"),
+            Ok(RESPValue::Array(Some(vec![
+                bulk("1=10"),
+                bulk("2=20"),
+                bulk("3=30"),
+                bulk("a=1"),
+                bulk("b=2"),
+                RESPValue::integer(30),
+            ])))
+        );
+        // Fields can be cleared during a traversal
+        assert_eq!(
+            run("local t = {a = 1, b = 2, c = 3}\n\
+                 for k in pairs(t) do t[k] = nil end\n\
+                 return next(t) == nil"),
+            Ok(RESPValue::integer(1))
+        );
+    }
+
+    #[test]
+    fn test_methods_and_varargs() {
+        assert_eq!(
+            run("local counter = {count = 0}\n\
+                 function counter:add(...)\n\
+                   for _, n in ipairs({...}) do self.count = self.count + n end\n\
+                   return select('#', ...), self.count\n\
+                 end\n\
+                 local n, total = counter:add(1, 2, 3)\n\
+                 return {n, total, ('abc'):upper(), select(2, unpack({'x', 'y', 'z'}))}"),
+            Ok(RESPValue::Array(Some(vec![
+                RESPValue::integer(3),
+                RESPValue::integer(6),
+                bulk("ABC"),
+                bulk("y"),
+                bulk("z"),
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_string_library() {
+        let results = vec![
+            ("string.sub('hello', 2, -2)", bulk("ell")),
+            ("('hello'):sub(-3)", bulk("llo")),
+            ("string.rep('ab', 3)", bulk("ababab")),
+            ("string.len('abc') + #'de'", RESPValue::integer(5)),
+            ("string.byte('A')", RESPValue::integer(65)),
+            ("string.char(104, 105)", bulk("hi")),
+            (
+                "string.format('%s=%d %5.2f %-3s| %x %q', 'n', 42, 3.14159, 'a', 255, 'a\"b')",
+                bulk("n=42  3.14 a  | ff \"a\\\"b\""),
+            ),
+            ("string.format('%g %g', 1e20, 0.5)", bulk("1e+20 0.5")),
+            ("string.find('a.b', '.', 1, true)", RESPValue::integer(2)),
+            (
+                "select(2, string.find('key:123', '(%d+)'))",
+                RESPValue::integer(7),
+            ),
+            ("select(3, string.find('key:123', '(%d+)'))", bulk("123")),
+            ("string.match('user:42:name', '^user:(%d+)')", bulk("42")),
+            ("string.match('abc', '^b')", RESPValue::bulk_string(None)),
+            (
+                "(string.gsub('hello world', 'o', '0'))",
+                bulk("hell0 w0rld"),
+            ),
+            ("(string.gsub('a b', '(%w)', '<%1>'))", bulk("<a> <b>")),
+            ("(string.gsub('$x $y', '%$(%w)', {x = 1}))", bulk("1 $y")),
+            ("(string.gsub('a b', '%w', string.upper))", bulk("A B")),
+            (
+                "select(2, string.gsub('aaa', 'a', 'b', 2))",
+                RESPValue::integer(2),
+            ),
+            ("tostring(1.5) .. tostring(10)", bulk("1.510")),
+            ("tonumber('ff', 16)", RESPValue::integer(255)),
+        ];
+        for (expression, expected) in results {
+            assert_eq!(
+                run(&format!("return {}", expression)),
+                Ok(expected),
+                "{}",
+                expression
+            );
+        }
+        assert_eq!(
+            run("local words = {}\n\
+                 for k, v in string.gmatch('a=1, b=2', '(%w+)=(%w+)') do words[#words + 1] = k .. v end\n\
+                 return words"),
+            Ok(RESPValue::Array(Some(vec![bulk("a1"), bulk("b2")])))
+        );
+        assert_eq!(
+            run("return string.rep('x', -1) .. string.format('%d', 'x')"),
+            Err("bad argument #2 to 'format' (number expected, got string)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_table_library() {
+        assert_eq!(
+            run("local t = {'b', 'd'}\n\
+                 table.insert(t, 'e')\n\
+                 table.insert(t, 1, 'a')\n\
+                 table.insert(t, 3, 'c')\n\
+                 local removed = table.remove(t, 1) .. table.remove(t)\n\
+                 return {table.concat(t, ','), removed, table.getn(t)}"),
+            Ok(RESPValue::Array(Some(vec![
+                bulk("b,c,d"),
+                bulk("ae"),
+                RESPValue::integer(3),
+            ])))
+        );
+        assert_eq!(
+            run("local t = {5, 2, 8, 1}\n\
+                 table.sort(t)\n\
+                 local names = {'bob', 'al', 'christine'}\n\
+                 table.sort(names, function(a, b) return #a > #b end)\n\
+                 return {table.concat(t, ' '), table.concat(names, ' ')}"),
+            Ok(RESPValue::Array(Some(vec![
+                bulk("1 2 5 8"),
+                bulk("christine bob al"),
+            ])))
+        );
+        assert_eq!(
+            run("return table.concat({1, {}})"),
+            Err("invalid value (at index 2) in table for 'concat'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pcall_and_error_values() {
+        assert_eq!(
+            run("local ok, err = pcall(redis.call, 'FAIL')\n\
+                 return {tostring(ok), err.err}"),
+            Ok(RESPValue::Array(Some(vec![
+                bulk("false"),
+                bulk("ERR failed")
+            ])))
+        );
+        assert_eq!(
+            run(
+                "local ok, err = pcall(function() local x = nil; return x.y end)\n\
+                 return err"
+            ),
+            Ok(bulk("attempt to index a nil value"))
+        );
+        assert_eq!(run("error('custom')"), Err("custom".to_string()));
+        // A killed script can't carry on by catching it
+        assert_eq!(
+            run("pcall(function() while true do end end)\nreturn 1"),
+            Err("ERR Script killed".to_string())
+        );
+    }
+
+    #[test]
+    fn test_realistic_libraries() {
+        // The example library of the Redis documentation
+        let (result, host) = load_and_call(
+            "#!lua name=mylib\n\
+             local function my_hset(keys, args)\n\
+               local hash = keys[1]\n\
+               local time = redis.call('TIME')[1]\n\
+               if table.getn(args) % 2 == 1 then\n\
+                 redis.log(redis.LOG_WARNING, 'odd number of arguments')\n\
+               end\n\
+               return redis.call('HSET', hash, '_last_modified_', time, unpack(args))\n\
+             end\n\
+             redis.register_function('my_hset', my_hset)",
+            &["h"],
+            &["a", "1"],
+        );
+        assert_eq!(result, Ok(RESPValue::integer(2)));
+        assert_eq!(
+            host.calls[1],
+            vec!["HSET", "h", "_last_modified_", "1700000000", "a", "1"]
+        );
+
+        let (result, host) = load_and_call(
+            "#!lua name=stats\n\
+             local function parse_tags(s)\n\
+               local tags = {}\n\
+               for name, value in string.gmatch(s, '(%w+):(%w+)') do tags[name] = value end\n\
+               return tags\n\
+             end\n\
+             local function encode(tags)\n\
+               local names = {}\n\
+               for name in pairs(tags) do table.insert(names, name) end\n\
+               table.sort(names)\n\
+               local parts = {}\n\
+               for _, name in ipairs(names) do\n\
+                 parts[#parts + 1] = string.format('%s=%s', name, tags[name])\n\
+               end\n\
+               return table.concat(parts, ';')\n\
+             end\n\
+             redis.register_function{\n\
+               function_name = 'record',\n\
+               callback = function(keys, args)\n\
+                 local key = keys[1] .. '{' .. encode(parse_tags(args[1])) .. '}'\n\
+                 local ok, result = pcall(redis.call, 'INCRBY', key, args[2])\n\
+                 if not ok then return redis.error_reply(result.err) end\n\
+                 return {key, result}\n\
+               end,\n\
+               flags = {'no-cluster'},\n\
+             }",
+            &["hits"],
+            &["region:eu, host:web1", "5"],
+        );
+        assert_eq!(
+            result,
+            Ok(RESPValue::Array(Some(vec![
+                bulk("hits{host=web1;region=eu}"),
+                RESPValue::integer(5),
+            ])))
+        );
+        assert_eq!(host.registered[0].flags, vec!["no-cluster".to_string()]);
+
+        let (result, host) = load_and_call(
+            "#!lua name=queue\n\
+             local Queue = {}\n\
+             function Queue.new(key) return {key = key, push = Queue.push, size = Queue.size} end\n\
+             function Queue:push(...)\n\
+               return redis.call('RPUSH', self.key, ...)\n\
+             end\n\
+             function Queue:size() return redis.call('LLEN', self.key) end\n\
+             redis.register_function('enqueue', function(keys, args)\n\
+               local queue = Queue.new(keys[1])\n\
+               local before = queue:size()\n\
+               return before + queue:push(unpack(args))\n\
+             end)",
+            &["jobs"],
+            &["a", "b", "c"],
+        );
+        assert_eq!(result, Ok(RESPValue::integer(3)));
+        assert_eq!(host.calls[1], vec!["RPUSH", "jobs", "a", "b", "c"]);
+    }
+}
@@ -0,0 +1,405 @@
+// Lua patterns, as used by string.find, string.match, string.gmatch and string.gsub, following
+// lstrlib.c of Lua 5.1: `.` and `%a`-style classes (upper case negates), `[...]` sets, the
+// `*`, `+`, `-` and `?` quantifiers, `^` and `$` anchors, `(...)` and `()` captures, `%1`-style
+// back references, `%bxy` balanced matches and `%f[...]` frontiers.
+//
+// Strings are matched as chars, each standing for a byte like everywhere else in the server.
+
+// Lua's LUA_MAXCAPTURES
+const MAX_CAPTURES: usize = 32;
+// Lua's MAXCCALLS, past which a pattern is too complex to match without overflowing the stack
+const MAX_DEPTH: usize = 200;
+
+/// A capture of a successful match
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    /// The start and end of the captured text
+    Text(usize, usize),
+    /// The position of an empty `()` capture
+    Position(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Match {
+    pub end: usize,
+    pub captures: Vec<Capture>,
+}
+
+/// Matches `pattern` against `s` starting exactly at `start`. A leading `^` is matched literally,
+/// anchoring is up to the caller as it differs between the string functions.
+pub fn match_at(s: &[char], pattern: &[char], start: usize) -> Result<Option<Match>, String> {
+    let mut matcher = Matcher {
+        s,
+        p: pattern,
+        level: 0,
+        captures: [(0, CaptureLen::Unfinished); MAX_CAPTURES],
+        depth: 0,
+    };
+    let end = match matcher.do_match(start, 0)? {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let captures = matcher.captures[..matcher.level]
+        .iter()
+        .map(|&(start, len)| match len {
+            CaptureLen::Len(len) => Ok(Capture::Text(start, start + len)),
+            CaptureLen::Position => Ok(Capture::Position(start)),
+            CaptureLen::Unfinished => Err("unfinished capture".to_string()),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Match { end, captures }))
+}
+
+/// Whether the pattern has no special characters, so string.find can look for it as is
+pub fn is_plain(pattern: &[char]) -> bool {
+    !pattern.iter().any(|c| "^$*+?.([%-".contains(*c))
+}
+
+#[derive(Clone, Copy)]
+enum CaptureLen {
+    Unfinished,
+    Position,
+    Len(usize),
+}
+
+struct Matcher<'a> {
+    s: &'a [char],
+    p: &'a [char],
+    level: usize,
+    captures: [(usize, CaptureLen); MAX_CAPTURES],
+    depth: usize,
+}
+
+impl<'a> Matcher<'a> {
+    fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("pattern too complex".to_string());
+        }
+        let result = self.match_here(s, p);
+        self.depth -= 1;
+        result
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        loop {
+            if p == self.p.len() {
+                return Ok(Some(s));
+            }
+            match self.p[p] {
+                '(' if self.p.get(p + 1) == Some(&')') => {
+                    return self.start_capture(s, p + 2, CaptureLen::Position)
+                }
+                '(' => return self.start_capture(s, p + 1, CaptureLen::Unfinished),
+                ')' => return self.end_capture(s, p + 1),
+                '$' if p + 1 == self.p.len() => {
+                    return Ok(if s == self.s.len() { Some(s) } else { None })
+                }
+                '%' => match self.p.get(p + 1).copied() {
+                    Some('b') => match self.match_balance(s, p + 2)? {
+                        Some(end) => {
+                            s = end;
+                            p += 4;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    },
+                    Some('f') => {
+                        p += 2;
+                        if self.p.get(p) != Some(&'[') {
+                            return Err("missing '[' after '%f' in pattern".to_string());
+                        }
+                        let end = self.class_end(p)?;
+                        let previous = if s == 0 { '\0' } else { self.s[s - 1] };
+                        let current = self.s.get(s).copied().unwrap_or('\0');
+                        if self.match_bracket_class(previous, p, end - 1)
+                            || !self.match_bracket_class(current, p, end - 1)
+                        {
+                            return Ok(None);
+                        }
+                        p = end;
+                        continue;
+                    }
+                    Some(d) if d.is_ascii_digit() => match self.match_capture(s, d)? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    },
+                    _ => {}
+                },
+                _ => {}
+            }
+
+            let end = self.class_end(p)?;
+            let matched = self.single_match(s, p, end);
+            match self.p.get(end) {
+                Some('?') => {
+                    if matched {
+                        if let Some(result) = self.do_match(s + 1, end + 1)? {
+                            return Ok(Some(result));
+                        }
+                    }
+                    p = end + 1;
+                }
+                Some('*') => return self.max_expand(s, p, end),
+                Some('+') if matched => return self.max_expand(s + 1, p, end),
+                Some('+') => return Ok(None),
+                Some('-') => return self.min_expand(s, p, end),
+                _ if matched => {
+                    s += 1;
+                    p = end;
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    // The end of the single character class starting at `p`
+    fn class_end(&self, p: usize) -> Result<usize, String> {
+        let mut p = p;
+        let c = self.p[p];
+        p += 1;
+        match c {
+            '%' if p == self.p.len() => Err("malformed pattern (ends with '%')".to_string()),
+            '%' => Ok(p + 1),
+            '[' => {
+                if self.p.get(p) == Some(&'^') {
+                    p += 1;
+                }
+                // The first character of a set is never its end, so `[]]` is a set of `]`
+                loop {
+                    let c = *self.p.get(p).ok_or("malformed pattern (missing ']')")?;
+                    p += 1;
+                    if c == '%' && p < self.p.len() {
+                        p += 1;
+                    }
+                    match self.p.get(p) {
+                        Some(']') => return Ok(p + 1),
+                        Some(_) => {}
+                        None => return Err("malformed pattern (missing ']')".to_string()),
+                    }
+                }
+            }
+            _ => Ok(p),
+        }
+    }
+
+    fn single_match(&self, s: usize, p: usize, end: usize) -> bool {
+        match self.s.get(s) {
+            None => false,
+            Some(&c) => match self.p[p] {
+                '.' => true,
+                '%' => match_class(c, self.p[p + 1]),
+                '[' => self.match_bracket_class(c, p, end - 1),
+                pc => pc == c,
+            },
+        }
+    }
+
+    // Whether `c` is in the set from the `[` at `p` to the `]` at `end`
+    fn match_bracket_class(&self, c: char, p: usize, end: usize) -> bool {
+        let mut p = p + 1;
+        let mut found = true;
+        if self.p[p] == '^' {
+            found = false;
+            p += 1;
+        }
+        while p < end {
+            if self.p[p] == '%' {
+                p += 1;
+                if match_class(c, self.p[p]) {
+                    return found;
+                }
+            } else if p + 2 < end && self.p[p + 1] == '-' {
+                if self.p[p] <= c && c <= self.p[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if self.p[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+        !found
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.p.len() {
+            return Err("unbalanced pattern".to_string());
+        }
+        let (open, close) = (self.p[p], self.p[p + 1]);
+        if self.s.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.s.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while self.single_match(s + count, p, end) {
+            count += 1;
+        }
+        // Backtrack from the longest repetition until the rest of the pattern matches
+        loop {
+            if let Some(result) = self.do_match(s + count, end + 1)? {
+                return Ok(Some(result));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(result) = self.do_match(s, end + 1)? {
+                return Ok(Some(result));
+            }
+            if !self.single_match(s, p, end) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        s: usize,
+        p: usize,
+        len: CaptureLen,
+    ) -> Result<Option<usize>, String> {
+        if self.level == MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.captures[self.level] = (s, len);
+        self.level += 1;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let open = (0..self.level)
+            .rev()
+            .find(|&l| matches!(self.captures[l].1, CaptureLen::Unfinished))
+            .ok_or("invalid pattern capture")?;
+        self.captures[open].1 = CaptureLen::Len(s - self.captures[open].0);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[open].1 = CaptureLen::Unfinished;
+        }
+        Ok(result)
+    }
+
+    // A back reference to the text of an earlier capture
+    fn match_capture(&self, s: usize, digit: char) -> Result<Option<usize>, String> {
+        let index = (digit as usize).wrapping_sub('1' as usize);
+        let (start, len) = match self.captures[..self.level].get(index) {
+            Some(&(start, CaptureLen::Len(len))) => (start, len),
+            Some(&(_, CaptureLen::Position)) => return Ok(Some(s)),
+            _ => return Err("invalid capture index".to_string()),
+        };
+        let matches = self.s.len() - s >= len && self.s[start..start + len] == self.s[s..s + len];
+        Ok(if matches { Some(s + len) } else { None })
+    }
+}
+
+fn match_class(c: char, class: char) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        'a' => c.is_ascii_alphabetic(),
+        'c' => c.is_ascii_control(),
+        'd' => c.is_ascii_digit(),
+        'l' => c.is_ascii_lowercase(),
+        'p' => c.is_ascii_punctuation(),
+        // C's isspace also counts the vertical tab
+        's' => c.is_ascii_whitespace() || c == '\x0b',
+        'u' => c.is_ascii_uppercase(),
+        'w' => c.is_ascii_alphanumeric(),
+        'x' => c.is_ascii_hexdigit(),
+        'z' => c == '\0',
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !matches
+    } else {
+        matches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn find(s: &str, pattern: &str) -> Option<(usize, usize, Vec<Capture>)> {
+        let s: Vec<char> = s.chars().collect();
+        let pattern: Vec<char> = pattern.chars().collect();
+        (0..=s.len()).find_map(|start| {
+            match_at(&s, &pattern, start)
+                .unwrap()
+                .map(|m| (start, m.end, m.captures))
+        })
+    }
+
+    #[test]
+    fn test_classes_and_quantifiers() {
+        assert_eq!(find("ab123c", "%d+"), Some((2, 5, vec![])));
+        assert_eq!(find("ab123c", "%a*"), Some((0, 2, vec![])));
+        assert_eq!(find("key:1:name", ":.-:"), Some((3, 6, vec![])));
+        assert_eq!(find("key:1:name", ":.*:"), Some((3, 6, vec![])));
+        assert_eq!(find("colour", "colou?r"), Some((0, 6, vec![])));
+        assert_eq!(find("x = 0x1F", "0[xX]%x+$"), Some((4, 8, vec![])));
+        assert_eq!(find("a-b", "[%a-]+"), Some((0, 3, vec![])));
+        assert_eq!(find("abc", "[^%a]"), None);
+        assert_eq!(find("THE (quick) fox", "%f[%a]%a+"), Some((0, 3, vec![])));
+        assert_eq!(find("f(a(b)c) d", "%b()"), Some((1, 8, vec![])));
+    }
+
+    #[test]
+    fn test_captures() {
+        assert_eq!(
+            find("name=value", "(%w+)=(%w+)"),
+            Some((0, 10, vec![Capture::Text(0, 4), Capture::Text(5, 10)]))
+        );
+        assert_eq!(
+            find("hello", "()ll()"),
+            Some((2, 4, vec![Capture::Position(2), Capture::Position(4)]))
+        );
+        assert_eq!(
+            find("say \"hi\" now", "([\"'])(.-)%1"),
+            Some((4, 8, vec![Capture::Text(4, 5), Capture::Text(5, 7)]))
+        );
+    }
+
+    #[test]
+    fn test_malformed_patterns() {
+        let s: Vec<char> = "abc".chars().collect();
+        let pattern = |p: &str| p.chars().collect::<Vec<_>>();
+        assert!(match_at(&s, &pattern("[a"), 0).is_err());
+        assert!(match_at(&s, &pattern("a%"), 0).is_err());
+        assert!(match_at(&s, &pattern("(a"), 0).is_err());
+        assert!(match_at(&s, &pattern("a)"), 0).is_err());
+        assert!(match_at(&s, &pattern("%1"), 0).is_err());
+        let deep: Vec<char> = "a*".repeat(MAX_DEPTH + 1).chars().collect();
+        assert_eq!(
+            match_at(&s, &deep, 0),
+            Err("pattern too complex".to_string())
+        );
+    }
+}
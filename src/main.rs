@@ -1,35 +1,272 @@
+mod acl;
+mod bitmaps;
+mod bus;
+mod clients;
+mod cluster;
+mod commands;
+mod config;
+mod databases;
+mod dict;
+mod functions;
+mod geo;
+mod glob;
+mod hyperloglog;
+mod info;
+mod keyspace;
+mod latency;
+mod lua;
+mod lua_patterns;
+mod memory;
+mod monitor;
+mod notify;
+mod persistence;
+mod pubsub;
+mod rdb;
+mod replication;
+mod sentinel;
+mod sha256;
+mod slowlog;
+mod sorted_set;
+mod strings;
+mod tracking;
+mod value;
+
+use bytes::Buf;
+use bytes::BytesMut;
+use dict::Keyspace;
+use functions::RestorePolicy;
+use info::STATS;
+use memory::Access;
+use redis_starter_rust::parse_command;
+use redis_starter_rust::parse_integer;
+use redis_starter_rust::ArgError;
+use redis_starter_rust::ArgResult;
+use redis_starter_rust::Args;
 use redis_starter_rust::BulkString;
+use redis_starter_rust::ParseError;
 use redis_starter_rust::RESPValue;
-use std::collections::HashMap;
-use std::convert::TryInto;
-#[allow(unused_imports)]
+use std::collections::hash_map::RandomState;
 use std::env;
 #[allow(unused_imports)]
 use std::fs;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::sync::RwLockWriteGuard;
 use std::time::Duration;
 use std::time::Instant;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use value::Value;
+
+type Entry = (Value, Option<(Instant, Duration)>, Access);
+type Table = Arc<RwLock<Keyspace>>;
+type Databases = Arc<Vec<Table>>;
+type Functions = Arc<RwLock<functions::Registry>>;
+
+// State shared by every connection
+struct Server {
+    started: Instant,
+    run_id: String,
+    config: RwLock<config::Config>,
+    // Where CONFIG REWRITE saves the configuration
+    config_file: Option<PathBuf>,
+    memory: memory::Memory,
+    clients: clients::Registry,
+    acl: acl::Acl,
+    monitor: monitor::Monitor,
+    slowlog: slowlog::Slowlog,
+    latency: latency::Latency,
+    pubsub: pubsub::PubSub,
+    tracking: tracking::Tracking,
+    cluster: cluster::Cluster,
+    replication: replication::Replication,
+    sentinel: sentinel::Sentinel,
+    // The functions being run, for FUNCTION KILL
+    scripts: functions::Running,
+    persistence: persistence::Persistence,
+    // Commands hold it for reading while they run, and functions and MIGRATE for writing, so that
+    // they run alone like in Redis, where they block the server
+    commands: tokio::sync::RwLock<()>,
+}
+
+impl Server {
+    fn config(&self) -> Result<config::Config, String> {
+        self.config
+            .read()
+            .map(|config| config.clone())
+            .map_err(|e| format!("Failed to acquire lock for config {}", e))
+    }
+}
+
+// State kept for each connection between commands
+#[derive(Clone, Debug, Default)]
+struct Session {
+    // Id of the connection in the client registry
+    client_id: u64,
+    // Index of the selected database
+    db: usize,
+    // The user commands run as, None until the connection authenticates
+    user: Option<String>,
+    // Set by MONITOR, the connection then only receives the commands of the others
+    monitor: bool,
+    // Where messages and other replies the connection didn't ask for are sent, None for the
+    // commands functions run
+    pushes: Option<pubsub::Pushes>,
+    // Set by HELLO 3
+    resp3: bool,
+    // Set by ASKING, for the next command only
+    asking: bool,
+    // Set by PSYNC, the connection then only streams the write commands to the replica
+    replica: bool,
+}
+
+// Write commands that never use more memory, allowed even when memory can't be brought back under
+// maxmemory
+const FREEING_COMMANDS: &[&str] = &[
+    "GETDEL", "MOVE", "SWAPDB", "FLUSHDB", "FLUSHALL", "RENAME", "RENAMENX", "UNLINK",
+];
 
-type Table = Arc<RwLock<HashMap<String, (String, Option<(Instant, Duration)>)>>>;
+// Commands that can't be called with redis.call
+const SCRIPT_DISALLOWED_COMMANDS: &[&str] = &[
+    "FUNCTION",
+    "FCALL",
+    "FCALL_RO",
+    "AUTH",
+    "MONITOR",
+    "HELLO",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "PSYNC",
+    "SYNC",
+    "REPLCONF",
+];
 
 #[tokio::main]
 async fn main() {
+    // Like redis-server, the config file comes first and options given after it override it
+    let mut args = env::args().skip(1).peekable();
+    let config_file = args.next_if(|a| !a.starts_with("--")).map(PathBuf::from);
+    let options = parse_options(args);
+    let config = match config::load(config_file.as_deref(), &options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Uncomment this block to pass the first stage
-    let listener = TcpListener::bind("0.0.0.0:6379").await.unwrap();
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port))
+        .await
+        .unwrap();
     let mut connections = vec![];
-    let table = Arc::new(RwLock::new(HashMap::new()));
+    let databases: Databases = Arc::new(
+        (0..config.databases)
+            .map(|_| Arc::new(RwLock::new(Keyspace::default())))
+            .collect(),
+    );
+    let functions = Arc::new(RwLock::new(functions::Registry::default()));
+    let server = Arc::new(Server {
+        started: Instant::now(),
+        run_id: info::new_run_id(),
+        config: RwLock::new(config),
+        config_file,
+        memory: memory::Memory::default(),
+        clients: clients::Registry::default(),
+        acl: acl::Acl::default(),
+        monitor: monitor::Monitor::default(),
+        slowlog: slowlog::Slowlog::default(),
+        latency: latency::Latency::default(),
+        pubsub: pubsub::PubSub::default(),
+        tracking: tracking::Tracking::default(),
+        cluster: cluster::Cluster::default(),
+        replication: replication::Replication::default(),
+        sentinel: sentinel::Sentinel::default(),
+        scripts: functions::Running::default(),
+        persistence: persistence::Persistence::default(),
+        commands: tokio::sync::RwLock::new(()),
+    });
+    let config = server.config().unwrap_or_default();
+    if let Err(e) = server.acl.set_requirepass(&config.requirepass) {
+        eprintln!("{}", e);
+    }
+    if !config.aclfile.is_empty() {
+        if let Err(e) = server.acl.load_file(std::path::Path::new(&config.aclfile)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = persistence::load_file(&config, &databases, &functions) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if config.cluster_enabled {
+        let path = std::path::Path::new(&config.cluster_config_file);
+        let result = match server.cluster.load(path, config.port as u16) {
+            Ok(()) => {
+                bus::start(
+                    Arc::clone(&server),
+                    Arc::clone(&databases),
+                    Arc::clone(&functions),
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if options.iter().any(|(name, _)| name == "sentinel") {
+        if let Err(e) = server.sentinel.load(server.config_file.as_deref()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        sentinel::start(Arc::clone(&server));
+    }
+    // Samples the statistics that are measured over time and deletes expired keys, like Redis'
+    // serverCron
+    let cron_databases = Arc::clone(&databases);
+    let cron_server = Arc::clone(&server);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            STATS.sample_ops();
+            let started = Instant::now();
+            let result = keyspace::active_expire_cycle(&cron_databases).and_then(|_| {
+                let threshold = cron_server.config()?.latency_monitor_threshold;
+                cron_server
+                    .latency
+                    .record("expire-cycle", started.elapsed(), threshold)?;
+                cron_server.replication.ping_replicas()?;
+                publish_changes(&cron_server, 0, None, vec![], notify::take_pending())
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+            }
+        }
+    });
 
     // TODO: Handle accept errors
     while let Ok((socket, addr)) = listener.accept().await {
-        let table = Arc::clone(&table);
+        let databases = Arc::clone(&databases);
+        let functions = Arc::clone(&functions);
+        let server = Arc::clone(&server);
         connections.push(tokio::task::spawn(async move {
-            handle_client(socket, addr, table).await
+            handle_client(socket, addr, databases, functions, server).await
         }));
     }
 
@@ -49,85 +286,486 @@ async fn main() {
     //     .await;
 }
 
-async fn handle_client(mut socket: TcpStream, addr: SocketAddr, table: Table) {
+// Reads settings given as `--name value` pairs, the same way Redis accepts config options
+fn parse_options(args: impl Iterator<Item = String>) -> Vec<(String, String)> {
+    let mut options = vec![];
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        if let Some(name) = arg.strip_prefix("--") {
+            let value = args.next_if(|a| !a.starts_with("--")).unwrap_or_default();
+            options.push((name.to_lowercase(), value));
+        }
+    }
+    options
+}
+
+async fn handle_client(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    databases: Databases,
+    functions: Functions,
+    server: Arc<Server>,
+) {
     eprintln!("Connected to client {}", addr);
-    let mut command_buf = [0u8; 4096];
+    let (pushes, mut pushed) = tokio::sync::mpsc::unbounded_channel();
+    let registered = server
+        .clients
+        .register(addr, socket.local_addr().ok(), pushes.clone());
+    let (client_id, killed) = match registered {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    STATS.connected_clients.fetch_add(1, Relaxed);
+    STATS.total_connections_received.fetch_add(1, Relaxed);
+    let mut session = Session {
+        client_id,
+        user: server.acl.initial_user().unwrap_or_default(),
+        pushes: Some(pushes),
+        ..Session::default()
+    };
+    let mut chunk = [0u8; 4096];
+    // Bytes read but not run yet, which may end with part of a command
+    let mut read_buf = BytesMut::new();
+    // Replies are encoded here, reusing its allocation
+    let mut reply_buf = BytesMut::new();
     loop {
-        match socket.read(&mut command_buf).await {
-            Ok(0) => {
-                eprintln!("Connection terminated by client {}", addr);
+        let command = match next_command(&mut read_buf) {
+            Ok(Some(command)) => command,
+            Ok(None) => {
+                let timeout = server.config().map_or(0, |config| config.timeout);
+                let idle = async {
+                    match timeout {
+                        0 => std::future::pending().await,
+                        t => tokio::time::sleep(Duration::from_secs(t as u64)).await,
+                    }
+                };
+                let result = tokio::select! {
+                    result = socket.read(&mut chunk) => result,
+                    Some(push) = pushed.recv() => {
+                        reply_buf.clear();
+                        encode_push(push, &session, &mut reply_buf);
+                        if let Err(e) = socket.write_all(&reply_buf).await {
+                            eprintln!("Error while writing data to client {}\n{}", addr, e);
+                            break;
+                        }
+                        continue;
+                    }
+                    _ = idle => {
+                        eprintln!("Closing idle client {}", addr);
+                        break;
+                    }
+                    _ = killed.notified() => {
+                        eprintln!("Client {} was killed", addr);
+                        break;
+                    }
+                };
+                match result {
+                    Ok(0) => {
+                        eprintln!("Connection terminated by client {}", addr);
+                        break;
+                    }
+                    Ok(n) => read_buf.extend_from_slice(&chunk[..n]),
+                    Err(e) => {
+                        eprintln!("Error while reading data from client {}\n{}", addr, e);
+                        break;
+                    }
+                }
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Protocol error from client {}\n{}", addr, e);
+                // Like Redis, the client is told why before the connection is closed
+                let reply = RESPValue::error(format!("ERR Protocol error: {}", e));
+                if let Err(e) = socket.write_all(&reply.to_bytes()).await {
+                    eprintln!("Error while writing data to client {}\n{}", addr, e);
+                }
+                break;
+            }
+        };
+        let locked = async {
+            server.clients.wait_unpaused(&command).await;
+            lock_command(&command, &server.commands).await
+        };
+        let guard = tokio::select! {
+            guard = locked => guard,
+            _ = killed.notified() => {
+                eprintln!("Client {} was killed", addr);
                 break;
             }
-            Ok(n) => match handle_command(&command_buf[..n], table.clone()) {
-                Ok(resp) => {
-                    eprintln!("Sending response {:?}", resp);
-                    socket.write(format!("{}", resp).as_bytes()).await.unwrap();
-                    socket.flush().await.unwrap();
+        };
+        let result = handle_command(
+            &command,
+            &databases,
+            &mut session,
+            functions.clone(),
+            &server,
+        );
+        drop(guard);
+        match result {
+            Ok(resp) => {
+                eprintln!("Sending response {:?}", resp);
+                // Replies pushed while the command ran, like the confirmations of all but the
+                // last channel of SUBSCRIBE, go first
+                reply_buf.clear();
+                while let Ok(push) = pushed.try_recv() {
+                    encode_push(push, &session, &mut reply_buf);
                 }
-                Err(e) => {
-                    eprintln!("Error while handling command\n{}", e);
+                if session.resp3 {
+                    resp.encode_into(&mut reply_buf);
+                } else {
+                    resp.into_resp2().encode_into(&mut reply_buf);
                 }
-            },
+                if let Err(e) = socket.write_all(&reply_buf).await {
+                    eprintln!("Error while writing data to client {}\n{}", addr, e);
+                    break;
+                }
+                if let Err(e) = socket.flush().await {
+                    eprintln!("Error while writing data to client {}\n{}", addr, e);
+                    break;
+                }
+            }
             Err(e) => {
-                eprintln!("Error while reading data from client {}\n{}", addr, e);
-                break;
+                eprintln!("Error while handling command\n{}", e);
             }
         }
+        if session.monitor {
+            stream_monitor(&mut socket, addr, &server, &killed).await;
+            break;
+        }
+        if session.replica {
+            replication::serve_replica(&mut socket, addr, &server, client_id, &killed).await;
+            break;
+        }
+    }
+    STATS.connected_clients.fetch_sub(1, Relaxed);
+    if let Err(e) = server.pubsub.remove_client(client_id) {
+        eprintln!("{}", e);
+    }
+    if let Err(e) = server.tracking.remove_client(client_id) {
+        eprintln!("{}", e);
+    }
+    if let Err(e) = server.replication.remove_client(client_id) {
+        eprintln!("{}", e);
+    }
+    if let Err(e) = server.clients.unregister(client_id) {
+        eprintln!("{}", e);
+    }
+}
+
+// Held while a command runs, the read guard for most commands and the write guard for functions
+// and MIGRATE
+type CommandGuard<'a> = (
+    Option<tokio::sync::RwLockReadGuard<'a, ()>>,
+    Option<tokio::sync::RwLockWriteGuard<'a, ()>>,
+);
+
+// Waits until the command can run. FUNCTION KILL and FUNCTION STATS don't wait for the function
+// running, like the commands Redis allows while it's busy, or it could never be stopped.
+async fn lock_command<'a>(
+    command: &[BulkString],
+    lock: &'a tokio::sync::RwLock<()>,
+) -> CommandGuard<'a> {
+    let name = |i: usize| {
+        command
+            .get(i)
+            .and_then(|arg| arg.as_deref())
+            .unwrap_or_default()
+            .to_uppercase()
+    };
+    match (name(0).as_str(), name(1).as_str()) {
+        ("FCALL", _) | ("FCALL_RO", _) | ("MIGRATE", _) => (None, Some(lock.write().await)),
+        ("FUNCTION", "KILL") | ("FUNCTION", "STATS") => (None, None),
+        _ => (Some(lock.read().await), None),
     }
 }
 
-fn handle_command(command_buf: &[u8], table: Table) -> Result<RESPValue, String> {
-    let (resp_value, _) = RESPValue::parse(command_buf).map_err(|e| format!("{:?}", e))?;
-    eprintln!("Received command: {:?}", resp_value);
-    // TODO: Make this easier
-    let command: Option<Vec<BulkString>> = resp_value.try_into().unwrap();
-    let command = command.unwrap_or_default();
+// Replies pushed to RESP3 connections are push replies, RESP2 ones get them as arrays
+fn encode_push(push: RESPValue, session: &Session, buf: &mut BytesMut) {
+    match push {
+        RESPValue::Array(Some(values)) if session.resp3 => RESPValue::Push(values).encode_into(buf),
+        push if session.resp3 => push.encode_into(buf),
+        push => push.into_resp2().encode_into(buf),
+    }
+}
+
+// Sends the feed of executed commands until the monitor disconnects or is killed
+async fn stream_monitor(
+    socket: &mut TcpStream,
+    addr: SocketAddr,
+    server: &Server,
+    killed: &Notify,
+) {
+    let mut feed = server.monitor.subscribe();
+    let mut buf = [0u8; 4096];
+    loop {
+        tokio::select! {
+            line = feed.recv() => match line {
+                Ok(line) => {
+                    let reply = RESPValue::simple_string(line).to_bytes();
+                    if let Err(e) = socket.write_all(&reply).await {
+                        eprintln!("Error while writing data to client {}\n{}", addr, e);
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    eprintln!("Monitor {} missed {} commands", addr, missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // Whatever a monitor sends is ignored
+            result = socket.read(&mut buf) => {
+                if matches!(result, Ok(0) | Err(_)) {
+                    eprintln!("Connection terminated by client {}", addr);
+                    break;
+                }
+            }
+            _ = killed.notified() => {
+                eprintln!("Client {} was killed", addr);
+                break;
+            }
+        }
+    }
+}
 
-    if command.is_empty() {
-        return Err("Empty command".into());
+// Takes the first complete command, with its arguments, off the read buffer. A command that
+// hasn't been read completely stays there, and empty ones are skipped.
+fn next_command(read_buf: &mut BytesMut) -> Result<Option<Vec<BulkString>>, String> {
+    loop {
+        let (command, len) = match parse_command(read_buf) {
+            Ok(parsed) => parsed,
+            Err(ParseError::NotEnoughBytes) => return Ok(None),
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        read_buf.advance(len);
+        eprintln!("Received command: {:?}", command);
+        match command {
+            Some(command) if !command.is_empty() => return Ok(Some(command)),
+            _ => {}
+        }
     }
+}
 
-    Ok(gen_response(
-        command[0].as_ref().unwrap(),
+fn handle_command(
+    command: &[BulkString],
+    databases: &Databases,
+    session: &mut Session,
+    functions: Functions,
+    server: &Arc<Server>,
+) -> Result<RESPValue, String> {
+    let args = match Args::new("", command).rest() {
+        Ok(args) => args,
+        Err(e) => return Ok(e.into()),
+    };
+    server.clients.record_command(session, &args)?;
+    if let Some(error) = acl::check(&args, session, "toplevel", server)? {
+        return Ok(error);
+    }
+    if let Some(error) = pubsub::check_subscribed(args[0], session, server)? {
+        return Ok(error);
+    }
+    let asking = std::mem::take(&mut session.asking);
+    if let Some(redirect) = cluster::check(&args, databases, asking, server)? {
+        return Ok(redirect);
+    }
+    if let Some(error) = replication::check(&args, server)? {
+        return Ok(error);
+    }
+    if let Some(error) = sentinel::check(&args, server)? {
+        return Ok(error);
+    }
+    // Like Redis, admin commands aren't shown to monitors
+    if server.monitor.is_active()
+        && commands::lookup(&args).is_some_and(|c| !c.has_category("admin"))
+    {
+        if let Some((addr, _)) = server.clients.peer(session.client_id)? {
+            server.monitor.feed(session.db, &addr, &args);
+        }
+    }
+    let db = session.db;
+    let started = Instant::now();
+    let response = gen_response(
+        args[0],
         &command[1..],
-        table,
-    )?)
+        databases,
+        session,
+        functions,
+        server,
+    );
+    let duration = started.elapsed();
+    if session.db != db {
+        server.clients.record_db(session)?;
+    }
+    // AUTH is left out of the slowlog, like Redis does, as its arguments are passwords
+    if let Some(command) = commands::lookup(&args).filter(|c| c.name != "auth") {
+        let config = server.config()?;
+        if let Some(peer) = server.clients.peer(session.client_id)? {
+            server.slowlog.record(
+                &args,
+                duration,
+                peer,
+                config.slowlog_log_slower_than,
+                config.slowlog_max_len,
+            )?;
+        }
+        let event = if command.has_category("fast") {
+            "fast-command"
+        } else {
+            "command"
+        };
+        server
+            .latency
+            .record(event, duration, config.latency_monitor_threshold)?;
+    }
+    response
 }
 
-fn gen_response(command: &String, args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+fn gen_response(
+    command: &str,
+    args: &[BulkString],
+    databases: &Databases,
+    session: &mut Session,
+    functions: Functions,
+    server: &Arc<Server>,
+) -> Result<RESPValue, String> {
     eprintln!("Handling command: {}", command);
-    match command.as_str() {
-        "ECHO" | "echo" => {
-            if args.is_empty() {
-                return Err("No message to ECHO".to_string());
+    STATS.total_commands_processed.fetch_add(1, Relaxed);
+    let full_args: Vec<&str> = std::iter::once(command)
+        .chain(args.iter().map(|a| a.as_deref().unwrap_or_default()))
+        .collect();
+    // The entry of the subcommand when there is one, FUNCTION LOAD writes while FUNCTION LIST
+    // doesn't
+    let write = commands::is_write(&full_args);
+    // Like Redis, memory is reclaimed before every command, and commands that may use more are
+    // refused when that isn't possible. As with replica-ignore-maxmemory, replicas leave that to
+    // their master and apply every write it streams, whatever memory they use.
+    let name = command.to_uppercase();
+    let config = server.config()?;
+    let evicted = server.replication.is_replica()? || {
+        let started = Instant::now();
+        let evicted = server
+            .memory
+            .evict(databases, &config.memory, &server.replication)?;
+        server.latency.record(
+            "eviction-cycle",
+            started.elapsed(),
+            config.latency_monitor_threshold,
+        )?;
+        evicted
+    };
+    if !evicted && write && !FREEING_COMMANDS.contains(&name.as_str()) {
+        return Ok(RESPValue::error(
+            "OOM command not allowed when used memory > 'maxmemory'.".to_string(),
+        ));
+    }
+
+    // Keyspace notifications tell created and deleted keys apart by looking at them before and
+    // after the command
+    let notifying = config.notify_keyspace_events & (notify::KEYSPACE | notify::KEYEVENT) != 0;
+    let db = session.db;
+    let before = if write && notifying {
+        notify::existing_keys(&full_args, databases, db)?
+    } else {
+        vec![]
+    };
+    // Writes are streamed to the replicas as they ran, unless they tell what to stream instead.
+    // MIGRATE streams its deletions itself, so as not to hold the guard while it waits for the
+    // target instance.
+    let writes = if write && name != "MIGRATE" {
+        Some(server.replication.lock_writes()?)
+    } else {
+        None
+    };
+    let response = dispatch(command, args, databases, session, functions, server);
+    let rewritten = replication::take_rewritten();
+    let response = response?;
+    match rewritten {
+        Some(commands) if write => {
+            for command in commands {
+                let command: Vec<&str> = command.iter().map(String::as_str).collect();
+                server.replication.propagate(Some(db), &command)?;
             }
-            // TODO: Make this easier
-            let message = args[0].as_ref().map(|s| s.as_str()).unwrap_or_default();
-            Ok(RESPValue::bulk_string(Some(message.to_string())))
+        }
+        None if write && !matches!(response, RESPValue::Error(_)) => {
+            server.replication.propagate(Some(db), &full_args)?
+        }
+        _ => {}
+    }
+    drop(writes);
+    server
+        .tracking
+        .record_command(session.client_id, &full_args)?;
+    let pending = notify::take_pending();
+    let (changed, events) = if write && !matches!(response, RESPValue::Error(_)) {
+        let changed = commands::lookup(&full_args).map_or(vec![], |c| c.keys(&full_args));
+        let events = if notifying {
+            let after = notify::existing_keys(&full_args, databases, db)?;
+            notify::command_events(&full_args, &response, &before, &after)
+        } else {
+            vec![]
+        };
+        (changed, events)
+    } else {
+        (vec![], vec![])
+    };
+    if matches!(name.as_str(), "FLUSHDB" | "FLUSHALL" | "SWAPDB") {
+        server.tracking.invalidate_all(server)?;
+    }
+    publish_changes(server, db, Some(session.client_id), changed, pending)?;
+    notify::publish(server, db, events)?;
+    Ok(response)
+}
+
+// Invalidates the keys changed by the client, or that expired or were evicted, and publishes
+// the events of the latter
+fn publish_changes(
+    server: &Server,
+    db: usize,
+    client_id: Option<u64>,
+    changed: Vec<&str>,
+    pending: Vec<notify::Event>,
+) -> Result<(), String> {
+    server.tracking.invalidate(&changed, client_id, server)?;
+    let removed: Vec<&str> = pending.iter().map(|e| e.key.as_str()).collect();
+    server.tracking.invalidate(&removed, None, server)?;
+    notify::publish(server, db, pending)
+}
+
+// Runs the command itself
+fn dispatch(
+    command: &str,
+    args: &[BulkString],
+    databases: &Databases,
+    session: &mut Session,
+    functions: Functions,
+    server: &Arc<Server>,
+) -> Result<RESPValue, String> {
+    let table = databases[session.db].clone();
+    match command {
+        "ECHO" | "echo" => {
+            let mut args = Args::new(command, args);
+            let message = args.next_str().and_then(|m| args.end().map(|_| m));
+            Ok(match message {
+                Ok(message) => RESPValue::bulk_string(Some(message.to_string())),
+                Err(e) => e.into(),
+            })
         }
         "SET" | "set" => {
-            let mut args = args.into_iter().flat_map(|s| s.as_deref());
-            // TODO: Make this easier
-            let key = args
-                .next()
-                .ok_or_else(|| "No key specified for set operation".to_string())?;
-            let value = args
-                .next()
-                .ok_or_else(|| "No key specified for set operation".to_string())?;
-            let expiry_time_millis = args
-                .find(|&a| a == "px")
-                .and_then(|_| args.next())
-                .map(|t| t.parse())
-                .and_then(Result::ok)
-                .map(Duration::from_millis);
+            let mut args = Args::new(command, args);
+            let (key, value, expiry_time_millis) = match set_args(&mut args) {
+                Ok(parsed) => parsed,
+                Err(e) => return Ok(e.into()),
+            };
 
             eprintln!("SET {} {}", key, value);
 
             Ok(match table.write() {
                 Ok(mut t) => {
                     match t.get_mut(key) {
-                        Some((old_value, expiry_info)) =>  {
-                            *old_value = value.to_string();
+                        Some((old_value, expiry_info, _)) => {
+                            *old_value = Value::from_string(value.to_string());
                             if let Some(new_expiry_time) = expiry_time_millis {
                                 *expiry_info = Some((Instant::now(), new_expiry_time));
                             }
@@ -136,8 +774,9 @@ fn gen_response(command: &String, args: &[BulkString], table: Table) -> Result<R
                             t.insert(
                                 key.to_string(),
                                 (
-                                    value.to_string(),
+                                    Value::from_string(value.to_string()),
                                     expiry_time_millis.map(|t| (Instant::now(), t)),
+                                    Access::new(),
                                 ),
                             );
                         }
@@ -152,34 +791,481 @@ fn gen_response(command: &String, args: &[BulkString], table: Table) -> Result<R
             })
         }
         "GET" | "get" => {
-            let mut args = args.into_iter();
-            // TODO: Make this easier
-            let key = args
-                .next()
-                .and_then(|s| s.as_deref())
-                .ok_or_else(|| "No key specified for get operation".to_string())?;
+            let mut args = Args::new(command, args);
+            let key = match args.next_key().and_then(|key| args.end().map(|_| key)) {
+                Ok(key) => key,
+                Err(e) => return Ok(e.into()),
+            };
 
             eprintln!("GET {}", key);
 
-            match table.read() {
-                Ok(t) => match t.get(key) {
-                    Some((value, None)) => Ok(RESPValue::bulk_string(Some(value.to_string()))),
-                    // Key still hasn't expired
-                    Some((value, Some((t_insert, duration))))
-                        if t_insert.elapsed() <= *duration =>
-                    {
-                        Ok(RESPValue::bulk_string(Some(value.to_string())))
-                    }
-                    // Key has expired
-                    // TODO: actually delete key
-                    Some((_, Some(_))) => Ok(RESPValue::bulk_string(None)),
-                    None => Ok(RESPValue::bulk_string(None)),
-                },
-                // TODO: Read up on error handling
-                Err(e) => Err(format!("Failed to acquire lock for table {}", e)),
+            // The write lock lets expired keys be deleted and the access be recorded for eviction
+            let mut t = write_table(&table)?;
+            match strings::get_string(&mut t, key) {
+                Ok(value) => Ok(RESPValue::bulk_string(value)),
+                Err(e) => Ok(e),
             }
         }
-        "PING" | "ping" => Ok(RESPValue::SimpleString("PONG".to_string())),
+        "INCR" | "incr" | "DECR" | "decr" | "INCRBY" | "incrby" | "DECRBY" | "decrby" => {
+            strings::incr_by(&command.to_uppercase(), args, table)
+        }
+        "INCRBYFLOAT" | "incrbyfloat" => strings::incr_by_float(args, table),
+        "APPEND" | "append" => strings::append(args, table),
+        "STRLEN" | "strlen" => strings::strlen(args, table),
+        "GETRANGE" | "getrange" => strings::getrange(args, table),
+        "SETRANGE" | "setrange" => strings::setrange(args, table),
+        "GETDEL" | "getdel" => strings::getdel(args, table),
+        "GETEX" | "getex" => strings::getex(args, table),
+        "GETSET" | "getset" => strings::getset(args, table),
+        "MGET" | "mget" => strings::mget(args, table),
+        "MSET" | "mset" => strings::mset(args, table, false),
+        "MSETNX" | "msetnx" => strings::mset(args, table, true),
+        "SETNX" | "setnx" => strings::setnx(args, table),
+        "SETEX" | "setex" => strings::setex(args, table, "EX"),
+        "PSETEX" | "psetex" => strings::setex(args, table, "PX"),
+        "LCS" | "lcs" => strings::lcs(args, table),
+        "SETBIT" | "setbit" => bitmaps::setbit(args, table),
+        "GETBIT" | "getbit" => bitmaps::getbit(args, table),
+        "BITCOUNT" | "bitcount" => bitmaps::bitcount(args, table),
+        "BITPOS" | "bitpos" => bitmaps::bitpos(args, table),
+        "BITOP" | "bitop" => bitmaps::bitop(args, table),
+        "BITFIELD" | "bitfield" => bitmaps::bitfield(args, table, false),
+        "BITFIELD_RO" | "bitfield_ro" => bitmaps::bitfield(args, table, true),
+        "PFADD" | "pfadd" => hyperloglog::pfadd(args, table),
+        "PFCOUNT" | "pfcount" => hyperloglog::pfcount(args, table),
+        "PFMERGE" | "pfmerge" => hyperloglog::pfmerge(args, table),
+        "GEOADD" | "geoadd" => geo::geoadd(args, table),
+        "GEOPOS" | "geopos" => geo::geopos(args, table),
+        "GEODIST" | "geodist" => geo::geodist(args, table),
+        "GEOHASH" | "geohash" => geo::geohash(args, table),
+        "GEOSEARCH" | "geosearch" => geo::geosearch(args, table, false),
+        "GEOSEARCHSTORE" | "geosearchstore" => geo::geosearch(args, table, true),
+        "PING" | "ping" => pubsub::ping(args, session, server),
+        "FUNCTION" | "function" => handle_function_command(args, functions, server),
+        "SELECT" | "select" => {
+            let cluster_enabled = server.cluster.state()?.is_some();
+            databases::select(args, databases, session, cluster_enabled)
+        }
+        "MOVE" | "move" => databases::move_key(args, databases, session),
+        "SWAPDB" | "swapdb" => databases::swapdb(args, databases),
+        "FLUSHDB" | "flushdb" => databases::flushdb(args, table),
+        "FLUSHALL" | "flushall" => databases::flushall(args, databases),
+        "DBSIZE" | "dbsize" => databases::dbsize(args, table),
+        "KEYS" | "keys" => keyspace::keys(args, table),
+        "SCAN" | "scan" => keyspace::scan(args, table),
+        "TYPE" | "type" => keyspace::key_type(args, table),
+        "RANDOMKEY" | "randomkey" => keyspace::randomkey(args, table),
+        "RENAME" | "rename" => keyspace::rename(args, table, false),
+        "RENAMENX" | "renamenx" => keyspace::rename(args, table, true),
+        "COPY" | "copy" => keyspace::copy(args, databases, session),
+        "UNLINK" | "unlink" => keyspace::unlink(args, table),
+        "TOUCH" | "touch" => keyspace::touch(args, table),
+        "DUMP" | "dump" => keyspace::dump(args, table),
+        "RESTORE" | "restore" | "RESTORE-ASKING" | "restore-asking" => {
+            keyspace::restore(args, table)
+        }
+        "MIGRATE" | "migrate" => cluster::migrate(args, table, session.db, &server.replication),
+        "ASKING" | "asking" => cluster::asking(args, session, server),
+        "MEMORY" | "memory" => memory::memory_command(args, table),
+        "INFO" | "info" => info::info(args, databases, server),
+        "SAVE" | "save" => persistence::save(args, databases, &functions, server),
+        "BGSAVE" | "bgsave" => persistence::bgsave(args, databases, &functions, server),
+        "LASTSAVE" | "lastsave" => persistence::lastsave(args, server),
+        "CONFIG" | "config" => config::config_command(args, server),
+        "CLIENT" | "client" => clients::client_command(args, session, server),
+        "AUTH" | "auth" => acl::auth(args, session, server),
+        "HELLO" | "hello" => clients::hello(args, session, server),
+        "CLUSTER" | "cluster" => cluster::cluster_command(args, databases, server),
+        "REPLICAOF" | "replicaof" | "SLAVEOF" | "slaveof" => {
+            replication::replicaof(command, args, databases, &functions, server)
+        }
+        "PSYNC" | "psync" | "SYNC" | "sync" => {
+            replication::psync(command, args, session, databases, &functions, server)
+        }
+        "REPLCONF" | "replconf" => replication::replconf(args, session, server),
+        "SENTINEL" | "sentinel" => sentinel::sentinel_command(args, server),
+        "ACL" | "acl" => acl::acl_command(args, session, server),
+        "SLOWLOG" | "slowlog" => slowlog::slowlog_command(args, server),
+        "LATENCY" | "latency" => latency::latency_command(args, server),
+        "SUBSCRIBE" | "subscribe" | "UNSUBSCRIBE" | "unsubscribe" | "PSUBSCRIBE" | "psubscribe"
+        | "PUNSUBSCRIBE" | "punsubscribe" => {
+            pubsub::subscribe_command(command, args, session, server)
+        }
+        "PUBLISH" | "publish" => pubsub::publish(args, server),
+        "PUBSUB" | "pubsub" => pubsub::pubsub_command(args, server),
+        "MONITOR" | "monitor" => {
+            session.monitor = true;
+            server.clients.record_monitor(session)?;
+            Ok(RESPValue::simple_string("OK".to_string()))
+        }
+        "FCALL" | "fcall" => fcall(args, databases, session, functions, server, false),
+        "FCALL_RO" | "fcall_ro" => fcall(args, databases, session, functions, server, true),
         c => Err(format!("Unknown command {}", c)),
     }
 }
+
+// Reads the key, value and PX expiry of SET
+fn set_args<'a>(args: &mut Args<'a>) -> ArgResult<(&'a str, &'a str, Option<Duration>)> {
+    let key = args.next_key()?;
+    let value = args.next_str()?;
+    let mut expiry = None;
+    while !args.is_empty() {
+        let millis = match args.keyword_with_value("PX")? {
+            Some(millis) => parse_integer(millis).ok_or(ArgError::NotAnInteger)?,
+            None => return Err(ArgError::Syntax),
+        };
+        if millis <= 0 {
+            return Err(ArgError::InvalidExpireTime(args.command().to_string()));
+        }
+        expiry = Some(Duration::from_millis(millis as u64));
+    }
+    Ok((key, value, expiry))
+}
+
+// Removes the key if it has expired, returns its entry otherwise
+fn get_live_entry<'a>(t: &'a mut Keyspace, key: &str) -> Option<&'a mut Entry> {
+    if let Some((_, Some((t_insert, duration)), _)) = t.get(key) {
+        if t_insert.elapsed() > *duration {
+            t.remove(key);
+            STATS.expired_keys.fetch_add(1, Relaxed);
+            notify::raise(notify::Event::new(notify::EXPIRED, "expired", key, None));
+        }
+    }
+    match t.get_mut(key) {
+        Some(entry) => {
+            STATS.keyspace_hits.fetch_add(1, Relaxed);
+            entry.2.touch();
+            Some(entry)
+        }
+        None => {
+            STATS.keyspace_misses.fetch_add(1, Relaxed);
+            None
+        }
+    }
+}
+
+fn write_table(table: &Table) -> Result<RwLockWriteGuard<'_, Keyspace>, String> {
+    table
+        .write()
+        .map_err(|e| format!("Failed to acquire lock for table {}", e))
+}
+
+// Every RandomState is seeded with fresh keys, which is enough randomness without a dependency
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn not_an_integer() -> RESPValue {
+    RESPValue::error("ERR value is not an integer or out of range".to_string())
+}
+
+fn wrong_type() -> RESPValue {
+    RESPValue::error(
+        "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+    )
+}
+
+fn handle_function_command(
+    args: &[BulkString],
+    functions: Functions,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let mut args = Args::new("function", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    // Functions run without the registry lock, which FUNCTION KILL must not wait for
+    match (subcommand.as_str(), args.as_slice()) {
+        ("KILL", []) => return server.scripts.kill(),
+        ("KILL", _) => {
+            return Ok(ArgError::WrongNumberOfArguments("function|kill".to_string()).into())
+        }
+        _ => {}
+    }
+    let mut registry = functions
+        .write()
+        .map_err(|e| format!("Failed to acquire lock for functions {}", e))?;
+    let syntax_error = || RESPValue::error("ERR syntax error".to_string());
+
+    Ok(match (subcommand.as_str(), args.as_slice()) {
+        ("LOAD", [code]) => into_reply(
+            registry
+                .load(code, false)
+                .map(|n| RESPValue::bulk_string(Some(n))),
+        ),
+        ("LOAD", [replace, code]) if replace.eq_ignore_ascii_case("REPLACE") => into_reply(
+            registry
+                .load(code, true)
+                .map(|n| RESPValue::bulk_string(Some(n))),
+        ),
+        ("DELETE", [library]) => {
+            if registry.delete(library) {
+                RESPValue::simple_string("OK".to_string())
+            } else {
+                RESPValue::error("ERR Library not found".to_string())
+            }
+        }
+        ("FLUSH", []) => {
+            registry.flush();
+            RESPValue::simple_string("OK".to_string())
+        }
+        ("FLUSH", [mode])
+            if mode.eq_ignore_ascii_case("ASYNC") || mode.eq_ignore_ascii_case("SYNC") =>
+        {
+            registry.flush();
+            RESPValue::simple_string("OK".to_string())
+        }
+        ("LIST", options) => {
+            let mut pattern = None;
+            let mut with_code = false;
+            let mut options = options.iter();
+            while let Some(option) = options.next() {
+                match option.to_uppercase().as_str() {
+                    "WITHCODE" => with_code = true,
+                    "LIBRARYNAME" => match options.next() {
+                        Some(p) => pattern = Some(*p),
+                        None => return Ok(syntax_error()),
+                    },
+                    _ => return Ok(syntax_error()),
+                }
+            }
+            registry.list(pattern, with_code)
+        }
+        ("DUMP", []) => {
+            RESPValue::bulk_string(Some(redis_starter_rust::bytes_to_string(&registry.dump())))
+        }
+        ("RESTORE", [payload, options @ ..]) => {
+            let policy = match options {
+                [] => RestorePolicy::Append,
+                [p] if p.eq_ignore_ascii_case("APPEND") => RestorePolicy::Append,
+                [p] if p.eq_ignore_ascii_case("REPLACE") => RestorePolicy::Replace,
+                [p] if p.eq_ignore_ascii_case("FLUSH") => RestorePolicy::Flush,
+                _ => {
+                    return Ok(RESPValue::error(
+                        "ERR Wrong restore policy given".to_string(),
+                    ))
+                }
+            };
+            let payload = redis_starter_rust::string_to_bytes(payload);
+            into_reply(
+                registry
+                    .restore(&payload, policy)
+                    .map(|_| RESPValue::simple_string("OK".to_string())),
+            )
+        }
+        ("LOAD", _) | ("DELETE", _) | ("FLUSH", _) | ("DUMP", _) | ("RESTORE", _) => syntax_error(),
+        (s, _) => RESPValue::error(format!("ERR unknown subcommand '{}'", s)),
+    })
+}
+
+fn into_reply(result: Result<RESPValue, String>) -> RESPValue {
+    result.unwrap_or_else(RESPValue::error)
+}
+
+fn fcall(
+    args: &[BulkString],
+    databases: &Databases,
+    session: &Session,
+    functions: Functions,
+    server: &Arc<Server>,
+    read_only: bool,
+) -> Result<RESPValue, String> {
+    let mut args = Args::new(if read_only { "fcall_ro" } else { "fcall" }, args);
+    let parsed = args
+        .next_str()
+        .and_then(|name| Ok((name.to_string(), args.next_i64()?, args.rest()?)));
+    let (name, numkeys, args) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut args: Vec<String> = args.into_iter().map(str::to_string).collect();
+    let numkeys = match numkeys {
+        n if n < 0 => {
+            return Ok(RESPValue::error(
+                "ERR Number of keys can't be negative".to_string(),
+            ))
+        }
+        n if n as usize > args.len() => {
+            return Ok(RESPValue::error(
+                "ERR Number of keys can't be greater than number of args".to_string(),
+            ))
+        }
+        n => n as usize,
+    };
+    let keys: Vec<String> = args.drain(..numkeys).collect();
+
+    // Don't hold the registry lock while the function runs, it may call back into the server
+    let function = functions
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for functions {}", e))?
+        .find(&name);
+    let function = match function {
+        Some(f) => f,
+        None => return Ok(RESPValue::error("ERR Function not found".to_string())),
+    };
+    if read_only && !function.is_read_only() {
+        return Ok(RESPValue::error(
+            "ERR Can not execute a script with write flag using *_ro command.".to_string(),
+        ));
+    }
+
+    // SELECT within the function doesn't affect the caller
+    let mut host = ScriptHost {
+        databases: databases.clone(),
+        session: Session {
+            pushes: None,
+            ..session.clone()
+        },
+        functions,
+        server: server.clone(),
+        read_only: function.is_read_only(),
+        script: server.scripts.start()?,
+    };
+    // Like MIGRATE, the worker thread is handed over to the other clients, so that they can send
+    // FUNCTION KILL while the function runs. Their other commands wait for it.
+    let reply = tokio::task::block_in_place(|| function.call(keys, args, &mut host));
+    server.scripts.finish(&host.script)?;
+    Ok(reply)
+}
+
+// Runs the commands issued by functions through redis.call, under the lock FCALL took
+struct ScriptHost {
+    databases: Databases,
+    session: Session,
+    functions: Functions,
+    server: Arc<Server>,
+    read_only: bool,
+    script: Arc<functions::Script>,
+}
+
+impl lua::Host for ScriptHost {
+    fn call(&mut self, args: Vec<String>) -> RESPValue {
+        let command = args[0].to_uppercase();
+        if SCRIPT_DISALLOWED_COMMANDS.contains(&command.as_str()) {
+            return RESPValue::error(
+                "ERR This Redis command is not allowed from script".to_string(),
+            );
+        }
+        let arg_strs: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        let write = commands::is_write(&arg_strs);
+        if self.read_only && write {
+            return RESPValue::error(
+                "ERR Write commands are not allowed from read-only scripts.".to_string(),
+            );
+        }
+        match acl::check(&arg_strs, &self.session, "lua", &self.server) {
+            Ok(Some(error)) => return error,
+            Ok(None) => {}
+            Err(e) => return RESPValue::error(format!("ERR {}", e)),
+        }
+        // Functions run on a replica can only read, the keys come from the master
+        match replication::check(&arg_strs, &self.server) {
+            Ok(Some(error)) => return error,
+            Ok(None) => {}
+            Err(e) => return RESPValue::error(format!("ERR {}", e)),
+        }
+        self.server.monitor.feed(self.session.db, "lua", &arg_strs);
+        let command_args: Vec<BulkString> =
+            args[1..].iter().cloned().map(BulkString::from).collect();
+        // A write, even a failed one, makes the function unkillable like in Redis
+        if write {
+            self.script.record_write();
+        }
+        match gen_response(
+            &command,
+            &command_args,
+            &self.databases,
+            &mut self.session,
+            self.functions.clone(),
+            &self.server,
+        ) {
+            Ok(reply) => reply,
+            Err(e) => RESPValue::error(format!("ERR {}", e)),
+        }
+    }
+
+    fn register_function(&mut self, _registration: lua::Registration) -> Result<(), String> {
+        Err("redis.register_function can only be called on FUNCTION LOAD command".to_string())
+    }
+
+    fn check_running(&mut self) -> Result<(), String> {
+        self.script.check_running()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<BulkString> {
+        args.iter()
+            .map(|a| BulkString::from(a.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_lock_command() {
+        let lock = tokio::sync::RwLock::new(());
+        let locked = |args: &[&str]| {
+            let command = command(args);
+            let lock = &lock;
+            async move {
+                let guard = lock_command(&command, lock);
+                tokio::time::timeout(Duration::from_millis(10), guard)
+                    .await
+                    .is_ok()
+            }
+        };
+        let reading = lock_command(&command(&["GET", "k"]), &lock).await;
+        assert!(locked(&["GET", "k"]).await);
+        assert!(!locked(&["FCALL", "f", "0"]).await);
+        assert!(!locked(&["MIGRATE", "host", "6379", "k", "0", "1000"]).await);
+        drop(reading);
+
+        // Nothing else runs along a function but what stops it
+        let function = lock_command(&command(&["fcall_ro", "f", "0"]), &lock).await;
+        assert!(!locked(&["GET", "k"]).await);
+        assert!(!locked(&["FCALL", "f", "0"]).await);
+        assert!(locked(&["FUNCTION", "KILL"]).await);
+        assert!(locked(&["function", "stats"]).await);
+        drop(function);
+        assert!(locked(&["GET", "k"]).await);
+    }
+
+    #[test]
+    fn test_next_command() {
+        let value = "x".repeat(10_000);
+        let set = RESPValue::Array(Some(
+            ["SET", "k", &value]
+                .iter()
+                .map(|a| RESPValue::bulk_string(Some(a.to_string())))
+                .collect(),
+        ))
+        .to_bytes();
+        let mut read_buf = BytesMut::new();
+
+        // A command read in chunks waits for the rest of it
+        for chunk in set.chunks(4096) {
+            assert_eq!(next_command(&mut read_buf), Ok(None));
+            read_buf.extend_from_slice(chunk);
+        }
+        assert_eq!(
+            next_command(&mut read_buf),
+            Ok(Some(command(&["SET", "k", &value])))
+        );
+
+        // Pipelined commands are all taken, empty ones skipped
+        read_buf.extend_from_slice(b"*1\r\n$4\r\nPING\r\n*0\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*");
+        assert_eq!(next_command(&mut read_buf), Ok(Some(command(&["PING"]))));
+        assert_eq!(
+            next_command(&mut read_buf),
+            Ok(Some(command(&["GET", "k"])))
+        );
+        assert_eq!(next_command(&mut read_buf), Ok(None));
+        assert_eq!(&read_buf[..], b"*");
+
+        read_buf.extend_from_slice(b"x\r\n");
+        assert!(next_command(&mut read_buf).is_err());
+    }
+}
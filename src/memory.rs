@@ -0,0 +1,482 @@
+// Memory accounting and the maxmemory limit, following Redis' evict.c: when used memory goes past
+// maxmemory, keys are sampled from every database into a small pool ordered by how good they are
+// to evict, and the best ones are removed until memory is back under the limit
+use crate::dict::Keyspace;
+use crate::info::STATS;
+use crate::notify;
+use crate::notify::Event;
+use crate::random_u64;
+use crate::replication::Replication;
+use crate::strings::syntax_error;
+use crate::value;
+use crate::write_table;
+use crate::Databases;
+use crate::Entry;
+use crate::Table;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::mem::size_of;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+use std::sync::RwLockReadGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+// Same defaults as Redis
+const DEFAULT_SAMPLES: usize = 5;
+const EVICTION_POOL_SIZE: usize = 16;
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+// The LFU counter is decremented once for every period the key goes unaccessed
+const LFU_DECAY_PERIOD: Duration = Duration::from_secs(60);
+
+// Every allocation of the process is counted, like Redis' zmalloc does, so that used memory covers
+// the keys and values along with everything else the server allocates for them
+struct CountingAllocator;
+
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            USED_MEMORY.fetch_add(layout.size(), Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            USED_MEMORY.fetch_add(layout.size(), Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        USED_MEMORY.fetch_sub(layout.size(), Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            USED_MEMORY.fetch_add(new_size, Relaxed);
+            USED_MEMORY.fetch_sub(layout.size(), Relaxed);
+        }
+        new_ptr
+    }
+}
+
+// Bytes currently allocated by the server
+pub fn used_memory() -> usize {
+    USED_MEMORY.load(Relaxed)
+}
+
+// Approximate number of bytes taken by a key and its entry in the table
+pub fn entry_size(key: &str, (value, _, _): &Entry) -> usize {
+    size_of::<(String, Entry)>() + key.len() + value.memory_usage()
+}
+
+// Access statistics kept for every key, the equivalent of the lru field of Redis objects
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    last_access: Instant,
+    // Logarithmic access counter used by the LFU policies
+    frequency: u8,
+}
+
+impl Access {
+    pub fn new() -> Self {
+        Self {
+            last_access: Instant::now(),
+            frequency: LFU_INIT_VAL,
+        }
+    }
+
+    // Statistics restored with RESTORE's IDLETIME or FREQ options
+    pub fn restored(idle_time: Option<Duration>, frequency: Option<u8>) -> Self {
+        let now = Instant::now();
+        Self {
+            last_access: idle_time
+                .and_then(|idle| now.checked_sub(idle))
+                .unwrap_or(now),
+            frequency: frequency.unwrap_or(LFU_INIT_VAL),
+        }
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.last_access.elapsed()
+    }
+
+    // The counter after decaying it for the time the key went unaccessed
+    pub fn frequency(&self) -> u8 {
+        let periods = self.idle_time().as_secs() / LFU_DECAY_PERIOD.as_secs();
+        self.frequency
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    // Records an access to the key. The counter grows with probability 1 / (counter * factor + 1)
+    // so that it takes about a million accesses to saturate it.
+    pub fn touch(&mut self) {
+        let mut frequency = self.frequency();
+        if frequency < u8::MAX {
+            let base = frequency.saturating_sub(LFU_INIT_VAL) as f64;
+            let r = random_u64() as f64 / u64::MAX as f64;
+            if r < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                frequency += 1;
+            }
+        }
+        self.frequency = frequency;
+        self.last_access = Instant::now();
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Policy {
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+pub const POLICIES: &[(Policy, &str)] = &[
+    (Policy::NoEviction, "noeviction"),
+    (Policy::AllKeysLru, "allkeys-lru"),
+    (Policy::VolatileLru, "volatile-lru"),
+    (Policy::AllKeysLfu, "allkeys-lfu"),
+    (Policy::VolatileLfu, "volatile-lfu"),
+    (Policy::AllKeysRandom, "allkeys-random"),
+    (Policy::VolatileRandom, "volatile-random"),
+    (Policy::VolatileTtl, "volatile-ttl"),
+];
+
+impl Policy {
+    pub fn name(&self) -> &'static str {
+        POLICIES
+            .iter()
+            .find(|(policy, _)| policy == self)
+            .map_or("", |(_, name)| name)
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        POLICIES
+            .iter()
+            .find(|(_, n)| n.eq_ignore_ascii_case(name))
+            .map(|(policy, _)| *policy)
+    }
+
+    // Whether only keys with an expiry can be evicted
+    fn is_volatile(&self) -> bool {
+        matches!(
+            self,
+            Self::VolatileLru | Self::VolatileLfu | Self::VolatileRandom | Self::VolatileTtl
+        )
+    }
+
+    fn is_random(&self) -> bool {
+        matches!(self, Self::AllKeysRandom | Self::VolatileRandom)
+    }
+
+    // How good the key is to evict, higher is better
+    fn score(&self, (_, expiry, access): &Entry) -> u64 {
+        match self {
+            Self::AllKeysLfu | Self::VolatileLfu => (u8::MAX - access.frequency()) as u64,
+            Self::VolatileTtl => {
+                let ttl = expiry.map_or(Duration::MAX, |(t_insert, duration)| {
+                    duration.saturating_sub(t_insert.elapsed())
+                });
+                u64::MAX - ttl.as_millis().min(u64::MAX as u128) as u64
+            }
+            _ => access.idle_time().as_millis().min(u64::MAX as u128) as u64,
+        }
+    }
+}
+
+// Parses a number of bytes with an optional unit, like Redis' memtoull:
+// k, m and g are powers of 1000 while kb, mb and gb are powers of 1024
+pub fn parse_memory(s: &str) -> Option<usize> {
+    let lower = s.to_ascii_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    // No limit when 0
+    pub maxmemory: usize,
+    pub policy: Policy,
+    // Number of keys sampled from each database for every eviction
+    pub samples: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            maxmemory: 0,
+            policy: Policy::default(),
+            samples: DEFAULT_SAMPLES,
+        }
+    }
+}
+
+// A key sampled for eviction
+#[derive(Debug)]
+struct Candidate {
+    score: u64,
+    db: usize,
+    key: String,
+}
+
+#[derive(Default)]
+pub struct Memory {
+    // The best candidates seen so far, ordered from worst to best. It's kept between evictions so
+    // that the approximation gets better with every sample.
+    pool: Mutex<Vec<Candidate>>,
+    // Random policies go through the databases in turn
+    next_db: AtomicUsize,
+}
+
+impl Memory {
+    // Evicts keys until used memory is back under maxmemory, streaming their deletion to the
+    // replicas. Returns false if that wasn't possible, in which case commands that may use more
+    // memory are refused.
+    pub fn evict(
+        &self,
+        databases: &Databases,
+        settings: &Settings,
+        replication: &Replication,
+    ) -> Result<bool, String> {
+        if settings.maxmemory == 0 || used_memory() <= settings.maxmemory {
+            return Ok(true);
+        }
+        if settings.policy == Policy::NoEviction {
+            return Ok(false);
+        }
+        while used_memory() > settings.maxmemory {
+            let victim = if settings.policy.is_random() {
+                self.random_victim(databases, settings)?
+            } else {
+                self.pool_victim(databases, settings)?
+            };
+            let (db, key) = match victim {
+                Some(victim) => victim,
+                None => return Ok(false),
+            };
+            let writes = replication.lock_writes()?;
+            let removed = write_table(&databases[db])?.remove(&key);
+            if removed.is_some() {
+                replication.propagate(Some(db), &["UNLINK", &key])?;
+                STATS.evicted_keys.fetch_add(1, Relaxed);
+                notify::raise(Event::new(notify::EVICTED, "evicted", &key, Some(db)));
+            }
+            drop(writes);
+            // Free the value now so that the memory it used is accounted for
+            drop(removed);
+        }
+        Ok(true)
+    }
+
+    fn random_victim(
+        &self,
+        databases: &Databases,
+        settings: &Settings,
+    ) -> Result<Option<(usize, String)>, String> {
+        for _ in 0..databases.len() {
+            let db = self.next_db.fetch_add(1, Relaxed) % databases.len();
+            let t = read_table(&databases[db])?;
+            let sampled = t.sample(1, settings.policy.is_volatile());
+            if let Some((key, _)) = sampled.into_iter().next() {
+                return Ok(Some((db, key.clone())));
+            }
+        }
+        Ok(None)
+    }
+
+    fn pool_victim(
+        &self,
+        databases: &Databases,
+        settings: &Settings,
+    ) -> Result<Option<(usize, String)>, String> {
+        let mut pool = self
+            .pool
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for eviction pool {}", e))?;
+        loop {
+            let mut sampled = 0;
+            for (db, table) in databases.iter().enumerate() {
+                let t = read_table(table)?;
+                sampled += populate_pool(&mut pool, db, &t, settings);
+            }
+            if sampled == 0 {
+                return Ok(None);
+            }
+            // Candidates may have been deleted or changed since they were sampled
+            while let Some(candidate) = pool.pop() {
+                let t = read_table(&databases[candidate.db])?;
+                if t.get(&candidate.key).is_some_and(|(_, expiry, _)| {
+                    expiry.is_some() || !settings.policy.is_volatile()
+                }) {
+                    return Ok(Some((candidate.db, candidate.key)));
+                }
+            }
+        }
+    }
+}
+
+fn read_table(table: &Table) -> Result<RwLockReadGuard<'_, Keyspace>, String> {
+    table
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for table {}", e))
+}
+
+// Adds sampled keys of a database to the pool, keeping only the best candidates.
+// Returns the number of keys sampled.
+fn populate_pool(pool: &mut Vec<Candidate>, db: usize, t: &Keyspace, settings: &Settings) -> usize {
+    let keys = t.sample(settings.samples, settings.policy.is_volatile());
+    let sampled = keys.len();
+    for (key, entry) in keys {
+        let score = settings.policy.score(entry);
+        if pool.len() == EVICTION_POOL_SIZE && score <= pool[0].score {
+            continue;
+        }
+        if let Some(position) = pool.iter().position(|c| c.db == db && c.key == *key) {
+            pool.remove(position);
+        }
+        let position = pool.partition_point(|c| c.score < score);
+        pool.insert(
+            position,
+            Candidate {
+                score,
+                db,
+                key: key.clone(),
+            },
+        );
+        if pool.len() > EVICTION_POOL_SIZE {
+            pool.remove(0);
+        }
+    }
+    sampled
+}
+
+// MEMORY USAGE key [SAMPLES count]. Values are always measured entirely so SAMPLES is only
+// validated.
+pub fn memory_command(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("memory", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    match (subcommand.as_str(), &args[..]) {
+        ("USAGE", [key]) => memory_usage(key, table),
+        ("USAGE", [key, option, samples]) if option.eq_ignore_ascii_case("SAMPLES") => {
+            match value::parse_integer(samples) {
+                Some(n) if n >= 0 => memory_usage(key, table),
+                _ => Ok(crate::not_an_integer()),
+            }
+        }
+        ("USAGE", _) => Ok(syntax_error()),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'",
+            s.to_lowercase()
+        ))),
+    }
+}
+
+fn memory_usage(key: &str, table: Table) -> Result<RESPValue, String> {
+    let mut t = write_table(&table)?;
+    Ok(match crate::get_live_entry(&mut t, key) {
+        Some(entry) => RESPValue::integer(entry_size(key, entry) as i64),
+        None => RESPValue::bulk_string(None),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::value::Value;
+
+    fn entry(idle_secs: u64, frequency: u8, ttl_secs: Option<u64>) -> Entry {
+        (
+            Value::Integer(0),
+            ttl_secs.map(|s| (Instant::now(), Duration::from_secs(s))),
+            Access::restored(Some(Duration::from_secs(idle_secs)), Some(frequency)),
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("2MB"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_memory("1gb"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_memory("1tb"), None);
+        assert_eq!(parse_memory("-1"), None);
+        for (policy, name) in POLICIES {
+            assert_eq!(Policy::parse(name), Some(*policy));
+            assert_eq!(policy.name(), *name);
+        }
+        assert_eq!(Policy::parse("ALLKEYS-LRU"), Some(Policy::AllKeysLru));
+        assert_eq!(Policy::parse("lru"), None);
+    }
+
+    #[test]
+    fn test_pool_orders_candidates() {
+        let mut t = Keyspace::default();
+        t.insert("recent".to_string(), entry(1, 200, None));
+        t.insert("old".to_string(), entry(100, 100, Some(1000)));
+        t.insert("older".to_string(), entry(1000, 50, Some(10)));
+        let candidates = |policy| {
+            let settings = Settings {
+                maxmemory: 1,
+                policy,
+                samples: 10,
+            };
+            let mut pool = vec![];
+            populate_pool(&mut pool, 0, &t, &settings);
+            pool.into_iter().map(|c| c.key).collect::<Vec<_>>()
+        };
+        assert_eq!(candidates(Policy::AllKeysLru), ["recent", "old", "older"]);
+        assert_eq!(candidates(Policy::AllKeysLfu), ["recent", "old", "older"]);
+        assert_eq!(candidates(Policy::VolatileLru), ["old", "older"]);
+        assert_eq!(candidates(Policy::VolatileTtl), ["old", "older"]);
+    }
+
+    #[test]
+    fn test_frequency() {
+        let mut access = Access::new();
+        for _ in 0..100 {
+            access.touch();
+        }
+        // The counter grows logarithmically
+        assert!(access.frequency() > LFU_INIT_VAL && access.frequency() < 20);
+        let idle = Access::restored(Some(LFU_DECAY_PERIOD * 3), Some(10));
+        assert_eq!(idle.frequency(), 7);
+    }
+}
@@ -0,0 +1,115 @@
+// The feed of executed commands sent to the connections that issued MONITOR
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::sync::broadcast;
+
+// Lines a slow monitor can fall behind by before it misses some
+const FEED_CAPACITY: usize = 1024;
+
+// Commands whose arguments are replaced, like Redis does for the ones carrying passwords
+const REDACTED_COMMANDS: &[&str] = &["AUTH"];
+
+pub struct Monitor {
+    feed: broadcast::Sender<String>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self {
+            feed: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+}
+
+impl Monitor {
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.feed.subscribe()
+    }
+
+    // Whether anyone is watching, so that commands are only formatted when needed
+    pub fn is_active(&self) -> bool {
+        self.feed.receiver_count() > 0
+    }
+
+    // Sends the command to every monitor. `source` is the client address, or lua for commands
+    // run by functions.
+    pub fn feed(&self, db: usize, source: &str, args: &[&str]) {
+        if !self.is_active() {
+            return;
+        }
+        let redacted = args
+            .first()
+            .is_some_and(|name| REDACTED_COMMANDS.contains(&name.to_uppercase().as_str()));
+        let args: Vec<String> = args
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| {
+                if redacted && i > 0 {
+                    "\"(redacted)\"".to_string()
+                } else {
+                    quote(arg)
+                }
+            })
+            .collect();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:06} [{} {}] {}",
+            now.as_secs(),
+            now.subsec_micros(),
+            db,
+            source,
+            args.join(" ")
+        );
+        // Fails only when the last monitor just went away
+        let _ = self.feed.send(line);
+    }
+}
+
+// Quotes the string with the escapes of Redis' sdscatrepr
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\x07' => quoted.push_str("\\a"),
+            '\x08' => quoted.push_str("\\b"),
+            c if (' '..='~').contains(&c) => quoted.push(c),
+            c => quoted.push_str(&format!("\\x{:02x}", c as u32 & 0xff)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_feed() {
+        let monitor = Monitor::default();
+        monitor.feed(0, "127.0.0.1:1000", &["GET", "a"]);
+        let mut feed = monitor.subscribe();
+        monitor.feed(1, "lua", &["set", "a b", "\"x\"\n\u{1}"]);
+        monitor.feed(0, "127.0.0.1:1000", &["auth", "user", "secret"]);
+        let line = feed.try_recv().unwrap();
+        assert!(
+            line.ends_with(r#" [1 lua] "set" "a b" "\"x\"\n\x01""#),
+            "{}",
+            line
+        );
+        let line = feed.try_recv().unwrap();
+        assert!(
+            line.ends_with(r#"] "auth" "(redacted)" "(redacted)""#),
+            "{}",
+            line
+        );
+        assert!(feed.try_recv().is_err());
+    }
+}
@@ -0,0 +1,318 @@
+// Keyspace notifications, published on __keyspace@<db>__:<key> with the event as message and on
+// __keyevent@<db>__:<event> with the key as message, for the classes of events enabled by
+// notify-keyspace-events
+use crate::commands;
+use crate::Databases;
+use crate::Server;
+use redis_starter_rust::RESPValue;
+use std::cell::RefCell;
+
+pub const KEYSPACE: u32 = 1 << 0;
+pub const KEYEVENT: u32 = 1 << 1;
+pub const GENERIC: u32 = 1 << 2;
+pub const STRING: u32 = 1 << 3;
+pub const LIST: u32 = 1 << 4;
+pub const SET: u32 = 1 << 5;
+pub const HASH: u32 = 1 << 6;
+pub const ZSET: u32 = 1 << 7;
+pub const EXPIRED: u32 = 1 << 8;
+pub const EVICTED: u32 = 1 << 9;
+pub const STREAM: u32 = 1 << 10;
+pub const NEW: u32 = 1 << 11;
+// What the A flag stands for, everything but new keys
+const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+// The flags in the order notify-keyspace-events shows them
+const FLAGS: &[(char, u32)] = &[
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+];
+
+pub fn parse_flags(s: &str) -> Option<u32> {
+    s.chars().try_fold(0, |flags, c| {
+        let flag = match c {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'n' => NEW,
+            c => FLAGS.iter().find(|(f, _)| *f == c)?.1,
+        };
+        Some(flags | flag)
+    })
+}
+
+pub fn flags_to_string(flags: u32) -> String {
+    let mut s = String::new();
+    if flags & ALL == ALL {
+        s.push('A');
+    } else {
+        s.extend(
+            FLAGS
+                .iter()
+                .filter(|(_, flag)| flags & flag != 0)
+                .map(|(c, _)| c),
+        );
+    }
+    for (c, flag) in [('K', KEYSPACE), ('E', KEYEVENT), ('n', NEW)] {
+        if flags & flag != 0 {
+            s.push(c);
+        }
+    }
+    s
+}
+
+// An event raised on a key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub class: u32,
+    pub name: &'static str,
+    pub key: String,
+    // None for the database of the command raising it
+    pub db: Option<usize>,
+}
+
+impl Event {
+    pub fn new(class: u32, name: &'static str, key: &str, db: Option<usize>) -> Self {
+        Self {
+            class,
+            name,
+            key: key.to_string(),
+            db,
+        }
+    }
+}
+
+thread_local! {
+    // Events raised deep inside the commands, like lazy expirations, waiting to be published
+    // once the command is done. Commands run without yielding to other tasks, so they are
+    // published by the same thread that raised them.
+    static PENDING: RefCell<Vec<Event>> = const { RefCell::new(vec![]) };
+}
+
+// Raises an event from code that has no access to the server
+pub fn raise(event: Event) {
+    PENDING.with(|pending| pending.borrow_mut().push(event));
+}
+
+// The events raised since the last call
+pub fn take_pending() -> Vec<Event> {
+    PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()))
+}
+
+// Publishes the events, `db` being the database of the command that raised them
+pub fn publish(server: &Server, db: usize, events: Vec<Event>) -> Result<(), String> {
+    let flags = server.config()?.notify_keyspace_events;
+    if flags & (KEYSPACE | KEYEVENT) == 0 {
+        return Ok(());
+    }
+    for event in events {
+        if flags & event.class == 0 {
+            continue;
+        }
+        let db = event.db.unwrap_or(db);
+        if flags & KEYSPACE != 0 {
+            let channel = format!("__keyspace@{}__:{}", db, event.key);
+            server.pubsub.publish(&channel, event.name)?;
+        }
+        if flags & KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", db, event.name);
+            server.pubsub.publish(&channel, &event.key)?;
+        }
+    }
+    Ok(())
+}
+
+// Whether each key of the command exists in the database, taken before it runs to tell which
+// keys it created or deleted
+pub fn existing_keys(args: &[&str], databases: &Databases, db: usize) -> Result<Vec<bool>, String> {
+    let keys = match commands::lookup(args) {
+        Some(command) => command.keys(args),
+        None => return Ok(vec![]),
+    };
+    let t = databases[db]
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+    Ok(keys.iter().map(|key| t.contains_key(key)).collect())
+}
+
+// The events of a write command that replied with `reply`, given which of its keys existed
+// before and after it ran
+pub fn command_events(
+    args: &[&str],
+    reply: &RESPValue,
+    before: &[bool],
+    after: &[bool],
+) -> Vec<Event> {
+    let command = match commands::lookup(args) {
+        Some(command) if command.has_category("write") => command,
+        _ => return vec![],
+    };
+    if matches!(reply, RESPValue::Error(_)) {
+        return vec![];
+    }
+    let keys = command.keys(args);
+    let name = command.name;
+    let option = |option: &str| args.iter().skip(2).any(|a| a.eq_ignore_ascii_case(option));
+    let changed = !matches!(
+        reply,
+        RESPValue::Integer(0) | RESPValue::BulkString(None) | RESPValue::Array(None)
+    );
+
+    let mut events: Vec<Event> = keys
+        .iter()
+        .zip(before.iter().zip(after))
+        .filter(|(_, (before, after))| !**before && **after)
+        .map(|(key, _)| Event::new(NEW, "new", key, None))
+        .collect();
+    let on_keys = |class, event_name| -> Vec<Event> {
+        keys.iter()
+            .map(|key| Event::new(class, event_name, key, None))
+            .collect()
+    };
+    let on_first = |class, event_name| -> Vec<Event> {
+        keys.first()
+            .map(|key| Event::new(class, event_name, key, None))
+            .into_iter()
+            .collect()
+    };
+    events.extend(match name {
+        "set" | "getset" | "mset" => {
+            let mut events = on_keys(STRING, "set");
+            if option("PX") || option("EX") {
+                events.extend(on_keys(GENERIC, "expire"));
+            }
+            events
+        }
+        "setex" | "psetex" => {
+            let mut events = on_keys(STRING, "set");
+            events.extend(on_keys(GENERIC, "expire"));
+            events
+        }
+        "setnx" | "msetnx" if changed => on_keys(STRING, "set"),
+        "incr" | "decr" | "incrby" | "decrby" => on_keys(STRING, "incrby"),
+        "incrbyfloat" => on_keys(STRING, "incrbyfloat"),
+        "append" => on_keys(STRING, "append"),
+        "setrange" => on_keys(STRING, "setrange"),
+        "getdel" if changed => on_keys(GENERIC, "del"),
+        "getex" if changed && option("PERSIST") => on_keys(GENERIC, "persist"),
+        "getex" if changed && ["EX", "PX", "EXAT", "PXAT"].iter().any(|o| option(o)) => {
+            on_keys(GENERIC, "expire")
+        }
+        "setbit" => on_keys(STRING, "setbit"),
+        "bitfield" if option("SET") || option("INCRBY") => on_keys(STRING, "setbit"),
+        "bitop" if after.first() == Some(&true) => on_first(STRING, "set"),
+        "bitop" if before.first() == Some(&true) => on_first(GENERIC, "del"),
+        "pfadd" if changed => on_keys(STRING, "pfadd"),
+        "pfmerge" => on_first(STRING, "pfadd"),
+        "geoadd" if changed => on_keys(ZSET, "zadd"),
+        "geosearchstore" if changed => on_first(ZSET, "geosearchstore"),
+        "geosearchstore" if before.first() == Some(&true) => on_first(GENERIC, "del"),
+        "rename" | "renamenx" if changed && keys.len() == 2 => vec![
+            Event::new(GENERIC, "rename_from", keys[0], None),
+            Event::new(GENERIC, "rename_to", keys[1], None),
+        ],
+        "copy" if changed && keys.len() == 2 => {
+            vec![Event::new(GENERIC, "copy_to", keys[1], copy_db(args))]
+        }
+        "move" if changed => {
+            let target = args.get(2).and_then(|db| db.parse().ok());
+            let mut events = on_first(GENERIC, "move_from");
+            events.extend(
+                keys.first()
+                    .map(|key| Event::new(GENERIC, "move_to", key, target)),
+            );
+            events
+        }
+        "unlink" | "migrate" => keys
+            .iter()
+            .zip(before.iter().zip(after))
+            .filter(|(_, (before, after))| **before && !**after)
+            .map(|(key, _)| Event::new(GENERIC, "del", key, None))
+            .collect(),
+        "restore" => on_keys(GENERIC, "restore"),
+        _ => vec![],
+    });
+    // COPY to another database and MOVE create keys that the existence check can't see
+    if let Some(event) = events
+        .iter()
+        .find(|e| e.db.is_some() && e.name.ends_with("_to"))
+    {
+        events.insert(0, Event::new(NEW, "new", &event.key.clone(), event.db));
+    }
+    events
+}
+
+// The database COPY writes to, None when it's the current one
+fn copy_db(args: &[&str]) -> Option<usize> {
+    args.iter()
+        .position(|a| a.eq_ignore_ascii_case("DB"))
+        .and_then(|i| args.get(i + 1))
+        .and_then(|db| db.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(parse_flags("KEA"), Some(KEYSPACE | KEYEVENT | ALL));
+        assert_eq!(flags_to_string(parse_flags("EAK").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("Kx$g").unwrap()), "g$xK");
+        assert_eq!(parse_flags(""), Some(0));
+        assert_eq!(parse_flags("Kq"), None);
+    }
+
+    #[test]
+    fn test_command_events() {
+        let ok = RESPValue::simple_string("OK".to_string());
+        let names = |events: Vec<Event>| -> Vec<(&'static str, String)> {
+            events.into_iter().map(|e| (e.name, e.key)).collect()
+        };
+        assert_eq!(
+            names(command_events(
+                &["SET", "a", "1", "px", "10"],
+                &ok,
+                &[false],
+                &[true]
+            )),
+            vec![
+                ("new", "a".to_string()),
+                ("set", "a".to_string()),
+                ("expire", "a".to_string())
+            ]
+        );
+        assert_eq!(
+            names(command_events(
+                &["UNLINK", "a", "b"],
+                &RESPValue::integer(1),
+                &[true, false],
+                &[false, false]
+            )),
+            vec![("del", "a".to_string())]
+        );
+        assert!(command_events(
+            &["SETNX", "a", "1"],
+            &RESPValue::integer(0),
+            &[true],
+            &[true]
+        )
+        .is_empty());
+        assert!(command_events(&["GET", "a"], &ok, &[true], &[true]).is_empty());
+        let copy = command_events(
+            &["COPY", "a", "b", "DB", "3"],
+            &RESPValue::integer(1),
+            &[true, false],
+            &[true, false],
+        );
+        assert_eq!(copy[0], Event::new(NEW, "new", "b", Some(3)));
+        assert_eq!(copy[1], Event::new(GENERIC, "copy_to", "b", Some(3)));
+    }
+}
@@ -0,0 +1,310 @@
+// RDB persistence. SAVE and BGSAVE write the keys and the function libraries to the RDB file in
+// `dir`, which is loaded again at startup. Masters send the same snapshots to their replicas to
+// sync them.
+//
+// The append-only file isn't supported: INFO reports aof_enabled:0 and the server refuses to
+// start with `appendonly yes` rather than run without the durability it was asked for.
+use crate::config::Config;
+use crate::dict::Keyspace;
+use crate::keyspace::is_expired;
+use crate::memory::Access;
+use crate::rdb;
+use crate::Databases;
+use crate::Functions;
+use crate::Server;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub struct Persistence {
+    // Unix time of the last successful save, the startup time until then like Redis
+    last_save: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            last_save: AtomicU64::new(unix_time().as_secs()),
+            bgsave_in_progress: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Persistence {
+    // The lines of the persistence section of INFO
+    pub fn info_fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("loading", "0".to_string()),
+            ("rdb_changes_since_last_save", "0".to_string()),
+            (
+                "rdb_bgsave_in_progress",
+                (self.bgsave_in_progress.load(Relaxed) as u8).to_string(),
+            ),
+            (
+                "rdb_last_save_time",
+                self.last_save.load(Relaxed).to_string(),
+            ),
+            ("aof_enabled", "0".to_string()),
+        ]
+    }
+}
+
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn path(config: &Config) -> PathBuf {
+    Path::new(&config.dir).join(&config.dbfilename)
+}
+
+// Copies the live keys and the libraries, holding each lock only while its part is copied
+pub fn snapshot(databases: &Databases, functions: &Functions) -> Result<rdb::Snapshot, String> {
+    let libraries = functions
+        .read()
+        .map_err(|e| format!("Failed to acquire lock for functions {}", e))?
+        .codes();
+    let now = unix_time();
+    let mut snapshot = rdb::Snapshot {
+        libraries,
+        databases: vec![],
+    };
+    for table in databases.iter() {
+        let t = table
+            .read()
+            .map_err(|e| format!("Failed to acquire lock for table {}", e))?;
+        let keys = t
+            .iter()
+            .filter(|(_, entry)| !is_expired(entry))
+            .map(|(key, (value, expiry, _))| {
+                let expires_at = expiry.map(|(t_insert, duration)| {
+                    let remaining = duration.saturating_sub(t_insert.elapsed());
+                    (now + remaining).as_millis() as u64
+                });
+                (key.clone(), value.clone(), expires_at)
+            })
+            .collect();
+        snapshot.databases.push(keys);
+    }
+    Ok(snapshot)
+}
+
+// Replaces the keys and libraries with those of the snapshot. Keys that expired in the meantime
+// are left out.
+pub fn load(
+    snapshot: rdb::Snapshot,
+    databases: &Databases,
+    functions: &Functions,
+) -> Result<(), String> {
+    if snapshot.databases.len() > databases.len() {
+        return Err(format!(
+            "Can't load database {}, only {} are configured",
+            snapshot.databases.len() - 1,
+            databases.len()
+        ));
+    }
+    functions
+        .write()
+        .map_err(|e| format!("Failed to acquire lock for functions {}", e))?
+        .replace_all(&snapshot.libraries)?;
+    let now = unix_time().as_millis() as u64;
+    let mut keys = snapshot.databases.into_iter();
+    for table in databases.iter() {
+        let contents: Keyspace = keys
+            .next()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, _, expires_at)| expires_at.is_none_or(|at| at > now))
+            .map(|(key, value, expires_at)| {
+                let expiry = expires_at.map(|at| (Instant::now(), Duration::from_millis(at - now)));
+                (key, (value, expiry, Access::new()))
+            })
+            .collect();
+        *table
+            .write()
+            .map_err(|e| format!("Failed to acquire lock for table {}", e))? = contents;
+    }
+    Ok(())
+}
+
+// Loads the RDB file at startup, if there's one
+pub fn load_file(
+    config: &Config,
+    databases: &Databases,
+    functions: &Functions,
+) -> Result<(), String> {
+    let path = path(config);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Can't open RDB file {}: {}", path.display(), e)),
+    };
+    let snapshot = rdb::read_file(&bytes)
+        .ok_or_else(|| format!("Bad file format reading RDB file {}", path.display()))?;
+    load(snapshot, databases, functions)
+        .map_err(|e| format!("Can't load RDB file {}: {}", path.display(), e))?;
+    eprintln!("DB loaded from disk: {}", path.display());
+    Ok(())
+}
+
+// Writes to a temporary file renamed over the old one, so that a failed save doesn't lose it
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    fs::write(&temp, contents)
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&temp);
+            format!("Failed saving the DB to {}: {}", path.display(), e)
+        })
+}
+
+pub fn save(
+    args: &[BulkString],
+    databases: &Databases,
+    functions: &Functions,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    if let Err(e) = Args::new("save", args).end() {
+        return Ok(e.into());
+    }
+    if server.persistence.bgsave_in_progress.load(Relaxed) {
+        return Ok(RESPValue::error(
+            "ERR Background save already in progress".to_string(),
+        ));
+    }
+    let contents = rdb::write_file(&snapshot(databases, functions)?);
+    if let Err(e) = write_file(&path(&server.config()?), &contents) {
+        eprintln!("{}", e);
+        return Ok(RESPValue::error("ERR".to_string()));
+    }
+    server
+        .persistence
+        .last_save
+        .store(unix_time().as_secs(), Relaxed);
+    eprintln!("DB saved on disk");
+    Ok(RESPValue::simple_string("OK".to_string()))
+}
+
+// Like SAVE, but the file is written on a blocking thread. The snapshot is taken before replying,
+// in place of the fork of Redis.
+pub fn bgsave(
+    args: &[BulkString],
+    databases: &Databases,
+    functions: &Functions,
+    server: &Arc<Server>,
+) -> Result<RESPValue, String> {
+    if let Err(e) = Args::new("bgsave", args).end() {
+        return Ok(e.into());
+    }
+    if server.persistence.bgsave_in_progress.swap(true, Relaxed) {
+        return Ok(RESPValue::error(
+            "ERR Background save already in progress".to_string(),
+        ));
+    }
+    let snapshot = snapshot(databases, functions);
+    let config = server.config();
+    let (snapshot, config) = match snapshot.and_then(|s| Ok((s, config?))) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            server.persistence.bgsave_in_progress.store(false, Relaxed);
+            return Err(e);
+        }
+    };
+    let server = Arc::clone(server);
+    tokio::task::spawn_blocking(move || {
+        match write_file(&path(&config), &rdb::write_file(&snapshot)) {
+            Ok(()) => {
+                server
+                    .persistence
+                    .last_save
+                    .store(unix_time().as_secs(), Relaxed);
+                eprintln!("Background saving terminated with success");
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+        server.persistence.bgsave_in_progress.store(false, Relaxed);
+    });
+    Ok(RESPValue::simple_string(
+        "Background saving started".to_string(),
+    ))
+}
+
+pub fn lastsave(args: &[BulkString], server: &Server) -> Result<RESPValue, String> {
+    if let Err(e) = Args::new("lastsave", args).end() {
+        return Ok(e.into());
+    }
+    Ok(RESPValue::integer(
+        server.persistence.last_save.load(Relaxed) as i64,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::functions::Registry;
+    use crate::value::Value;
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_snapshot_and_load() {
+        let databases: Databases = Arc::new(
+            (0..2)
+                .map(|_| Arc::new(RwLock::new(Keyspace::default())))
+                .collect(),
+        );
+        let functions: Functions = Arc::new(RwLock::new(Registry::default()));
+        let code = "#!lua name=lib\nredis.register_function('f', function() return 1 end)";
+        functions.write().unwrap().load(code, false).unwrap();
+        {
+            let mut t = databases[1].write().unwrap();
+            let hour = Some((Instant::now(), Duration::from_secs(3600)));
+            let expired = Some((Instant::now(), Duration::ZERO));
+            t.insert("a".to_string(), (Value::Integer(1), None, Access::new()));
+            t.insert("b".to_string(), (Value::Integer(2), hour, Access::new()));
+            t.insert("c".to_string(), (Value::Integer(3), expired, Access::new()));
+        }
+        std::thread::sleep(Duration::from_millis(1));
+        let file = rdb::write_file(&snapshot(&databases, &functions).unwrap());
+
+        let restored: Databases = Arc::new(
+            (0..2)
+                .map(|_| Arc::new(RwLock::new(Keyspace::default())))
+                .collect(),
+        );
+        restored[0]
+            .write()
+            .unwrap()
+            .insert("old".to_string(), (Value::Integer(0), None, Access::new()));
+        let restored_functions: Functions = Arc::new(RwLock::new(Registry::default()));
+        load(
+            rdb::read_file(&file).unwrap(),
+            &restored,
+            &restored_functions,
+        )
+        .unwrap();
+        assert!(restored[0].read().unwrap().is_empty());
+        let t = restored[1].read().unwrap();
+        let mut keys: Vec<&String> = t.iter().map(|(key, _)| key).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "b"]);
+        let (_, expiry, _) = &t["b"];
+        assert!(expiry.is_some_and(|(_, d)| d > Duration::from_secs(3590)));
+        assert!(restored_functions.read().unwrap().find("f").is_some());
+
+        let one: Databases = Arc::new(vec![Arc::new(RwLock::new(Keyspace::default()))]);
+        assert!(load(rdb::read_file(&file).unwrap(), &one, &restored_functions).is_err());
+    }
+}
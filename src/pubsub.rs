@@ -0,0 +1,297 @@
+// Channels, patterns and their subscribers, and the pub/sub commands. Messages are sent to the
+// connections through the channel each of them reads pushed replies from.
+use crate::glob;
+use crate::strings::wrong_number_of_arguments;
+use crate::Server;
+use crate::Session;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use tokio::sync::mpsc::UnboundedSender;
+
+// Where the replies pushed to a connection outside of the command/reply flow are sent
+pub type Pushes = UnboundedSender<RESPValue>;
+
+// The commands a connection can still run once it has subscriptions
+const SUBSCRIBED_COMMANDS: &[&str] = &[
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "PING",
+];
+
+#[derive(Default)]
+struct Subscriptions {
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Subscriptions {
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+// Subscribers by channel or pattern, ordered by client id
+type Subscribers = BTreeMap<String, BTreeMap<u64, Pushes>>;
+
+#[derive(Default)]
+struct State {
+    channels: Subscribers,
+    patterns: Subscribers,
+    clients: HashMap<u64, Subscriptions>,
+}
+
+#[derive(Default)]
+pub struct PubSub {
+    state: Mutex<State>,
+}
+
+fn bulk(s: &str) -> RESPValue {
+    RESPValue::bulk_string(Some(s.to_string()))
+}
+
+// The reply confirming a change of subscriptions, like ["subscribe", "news", 1]
+fn confirmation(kind: &str, name: Option<&str>, count: usize) -> RESPValue {
+    RESPValue::Array(Some(vec![
+        bulk(kind),
+        RESPValue::bulk_string(name.map(|n| n.to_string())),
+        RESPValue::integer(count as i64),
+    ]))
+}
+
+impl PubSub {
+    fn state(&self) -> Result<MutexGuard<'_, State>, String> {
+        self.state
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for pub/sub {}", e))
+    }
+
+    // Number of channels and patterns the client is subscribed to
+    pub fn subscriptions(&self, client_id: u64) -> Result<usize, String> {
+        Ok(self
+            .state()?
+            .clients
+            .get(&client_id)
+            .map_or(0, |s| s.count()))
+    }
+
+    // Sends the message to the subscribers of the channel and of the patterns matching it,
+    // returning how many received it
+    pub fn publish(&self, channel: &str, message: &str) -> Result<usize, String> {
+        let state = self.state()?;
+        let mut receivers = 0;
+        if let Some(subscribers) = state.channels.get(channel) {
+            let reply = RESPValue::Array(Some(vec![bulk("message"), bulk(channel), bulk(message)]));
+            for pushes in subscribers.values() {
+                if pushes.send(reply.clone()).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        for (pattern, subscribers) in &state.patterns {
+            if !glob::matches(pattern, channel, false) {
+                continue;
+            }
+            let reply = RESPValue::Array(Some(vec![
+                bulk("pmessage"),
+                bulk(pattern),
+                bulk(channel),
+                bulk(message),
+            ]));
+            for pushes in subscribers.values() {
+                if pushes.send(reply.clone()).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        Ok(receivers)
+    }
+
+    // Forgets the subscriptions of a connection that went away
+    pub fn remove_client(&self, client_id: u64) -> Result<(), String> {
+        let mut state = self.state()?;
+        let subscriptions = match state.clients.remove(&client_id) {
+            Some(subscriptions) => subscriptions,
+            None => return Ok(()),
+        };
+        for channel in subscriptions.channels {
+            remove_subscriber(&mut state.channels, &channel, client_id);
+        }
+        for pattern in subscriptions.patterns {
+            remove_subscriber(&mut state.patterns, &pattern, client_id);
+        }
+        Ok(())
+    }
+}
+
+fn remove_subscriber(subscribers: &mut Subscribers, name: &str, client_id: u64) {
+    if let Some(clients) = subscribers.get_mut(name) {
+        clients.remove(&client_id);
+        if clients.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
+
+// The error for commands a subscribed connection can't run, None if it can
+pub fn check_subscribed(
+    command: &str,
+    session: &Session,
+    server: &Server,
+) -> Result<Option<RESPValue>, String> {
+    let command = command.to_uppercase();
+    if SUBSCRIBED_COMMANDS.contains(&command.as_str())
+        || server.pubsub.subscriptions(session.client_id)? == 0
+    {
+        return Ok(None);
+    }
+    Ok(Some(RESPValue::error(format!(
+        "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+        command.to_lowercase()
+    ))))
+}
+
+// SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE and PUNSUBSCRIBE. There is a confirmation for every channel,
+// all but the last are pushed to the connection ahead of the reply.
+pub fn subscribe_command(
+    command: &str,
+    args: &[BulkString],
+    session: &Session,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let command = command.to_lowercase();
+    let names = match Args::new(&command, args).rest() {
+        Ok(names) => names,
+        Err(e) => return Ok(e.into()),
+    };
+    let subscribing = !command.ends_with("unsubscribe");
+    if subscribing && names.is_empty() {
+        return Ok(wrong_number_of_arguments(&command));
+    }
+    let pushes = match &session.pushes {
+        Some(pushes) => pushes,
+        None => {
+            return Ok(RESPValue::error(format!(
+                "ERR {} isn't allowed for this client",
+                command.to_uppercase()
+            )))
+        }
+    };
+    let patterns = command.starts_with('p');
+    let mut state = server.pubsub.state()?;
+    let state = &mut *state;
+    let subscriptions = state.clients.entry(session.client_id).or_default();
+    let names: Vec<String> = match (subscribing, names.is_empty(), patterns) {
+        // Without names, unsubscribing is from everything
+        (false, true, false) => subscriptions.channels.iter().cloned().collect(),
+        (false, true, true) => subscriptions.patterns.iter().cloned().collect(),
+        _ => names.iter().map(|n| n.to_string()).collect(),
+    };
+
+    let mut replies = vec![];
+    for name in &names {
+        let (own, subscribers) = if patterns {
+            (&mut subscriptions.patterns, &mut state.patterns)
+        } else {
+            (&mut subscriptions.channels, &mut state.channels)
+        };
+        if subscribing {
+            if own.insert(name.clone()) {
+                subscribers
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(session.client_id, pushes.clone());
+            }
+        } else if own.remove(name) {
+            remove_subscriber(subscribers, name, session.client_id);
+        }
+        replies.push(confirmation(&command, Some(name), subscriptions.count()));
+    }
+    let count = subscriptions.count();
+    if replies.is_empty() {
+        replies.push(confirmation(&command, None, count));
+    }
+    if count == 0 {
+        state.clients.remove(&session.client_id);
+    }
+    server.clients.record_subscriptions(session, count)?;
+
+    let last = replies.pop().unwrap_or(RESPValue::Array(None));
+    for reply in replies {
+        // The connection only goes away after the command, the send can't fail
+        let _ = pushes.send(reply);
+    }
+    Ok(last)
+}
+
+// PING, which replies like a pushed message once the connection has subscriptions
+pub fn ping(args: &[BulkString], session: &Session, server: &Server) -> Result<RESPValue, String> {
+    let message = match Args::new("ping", args).parse(Args::optional_str) {
+        Ok(message) => message,
+        Err(e) => return Ok(e.into()),
+    };
+    if server.pubsub.subscriptions(session.client_id)? > 0 {
+        return Ok(RESPValue::Array(Some(vec![
+            bulk("pong"),
+            bulk(message.unwrap_or_default()),
+        ])));
+    }
+    Ok(match message {
+        Some(message) => bulk(message),
+        None => RESPValue::SimpleString("PONG".to_string()),
+    })
+}
+
+pub fn publish(args: &[BulkString], server: &Server) -> Result<RESPValue, String> {
+    let parsed = Args::new("publish", args).parse(|args| Ok((args.next_str()?, args.next_str()?)));
+    match parsed {
+        Ok((channel, message)) => Ok(RESPValue::integer(
+            server.pubsub.publish(channel, message)? as i64
+        )),
+        Err(e) => Ok(e.into()),
+    }
+}
+
+pub fn pubsub_command(args: &[BulkString], server: &Server) -> Result<RESPValue, String> {
+    let mut args = Args::new("pubsub", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    let state = server.pubsub.state()?;
+    match (subcommand.as_str(), &args[..]) {
+        ("CHANNELS", []) | ("CHANNELS", [_]) => Ok(RESPValue::Array(Some(
+            state
+                .channels
+                .keys()
+                .filter(|c| args.first().is_none_or(|p| glob::matches(p, c, false)))
+                .map(|c| bulk(c))
+                .collect(),
+        ))),
+        ("NUMSUB", channels) => Ok(RESPValue::Array(Some(
+            channels
+                .iter()
+                .flat_map(|c| {
+                    let count = state.channels.get(*c).map_or(0, |s| s.len());
+                    [bulk(c), RESPValue::integer(count as i64)]
+                })
+                .collect(),
+        ))),
+        ("NUMPAT", []) => Ok(RESPValue::integer(state.patterns.len() as i64)),
+        ("CHANNELS", _) | ("NUMPAT", _) => Ok(wrong_number_of_arguments(&format!(
+            "pubsub|{}",
+            subcommand.to_lowercase()
+        ))),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'. Try PUBSUB HELP.",
+            s.to_lowercase()
+        ))),
+    }
+}
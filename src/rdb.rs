@@ -0,0 +1,645 @@
+// The RDB serialization format, both the pieces shared by the commands that exchange serialized
+// payloads (FUNCTION DUMP/RESTORE, DUMP/RESTORE) and whole RDB files (SAVE, full syncs).
+
+use crate::info::REDIS_VERSION;
+use crate::sorted_set::SortedSet;
+use crate::value::Value;
+use redis_starter_rust::bytes_to_string;
+use redis_starter_rust::string_to_bytes;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+// Version written in payload footers. We accept payloads from any version up to this one.
+pub const RDB_VERSION: u16 = 11;
+
+pub const RDB_OPCODE_FUNCTION2: u8 = 245;
+const RDB_OPCODE_IDLE: u8 = 248;
+const RDB_OPCODE_FREQ: u8 = 249;
+const RDB_OPCODE_AUX: u8 = 250;
+const RDB_OPCODE_RESIZEDB: u8 = 251;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 252;
+const RDB_OPCODE_EXPIRETIME: u8 = 253;
+const RDB_OPCODE_SELECTDB: u8 = 254;
+const RDB_OPCODE_EOF: u8 = 255;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+
+const RDB_6BITLEN: u8 = 0;
+const RDB_14BITLEN: u8 = 1;
+const RDB_32BITLEN: u8 = 0x80;
+const RDB_64BITLEN: u8 = 0x81;
+const RDB_ENCVAL: u8 = 3;
+
+// Special string encodings, in the low bits of a length byte with the RDB_ENCVAL prefix
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+// Jones polynomial in reflected form, as used by Redis' crc64
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+pub fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        crc ^= byte as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+pub fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push((RDB_6BITLEN << 6) | len as u8);
+    } else if len < 1 << 14 {
+        out.push((RDB_14BITLEN << 6) | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(RDB_32BITLEN);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(RDB_64BITLEN);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+// Reads a length, returns the remaining bytes
+pub fn read_length(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let (&first, rest) = bytes.split_first()?;
+    match first >> 6 {
+        0 => Some(((first & 0x3f) as u64, rest)),
+        1 => {
+            let (&second, rest) = rest.split_first()?;
+            Some(((((first & 0x3f) as u64) << 8) | second as u64, rest))
+        }
+        _ if first == RDB_32BITLEN && rest.len() >= 4 => {
+            let (len, rest) = rest.split_at(4);
+            Some((u32::from_be_bytes(len.try_into().ok()?) as u64, rest))
+        }
+        _ if first == RDB_64BITLEN && rest.len() >= 8 => {
+            let (len, rest) = rest.split_at(8);
+            Some((u64::from_be_bytes(len.try_into().ok()?), rest))
+        }
+        // Special string encodings are handled by read_string
+        _ => None,
+    }
+}
+
+pub fn write_string(out: &mut Vec<u8>, s: &[u8]) {
+    write_length(out, s.len() as u64);
+    out.extend_from_slice(s);
+}
+
+// Writes integers that fit in 32 bits with the compact integer encodings, like Redis
+pub fn write_integer(out: &mut Vec<u8>, n: i64) {
+    let prefix = RDB_ENCVAL << 6;
+    if let Ok(n) = i8::try_from(n) {
+        out.push(prefix | RDB_ENC_INT8);
+        out.extend_from_slice(&n.to_le_bytes());
+    } else if let Ok(n) = i16::try_from(n) {
+        out.push(prefix | RDB_ENC_INT16);
+        out.extend_from_slice(&n.to_le_bytes());
+    } else if let Ok(n) = i32::try_from(n) {
+        out.push(prefix | RDB_ENC_INT32);
+        out.extend_from_slice(&n.to_le_bytes());
+    } else {
+        write_string(out, n.to_string().as_bytes());
+    }
+}
+
+// Reads a string in any of its encodings, returns the remaining bytes
+pub fn read_string(bytes: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let (&first, rest) = bytes.split_first()?;
+    if first >> 6 != RDB_ENCVAL {
+        let (len, rest) = read_length(bytes)?;
+        let len = usize::try_from(len).ok()?;
+        if rest.len() < len {
+            return None;
+        }
+        let (s, rest) = rest.split_at(len);
+        return Some((s.to_vec(), rest));
+    }
+    let integer = |size: usize| {
+        let (n, rest) = (rest.get(..size)?, &rest[size..]);
+        let mut buf = [0; 8];
+        buf[..size].copy_from_slice(n);
+        // Sign extend
+        if n[size - 1] & 0x80 != 0 {
+            buf[size..].fill(0xff);
+        }
+        Some((i64::from_le_bytes(buf).to_string().into_bytes(), rest))
+    };
+    match first & 0x3f {
+        RDB_ENC_INT8 => integer(1),
+        RDB_ENC_INT16 => integer(2),
+        RDB_ENC_INT32 => integer(4),
+        RDB_ENC_LZF => {
+            let (compressed_len, rest) = read_length(rest)?;
+            let (len, rest) = read_length(rest)?;
+            let compressed_len = usize::try_from(compressed_len).ok()?;
+            if rest.len() < compressed_len {
+                return None;
+            }
+            let (compressed, rest) = rest.split_at(compressed_len);
+            let s = lzf_decompress(compressed, usize::try_from(len).ok()?)?;
+            Some((s, rest))
+        }
+        _ => None,
+    }
+}
+
+// Decompresses LZF data (as written by liblzf), checking it has the expected length
+fn lzf_decompress(mut input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    while let Some((&ctrl, rest)) = input.split_first() {
+        input = rest;
+        if ctrl < 32 {
+            // Literal run
+            let run = ctrl as usize + 1;
+            out.extend_from_slice(input.get(..run)?);
+            input = &input[run..];
+        } else {
+            // Back reference
+            let mut run = (ctrl >> 5) as usize;
+            if run == 7 {
+                let (&extra, rest) = input.split_first()?;
+                run += extra as usize;
+                input = rest;
+            }
+            let (&low, rest) = input.split_first()?;
+            input = rest;
+            let distance = (((ctrl & 0x1f) as usize) << 8) + low as usize + 1;
+            let start = out.len().checked_sub(distance)?;
+            for i in 0..run + 2 {
+                out.push(out[start + i]);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+// Scores of RDB_TYPE_ZSET are stored as text with a length byte, with special lengths for
+// NaN and infinities
+fn read_text_double(bytes: &[u8]) -> Option<(f64, &[u8])> {
+    let (&len, rest) = bytes.split_first()?;
+    match len {
+        253 => Some((f64::NAN, rest)),
+        254 => Some((f64::INFINITY, rest)),
+        255 => Some((f64::NEG_INFINITY, rest)),
+        _ => {
+            let (s, rest) = (rest.get(..len as usize)?, &rest[len as usize..]);
+            Some((std::str::from_utf8(s).ok()?.parse().ok()?, rest))
+        }
+    }
+}
+
+// Decodes the entries of a listpack, integers being returned in their decimal form
+fn read_listpack(lp: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let total = u32::from_le_bytes(lp.get(..4)?.try_into().ok()?) as usize;
+    if total != lp.len() {
+        return None;
+    }
+    let mut p = &lp[6..];
+    let mut entries = vec![];
+    loop {
+        let (&encoding, rest) = p.split_first()?;
+        let signed = |size: usize, value: u64| {
+            let shift = 64 - size;
+            (((value << shift) as i64) >> shift)
+                .to_string()
+                .into_bytes()
+        };
+        let (entry, data_len, header_len) = match encoding {
+            0xff => break,
+            e if e & 0x80 == 0 => ((e as i64).to_string().into_bytes(), 0, 1),
+            e if e & 0xc0 == 0x80 => {
+                let len = (e & 0x3f) as usize;
+                (rest.get(..len)?.to_vec(), len, 1)
+            }
+            e if e & 0xe0 == 0xc0 => {
+                let value = (((e & 0x1f) as u64) << 8) | *rest.first()? as u64;
+                (signed(13, value), 1, 1)
+            }
+            e if e & 0xf0 == 0xe0 => {
+                let len = (((e & 0x0f) as usize) << 8) | *rest.first()? as usize;
+                (rest.get(1..1 + len)?.to_vec(), len + 1, 1)
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+                (rest.get(4..4 + len)?.to_vec(), len + 4, 1)
+            }
+            e @ 0xf1..=0xf4 => {
+                let size = [2, 3, 4, 8][(e - 0xf1) as usize];
+                let mut buf = [0; 8];
+                buf[..size].copy_from_slice(rest.get(..size)?);
+                (signed(size * 8, u64::from_le_bytes(buf)), size, 1)
+            }
+            _ => return None,
+        };
+        let len = header_len + data_len;
+        // The entry is followed by its length, encoded on 1 to 5 bytes
+        let backlen = match len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        p = p.get(len + backlen..)?;
+        entries.push(entry);
+    }
+    Some(entries)
+}
+
+fn sorted_set_from_pairs(pairs: impl Iterator<Item = (Vec<u8>, f64)>) -> Option<SortedSet> {
+    let mut set = SortedSet::default();
+    for (member, score) in pairs {
+        if score.is_nan() || set.insert(bytes_to_string(&member), score).is_some() {
+            return None;
+        }
+    }
+    (set.len() > 0).then_some(set)
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) | Value::Integer(_) => RDB_TYPE_STRING,
+        Value::SortedSet(_) => RDB_TYPE_ZSET_2,
+    }
+}
+
+// Appends the type and serialization of a value
+pub fn write_value(out: &mut Vec<u8>, value: &Value) {
+    out.push(value_type(value));
+    write_value_body(out, value);
+}
+
+// Appends the serialization of a value, which follows its type and key in RDB files
+fn write_value_body(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(s) => write_string(out, &string_to_bytes(s)),
+        Value::Integer(n) => write_integer(out, *n),
+        Value::SortedSet(set) => {
+            write_length(out, set.len() as u64);
+            // Highest scores first, so that loading inserts at the head of the skiplist
+            for (member, score) in set.iter().rev() {
+                write_string(out, &string_to_bytes(member));
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+// Reads a value written by write_value or by Redis, returns the remaining bytes
+pub fn read_value(bytes: &[u8]) -> Option<(Value, &[u8])> {
+    let (&value_type, rest) = bytes.split_first()?;
+    read_value_body(value_type, rest)
+}
+
+fn read_value_body(value_type: u8, rest: &[u8]) -> Option<(Value, &[u8])> {
+    match value_type {
+        RDB_TYPE_STRING => {
+            let (s, rest) = read_string(rest)?;
+            Some((Value::from_string(bytes_to_string(&s)), rest))
+        }
+        RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+            let (len, mut rest) = read_length(rest)?;
+            let mut pairs = vec![];
+            for _ in 0..len {
+                let (member, r) = read_string(rest)?;
+                let (score, r) = if value_type == RDB_TYPE_ZSET_2 {
+                    let score = f64::from_le_bytes(r.get(..8)?.try_into().ok()?);
+                    (score, &r[8..])
+                } else {
+                    read_text_double(r)?
+                };
+                pairs.push((member, score));
+                rest = r;
+            }
+            let set = sorted_set_from_pairs(pairs.into_iter())?;
+            Some((Value::SortedSet(set), rest))
+        }
+        RDB_TYPE_ZSET_LISTPACK => {
+            let (lp, rest) = read_string(rest)?;
+            let entries = read_listpack(&lp)?;
+            if entries.len() % 2 != 0 {
+                return None;
+            }
+            let mut pairs = vec![];
+            for pair in entries.chunks(2) {
+                let score = std::str::from_utf8(&pair[1]).ok()?.parse().ok()?;
+                pairs.push((pair[0].clone(), score));
+            }
+            let set = sorted_set_from_pairs(pairs.into_iter())?;
+            Some((Value::SortedSet(set), rest))
+        }
+        _ => None,
+    }
+}
+
+// Appends the 2 byte RDB version and the 8 byte crc64 of everything before it
+pub fn append_footer(payload: &mut Vec<u8>) {
+    payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = crc64(0, payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+}
+
+// Checks the version and checksum of a payload, returns the body without the footer
+pub fn verify_footer(payload: &[u8]) -> Option<&[u8]> {
+    if payload.len() < 10 {
+        return None;
+    }
+    let (data, crc) = payload.split_at(payload.len() - 8);
+    let version = u16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]);
+    if version > RDB_VERSION || crc64(0, data).to_le_bytes() != crc {
+        return None;
+    }
+    Some(&data[..data.len() - 2])
+}
+
+// The contents of an RDB file
+#[derive(Debug, Default, PartialEq)]
+pub struct Snapshot {
+    // The code of each function library
+    pub libraries: Vec<String>,
+    // The keys of each database, with the unix time in milliseconds they expire at
+    pub databases: Vec<Vec<(String, Value, Option<u64>)>>,
+}
+
+// Serializes a snapshot as an RDB file, which ends with the crc64 of everything before it
+pub fn write_file(snapshot: &Snapshot) -> Vec<u8> {
+    let mut out = format!("REDIS{:04}", RDB_VERSION).into_bytes();
+    for (name, value) in [("redis-ver", REDIS_VERSION), ("redis-bits", "64")] {
+        out.push(RDB_OPCODE_AUX);
+        write_string(&mut out, name.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
+    for code in &snapshot.libraries {
+        out.push(RDB_OPCODE_FUNCTION2);
+        write_string(&mut out, &string_to_bytes(code));
+    }
+    for (index, keys) in snapshot.databases.iter().enumerate() {
+        if keys.is_empty() {
+            continue;
+        }
+        out.push(RDB_OPCODE_SELECTDB);
+        write_length(&mut out, index as u64);
+        out.push(RDB_OPCODE_RESIZEDB);
+        write_length(&mut out, keys.len() as u64);
+        let volatile = keys
+            .iter()
+            .filter(|(_, _, expiry)| expiry.is_some())
+            .count();
+        write_length(&mut out, volatile as u64);
+        for (key, value, expiry) in keys {
+            if let Some(expiry) = expiry {
+                out.push(RDB_OPCODE_EXPIRETIME_MS);
+                out.extend_from_slice(&expiry.to_le_bytes());
+            }
+            out.push(value_type(value));
+            write_string(&mut out, &string_to_bytes(key));
+            write_value_body(&mut out, value);
+        }
+    }
+    out.push(RDB_OPCODE_EOF);
+    let crc = crc64(0, &out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+// Reads an RDB file written by write_file or by Redis, None when it's corrupt or uses types we
+// don't support
+pub fn read_file(bytes: &[u8]) -> Option<Snapshot> {
+    let version: u16 = std::str::from_utf8(bytes.strip_prefix(b"REDIS")?.get(..4)?)
+        .ok()?
+        .parse()
+        .ok()?;
+    if version > RDB_VERSION {
+        return None;
+    }
+    let mut snapshot = Snapshot::default();
+    let mut db = 0;
+    let mut expiry = None;
+    let mut rest = &bytes[9..];
+    loop {
+        let (&opcode, r) = rest.split_first()?;
+        rest = r;
+        match opcode {
+            RDB_OPCODE_EOF => break,
+            RDB_OPCODE_AUX => {
+                let (_, r) = read_string(rest)?;
+                let (_, r) = read_string(r)?;
+                rest = r;
+            }
+            RDB_OPCODE_FUNCTION2 => {
+                let (code, r) = read_string(rest)?;
+                snapshot.libraries.push(bytes_to_string(&code));
+                rest = r;
+            }
+            RDB_OPCODE_SELECTDB => {
+                let (index, r) = read_length(rest)?;
+                db = usize::try_from(index).ok()?;
+                rest = r;
+            }
+            RDB_OPCODE_RESIZEDB => {
+                let (_, r) = read_length(rest)?;
+                let (_, r) = read_length(r)?;
+                rest = r;
+            }
+            RDB_OPCODE_EXPIRETIME_MS => {
+                expiry = Some(u64::from_le_bytes(rest.get(..8)?.try_into().ok()?));
+                rest = &rest[8..];
+            }
+            RDB_OPCODE_EXPIRETIME => {
+                let seconds = u32::from_le_bytes(rest.get(..4)?.try_into().ok()?);
+                expiry = Some(seconds as u64 * 1000);
+                rest = &rest[4..];
+            }
+            // The LRU and LFU information of the next key isn't kept
+            RDB_OPCODE_IDLE => rest = read_length(rest)?.1,
+            RDB_OPCODE_FREQ => rest = rest.get(1..)?,
+            value_type => {
+                let (key, r) = read_string(rest)?;
+                let (value, r) = read_value_body(value_type, r)?;
+                if snapshot.databases.len() <= db {
+                    snapshot.databases.resize_with(db + 1, Vec::new);
+                }
+                snapshot.databases[db].push((bytes_to_string(&key), value, expiry.take()));
+                rest = r;
+            }
+        }
+    }
+    // Files from before version 5 have no checksum, and it's 0 when Redis was told not to
+    // compute it
+    if version >= 5 {
+        let crc: [u8; 8] = rest.get(..8)?.try_into().ok()?;
+        let data = &bytes[..bytes.len() - rest.len()];
+        if crc != [0; 8] && crc64(0, data).to_le_bytes() != crc {
+            return None;
+        }
+    }
+    Some(snapshot)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn test_length_round_trip() {
+        for len in [
+            0,
+            63,
+            64,
+            16383,
+            16384,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+        ] {
+            let mut out = vec![];
+            write_length(&mut out, len);
+            assert_eq!(read_length(&out), Some((len, &[][..])));
+        }
+    }
+
+    #[test]
+    fn test_footer() {
+        let mut payload = vec![];
+        write_string(&mut payload, b"hello");
+        append_footer(&mut payload);
+        let body = verify_footer(&payload).unwrap();
+        assert_eq!(read_string(body), Some((b"hello".to_vec(), &[][..])));
+
+        payload[1] = b'j';
+        assert_eq!(verify_footer(&payload), None);
+    }
+
+    #[test]
+    fn test_integer_strings() {
+        for n in [0, -1, 127, -129, 40000, -2_000_000_000, 1 << 40] {
+            let mut out = vec![];
+            write_integer(&mut out, n);
+            assert_eq!(
+                read_string(&out),
+                Some((n.to_string().into_bytes(), &[][..]))
+            );
+        }
+        assert_eq!(
+            read_string(&[0xc1, 0x39, 0x30]),
+            Some((b"12345".to_vec(), &[][..]))
+        );
+    }
+
+    #[test]
+    fn test_lzf_string() {
+        // 50 times 'a': a literal 'a' followed by a back reference of 49 bytes at distance 1
+        let compressed = [0xc3, 0x05, 0x32, 0x00, 0x61, 0xe0, 0x28, 0x00];
+        assert_eq!(read_string(&compressed), Some((vec![b'a'; 50], &[][..])));
+        assert_eq!(
+            read_string(&[0xc3, 0x05, 0x33, 0x00, 0x61, 0xe0, 0x28, 0x00]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let mut set = SortedSet::default();
+        set.insert("a".to_string(), 1.5);
+        set.insert("b".to_string(), -2.0);
+        for value in [
+            Value::Integer(-5),
+            Value::String("hello\u{0}\u{ff}".to_string()),
+            Value::SortedSet(set),
+        ] {
+            let mut out = vec![];
+            write_value(&mut out, &value);
+            assert_eq!(read_value(&out), Some((value, &[][..])));
+        }
+    }
+
+    #[test]
+    fn test_listpack_sorted_set() {
+        // Small sorted sets are dumped by Redis as listpacks, here {a: 1, b: 2.5}
+        let payload = [
+            0x11, 0x14, 0x14, 0x00, 0x00, 0x00, 0x04, 0x00, 0x81, 0x61, 0x02, 0x01, 0x01, 0x81,
+            0x62, 0x02, 0x83, 0x32, 0x2e, 0x35, 0x04, 0xff,
+        ];
+        let mut expected = SortedSet::default();
+        expected.insert("a".to_string(), 1.0);
+        expected.insert("b".to_string(), 2.5);
+        assert_eq!(
+            read_value(&payload),
+            Some((Value::SortedSet(expected), &[][..]))
+        );
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let mut set = SortedSet::default();
+        set.insert("a".to_string(), 1.5);
+        let snapshot = Snapshot {
+            libraries: vec!["#!lua name=lib\nreturn 1".to_string()],
+            databases: vec![
+                vec![
+                    ("counter".to_string(), Value::Integer(42), None),
+                    (
+                        "set".to_string(),
+                        Value::SortedSet(set),
+                        Some(1_700_000_000_000),
+                    ),
+                ],
+                vec![],
+                vec![("key".to_string(), Value::String("value".to_string()), None)],
+            ],
+        };
+        let file = write_file(&snapshot);
+        assert!(file.starts_with(b"REDIS0011"));
+        assert_eq!(read_file(&file), Some(snapshot));
+
+        let mut corrupt = file.clone();
+        let last = corrupt.len() - 9;
+        corrupt[last - 1] ^= 1;
+        assert_eq!(read_file(&corrupt), None);
+        assert_eq!(read_file(&file[..file.len() - 4]), None);
+        assert_eq!(read_file(b"REDIS0099\xff"), None);
+    }
+
+    #[test]
+    fn test_redis_file() {
+        // Laid out like Redis saves a single key "k" set to "v" in database 1, expiring at
+        // 1700000000000 ms, with checksums turned off
+        let mut file = b"REDIS0011\xfa\x09redis-ver\x057.2.0\xfe\x01\xfb\x01\x01".to_vec();
+        file.push(RDB_OPCODE_EXPIRETIME_MS);
+        file.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        file.extend_from_slice(b"\x00\x01k\x01v\xff");
+        file.extend_from_slice(&[0; 8]);
+        let snapshot = read_file(&file).unwrap();
+        assert_eq!(
+            snapshot.databases,
+            [
+                vec![],
+                vec![(
+                    "k".to_string(),
+                    Value::String("v".to_string()),
+                    Some(1_700_000_000_000)
+                )]
+            ]
+        );
+    }
+}
@@ -0,0 +1,1061 @@
+// Replication set with REPLICAOF. A replica keeps a link to its master, on which it announces the
+// port it listens on with REPLCONF and asks for a full sync with PSYNC: the master replies with an
+// RDB snapshot of its keys and functions, and then streams the write commands it runs, which the
+// replica applies. Every second the master pings its replicas through the stream and the replicas
+// acknowledge the offset they reached, so that the master lists them in INFO with their lag and
+// sentinels can compare them. Partial resyncs aren't supported, every link starts with a full
+// sync. Like Redis with replica-read-only, replicas refuse writes from their clients.
+use crate::commands;
+use crate::not_an_integer;
+use crate::persistence;
+use crate::rdb;
+use crate::strings::syntax_error;
+use crate::strings::wrong_number_of_arguments;
+use crate::Databases;
+use crate::Functions;
+use crate::Server;
+use crate::Session;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::Frame;
+use redis_starter_rust::ParseError;
+use redis_starter_rust::RESPValue;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::Notify;
+
+// How long connecting to an instance or waiting for its reply may take
+pub const TIMEOUT: Duration = Duration::from_secs(1);
+// How long a replica waits for its master to send something, it pings every PING_PERIOD and sends
+// newlines while it encodes a snapshot
+const REPL_TIMEOUT: Duration = Duration::from_secs(5);
+const PING_PERIOD: Duration = Duration::from_secs(1);
+// Bytes of the stream a replica may have waiting to be sent before it's disconnected, Redis'
+// default hard client-output-buffer-limit for replicas
+const REPLICA_BUFFER_LIMIT: usize = 256 * 1024 * 1024;
+
+thread_local! {
+    // What the running command propagates instead of itself, for commands replicas can't run
+    // again like MIGRATE. Commands run without yielding to other tasks, so it's taken by the
+    // thread that set it.
+    static REWRITTEN: RefCell<Option<Vec<Vec<String>>>> = const { RefCell::new(None) };
+}
+
+struct Master {
+    host: String,
+    port: u16,
+    // Tells the link to this master from the links to earlier ones, which stop
+    link_id: u64,
+    // When the link went down, None while it's up
+    down_since: Option<Instant>,
+    // When the master last sent something
+    last_io: Instant,
+    // Whether a full sync with this master completed, the replica has none of its keys until
+    // then
+    synced: bool,
+    // The replication id of the master and the offset reached in its stream
+    replid: String,
+    offset: u64,
+}
+
+struct Replica {
+    ip: String,
+    port: u16,
+    // The stream of write commands, once the replica asked for a full sync
+    feed: Option<mpsc::UnboundedSender<Arc<Vec<u8>>>>,
+    // The other end, until the connection of the replica starts streaming
+    pending: Option<mpsc::UnboundedReceiver<Arc<Vec<u8>>>>,
+    // The keys and functions when the full sync started, until the connection sends them
+    snapshot: Option<rdb::Snapshot>,
+    // Bytes of the stream sent to the feed but not to the replica yet
+    queued: Arc<AtomicUsize>,
+    // The offset the replica last acknowledged, and when
+    ack_offset: u64,
+    ack_time: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    master: Option<Master>,
+    links: u64,
+    // The replicas connected to this server by client id
+    replicas: BTreeMap<u64, Replica>,
+    // Bytes sent on the stream of write commands
+    offset: u64,
+    // The database the stream last selected, None when the next command needs a SELECT
+    stream_db: Option<usize>,
+    last_ping: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct Replication {
+    state: Mutex<State>,
+    // Write commands hold it for reading while they run and are propagated, and full syncs for
+    // writing while they take their snapshot, so that every write is either in the snapshot or
+    // streamed after it
+    writes: RwLock<()>,
+}
+
+impl Replication {
+    fn state(&self) -> Result<MutexGuard<'_, State>, String> {
+        self.state
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for replication {}", e))
+    }
+
+    // Held by write commands until they are propagated
+    pub fn lock_writes(&self) -> Result<RwLockReadGuard<'_, ()>, String> {
+        self.writes
+            .read()
+            .map_err(|e| format!("Failed to acquire lock for writes {}", e))
+    }
+
+    // The master the link should connect to, None once the link is outdated
+    fn master(&self, link_id: u64) -> Result<Option<(String, u16)>, String> {
+        Ok(self
+            .state()?
+            .master
+            .as_ref()
+            .filter(|master| master.link_id == link_id)
+            .map(|master| (master.host.clone(), master.port)))
+    }
+
+    // Runs f on the master of the link, unless the link is outdated
+    fn with_master(&self, link_id: u64, f: impl FnOnce(&mut Master)) -> Result<(), String> {
+        let mut state = self.state()?;
+        if let Some(master) = state.master.as_mut().filter(|m| m.link_id == link_id) {
+            f(master);
+        }
+        Ok(())
+    }
+
+    fn set_link_up(&self, link_id: u64, up: bool) -> Result<(), String> {
+        self.with_master(link_id, |master| match (up, master.down_since) {
+            (true, Some(_)) => master.down_since = None,
+            (false, None) => master.down_since = Some(Instant::now()),
+            _ => {}
+        })
+    }
+
+    // The offset the replica reached in the stream of its master
+    fn offset(&self, link_id: u64) -> Result<Option<u64>, String> {
+        Ok(self
+            .state()?
+            .master
+            .as_ref()
+            .filter(|master| master.link_id == link_id)
+            .map(|master| master.offset))
+    }
+
+    // Makes this server a master again, keeping the keys it replicated
+    pub fn stop_following(&self) -> Result<(), String> {
+        if self.state()?.master.take().is_some() {
+            eprintln!("MASTER MODE enabled");
+        }
+        Ok(())
+    }
+
+    pub fn is_replica(&self) -> Result<bool, String> {
+        Ok(self.state()?.master.is_some())
+    }
+
+    // For how long the link of this replica to its master has been down, None on a master and
+    // until a full sync completed
+    pub fn synced(&self) -> Result<Option<Duration>, String> {
+        Ok(self
+            .state()?
+            .master
+            .as_ref()
+            .filter(|master| master.synced)
+            .map(|master| master.down_since.map_or(Duration::ZERO, |s| s.elapsed())))
+    }
+
+    // The offset reached in the stream of writes, that of the master on a replica
+    pub fn repl_offset(&self) -> Result<u64, String> {
+        let state = self.state()?;
+        Ok(state.master.as_ref().map_or(state.offset, |m| m.offset))
+    }
+
+    // Forgets a replica whose link went away
+    pub fn remove_client(&self, client_id: u64) -> Result<(), String> {
+        self.state()?.replicas.remove(&client_id);
+        Ok(())
+    }
+
+    // Sends a write command to the replicas, selecting its database first when it has one
+    pub fn propagate(&self, db: Option<usize>, command: &[&str]) -> Result<(), String> {
+        let mut state = self.state()?;
+        if state.replicas.values().all(|r| r.feed.is_none()) {
+            return Ok(());
+        }
+        let mut bytes = vec![];
+        if let Some(db) = db.filter(|db| state.stream_db != Some(*db)) {
+            bytes.extend(encode(&["SELECT", &db.to_string()]));
+            state.stream_db = Some(db);
+        }
+        bytes.extend(encode(command));
+        state.offset += bytes.len() as u64;
+        let bytes = Arc::new(bytes);
+        for (client_id, replica) in state.replicas.iter_mut() {
+            let sent = replica
+                .feed
+                .as_ref()
+                .is_some_and(|feed| feed.send(Arc::clone(&bytes)).is_ok());
+            if !sent {
+                replica.feed = None;
+                continue;
+            }
+            // Left over the limit, which the connection of the replica sees and closes on
+            let queued = replica.queued.fetch_add(bytes.len(), Relaxed) + bytes.len();
+            if queued > REPLICA_BUFFER_LIMIT {
+                eprintln!(
+                    "Replica {} scheduled to be closed for overcoming of output buffer limits",
+                    client_id
+                );
+                replica.feed = None;
+            }
+        }
+        Ok(())
+    }
+
+    // Pings the replicas through the stream every PING_PERIOD, so that they can tell a master
+    // with nothing to write from a broken link
+    pub fn ping_replicas(&self) -> Result<(), String> {
+        let due = self
+            .state()?
+            .last_ping
+            .is_none_or(|last| last.elapsed() >= PING_PERIOD);
+        if due {
+            self.state()?.last_ping = Some(Instant::now());
+            self.propagate(None, &["PING"])?;
+        }
+        Ok(())
+    }
+
+    // The lines of the replication section of INFO
+    pub fn info_lines(&self, run_id: &str, priority: usize) -> Result<Vec<String>, String> {
+        let state = self.state()?;
+        let mut lines = vec![];
+        match &state.master {
+            Some(master) => {
+                let status = if master.down_since.is_some() {
+                    "down"
+                } else {
+                    "up"
+                };
+                lines.push("role:slave".to_string());
+                lines.push(format!("master_host:{}", master.host));
+                lines.push(format!("master_port:{}", master.port));
+                lines.push(format!("master_link_status:{}", status));
+                lines.push(format!(
+                    "master_last_io_seconds_ago:{}",
+                    master.last_io.elapsed().as_secs()
+                ));
+                lines.push(format!("master_sync_in_progress:{}", !master.synced as u8));
+                lines.push(format!("slave_repl_offset:{}", master.offset));
+                if let Some(since) = master.down_since {
+                    lines.push(format!(
+                        "master_link_down_since_seconds:{}",
+                        since.elapsed().as_secs()
+                    ));
+                }
+                lines.push(format!("slave_priority:{}", priority));
+                lines.push("slave_read_only:1".to_string());
+            }
+            None => lines.push("role:master".to_string()),
+        }
+        lines.push(format!("connected_slaves:{}", state.replicas.len()));
+        for (i, replica) in state.replicas.values().enumerate() {
+            let sync_state = if replica.feed.is_some() {
+                "online"
+            } else {
+                "wait_bgsave"
+            };
+            lines.push(format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}",
+                i,
+                replica.ip,
+                replica.port,
+                sync_state,
+                replica.ack_offset,
+                replica.ack_time.elapsed().as_secs()
+            ));
+        }
+        // A replica continues the history of its master
+        let (replid, offset) = match &state.master {
+            Some(master) => (master.replid.as_str(), master.offset),
+            None => (run_id, state.offset),
+        };
+        lines.push(format!("master_replid:{}", replid));
+        lines.push(format!("master_repl_offset:{}", offset));
+        Ok(lines)
+    }
+}
+
+// Encodes a command the way clients send them
+fn encode(command: &[&str]) -> Vec<u8> {
+    RESPValue::Array(Some(
+        command
+            .iter()
+            .map(|arg| RESPValue::bulk_string(Some(arg.to_string())))
+            .collect(),
+    ))
+    .to_bytes()
+}
+
+// Makes the running command propagate these commands instead of itself, none to propagate
+// nothing
+pub fn propagate_instead(commands: Vec<Vec<String>>) {
+    REWRITTEN.with(|rewritten| *rewritten.borrow_mut() = Some(commands));
+}
+
+// What the command that ran last propagates instead of itself, if it said so
+pub fn take_rewritten() -> Option<Vec<Vec<String>>> {
+    REWRITTEN.with(|rewritten| rewritten.borrow_mut().take())
+}
+
+// Replicas refuse the commands that write
+pub fn check(args: &[&str], server: &Server) -> Result<Option<RESPValue>, String> {
+    if !commands::is_write(args) || !server.replication.is_replica()? {
+        return Ok(None);
+    }
+    Ok(Some(RESPValue::error(
+        "READONLY You can't write against a read only replica.".to_string(),
+    )))
+}
+
+// REPLICAOF and its older name SLAVEOF
+pub fn replicaof(
+    command: &str,
+    args: &[BulkString],
+    databases: &Databases,
+    functions: &Functions,
+    server: &Arc<Server>,
+) -> Result<RESPValue, String> {
+    let args = match Args::new(command, args).rest() {
+        Ok(args) => args,
+        Err(e) => return Ok(e.into()),
+    };
+    if server.cluster.state()?.is_some() {
+        return Ok(RESPValue::error(format!(
+            "ERR {} not allowed in cluster mode.",
+            command.to_uppercase()
+        )));
+    }
+    let ok = || Ok(RESPValue::simple_string("OK".to_string()));
+    match args.as_slice() {
+        // The keys are kept, the replica becomes a master with what it replicated
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => {
+            server.replication.stop_following()?;
+            ok()
+        }
+        [host, port] => {
+            let port = match port.parse::<u16>() {
+                Ok(port) => port,
+                Err(_) => return Ok(not_an_integer()),
+            };
+            if !follow(host, port, databases, functions, server)? {
+                return Ok(RESPValue::simple_string(
+                    "OK Already connected to specified master".to_string(),
+                ));
+            }
+            ok()
+        }
+        _ => Ok(wrong_number_of_arguments(&command.to_lowercase())),
+    }
+}
+
+// Makes this server a replica of the master at host:port, returning false if it already is. The
+// keys are replaced by those of the master once the link completes a full sync.
+pub fn follow(
+    host: &str,
+    port: u16,
+    databases: &Databases,
+    functions: &Functions,
+    server: &Arc<Server>,
+) -> Result<bool, String> {
+    let mut state = server.replication.state()?;
+    if state
+        .master
+        .as_ref()
+        .is_some_and(|m| m.host.eq_ignore_ascii_case(host) && m.port == port)
+    {
+        return Ok(false);
+    }
+    state.links += 1;
+    let link_id = state.links;
+    state.master = Some(Master {
+        host: host.to_string(),
+        port,
+        link_id,
+        down_since: Some(Instant::now()),
+        last_io: Instant::now(),
+        synced: false,
+        replid: String::new(),
+        offset: 0,
+    });
+    eprintln!("REPLICAOF {}:{} enabled", host, port);
+    tokio::spawn(link(
+        Arc::clone(server),
+        Arc::clone(databases),
+        Arc::clone(functions),
+        link_id,
+    ));
+    Ok(true)
+}
+
+// What a replica tells its master about itself, in option value pairs
+pub fn replconf(
+    args: &[BulkString],
+    session: &Session,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let args = match Args::new("replconf", args).rest() {
+        Ok(args) => args,
+        Err(e) => return Ok(e.into()),
+    };
+    if !args.len().is_multiple_of(2) {
+        return Ok(syntax_error());
+    }
+    for pair in args.chunks(2) {
+        match pair[0].to_lowercase().as_str() {
+            "listening-port" => {
+                let port = match pair[1].parse::<u16>() {
+                    Ok(port) => port,
+                    Err(_) => return Ok(not_an_integer()),
+                };
+                let ip = server
+                    .clients
+                    .peer(session.client_id)?
+                    .and_then(|(addr, _)| addr.parse::<SocketAddr>().ok())
+                    .map(|addr| addr.ip().to_string())
+                    .unwrap_or_default();
+                let mut state = server.replication.state()?;
+                let replica = state
+                    .replicas
+                    .entry(session.client_id)
+                    .or_insert_with(|| Replica {
+                        ip: String::new(),
+                        port: 0,
+                        feed: None,
+                        pending: None,
+                        snapshot: None,
+                        queued: Arc::default(),
+                        ack_offset: 0,
+                        ack_time: Instant::now(),
+                    });
+                replica.ip = ip;
+                replica.port = port;
+            }
+            "ip-address" => {
+                let mut state = server.replication.state()?;
+                if let Some(replica) = state.replicas.get_mut(&session.client_id) {
+                    replica.ip = pair[1].to_string();
+                }
+            }
+            "capa" | "ack" => {}
+            option => {
+                return Ok(RESPValue::error(format!(
+                    "ERR Unrecognized REPLCONF option: {}",
+                    option
+                )))
+            }
+        }
+    }
+    Ok(RESPValue::simple_string("OK".to_string()))
+}
+
+// PSYNC and SYNC, which always start a full sync. The keys are copied while writes wait, like
+// Redis forks, and the connection encodes and sends them before the stream once the reply went
+// out, after which it only serves the stream.
+pub fn psync(
+    command: &str,
+    args: &[BulkString],
+    session: &mut Session,
+    databases: &Databases,
+    functions: &Functions,
+    server: &Server,
+) -> Result<RESPValue, String> {
+    let parsed = if command.eq_ignore_ascii_case("sync") {
+        Args::new(command, args).end()
+    } else {
+        Args::new(command, args).parse(|args| {
+            args.next_str()?;
+            args.next_str()?;
+            Ok(())
+        })
+    };
+    if let Err(e) = parsed {
+        return Ok(e.into());
+    }
+    if server
+        .replication
+        .state()?
+        .master
+        .as_ref()
+        .is_some_and(|master| !master.synced || master.down_since.is_some())
+    {
+        return Ok(RESPValue::error(
+            "NOMASTERLINK Can't SYNC while not connected with my master".to_string(),
+        ));
+    }
+
+    let _writes = server
+        .replication
+        .writes
+        .write()
+        .map_err(|e| format!("Failed to acquire lock for writes {}", e))?;
+    let snapshot = persistence::snapshot(databases, functions)?;
+    let (feed, pending) = mpsc::unbounded_channel();
+    let peer = server.clients.peer(session.client_id)?;
+    let mut state = server.replication.state()?;
+    let offset = state.offset;
+    // Replicas that didn't announce their port are listed with the one they connected from
+    let replica = state.replicas.entry(session.client_id).or_insert_with(|| {
+        let addr = peer.and_then(|(addr, _)| addr.parse::<SocketAddr>().ok());
+        Replica {
+            ip: addr.map(|a| a.ip().to_string()).unwrap_or_default(),
+            port: addr.map_or(0, |a| a.port()),
+            feed: None,
+            pending: None,
+            snapshot: None,
+            queued: Arc::default(),
+            ack_offset: 0,
+            ack_time: Instant::now(),
+        }
+    });
+    replica.feed = Some(feed);
+    replica.pending = Some(pending);
+    replica.snapshot = Some(snapshot);
+    replica.queued = Arc::default();
+    replica.ack_offset = offset;
+    // The replica doesn't know which database the stream selected
+    state.stream_db = None;
+    session.replica = true;
+    eprintln!("Starting a full sync for replica {}", session.client_id);
+    Ok(RESPValue::simple_string(format!(
+        "FULLRESYNC {} {}",
+        server.run_id, offset
+    )))
+}
+
+// Sends the stream to a replica that asked for a full sync, reading its acknowledgements, until
+// either side closes the connection
+pub async fn serve_replica(
+    socket: &mut TcpStream,
+    addr: SocketAddr,
+    server: &Server,
+    client_id: u64,
+    killed: &Notify,
+) {
+    let pending = server.replication.state().map(|mut state| {
+        let replica = state.replicas.get_mut(&client_id)?;
+        Some((
+            replica.pending.take()?,
+            replica.snapshot.take()?,
+            Arc::clone(&replica.queued),
+        ))
+    });
+    let (mut feed, snapshot, queued) = match pending {
+        Ok(Some(pending)) => pending,
+        _ => return,
+    };
+    if let Err(e) = send_snapshot(socket, snapshot, killed).await {
+        eprintln!(
+            "Error while sending the snapshot to replica {}\n{}",
+            addr, e
+        );
+        return;
+    }
+    let mut buffer = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        tokio::select! {
+            bytes = feed.recv() => match bytes {
+                Some(_) if queued.load(Relaxed) > REPLICA_BUFFER_LIMIT => {
+                    eprintln!("Replica {} overcame the output buffer limit", addr);
+                    break;
+                }
+                Some(bytes) => {
+                    if let Err(e) = socket.write_all(&bytes).await {
+                        eprintln!("Error while writing data to replica {}\n{}", addr, e);
+                        break;
+                    }
+                    queued.fetch_sub(bytes.len(), Relaxed);
+                }
+                None => break,
+            },
+            result = socket.read(&mut chunk) => match result {
+                Ok(0) | Err(_) => {
+                    eprintln!("Connection with replica {} lost", addr);
+                    break;
+                }
+                Ok(n) => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    while let Some((command, _)) = take_command(&mut buffer) {
+                        record_ack(server, client_id, &command);
+                    }
+                }
+            },
+            _ = killed.notified() => {
+                eprintln!("Client {} was killed", addr);
+                break;
+            }
+        }
+    }
+}
+
+// Encodes the snapshot of a full sync away from the tasks serving clients and sends it like a bulk
+// string without the final CRLF. Like Redis during BGSAVE, a newline goes out every PING_PERIOD
+// meanwhile so that the replica knows the master is alive.
+async fn send_snapshot(
+    socket: &mut TcpStream,
+    snapshot: rdb::Snapshot,
+    killed: &Notify,
+) -> Result<(), String> {
+    let mut encoding = tokio::task::spawn_blocking(move || rdb::write_file(&snapshot));
+    let mut keepalive = tokio::time::interval(PING_PERIOD);
+    let contents = loop {
+        tokio::select! {
+            contents = &mut encoding => break contents.map_err(|e| e.to_string())?,
+            _ = keepalive.tick() => {
+                socket.write_all(b"\n").await.map_err(|e| e.to_string())?;
+            }
+            _ = killed.notified() => return Err("killed".to_string()),
+        }
+    };
+    let header = format!("${}\r\n", contents.len());
+    socket
+        .write_all(header.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.write_all(&contents).await.map_err(|e| e.to_string())
+}
+
+// Records a REPLCONF ACK, the only command replicas send on the stream
+fn record_ack(server: &Server, client_id: u64, command: &[BulkString]) {
+    let args: Vec<&str> = command
+        .iter()
+        .map(|arg| arg.as_deref().unwrap_or_default())
+        .collect();
+    let offset = match args.as_slice() {
+        [replconf, ack, offset, ..]
+            if replconf.eq_ignore_ascii_case("replconf") && ack.eq_ignore_ascii_case("ack") =>
+        {
+            offset.parse::<u64>().ok()
+        }
+        _ => None,
+    };
+    if let (Some(offset), Ok(mut state)) = (offset, server.replication.state()) {
+        if let Some(replica) = state.replicas.get_mut(&client_id) {
+            replica.ack_offset = offset;
+            replica.ack_time = Instant::now();
+        }
+    }
+}
+
+// Takes the first complete command off the buffer, with the number of bytes it took
+fn take_command(buffer: &mut Vec<u8>) -> Option<(Vec<BulkString>, usize)> {
+    loop {
+        let len = Frame::check(buffer).ok()?;
+        let (value, _) = RESPValue::parse(&buffer[..len]).ok()?;
+        buffer.drain(..len);
+        let command: Result<Option<Vec<BulkString>>, _> = value.try_into();
+        match command {
+            Ok(Some(command)) if !command.is_empty() => return Some((command, len)),
+            _ => {}
+        }
+    }
+}
+
+// Keeps a link to the master up until the replica is given another master or none
+async fn link(server: Arc<Server>, databases: Databases, functions: Functions, link_id: u64) {
+    loop {
+        let (host, port) = match server.replication.master(link_id) {
+            Ok(Some(addr)) => addr,
+            _ => return,
+        };
+        eprintln!("Connecting to MASTER {}:{}", host, port);
+        serve_link(&server, &databases, &functions, link_id, &host, port).await;
+        if server.replication.set_link_up(link_id, false).is_err() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// Returns when the link breaks or is outdated
+async fn serve_link(
+    server: &Arc<Server>,
+    databases: &Databases,
+    functions: &Functions,
+    link_id: u64,
+    host: &str,
+    port: u16,
+) {
+    let connected = tokio::time::timeout(TIMEOUT, TcpStream::connect((host, port))).await;
+    let mut stream = match connected {
+        Ok(Ok(stream)) => stream,
+        _ => return,
+    };
+    let listening_port = server.config().map_or(0, |config| config.port);
+    let mut buffer = vec![];
+    let handshake = [
+        vec!["PING".to_string()],
+        vec![
+            "REPLCONF".to_string(),
+            "listening-port".to_string(),
+            listening_port.to_string(),
+        ],
+        vec!["PSYNC".to_string(), "?".to_string(), "-1".to_string()],
+    ];
+    let mut reply = None;
+    for command in &handshake {
+        // The master copies its keys before it replies to PSYNC
+        reply = match request_within(&mut stream, &mut buffer, command, REPL_TIMEOUT).await {
+            Some(RESPValue::Error(e)) => {
+                eprintln!("Error reply from MASTER {}:{}: {}", host, port, e);
+                return;
+            }
+            Some(reply) => Some(reply),
+            None => return,
+        };
+    }
+    let (replid, offset) = match &reply {
+        Some(RESPValue::SimpleString(s)) => match s.split(' ').collect::<Vec<_>>()[..] {
+            ["FULLRESYNC", replid, offset] => match offset.parse::<u64>() {
+                Ok(offset) => (replid.to_string(), offset),
+                Err(_) => return,
+            },
+            _ => return,
+        },
+        _ => return,
+    };
+    eprintln!("Full resync from MASTER {}:{}: {}", host, port, replid);
+
+    let rdb = match read_rdb(&mut stream, &mut buffer).await {
+        Some(rdb) => rdb,
+        None => return,
+    };
+    let loaded = rdb::read_file(&rdb)
+        .ok_or_else(|| "Bad RDB file received from MASTER".to_string())
+        .and_then(|snapshot| persistence::load(snapshot, databases, functions))
+        .and_then(|_| server.tracking.invalidate_all(server));
+    if let Err(e) = loaded {
+        eprintln!("{}", e);
+        return;
+    }
+    let synced = server.replication.with_master(link_id, |master| {
+        master.synced = true;
+        master.replid = replid;
+        master.offset = offset;
+        master.last_io = Instant::now();
+        master.down_since = None;
+    });
+    if synced.is_err() {
+        return;
+    }
+    eprintln!("MASTER <-> REPLICA sync: Finished with success");
+
+    // The commands of the stream run like those of a client, on their own database selection
+    let mut session = Session::default();
+    let mut acks = tokio::time::interval(PING_PERIOD);
+    let mut chunk = [0u8; 4096];
+    let mut last_io = Instant::now();
+    loop {
+        while let Some((command, len)) = take_command(&mut buffer) {
+            let guard = crate::lock_command(&command, &server.commands).await;
+            apply(&command, &mut session, databases, functions, server);
+            drop(guard);
+            let advanced = server
+                .replication
+                .with_master(link_id, |master| master.offset += len as u64);
+            if advanced.is_err() {
+                return;
+            }
+        }
+        if matches!(Frame::check(&buffer), Err(e) if e != ParseError::NotEnoughBytes) {
+            eprintln!("Protocol error from MASTER {}:{}", host, port);
+            return;
+        }
+        tokio::select! {
+            result = stream.read(&mut chunk) => match result {
+                Ok(0) | Err(_) => return,
+                Ok(n) => {
+                    buffer.extend_from_slice(&chunk[..n]);
+                    last_io = Instant::now();
+                    let _ = server.replication.with_master(link_id, |m| m.last_io = last_io);
+                }
+            },
+            _ = acks.tick() => {
+                let offset = match server.replication.offset(link_id) {
+                    Ok(Some(offset)) => offset,
+                    _ => return,
+                };
+                if last_io.elapsed() > REPL_TIMEOUT {
+                    eprintln!("MASTER {}:{} timed out", host, port);
+                    return;
+                }
+                let ack = encode(&["REPLCONF", "ACK", &offset.to_string()]);
+                if stream.write_all(&ack).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Runs a command of the stream of the master
+fn apply(
+    command: &[BulkString],
+    session: &mut Session,
+    databases: &Databases,
+    functions: &Functions,
+    server: &Arc<Server>,
+) {
+    let args = match Args::new("", command).rest() {
+        Ok(args) => args,
+        Err(_) => return,
+    };
+    let result = crate::gen_response(
+        args[0],
+        &command[1..],
+        databases,
+        session,
+        Arc::clone(functions),
+        server,
+    );
+    match result {
+        Ok(RESPValue::Error(e)) => eprintln!("Error from the command of MASTER {}", e),
+        Ok(_) => {}
+        Err(e) => eprintln!("Error while handling command of MASTER\n{}", e),
+    }
+}
+
+// Reads the RDB file of a full sync, which is sent like a bulk string without the final CRLF after
+// the newlines the master sends while it encodes it. However long the file, only REPL_TIMEOUT
+// without anything arriving makes it fail.
+async fn read_rdb(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let mut chunk = [0; 4096];
+    let mut header = None;
+    loop {
+        let keepalives = buffer.iter().take_while(|&&b| b == b'\n').count();
+        buffer.drain(..keepalives);
+        if header.is_none() {
+            if let Some(end) = buffer.windows(2).position(|w| w == b"\r\n") {
+                let len = std::str::from_utf8(buffer[..end].strip_prefix(b"$")?)
+                    .ok()?
+                    .parse::<usize>()
+                    .ok()?;
+                buffer.drain(..end + 2);
+                header = Some(len);
+            }
+        }
+        if let Some(len) = header.filter(|len| buffer.len() >= *len) {
+            return Some(buffer.drain(..len).collect());
+        }
+        match tokio::time::timeout(REPL_TIMEOUT, stream.read(&mut chunk)).await {
+            Ok(Ok(n)) if n > 0 => buffer.extend_from_slice(&chunk[..n]),
+            _ => return None,
+        }
+    }
+}
+
+// Sends a command and waits for its reply, None when either fails or takes too long
+pub async fn request(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    command: &[String],
+) -> Option<RESPValue> {
+    request_within(stream, buffer, command, TIMEOUT).await
+}
+
+// Like request, waiting up to timeout for each of the write and the reply
+async fn request_within(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    command: &[String],
+    timeout: Duration,
+) -> Option<RESPValue> {
+    let command = RESPValue::Array(Some(
+        command
+            .iter()
+            .map(|arg| RESPValue::bulk_string(Some(arg.clone())))
+            .collect(),
+    ));
+    tokio::time::timeout(timeout, stream.write_all(&command.to_bytes()))
+        .await
+        .ok()?
+        .ok()?;
+    tokio::time::timeout(timeout, read_reply(stream, buffer))
+        .await
+        .ok()?
+}
+
+// Reads the next value from the stream, keeping what follows it in the buffer
+pub async fn read_reply(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<RESPValue> {
+    let mut chunk = [0; 4096];
+    loop {
+        match Frame::check(buffer) {
+            Ok(len) => {
+                let (value, _) = RESPValue::parse(&buffer[..len]).ok()?;
+                buffer.drain(..len);
+                return Some(value);
+            }
+            Err(ParseError::NotEnoughBytes) => {}
+            Err(_) => return None,
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn replica(ip: &str, port: u16) -> Replica {
+        Replica {
+            ip: ip.to_string(),
+            port,
+            feed: None,
+            pending: None,
+            snapshot: None,
+            queued: Arc::default(),
+            ack_offset: 0,
+            ack_time: Instant::now(),
+        }
+    }
+
+    fn master(link_id: u64) -> Master {
+        Master {
+            host: "10.0.0.1".to_string(),
+            port: 6379,
+            link_id,
+            down_since: None,
+            last_io: Instant::now(),
+            synced: true,
+            replid: "def".to_string(),
+            offset: 42,
+        }
+    }
+
+    #[test]
+    fn test_info_lines() {
+        let replication = Replication::default();
+        replication
+            .state()
+            .unwrap()
+            .replicas
+            .insert(7, replica("127.0.0.1", 6380));
+        let lines = replication.info_lines("abc", 100).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "role:master",
+                "connected_slaves:1",
+                "slave0:ip=127.0.0.1,port=6380,state=wait_bgsave,offset=0,lag=0",
+                "master_replid:abc",
+                "master_repl_offset:0",
+            ]
+        );
+
+        replication.state().unwrap().master = Some(master(1));
+        let lines = replication.info_lines("abc", 10).unwrap();
+        assert_eq!(
+            lines[..7],
+            [
+                "role:slave",
+                "master_host:10.0.0.1",
+                "master_port:6379",
+                "master_link_status:up",
+                "master_last_io_seconds_ago:0",
+                "master_sync_in_progress:0",
+                "slave_repl_offset:42",
+            ]
+        );
+        assert!(lines.contains(&"slave_priority:10".to_string()));
+        assert!(lines.contains(&"master_replid:def".to_string()));
+        replication.set_link_up(1, false).unwrap();
+        let lines = replication.info_lines("abc", 10).unwrap();
+        assert_eq!(lines[3], "master_link_status:down");
+        assert_eq!(lines[7], "master_link_down_since_seconds:0");
+    }
+
+    #[test]
+    fn test_propagate() {
+        let replication = Replication::default();
+        // Nothing is streamed without replicas
+        replication.propagate(Some(0), &["SET", "a", "1"]).unwrap();
+        assert_eq!(replication.state().unwrap().offset, 0);
+
+        let (feed, mut received) = mpsc::unbounded_channel();
+        let mut online = replica("127.0.0.1", 6380);
+        online.feed = Some(feed);
+        replication.state().unwrap().replicas.insert(7, online);
+        replication.propagate(Some(2), &["SET", "a", "1"]).unwrap();
+        replication.propagate(Some(2), &["DEL", "a"]).unwrap();
+        replication.propagate(None, &["PING"]).unwrap();
+        let mut stream = vec![];
+        while let Ok(bytes) = received.try_recv() {
+            stream.extend_from_slice(&bytes);
+        }
+        assert_eq!(
+            stream,
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n\
+              *2\r\n$3\r\nDEL\r\n$1\r\na\r\n*1\r\n$4\r\nPING\r\n"
+        );
+        assert_eq!(replication.state().unwrap().offset, stream.len() as u64);
+
+        let mut buffer = stream.clone();
+        buffer.extend_from_slice(b"*1\r\n$4\r\nPI");
+        let (command, len) = take_command(&mut buffer).unwrap();
+        assert_eq!(len, 23);
+        assert_eq!(command[0].as_deref(), Some("SELECT"));
+        let commands = std::iter::from_fn(|| take_command(&mut buffer)).count();
+        assert_eq!(commands, 3);
+        assert_eq!(buffer, b"*1\r\n$4\r\nPI");
+
+        // Replicas that went away stop being streamed to
+        drop(received);
+        replication.propagate(None, &["PING"]).unwrap();
+        assert!(replication.state().unwrap().replicas[&7].feed.is_none());
+    }
+
+    #[test]
+    fn test_buffer_limit() {
+        let replication = Replication::default();
+        let (feed, _received) = mpsc::unbounded_channel();
+        let mut slow = replica("127.0.0.1", 6380);
+        slow.feed = Some(feed);
+        let queued = Arc::clone(&slow.queued);
+        replication.state().unwrap().replicas.insert(7, slow);
+        replication.propagate(None, &["PING"]).unwrap();
+        assert_eq!(queued.load(Relaxed), 14);
+        assert!(replication.state().unwrap().replicas[&7].feed.is_some());
+
+        // A replica that doesn't read the stream fast enough is dropped once over the limit
+        queued.store(REPLICA_BUFFER_LIMIT - 14, Relaxed);
+        replication.propagate(None, &["PING"]).unwrap();
+        assert!(replication.state().unwrap().replicas[&7].feed.is_some());
+        replication.propagate(None, &["PING"]).unwrap();
+        assert!(replication.state().unwrap().replicas[&7].feed.is_none());
+    }
+}
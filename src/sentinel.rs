@@ -0,0 +1,2160 @@
+// Sentinel mode, started with --sentinel. Instead of serving keys, the server monitors the masters
+// of its `sentinel monitor` lines and fails them over. Like in Redis, masters and their replicas
+// are pinged and asked for INFO every second, which is how the replicas are found, and sentinels
+// find each other through the hello messages they publish on the __sentinel__:hello channel of
+// the instances they monitor. A master that doesn't answer for down-after-milliseconds is
+// subjectively down (SDOWN), and objectively down (ODOWN) once quorum sentinels agree. The
+// sentinels then elect a leader for a new epoch, which promotes a replica with REPLICAOF NO ONE
+// and points the other replicas to it. The other sentinels learn of the new master from the hello
+// messages of the leader, which carry the config epoch of the failover.
+use crate::bus::now_ms;
+use crate::config;
+use crate::glob;
+use crate::info;
+use crate::not_an_integer;
+use crate::random_u64;
+use crate::replication;
+use crate::strings::wrong_number_of_arguments;
+use crate::Server;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+pub const DEFAULT_PORT: usize = 26379;
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+const DEFAULT_DOWN_AFTER: u64 = 30000;
+const DEFAULT_FAILOVER_TIMEOUT: u64 = 180000;
+// Answers to IS-MASTER-DOWN-BY-ADDR count for five of the seconds between questions, like in Redis
+const DOWN_REPLY_VALIDITY: u64 = 5000;
+// Replicas whose INFO is older than this aren't promoted
+const INFO_VALIDITY: u64 = 5000;
+// How long a replica has to report the wrong master before it's pointed to the right one, four
+// hello periods like in Redis
+const RECONFIGURE_WAIT: u64 = 8000;
+
+// The commands a sentinel serves, like Redis' sentinelcmds
+const SENTINEL_COMMANDS: &[&str] = &[
+    "PING",
+    "SENTINEL",
+    "INFO",
+    "SUBSCRIBE",
+    "UNSUBSCRIBE",
+    "PSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "PUBLISH",
+    "CLIENT",
+    "AUTH",
+    "HELLO",
+    "ACL",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Instance {
+    ip: String,
+    port: u16,
+    run_id: Option<String>,
+    // Unix times in milliseconds: when a PING last got a valid reply, or the instance was added,
+    // and when it was flagged as subjectively down
+    last_reply: u64,
+    sdown_since: Option<u64>,
+}
+
+impl Instance {
+    fn new(ip: &str, port: u16, now: u64) -> Self {
+        Self {
+            ip: ip.to_string(),
+            port,
+            run_id: None,
+            last_reply: now,
+            sdown_since: None,
+        }
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Replica {
+    instance: Instance,
+    // What the INFO of the replica last reported, and since when its role and master are those
+    role_reported: String,
+    master_addr: Option<(String, u16)>,
+    master_link_up: bool,
+    // Milliseconds the link to the master has been down for
+    master_link_down: u64,
+    // Whether the replica completed a full sync with its master, it has none of its keys before
+    synced: bool,
+    repl_offset: u64,
+    priority: usize,
+    info_refresh: u64,
+    reported_since: u64,
+}
+
+impl Replica {
+    fn new(ip: &str, port: u16, now: u64) -> Self {
+        Self {
+            instance: Instance::new(ip, port, now),
+            role_reported: String::new(),
+            master_addr: None,
+            master_link_up: false,
+            master_link_down: 0,
+            synced: false,
+            repl_offset: 0,
+            priority: 100,
+            info_refresh: 0,
+            reported_since: now,
+        }
+    }
+}
+
+// Another sentinel monitoring the same master
+#[derive(Clone, Debug, PartialEq)]
+struct Peer {
+    instance: Instance,
+    last_hello: u64,
+    // Its last answer to IS-MASTER-DOWN-BY-ADDR, and when it came
+    master_down: bool,
+    down_reply: u64,
+    // The sentinel it voted for to lead the failover of leader_epoch
+    leader: Option<String>,
+    leader_epoch: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    // Waiting for the votes of the other sentinels
+    Election,
+    // The replica at the address was sent REPLICAOF NO ONE, its INFO has to report it's a master
+    Promotion(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Failover {
+    step: Step,
+    epoch: u64,
+    // When the current step started
+    step_time: u64,
+    // Set by SENTINEL FAILOVER, which doesn't wait for the other sentinels to agree
+    forced: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Master {
+    name: String,
+    instance: Instance,
+    quorum: usize,
+    down_after: u64,
+    failover_timeout: u64,
+    // The epoch of the failover that made it the master
+    config_epoch: u64,
+    role_reported: String,
+    odown_since: Option<u64>,
+    // By address
+    replicas: BTreeMap<String, Replica>,
+    // By run id
+    sentinels: BTreeMap<String, Peer>,
+    // The sentinel this one voted for to lead the failover of leader_epoch
+    leader: Option<String>,
+    leader_epoch: u64,
+    failover: Option<Failover>,
+    // When the last failover started, the next one waits for twice the failover timeout
+    failover_start: u64,
+    // When a failover can start once the master is objectively down. Sentinels wait a random
+    // part of a second so that those that saw it go down at the same time don't all start one
+    // and split the votes.
+    failover_after: u64,
+}
+
+impl Master {
+    fn new(name: &str, ip: &str, port: u16, quorum: usize, now: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            instance: Instance::new(ip, port, now),
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            config_epoch: 0,
+            role_reported: String::new(),
+            odown_since: None,
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            leader: None,
+            leader_epoch: 0,
+            failover: None,
+            failover_start: 0,
+            failover_after: 0,
+        }
+    }
+
+    // How events name the master, and the instances of the master after an @
+    fn describe(&self) -> String {
+        format!(
+            "master {} {} {}",
+            self.name, self.instance.ip, self.instance.port
+        )
+    }
+
+    fn at(&self) -> String {
+        format!(
+            "@ {} {} {}",
+            self.name, self.instance.ip, self.instance.port
+        )
+    }
+}
+
+fn describe_replica(replica: &Replica, at: &str) -> String {
+    let instance = &replica.instance;
+    format!(
+        "slave {} {} {} {}",
+        instance.addr(),
+        instance.ip,
+        instance.port,
+        at
+    )
+}
+
+fn describe_peer(id: &str, peer: &Peer, at: &str) -> String {
+    format!(
+        "sentinel {} {} {} {}",
+        id, peer.instance.ip, peer.instance.port, at
+    )
+}
+
+// An instance a link is kept to: a master, one of its replicas by address, or another sentinel
+// monitoring it by run id
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Master(String),
+    Replica(String, String),
+    Sentinel(String, String),
+}
+
+impl Target {
+    fn master(&self) -> &str {
+        match self {
+            Target::Master(name) | Target::Replica(name, _) | Target::Sentinel(name, _) => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Action {
+    // An event, published on the channel of its name like +sdown
+    Event(&'static str, String),
+    // A command to send to the instance at the address
+    Send(String, u16, Vec<String>),
+    // Asks the sentinel about the master, and for its vote, without waiting for the next check
+    Ask(Target),
+}
+
+fn event(name: &'static str, message: String) -> Action {
+    Action::Event(name, message)
+}
+
+fn replicaof(ip: &str, port: u16) -> Vec<String> {
+    vec!["REPLICAOF".to_string(), ip.to_string(), port.to_string()]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct State {
+    myid: String,
+    current_epoch: u64,
+    masters: BTreeMap<String, Master>,
+    // The links running, with the address they're to
+    links: BTreeSet<(Target, String, u16)>,
+    // Whether the config file is out of date
+    dirty: bool,
+}
+
+impl State {
+    fn new() -> Self {
+        Self {
+            myid: info::new_run_id(),
+            current_epoch: 0,
+            masters: BTreeMap::new(),
+            links: BTreeSet::new(),
+            dirty: true,
+        }
+    }
+
+    // Reads the `sentinel` lines of the config file, the ones Redis writes and the monitors
+    // added by hand
+    fn from_config(config: &str, now: u64) -> Result<Self, String> {
+        let mut state = Self::new();
+        state.dirty = false;
+        for (name, value) in config.lines().filter_map(config::parse_line) {
+            if name != "sentinel" {
+                continue;
+            }
+            let args: Vec<&str> = value.split_whitespace().collect();
+            state
+                .apply_config_line(&args, now)
+                .ok_or_else(|| format!("Invalid sentinel config line 'sentinel {}'", value))?;
+        }
+        Ok(state)
+    }
+
+    fn apply_config_line(&mut self, args: &[&str], now: u64) -> Option<()> {
+        let option = args.first()?.to_lowercase();
+        match (option.as_str(), &args[1..]) {
+            ("myid", [id]) if id.len() == 40 => self.myid = id.to_string(),
+            ("current-epoch", [epoch]) => self.current_epoch = epoch.parse().ok()?,
+            ("monitor", [name, ip, port, quorum]) => {
+                let quorum = quorum.parse().ok().filter(|q| *q > 0)?;
+                let master = Master::new(name, ip, port.parse().ok()?, quorum, now);
+                self.masters.insert(name.to_string(), master);
+            }
+            (_, [name, rest @ ..]) => {
+                let master = self.masters.get_mut(*name)?;
+                match (option.as_str(), rest) {
+                    ("down-after-milliseconds", [ms]) => {
+                        master.down_after = ms.parse().ok().filter(|ms| *ms > 0)?
+                    }
+                    ("failover-timeout", [ms]) => {
+                        master.failover_timeout = ms.parse().ok().filter(|ms| *ms > 0)?
+                    }
+                    ("config-epoch", [epoch]) => master.config_epoch = epoch.parse().ok()?,
+                    ("leader-epoch", [epoch]) => master.leader_epoch = epoch.parse().ok()?,
+                    ("known-replica", [ip, port]) | ("known-slave", [ip, port]) => {
+                        let replica = Replica::new(ip, port.parse().ok()?, now);
+                        master.replicas.insert(replica.instance.addr(), replica);
+                    }
+                    ("known-sentinel", [ip, port, id]) => {
+                        let peer = Peer::new(ip, port.parse().ok()?, id, now);
+                        master.sentinels.insert(id.to_string(), peer);
+                    }
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    // The `sentinel` lines for the config file, which Redis rewrites on every change
+    fn to_config(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("sentinel myid {}", self.myid),
+            format!("sentinel current-epoch {}", self.current_epoch),
+        ];
+        for master in self.masters.values() {
+            let name = &master.name;
+            lines.push(format!(
+                "sentinel monitor {} {} {} {}",
+                name, master.instance.ip, master.instance.port, master.quorum
+            ));
+            if master.down_after != DEFAULT_DOWN_AFTER {
+                lines.push(format!(
+                    "sentinel down-after-milliseconds {} {}",
+                    name, master.down_after
+                ));
+            }
+            if master.failover_timeout != DEFAULT_FAILOVER_TIMEOUT {
+                lines.push(format!(
+                    "sentinel failover-timeout {} {}",
+                    name, master.failover_timeout
+                ));
+            }
+            lines.push(format!(
+                "sentinel config-epoch {} {}",
+                name, master.config_epoch
+            ));
+            lines.push(format!(
+                "sentinel leader-epoch {} {}",
+                name, master.leader_epoch
+            ));
+            for replica in master.replicas.values() {
+                lines.push(format!(
+                    "sentinel known-replica {} {} {}",
+                    name, replica.instance.ip, replica.instance.port
+                ));
+            }
+            for (id, peer) in &master.sentinels {
+                lines.push(format!(
+                    "sentinel known-sentinel {} {} {} {}",
+                    name, peer.instance.ip, peer.instance.port, id
+                ));
+            }
+        }
+        lines
+    }
+
+    fn instance_mut(&mut self, target: &Target) -> Option<&mut Instance> {
+        let master = self.masters.get_mut(target.master())?;
+        match target {
+            Target::Master(_) => Some(&mut master.instance),
+            Target::Replica(_, addr) => master.replicas.get_mut(addr).map(|r| &mut r.instance),
+            Target::Sentinel(_, id) => master.sentinels.get_mut(id).map(|p| &mut p.instance),
+        }
+    }
+
+    // Every instance a link should be kept to, with its address
+    fn targets(&self) -> Vec<(Target, String, u16)> {
+        let mut targets = vec![];
+        for (name, master) in &self.masters {
+            let instance = &master.instance;
+            targets.push((
+                Target::Master(name.clone()),
+                instance.ip.clone(),
+                instance.port,
+            ));
+            for (addr, replica) in &master.replicas {
+                targets.push((
+                    Target::Replica(name.clone(), addr.clone()),
+                    replica.instance.ip.clone(),
+                    replica.instance.port,
+                ));
+            }
+            for (id, peer) in &master.sentinels {
+                targets.push((
+                    Target::Sentinel(name.clone(), id.clone()),
+                    peer.instance.ip.clone(),
+                    peer.instance.port,
+                ));
+            }
+        }
+        targets
+    }
+
+    // Whether the link is still to one of the instances, at the same address
+    fn wants(&mut self, target: &Target, ip: &str, port: u16) -> bool {
+        self.instance_mut(target)
+            .is_some_and(|instance| instance.ip == ip && instance.port == port)
+    }
+
+    // Replies other than PONG, LOADING and MASTERDOWN don't tell the instance is working
+    fn record_ping(&mut self, target: &Target, reply: &RESPValue, now: u64) {
+        let valid = match reply {
+            RESPValue::SimpleString(s) => s == "PONG",
+            RESPValue::Error(e) => e.starts_with("LOADING") || e.starts_with("MASTERDOWN"),
+            _ => false,
+        };
+        if let Some(instance) = self.instance_mut(target).filter(|_| valid) {
+            instance.last_reply = now;
+        }
+    }
+
+    // Updates what's known of a master or replica from its INFO, returning what to do about it
+    fn apply_info(&mut self, target: &Target, info: &str, now: u64) -> Vec<Action> {
+        let fields: BTreeMap<&str, &str> = info
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .collect();
+        let run_id = fields.get("run_id").map(|id| id.to_string());
+        let role = fields.get("role").copied().unwrap_or_default().to_string();
+        match target {
+            Target::Master(name) => {
+                let master = match self.masters.get_mut(name) {
+                    Some(master) => master,
+                    None => return vec![],
+                };
+                master.instance.run_id = run_id;
+                master.role_reported = role;
+                let at = master.at();
+                let mut actions = vec![];
+                // Replicas are listed as slave0:ip=...,port=...,state=online
+                for (key, value) in &fields {
+                    if !key
+                        .strip_prefix("slave")
+                        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                    {
+                        continue;
+                    }
+                    let replica: BTreeMap<&str, &str> = value
+                        .split(',')
+                        .filter_map(|field| field.split_once('='))
+                        .collect();
+                    let port = replica.get("port").and_then(|p| p.parse::<u16>().ok());
+                    let (ip, port) = match (replica.get("ip"), port) {
+                        (Some(ip), Some(port)) => (ip, port),
+                        _ => continue,
+                    };
+                    let replica = Replica::new(ip, port, now);
+                    if let Entry::Vacant(entry) = master.replicas.entry(replica.instance.addr()) {
+                        actions.push(event("+slave", describe_replica(&replica, &at)));
+                        entry.insert(replica);
+                        self.dirty = true;
+                    }
+                }
+                actions
+            }
+            Target::Replica(name, addr) => {
+                let master = match self.masters.get_mut(name) {
+                    Some(master) => master,
+                    None => return vec![],
+                };
+                let (master_ip, master_port) = (master.instance.ip.clone(), master.instance.port);
+                let expected = Some((master_ip.clone(), master_port));
+                let sane =
+                    master.instance.sdown_since.is_none() && master.role_reported == "master";
+                let at = master.at();
+                let replica = match master.replicas.get_mut(addr) {
+                    Some(replica) => replica,
+                    None => return vec![],
+                };
+                let master_addr = fields
+                    .get("master_host")
+                    .zip(fields.get("master_port").and_then(|p| p.parse().ok()))
+                    .map(|(host, port)| (host.to_string(), port));
+                if role != replica.role_reported || master_addr != replica.master_addr {
+                    replica.reported_since = now;
+                }
+                replica.instance.run_id = run_id;
+                replica.role_reported = role;
+                replica.master_addr = master_addr;
+                replica.master_link_up = fields.get("master_link_status") == Some(&"up");
+                replica.master_link_down = fields
+                    .get("master_link_down_since_seconds")
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .map_or(0, |s| s * 1000);
+                replica.synced = fields.get("master_sync_in_progress") == Some(&"0");
+                replica.repl_offset = fields
+                    .get("slave_repl_offset")
+                    .and_then(|o| o.parse().ok())
+                    .unwrap_or(0);
+                replica.priority = fields
+                    .get("slave_priority")
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(100);
+                replica.info_refresh = now;
+
+                if let Some(failover) = &master.failover {
+                    if failover.step == Step::Promotion(addr.clone())
+                        && replica.role_reported == "master"
+                    {
+                        return self.promoted(name, addr, now);
+                    }
+                    return vec![];
+                }
+                // Replicas that don't replicate the master, like a failed master that came back
+                // after its replica was promoted, are pointed to it once they've been reporting
+                // the wrong master for a while and the master looks fine
+                if !sane
+                    || replica.instance.sdown_since.is_some()
+                    || (replica.role_reported == "slave" && replica.master_addr == expected)
+                    || now.saturating_sub(replica.reported_since) <= RECONFIGURE_WAIT
+                {
+                    return vec![];
+                }
+                replica.reported_since = now;
+                let name = if replica.role_reported == "master" {
+                    "+convert-to-slave"
+                } else {
+                    "+fix-slave-config"
+                };
+                vec![
+                    event(name, describe_replica(replica, &at)),
+                    Action::Send(
+                        replica.instance.ip.clone(),
+                        replica.instance.port,
+                        replicaof(&master_ip, master_port),
+                    ),
+                ]
+            }
+            Target::Sentinel(..) => vec![],
+        }
+    }
+
+    // The hello message to publish on an instance of the master, from the address the instance
+    // reaches this sentinel at
+    fn hello(&self, target: &Target, ip: &str, port: usize) -> Option<String> {
+        let master = self.masters.get(target.master())?;
+        Some(format!(
+            "{},{},{},{},{},{},{},{}",
+            ip,
+            port,
+            self.myid,
+            self.current_epoch,
+            master.name,
+            master.instance.ip,
+            master.instance.port,
+            master.config_epoch
+        ))
+    }
+
+    // Learns of the sentinel that sent the hello message, and of the master config it has when
+    // it's newer, like Redis' sentinelProcessHelloMessage
+    fn handle_hello(&mut self, message: &str, now: u64) -> Vec<Action> {
+        let fields: Vec<&str> = message.split(',').collect();
+        let (ip, port, id, epoch, name, master_ip, master_port, config_epoch) =
+            match fields.as_slice() {
+                [ip, port, id, epoch, name, master_ip, master_port, config_epoch] => {
+                    match (
+                        port.parse::<u16>(),
+                        epoch.parse::<u64>(),
+                        master_port.parse::<u16>(),
+                        config_epoch.parse::<u64>(),
+                    ) {
+                        (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) => (
+                            *ip,
+                            port,
+                            *id,
+                            epoch,
+                            *name,
+                            *master_ip,
+                            master_port,
+                            config_epoch,
+                        ),
+                        _ => return vec![],
+                    }
+                }
+                _ => return vec![],
+            };
+        if id == self.myid {
+            return vec![];
+        }
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return vec![],
+        };
+        let at = master.at();
+        let mut actions = vec![];
+        if !master.sentinels.contains_key(id) {
+            // A sentinel that restarted with a new id, or whose address another one took over,
+            // is only known by its new id
+            let duplicates: Vec<String> = master
+                .sentinels
+                .iter()
+                .filter(|(_, peer)| peer.instance.ip == ip && peer.instance.port == port)
+                .map(|(id, _)| id.clone())
+                .collect();
+            for duplicate in duplicates {
+                let peer = master.sentinels.remove(&duplicate).expect("listed above");
+                actions.push(event(
+                    "-dup-sentinel",
+                    describe_peer(&duplicate, &peer, &at),
+                ));
+            }
+            let peer = Peer::new(ip, port, id, now);
+            actions.push(event("+sentinel", describe_peer(id, &peer, &at)));
+            master.sentinels.insert(id.to_string(), peer);
+            self.dirty = true;
+        }
+        let peer = master.sentinels.get_mut(id).expect("added above");
+        peer.last_hello = now;
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            self.dirty = true;
+            actions.push(event("+new-epoch", epoch.to_string()));
+        }
+        if config_epoch > master.config_epoch {
+            master.config_epoch = config_epoch;
+            self.dirty = true;
+            if master.instance.ip != master_ip || master.instance.port != master_port {
+                let peer = &master.sentinels[id];
+                actions.push(event("+config-update-from", describe_peer(id, peer, &at)));
+                actions.extend(self.switch_master(name, master_ip, master_port, now));
+            }
+        }
+        actions
+    }
+
+    // The question to ask the other sentinels about a master that is down here, with the vote
+    // of this sentinel asked for when it's failing the master over
+    fn down_question(&self, name: &str) -> Option<Vec<String>> {
+        let master = self.masters.get(name)?;
+        master.instance.sdown_since?;
+        let candidate = match master.failover {
+            Some(_) => self.myid.clone(),
+            None => "*".to_string(),
+        };
+        Some(vec![
+            "SENTINEL".to_string(),
+            "IS-MASTER-DOWN-BY-ADDR".to_string(),
+            master.instance.ip.clone(),
+            master.instance.port.to_string(),
+            self.current_epoch.to_string(),
+            candidate,
+        ])
+    }
+
+    // Records the answer of a sentinel to the question above
+    fn record_down_reply(&mut self, target: &Target, reply: &RESPValue, now: u64) {
+        let (name, id) = match target {
+            Target::Sentinel(name, id) => (name, id),
+            _ => return,
+        };
+        let (down, leader, epoch) = match reply {
+            RESPValue::Array(Some(values)) => match values.as_slice() {
+                [RESPValue::Integer(down), RESPValue::BulkString(Some(leader)), RESPValue::Integer(epoch)] => {
+                    (*down == 1, leader.clone(), *epoch as u64)
+                }
+                _ => return,
+            },
+            _ => return,
+        };
+        let peer = match self
+            .masters
+            .get_mut(name)
+            .and_then(|master| master.sentinels.get_mut(id))
+        {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.master_down = down;
+        peer.down_reply = now;
+        if leader != "*" {
+            peer.leader = Some(leader);
+            peer.leader_epoch = epoch;
+        }
+    }
+
+    // Votes for the candidate to lead the failover of the master in the epoch, unless this
+    // sentinel voted in that epoch already, like Redis' sentinelVoteLeader. Returns the sentinel
+    // voted for, in the epoch of the vote.
+    fn vote(
+        &mut self,
+        name: &str,
+        epoch: u64,
+        candidate: &str,
+        now: u64,
+    ) -> (Option<String>, u64, Vec<Action>) {
+        let mut actions = vec![];
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            self.dirty = true;
+            actions.push(event("+new-epoch", epoch.to_string()));
+        }
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return (None, 0, actions),
+        };
+        if master.leader_epoch < epoch && self.current_epoch <= epoch {
+            master.leader = Some(candidate.to_string());
+            master.leader_epoch = self.current_epoch;
+            self.dirty = true;
+            actions.push(event(
+                "+vote-for-leader",
+                format!("{} {}", candidate, master.leader_epoch),
+            ));
+            // Having voted for another sentinel, this one doesn't try to fail the master over
+            // itself for a while
+            if candidate != self.myid {
+                master.failover_start = now + random_u64() % 1000;
+            }
+        }
+        (master.leader.clone(), master.leader_epoch, actions)
+    }
+
+    // The sentinel elected to lead the failover of the master in the epoch, which needs the
+    // votes of a majority of the sentinels and at least quorum of them, like Redis'
+    // sentinelGetLeader. This sentinel votes for the one with the most votes, itself when none
+    // has any.
+    fn leader(&mut self, name: &str, epoch: u64, now: u64) -> (Option<String>, Vec<Action>) {
+        let master = match self.masters.get(name) {
+            Some(master) => master,
+            None => return (None, vec![]),
+        };
+        let mut votes: BTreeMap<String, usize> = BTreeMap::new();
+        for peer in master.sentinels.values() {
+            if let Some(leader) = peer.leader.as_ref().filter(|_| peer.leader_epoch == epoch) {
+                *votes.entry(leader.clone()).or_default() += 1;
+            }
+        }
+        let voters = master.sentinels.len() + 1;
+        let quorum = master.quorum;
+        let candidate = votes
+            .iter()
+            .max_by_key(|(_, count)| **count)
+            .map_or(self.myid.clone(), |(id, _)| id.clone());
+        let (voted, voted_epoch, actions) = self.vote(name, epoch, &candidate, now);
+        if let Some(voted) = voted.filter(|_| voted_epoch == epoch) {
+            *votes.entry(voted).or_default() += 1;
+        }
+        let leader = votes
+            .into_iter()
+            .max_by_key(|(_, count)| *count)
+            .filter(|(_, count)| *count > voters / 2 && *count >= quorum)
+            .map(|(id, _)| id);
+        (leader, actions)
+    }
+
+    // Runs every 100 milliseconds, like Redis' sentinelHandleRedisInstance
+    fn tick(&mut self, now: u64) -> Vec<Action> {
+        let mut actions = vec![];
+        let names: Vec<String> = self.masters.keys().cloned().collect();
+        for name in names {
+            if let Some(master) = self.masters.get_mut(&name) {
+                actions.extend(check_down(master, now));
+            }
+            actions.extend(self.start_failover_if_needed(&name, now));
+            actions.extend(self.failover(&name, now));
+        }
+        actions
+    }
+
+    fn start_failover_if_needed(&mut self, name: &str, now: u64) -> Vec<Action> {
+        let master = match self.masters.get(name) {
+            Some(master) => master,
+            None => return vec![],
+        };
+        if master.odown_since.is_none()
+            || master.failover.is_some()
+            || now < master.failover_after
+            || now.saturating_sub(master.failover_start) < 2 * master.failover_timeout
+        {
+            return vec![];
+        }
+        self.start_failover(name, false, now)
+    }
+
+    // Starts the failover of the master in a new epoch
+    fn start_failover(&mut self, name: &str, forced: bool, now: u64) -> Vec<Action> {
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return vec![],
+        };
+        self.current_epoch += 1;
+        self.dirty = true;
+        master.failover = Some(Failover {
+            step: Step::Election,
+            epoch: self.current_epoch,
+            step_time: now,
+            forced,
+        });
+        // Sentinels that failed at the same time don't all try again at the same time
+        master.failover_start = now + random_u64() % 1000;
+        let mut actions = vec![
+            event("+new-epoch", self.current_epoch.to_string()),
+            event("+try-failover", master.describe()),
+        ];
+        actions.extend(
+            master
+                .sentinels
+                .keys()
+                .map(|id| Action::Ask(Target::Sentinel(name.to_string(), id.clone()))),
+        );
+        actions
+    }
+
+    // Moves the failover of the master along, like Redis' sentinelFailoverStateMachine
+    fn failover(&mut self, name: &str, now: u64) -> Vec<Action> {
+        let failover = match self.masters.get(name).and_then(|m| m.failover.clone()) {
+            Some(failover) => failover,
+            None => return vec![],
+        };
+        let mut actions = vec![];
+        let leader = match failover.step {
+            Step::Election if failover.forced => Some(self.myid.clone()),
+            Step::Election => {
+                let (leader, votes) = self.leader(name, failover.epoch, now);
+                actions.extend(votes);
+                leader
+            }
+            Step::Promotion(_) => None,
+        };
+        let master = self.masters.get_mut(name).expect("failing over");
+        let elapsed = now.saturating_sub(failover.step_time);
+        let at = master.at();
+        match failover.step {
+            Step::Election if leader.as_ref() != Some(&self.myid) => {
+                if elapsed > master.failover_timeout.min(10000) {
+                    master.failover = None;
+                    actions.push(event("-failover-abort-not-elected", master.describe()));
+                }
+            }
+            Step::Election => {
+                actions.push(event("+elected-leader", master.describe()));
+                let addr = match select_replica(master, now) {
+                    Some(addr) => addr,
+                    None => {
+                        master.failover = None;
+                        actions.push(event("-failover-abort-no-good-slave", master.describe()));
+                        return actions;
+                    }
+                };
+                let replica = &master.replicas[&addr];
+                let description = describe_replica(replica, &at);
+                actions.push(event("+selected-slave", description.clone()));
+                actions.push(Action::Send(
+                    replica.instance.ip.clone(),
+                    replica.instance.port,
+                    vec!["REPLICAOF".to_string(), "NO".to_string(), "ONE".to_string()],
+                ));
+                actions.push(event("+failover-state-wait-promotion", description));
+                master.failover = Some(Failover {
+                    step: Step::Promotion(addr),
+                    step_time: now,
+                    ..failover
+                });
+            }
+            Step::Promotion(_) => {
+                if elapsed > master.failover_timeout {
+                    master.failover = None;
+                    actions.push(event("-failover-abort-slave-timeout", master.describe()));
+                }
+            }
+        }
+        actions
+    }
+
+    // The replica being promoted reported it's a master: the master takes the epoch of the
+    // failover, and the other replicas are pointed to the new master
+    fn promoted(&mut self, name: &str, addr: &str, now: u64) -> Vec<Action> {
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return vec![],
+        };
+        let failover = match master.failover.take() {
+            Some(failover) => failover,
+            None => return vec![],
+        };
+        master.config_epoch = failover.epoch;
+        self.dirty = true;
+        let at = master.at();
+        let promoted = master.replicas[addr].instance.clone();
+        let mut actions = vec![
+            event(
+                "+promoted-slave",
+                describe_replica(&master.replicas[addr], &at),
+            ),
+            event("+failover-state-reconf-slaves", master.describe()),
+        ];
+        // Replicas that are down are reconfigured when they come back
+        for replica in master.replicas.values() {
+            if replica.instance.addr() == addr || replica.instance.sdown_since.is_some() {
+                continue;
+            }
+            actions.push(Action::Send(
+                replica.instance.ip.clone(),
+                replica.instance.port,
+                replicaof(&promoted.ip, promoted.port),
+            ));
+            actions.push(event("+slave-reconf-sent", describe_replica(replica, &at)));
+        }
+        actions.push(event("+failover-end", master.describe()));
+        actions.extend(self.switch_master(name, &promoted.ip, promoted.port, now));
+        actions
+    }
+
+    // Monitors the master at its new address, with the old master as one of its replicas, like
+    // Redis' sentinelResetMasterAndChangeAddress
+    fn switch_master(&mut self, name: &str, ip: &str, port: u16, now: u64) -> Vec<Action> {
+        let master = match self.masters.get_mut(name) {
+            Some(master) => master,
+            None => return vec![],
+        };
+        let old = master.instance.clone();
+        let mut replicas: Vec<(String, u16)> = master
+            .replicas
+            .values()
+            .map(|replica| (replica.instance.ip.clone(), replica.instance.port))
+            .filter(|(replica_ip, replica_port)| replica_ip != ip || *replica_port != port)
+            .collect();
+        if old.ip != ip || old.port != port {
+            replicas.push((old.ip.clone(), old.port));
+        }
+        master.instance = Instance::new(ip, port, now);
+        master.replicas = replicas
+            .into_iter()
+            .map(|(ip, port)| {
+                let replica = Replica::new(&ip, port, now);
+                (replica.instance.addr(), replica)
+            })
+            .collect();
+        master.role_reported = String::new();
+        master.odown_since = None;
+        master.failover = None;
+        master.failover_start = 0;
+        for peer in master.sentinels.values_mut() {
+            peer.master_down = false;
+        }
+        self.dirty = true;
+        vec![event(
+            "+switch-master",
+            format!("{} {} {} {} {}", name, old.ip, old.port, ip, port),
+        )]
+    }
+}
+
+impl Peer {
+    fn new(ip: &str, port: u16, id: &str, now: u64) -> Self {
+        let mut instance = Instance::new(ip, port, now);
+        instance.run_id = Some(id.to_string());
+        Self {
+            instance,
+            last_hello: now,
+            master_down: false,
+            down_reply: 0,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+}
+
+// Flags the instance as subjectively down when it hasn't answered for down_after, returning
+// whether that changed and how
+fn check_sdown(instance: &mut Instance, down_after: u64, now: u64) -> Option<bool> {
+    let down = now.saturating_sub(instance.last_reply) > down_after;
+    match (down, instance.sdown_since) {
+        (true, None) => instance.sdown_since = Some(now),
+        (false, Some(_)) => instance.sdown_since = None,
+        _ => return None,
+    }
+    Some(down)
+}
+
+// Flags the master and its instances that don't answer as subjectively down, and the master as
+// objectively down when quorum sentinels, this one included, agree that it is
+fn check_down(master: &mut Master, now: u64) -> Vec<Action> {
+    let mut actions = vec![];
+    let sign = |down| if down { "+sdown" } else { "-sdown" };
+    let down_after = master.down_after;
+    if let Some(down) = check_sdown(&mut master.instance, down_after, now) {
+        actions.push(event(sign(down), master.describe()));
+    }
+    let at = master.at();
+    for replica in master.replicas.values_mut() {
+        if let Some(down) = check_sdown(&mut replica.instance, down_after, now) {
+            actions.push(event(sign(down), describe_replica(replica, &at)));
+        }
+    }
+    for (id, peer) in master.sentinels.iter_mut() {
+        if let Some(down) = check_sdown(&mut peer.instance, down_after, now) {
+            actions.push(event(sign(down), describe_peer(id, peer, &at)));
+        }
+    }
+
+    let agreeing = 1 + master
+        .sentinels
+        .values()
+        .filter(|peer| {
+            peer.master_down && now.saturating_sub(peer.down_reply) <= DOWN_REPLY_VALIDITY
+        })
+        .count();
+    let odown = master.instance.sdown_since.is_some() && agreeing >= master.quorum;
+    match (odown, master.odown_since) {
+        (true, None) => {
+            master.odown_since = Some(now);
+            master.failover_after = now + random_u64() % 1000;
+            actions.push(event(
+                "+odown",
+                format!(
+                    "{} #quorum {}/{}",
+                    master.describe(),
+                    agreeing,
+                    master.quorum
+                ),
+            ));
+        }
+        (false, Some(_)) => {
+            master.odown_since = None;
+            actions.push(event("-odown", master.describe()));
+        }
+        _ => {}
+    }
+    actions
+}
+
+// The replica to promote, like Redis' sentinelSelectSlave: one that answers and reported its
+// INFO recently, with the lowest priority but never 0, then the most data replicated and then the
+// smallest run id. Replicas that never completed a sync have none of the keys of the master, and
+// like in Redis, those whose link went down well before the master did have stale ones.
+fn select_replica(master: &Master, now: u64) -> Option<String> {
+    let master_down = master
+        .instance
+        .sdown_since
+        .map_or(0, |since| now.saturating_sub(since));
+    let max_link_down = master_down + master.down_after * 10;
+    master
+        .replicas
+        .values()
+        .filter(|replica| {
+            replica.instance.sdown_since.is_none()
+                && replica.priority != 0
+                && replica.synced
+                && replica.master_link_down <= max_link_down
+                && now.saturating_sub(replica.instance.last_reply) <= INFO_VALIDITY
+                && now.saturating_sub(replica.info_refresh) <= INFO_VALIDITY
+        })
+        .min_by(|a, b| {
+            a.priority
+                .cmp(&b.priority)
+                .then_with(|| b.repl_offset.cmp(&a.repl_offset))
+                .then_with(|| match (&a.instance.run_id, &b.instance.run_id) {
+                    (Some(a), Some(b)) => a.cmp(b),
+                    (a, b) => b.is_some().cmp(&a.is_some()),
+                })
+        })
+        .map(|replica| replica.instance.addr())
+}
+
+fn master_flags(master: &Master) -> String {
+    let mut flags = vec!["master"];
+    if master.instance.sdown_since.is_some() {
+        flags.push("s_down");
+    }
+    if master.odown_since.is_some() {
+        flags.push("o_down");
+    }
+    if master.failover.is_some() {
+        flags.push("failover_in_progress");
+    }
+    flags.join(",")
+}
+
+// The state of the sentinel, None unless the server runs as one
+#[derive(Default)]
+pub struct Sentinel {
+    state: Mutex<Option<State>>,
+}
+
+impl Sentinel {
+    fn state(&self) -> Result<MutexGuard<'_, Option<State>>, String> {
+        self.state
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for sentinel {}", e))
+    }
+
+    pub fn is_enabled(&self) -> Result<bool, String> {
+        Ok(self.state()?.is_some())
+    }
+
+    // Runs the function on the state, None when the server isn't a sentinel or the lock failed
+    fn with_state<T>(&self, f: impl FnOnce(&mut State) -> T) -> Option<T> {
+        self.state().ok()?.as_mut().map(f)
+    }
+
+    // Enables sentinel mode with the `sentinel` lines of the config file
+    pub fn load(&self, path: Option<&Path>) -> Result<(), String> {
+        let config = match path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?,
+            None => String::new(),
+        };
+        *self.state()? = Some(State::from_config(&config, now_ms())?);
+        Ok(())
+    }
+
+    // Rewrites the `sentinel` lines of the config file if the state changed since they were last
+    // written. Without a config file, the state isn't saved.
+    pub fn save_if_dirty(&self, path: Option<&Path>) -> Result<(), String> {
+        let lines = match &mut *self.state()? {
+            Some(state) if state.dirty => {
+                state.dirty = false;
+                state.to_config()
+            }
+            _ => return Ok(()),
+        };
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let old = fs::read_to_string(path).unwrap_or_default();
+        let mut config: Vec<&str> = old
+            .lines()
+            .filter(|line| config::parse_line(line).is_none_or(|(name, _)| name != "sentinel"))
+            .collect();
+        if !config.contains(&config::REWRITE_SIGNATURE) {
+            config.push(config::REWRITE_SIGNATURE);
+        }
+        let contents: String = config
+            .into_iter()
+            .map(str::to_string)
+            .chain(lines)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        // Written to a temporary file first so that a failure can't leave a truncated file behind
+        let temporary = path.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&temporary, contents)
+            .and_then(|_| fs::rename(&temporary, path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&temporary);
+                format!(
+                    "Failed to save the sentinel config to '{}': {}",
+                    path.display(),
+                    e
+                )
+            })
+    }
+
+    // The lines of the sentinel section of INFO
+    pub fn info_lines(&self) -> Result<Vec<String>, String> {
+        let state = self.state()?;
+        let state = match &*state {
+            Some(state) => state,
+            None => return Ok(vec![]),
+        };
+        let mut lines = vec![
+            format!("sentinel_masters:{}", state.masters.len()),
+            "sentinel_tilt:0".to_string(),
+            "sentinel_running_scripts:0".to_string(),
+            "sentinel_scripts_queue_length:0".to_string(),
+        ];
+        for (i, master) in state.masters.values().enumerate() {
+            let status = if master.odown_since.is_some() {
+                "odown"
+            } else if master.instance.sdown_since.is_some() {
+                "sdown"
+            } else {
+                "ok"
+            };
+            lines.push(format!(
+                "master{}:name={},status={},address={},slaves={},sentinels={}",
+                i,
+                master.name,
+                status,
+                master.instance.addr(),
+                master.replicas.len(),
+                master.sentinels.len() + 1
+            ));
+        }
+        Ok(lines)
+    }
+}
+
+// Sentinels only serve the commands of SENTINEL_COMMANDS
+pub fn check(args: &[&str], server: &Server) -> Result<Option<RESPValue>, String> {
+    if !server.sentinel.is_enabled()?
+        || SENTINEL_COMMANDS.contains(&args[0].to_uppercase().as_str())
+    {
+        return Ok(None);
+    }
+    Ok(Some(unknown_command(args)))
+}
+
+fn unknown_command(args: &[&str]) -> RESPValue {
+    let rest: String = args[1..].iter().map(|arg| format!("'{}' ", arg)).collect();
+    RESPValue::error(format!(
+        "ERR unknown command '{}', with args beginning with: {}",
+        args[0], rest
+    ))
+}
+
+// Publishes the events and sends the commands
+fn run(server: &Arc<Server>, actions: Vec<Action>) -> Result<(), String> {
+    for action in actions {
+        match action {
+            Action::Event(name, message) => {
+                eprintln!("{} {}", name, message);
+                server.pubsub.publish(name, &message)?;
+            }
+            Action::Send(ip, port, command) => {
+                tokio::spawn(send(ip, port, command));
+            }
+            Action::Ask(target) => {
+                tokio::spawn(ask(Arc::clone(server), target));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn ask(server: Arc<Server>, target: Target) {
+    let question = server
+        .sentinel
+        .with_state(|state| {
+            let question = state.down_question(target.master())?;
+            let instance = state.instance_mut(&target)?;
+            Some((instance.ip.clone(), instance.port, question))
+        })
+        .flatten();
+    let (ip, port, question) = match question {
+        Some(question) => question,
+        None => return,
+    };
+    let connected = tokio::time::timeout(
+        replication::TIMEOUT,
+        TcpStream::connect((ip.as_str(), port)),
+    )
+    .await;
+    let reply = match connected {
+        Ok(Ok(mut stream)) => replication::request(&mut stream, &mut vec![], &question).await,
+        _ => None,
+    };
+    if let Some(reply) = reply {
+        server
+            .sentinel
+            .with_state(|state| state.record_down_reply(&target, &reply, now_ms()));
+    }
+}
+
+async fn send(ip: String, port: u16, command: Vec<String>) {
+    let connected = tokio::time::timeout(
+        replication::TIMEOUT,
+        TcpStream::connect((ip.as_str(), port)),
+    )
+    .await;
+    let reply = match connected {
+        Ok(Ok(mut stream)) => replication::request(&mut stream, &mut vec![], &command).await,
+        _ => None,
+    };
+    match reply {
+        Some(RESPValue::Error(e)) => eprintln!("{} failed on {}:{}: {}", command[0], ip, port, e),
+        Some(_) => {}
+        None => eprintln!("Failed to send {} to {}:{}", command[0], ip, port),
+    }
+}
+
+// Starts monitoring: links to the instances are opened as they're found, and the instances are
+// checked on every 100 milliseconds
+pub fn start(server: Arc<Server>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            if let Err(e) = cron(&server) {
+                eprintln!("{}", e);
+            }
+        }
+    });
+}
+
+fn cron(server: &Arc<Server>) -> Result<(), String> {
+    let now = now_ms();
+    let (actions, links) = match &mut *server.sentinel.state()? {
+        Some(state) => {
+            let mut links = vec![];
+            for link in state.targets() {
+                if state.links.insert(link.clone()) {
+                    links.push(link);
+                }
+            }
+            (state.tick(now), links)
+        }
+        None => return Ok(()),
+    };
+    for (target, ip, port) in links {
+        tokio::spawn(link(Arc::clone(server), target, ip, port));
+    }
+    run(server, actions)?;
+    server.sentinel.save_if_dirty(server.config_file.as_deref())
+}
+
+// Keeps a link to the instance while it's monitored at that address. Every second the instance
+// is pinged, masters and replicas are asked for INFO and every other second sent the hello
+// message, and other sentinels are asked about the master when it's down here.
+async fn link(server: Arc<Server>, target: Target, ip: String, port: u16) {
+    if !matches!(target, Target::Sentinel(..)) {
+        tokio::spawn(subscribe(
+            Arc::clone(&server),
+            target.clone(),
+            ip.clone(),
+            port,
+        ));
+    }
+    let wants = |server: &Server| {
+        server
+            .sentinel
+            .with_state(|state| state.wants(&target, &ip, port))
+            .unwrap_or(false)
+    };
+    let mut stream: Option<TcpStream> = None;
+    let mut buffer = vec![];
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut iteration: u64 = 0;
+    while wants(&server) {
+        interval.tick().await;
+        iteration += 1;
+        if stream.is_none() {
+            let connected = tokio::time::timeout(
+                replication::TIMEOUT,
+                TcpStream::connect((ip.as_str(), port)),
+            )
+            .await;
+            stream = connected.ok().and_then(Result::ok);
+            buffer.clear();
+        }
+        let up = match &mut stream {
+            Some(stream) => check_instance(&server, &target, stream, &mut buffer, iteration).await,
+            None => false,
+        };
+        if !up {
+            stream = None;
+        }
+    }
+    server.sentinel.with_state(|state| {
+        state.links.remove(&(target.clone(), ip.clone(), port));
+    });
+}
+
+// One round of checks on the instance, false when the link broke
+async fn check_instance(
+    server: &Arc<Server>,
+    target: &Target,
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    iteration: u64,
+) -> bool {
+    let command = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    let reply = match replication::request(stream, buffer, &command(&["PING"])).await {
+        Some(reply) => reply,
+        None => return false,
+    };
+    server
+        .sentinel
+        .with_state(|state| state.record_ping(target, &reply, now_ms()));
+    if let Target::Sentinel(name, _) = target {
+        let question = server
+            .sentinel
+            .with_state(|state| state.down_question(name))
+            .flatten();
+        if let Some(question) = question {
+            let reply = match replication::request(stream, buffer, &question).await {
+                Some(reply) => reply,
+                None => return false,
+            };
+            server
+                .sentinel
+                .with_state(|state| state.record_down_reply(target, &reply, now_ms()));
+        }
+        return true;
+    }
+
+    let info = match replication::request(stream, buffer, &command(&["INFO"])).await {
+        Some(RESPValue::BulkString(Some(info))) => info,
+        Some(_) => String::new(),
+        None => return false,
+    };
+    let actions = server
+        .sentinel
+        .with_state(|state| state.apply_info(target, &info, now_ms()))
+        .unwrap_or_default();
+    if let Err(e) = run(server, actions) {
+        eprintln!("{}", e);
+    }
+    if iteration.is_multiple_of(2) {
+        let ip = stream
+            .local_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default();
+        let port = server.config().map_or(DEFAULT_PORT, |config| config.port);
+        let hello = server
+            .sentinel
+            .with_state(|state| state.hello(target, &ip, port))
+            .flatten();
+        if let Some(hello) = hello {
+            let publish = command(&["PUBLISH", HELLO_CHANNEL, &hello]);
+            if replication::request(stream, buffer, &publish)
+                .await
+                .is_none()
+            {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// Receives the hello messages published on the instance while it's monitored at that address
+async fn subscribe(server: Arc<Server>, target: Target, ip: String, port: u16) {
+    let wants = |server: &Server| {
+        server
+            .sentinel
+            .with_state(|state| state.wants(&target, &ip, port))
+            .unwrap_or(false)
+    };
+    let command = vec!["SUBSCRIBE".to_string(), HELLO_CHANNEL.to_string()];
+    while wants(&server) {
+        let connected = tokio::time::timeout(
+            replication::TIMEOUT,
+            TcpStream::connect((ip.as_str(), port)),
+        )
+        .await;
+        let mut stream = match connected {
+            Ok(Ok(stream)) => stream,
+            _ => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let mut buffer = vec![];
+        if replication::request(&mut stream, &mut buffer, &command)
+            .await
+            .is_none()
+        {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        // Waits for messages a second at a time, to notice when the instance isn't monitored
+        // anymore
+        while wants(&server) {
+            let read = replication::read_reply(&mut stream, &mut buffer);
+            let message = match tokio::time::timeout(Duration::from_secs(1), read).await {
+                Ok(Some(RESPValue::Array(Some(values)))) => match values.as_slice() {
+                    [RESPValue::BulkString(Some(kind)), _, RESPValue::BulkString(Some(message))]
+                        if kind == "message" =>
+                    {
+                        message.clone()
+                    }
+                    _ => continue,
+                },
+                Ok(Some(_)) | Err(_) => continue,
+                Ok(None) => break,
+            };
+            let actions = server
+                .sentinel
+                .with_state(|state| state.handle_hello(&message, now_ms()))
+                .unwrap_or_default();
+            if let Err(e) = run(&server, actions) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+}
+
+fn bulk(s: &str) -> RESPValue {
+    RESPValue::bulk_string(Some(s.to_string()))
+}
+
+// Fields replied as a map, every value a string like in Redis
+fn fields_map(fields: Vec<(&str, String)>) -> RESPValue {
+    RESPValue::Map(
+        fields
+            .into_iter()
+            .map(|(name, value)| (bulk(name), RESPValue::bulk_string(Some(value))))
+            .collect(),
+    )
+}
+
+fn master_fields(master: &Master, now: u64) -> RESPValue {
+    let instance = &master.instance;
+    let mut fields = vec![
+        ("name", master.name.clone()),
+        ("ip", instance.ip.clone()),
+        ("port", instance.port.to_string()),
+        ("runid", instance.run_id.clone().unwrap_or_default()),
+        ("flags", master_flags(master)),
+        (
+            "last-ok-ping-reply",
+            now.saturating_sub(instance.last_reply).to_string(),
+        ),
+        ("down-after-milliseconds", master.down_after.to_string()),
+        ("role-reported", master.role_reported.clone()),
+        ("config-epoch", master.config_epoch.to_string()),
+        ("num-slaves", master.replicas.len().to_string()),
+        ("num-other-sentinels", master.sentinels.len().to_string()),
+        ("quorum", master.quorum.to_string()),
+        ("failover-timeout", master.failover_timeout.to_string()),
+    ];
+    if let Some(since) = instance.sdown_since {
+        fields.push(("s-down-time", now.saturating_sub(since).to_string()));
+    }
+    if let Some(since) = master.odown_since {
+        fields.push(("o-down-time", now.saturating_sub(since).to_string()));
+    }
+    if let Some(failover) = &master.failover {
+        let step = match failover.step {
+            Step::Election => "wait_start",
+            Step::Promotion(_) => "wait_promotion",
+        };
+        fields.push(("failover-state", step.to_string()));
+    }
+    fields_map(fields)
+}
+
+fn replica_fields(replica: &Replica, master: &Master, now: u64) -> RESPValue {
+    let instance = &replica.instance;
+    let mut flags = vec!["slave"];
+    if instance.sdown_since.is_some() {
+        flags.push("s_down");
+    }
+    if matches!(&master.failover, Some(f) if f.step == Step::Promotion(instance.addr())) {
+        flags.push("promoted");
+    }
+    let (master_host, master_port) = replica
+        .master_addr
+        .clone()
+        .map_or((String::from("?"), String::from("0")), |(host, port)| {
+            (host, port.to_string())
+        });
+    fields_map(vec![
+        ("name", instance.addr()),
+        ("ip", instance.ip.clone()),
+        ("port", instance.port.to_string()),
+        ("runid", instance.run_id.clone().unwrap_or_default()),
+        ("flags", flags.join(",")),
+        (
+            "last-ok-ping-reply",
+            now.saturating_sub(instance.last_reply).to_string(),
+        ),
+        ("down-after-milliseconds", master.down_after.to_string()),
+        (
+            "info-refresh",
+            now.saturating_sub(replica.info_refresh).to_string(),
+        ),
+        ("role-reported", replica.role_reported.clone()),
+        (
+            "master-link-status",
+            if replica.master_link_up { "ok" } else { "err" }.to_string(),
+        ),
+        ("master-host", master_host),
+        ("master-port", master_port),
+        ("slave-priority", replica.priority.to_string()),
+        ("slave-repl-offset", replica.repl_offset.to_string()),
+    ])
+}
+
+fn peer_fields(id: &str, peer: &Peer, master: &Master, now: u64) -> RESPValue {
+    let instance = &peer.instance;
+    let mut flags = vec!["sentinel"];
+    if instance.sdown_since.is_some() {
+        flags.push("s_down");
+    }
+    if peer.master_down {
+        flags.push("master_down");
+    }
+    fields_map(vec![
+        ("name", id.to_string()),
+        ("ip", instance.ip.clone()),
+        ("port", instance.port.to_string()),
+        ("runid", id.to_string()),
+        ("flags", flags.join(",")),
+        (
+            "last-ok-ping-reply",
+            now.saturating_sub(instance.last_reply).to_string(),
+        ),
+        ("down-after-milliseconds", master.down_after.to_string()),
+        (
+            "last-hello-message",
+            now.saturating_sub(peer.last_hello).to_string(),
+        ),
+        (
+            "voted-leader",
+            peer.leader.clone().unwrap_or("?".to_string()),
+        ),
+        ("voted-leader-epoch", peer.leader_epoch.to_string()),
+    ])
+}
+
+pub fn sentinel_command(args: &[BulkString], server: &Arc<Server>) -> Result<RESPValue, String> {
+    let args = match Args::new("sentinel", args).rest() {
+        Ok(args) => args,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut sentinel = server.sentinel.state()?;
+    let state = match &mut *sentinel {
+        Some(state) => state,
+        None => {
+            let args: Vec<&str> = std::iter::once("sentinel").chain(args).collect();
+            return Ok(unknown_command(&args));
+        }
+    };
+    let (subcommand, args) = match args.split_first() {
+        Some((subcommand, args)) => (subcommand.to_uppercase(), args),
+        None => return Ok(wrong_number_of_arguments("sentinel")),
+    };
+    let now = now_ms();
+    let ok = || RESPValue::simple_string("OK".to_string());
+    let no_such_master = || {
+        Ok(RESPValue::error(
+            "ERR No such master with that name".to_string(),
+        ))
+    };
+    let mut actions = vec![];
+    let reply = match (subcommand.as_str(), args) {
+        ("MYID", []) => bulk(&state.myid),
+        ("MASTERS", []) => RESPValue::Array(Some(
+            state
+                .masters
+                .values()
+                .map(|master| master_fields(master, now))
+                .collect(),
+        )),
+        ("MASTER", [name]) => match state.masters.get(*name) {
+            Some(master) => master_fields(master, now),
+            None => return no_such_master(),
+        },
+        ("REPLICAS", [name]) | ("SLAVES", [name]) => match state.masters.get(*name) {
+            Some(master) => RESPValue::Array(Some(
+                master
+                    .replicas
+                    .values()
+                    .map(|replica| replica_fields(replica, master, now))
+                    .collect(),
+            )),
+            None => return no_such_master(),
+        },
+        ("SENTINELS", [name]) => match state.masters.get(*name) {
+            Some(master) => RESPValue::Array(Some(
+                master
+                    .sentinels
+                    .iter()
+                    .map(|(id, peer)| peer_fields(id, peer, master, now))
+                    .collect(),
+            )),
+            None => return no_such_master(),
+        },
+        ("GET-MASTER-ADDR-BY-NAME", [name]) => match state.masters.get(*name) {
+            Some(master) => RESPValue::Array(Some(vec![
+                bulk(&master.instance.ip),
+                bulk(&master.instance.port.to_string()),
+            ])),
+            None => RESPValue::Array(None),
+        },
+        ("IS-MASTER-DOWN-BY-ADDR", [ip, port, epoch, candidate]) => {
+            let (port, epoch) = match (port.parse::<u16>(), epoch.parse::<u64>()) {
+                (Ok(port), Ok(epoch)) => (port, epoch),
+                _ => return Ok(not_an_integer()),
+            };
+            let name = state
+                .masters
+                .values()
+                .find(|master| master.instance.ip == *ip && master.instance.port == port)
+                .map(|master| master.name.clone());
+            let (down, leader, leader_epoch) = match name {
+                Some(name) => {
+                    let down = state.masters[&name].instance.sdown_since.is_some();
+                    let (leader, leader_epoch) = if *candidate == "*" {
+                        (None, 0)
+                    } else {
+                        let (leader, leader_epoch, votes) =
+                            state.vote(&name, epoch, candidate, now);
+                        actions.extend(votes);
+                        (leader, leader_epoch)
+                    };
+                    (down, leader, leader_epoch)
+                }
+                None => (false, None, 0),
+            };
+            RESPValue::Array(Some(vec![
+                RESPValue::integer(down as i64),
+                bulk(leader.as_deref().unwrap_or("*")),
+                RESPValue::integer(leader_epoch as i64),
+            ]))
+        }
+        ("FAILOVER", [name]) => {
+            let master = match state.masters.get(*name) {
+                Some(master) => master,
+                None => return no_such_master(),
+            };
+            if master.failover.is_some() {
+                return Ok(RESPValue::error(
+                    "INPROG Failover already in progress".to_string(),
+                ));
+            }
+            if select_replica(master, now).is_none() {
+                return Ok(RESPValue::error(
+                    "NOGOODSLAVE No suitable replica to promote".to_string(),
+                ));
+            }
+            actions.extend(state.start_failover(name, true, now));
+            ok()
+        }
+        ("MONITOR", [name, ip, port, quorum]) => {
+            if state.masters.contains_key(*name) {
+                return Ok(RESPValue::error("ERR Duplicated master name".to_string()));
+            }
+            let port = match port.parse::<u16>() {
+                Ok(port) if port > 0 => port,
+                _ => return Ok(RESPValue::error("ERR Invalid port number".to_string())),
+            };
+            let quorum = match quorum.parse::<usize>() {
+                Ok(quorum) if quorum > 0 => quorum,
+                Ok(_) => {
+                    return Ok(RESPValue::error(
+                        "ERR Quorum must be 1 or greater.".to_string(),
+                    ))
+                }
+                Err(_) => return Ok(not_an_integer()),
+            };
+            let master = Master::new(name, ip, port, quorum, now);
+            actions.push(event(
+                "+monitor",
+                format!("{} quorum {}", master.describe(), quorum),
+            ));
+            state.masters.insert(name.to_string(), master);
+            state.dirty = true;
+            ok()
+        }
+        ("REMOVE", [name]) => match state.masters.remove(*name) {
+            Some(master) => {
+                actions.push(event("-monitor", master.describe()));
+                state.dirty = true;
+                ok()
+            }
+            None => return no_such_master(),
+        },
+        ("SET", [name, pairs @ ..]) if !pairs.is_empty() && pairs.len().is_multiple_of(2) => {
+            let master = match state.masters.get_mut(*name) {
+                Some(master) => master,
+                None => return no_such_master(),
+            };
+            // Every value is checked before any is applied
+            let mut updated = master.clone();
+            for pair in pairs.chunks(2) {
+                let value = pair[1].parse::<u64>().ok().filter(|v| *v > 0);
+                let invalid = match (pair[0].to_lowercase().as_str(), value) {
+                    ("down-after-milliseconds", Some(ms)) => {
+                        updated.down_after = ms;
+                        None
+                    }
+                    ("failover-timeout", Some(ms)) => {
+                        updated.failover_timeout = ms;
+                        None
+                    }
+                    ("quorum", Some(quorum)) => {
+                        updated.quorum = quorum as usize;
+                        None
+                    }
+                    ("down-after-milliseconds" | "failover-timeout" | "quorum", None) => {
+                        Some(pair[1])
+                    }
+                    _ => Some(pair[0]),
+                };
+                if let Some(invalid) = invalid {
+                    return Ok(RESPValue::error(format!(
+                        "ERR Invalid argument '{}' for SENTINEL SET '{}'",
+                        invalid, name
+                    )));
+                }
+                actions.push(event(
+                    "+set",
+                    format!("{} {} {}", master.describe(), pair[0], pair[1]),
+                ));
+            }
+            *master = updated;
+            state.dirty = true;
+            ok()
+        }
+        ("RESET", [pattern]) => {
+            let mut reset = 0;
+            for master in state.masters.values_mut() {
+                if !glob::matches(pattern, &master.name, false) {
+                    continue;
+                }
+                let mut fresh = Master::new(
+                    &master.name,
+                    &master.instance.ip,
+                    master.instance.port,
+                    master.quorum,
+                    now,
+                );
+                fresh.down_after = master.down_after;
+                fresh.failover_timeout = master.failover_timeout;
+                fresh.config_epoch = master.config_epoch;
+                fresh.leader_epoch = master.leader_epoch;
+                actions.push(event("+reset-master", master.describe()));
+                *master = fresh;
+                reset += 1;
+            }
+            state.dirty |= reset > 0;
+            RESPValue::integer(reset)
+        }
+        ("CKQUORUM", [name]) => {
+            let master = match state.masters.get(*name) {
+                Some(master) => master,
+                None => return no_such_master(),
+            };
+            let usable = 1 + master
+                .sentinels
+                .values()
+                .filter(|peer| peer.instance.sdown_since.is_none())
+                .count();
+            let voters = master.sentinels.len() + 1;
+            if usable < master.quorum {
+                RESPValue::error(format!(
+                    "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the \
+                     specified quorum for this master",
+                    usable
+                ))
+            } else if usable < voters / 2 + 1 {
+                RESPValue::error(format!(
+                    "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the \
+                     majority and authorize a failover",
+                    usable
+                ))
+            } else {
+                RESPValue::simple_string(format!(
+                    "OK {} usable Sentinels. Quorum and failover authorization can be reached",
+                    usable
+                ))
+            }
+        }
+        (
+            "MYID"
+            | "MASTERS"
+            | "MASTER"
+            | "REPLICAS"
+            | "SLAVES"
+            | "SENTINELS"
+            | "GET-MASTER-ADDR-BY-NAME"
+            | "IS-MASTER-DOWN-BY-ADDR"
+            | "FAILOVER"
+            | "MONITOR"
+            | "REMOVE"
+            | "SET"
+            | "RESET"
+            | "CKQUORUM",
+            _,
+        ) => {
+            return Ok(wrong_number_of_arguments(&format!(
+                "sentinel|{}",
+                subcommand.to_lowercase()
+            )))
+        }
+        (s, _) => {
+            return Ok(RESPValue::error(format!(
+                "ERR unknown subcommand '{}'. Try SENTINEL HELP.",
+                s.to_lowercase()
+            )))
+        }
+    };
+    drop(sentinel);
+    run(server, actions)?;
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A sentinel monitoring mymaster at 127.0.0.1:6379 with a quorum of 2, with a replica and
+    // another sentinel
+    fn sentinel(now: u64) -> State {
+        let config = "sentinel myid 0000000000000000000000000000000000000001\n\
+                      sentinel monitor mymaster 127.0.0.1 6379 2\n\
+                      sentinel down-after-milliseconds mymaster 1000\n\
+                      sentinel failover-timeout mymaster 5000\n\
+                      sentinel known-replica mymaster 127.0.0.1 6380\n\
+                      sentinel known-sentinel mymaster 127.0.0.1 26380 \
+                      0000000000000000000000000000000000000002\n";
+        State::from_config(config, now).unwrap()
+    }
+
+    fn master() -> Target {
+        Target::Master("mymaster".to_string())
+    }
+
+    fn replica() -> Target {
+        Target::Replica("mymaster".to_string(), "127.0.0.1:6380".to_string())
+    }
+
+    fn peer() -> Target {
+        Target::Sentinel(
+            "mymaster".to_string(),
+            "0000000000000000000000000000000000000002".to_string(),
+        )
+    }
+
+    fn events(actions: &[Action]) -> Vec<&str> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Event(name, _) => Some(*name),
+                Action::Send(..) | Action::Ask(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_config() {
+        let state = sentinel(0);
+        let lines = state.to_config();
+        assert!(lines.contains(&"sentinel monitor mymaster 127.0.0.1 6379 2".to_string()));
+        assert!(lines.contains(&"sentinel failover-timeout mymaster 5000".to_string()));
+        let reloaded = State::from_config(&lines.join("\n"), 0).unwrap();
+        assert_eq!(reloaded.myid, state.myid);
+        assert_eq!(reloaded.masters, state.masters);
+        assert!(State::from_config("sentinel down-after-milliseconds other 10", 0).is_err());
+        assert!(State::from_config("sentinel monitor m 127.0.0.1 6379 0", 0).is_err());
+    }
+
+    #[test]
+    fn test_info() {
+        let mut state = sentinel(0);
+        let info = "# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+                    slave0:ip=127.0.0.1,port=6380,state=online,offset=0,lag=0\r\n\
+                    slave1:ip=127.0.0.1,port=6381,state=online,offset=0,lag=0\r\n";
+        let actions = state.apply_info(&master(), info, 0);
+        assert_eq!(
+            actions,
+            vec![event(
+                "+slave",
+                "slave 127.0.0.1:6381 127.0.0.1 6381 @ mymaster 127.0.0.1 6379".to_string()
+            )]
+        );
+        assert_eq!(state.masters["mymaster"].replicas.len(), 2);
+
+        // A replica that keeps reporting another master is pointed back to this one
+        state.apply_info(&master(), "role:master\r\n", 0);
+        let info = "role:slave\r\nmaster_host:10.0.0.1\r\nmaster_port:6379\r\n\
+                    master_link_status:up\r\nslave_priority:10\r\n";
+        assert!(state.apply_info(&replica(), info, 1000).is_empty());
+        assert_eq!(
+            state.masters["mymaster"].replicas["127.0.0.1:6380"].priority,
+            10
+        );
+        let actions = state.apply_info(&replica(), info, 1000 + RECONFIGURE_WAIT + 1);
+        assert_eq!(events(&actions), vec!["+fix-slave-config"]);
+        assert_eq!(
+            actions[1],
+            Action::Send("127.0.0.1".to_string(), 6380, replicaof("127.0.0.1", 6379))
+        );
+    }
+
+    #[test]
+    fn test_hello() {
+        let mut state = sentinel(0);
+        let hello = state.hello(&master(), "127.0.0.1", 26379).unwrap();
+        assert_eq!(
+            hello,
+            "127.0.0.1,26379,0000000000000000000000000000000000000001,0,mymaster,127.0.0.1,6379,0"
+        );
+        // Its own messages are ignored
+        assert!(state.handle_hello(&hello, 0).is_empty());
+
+        // A new sentinel at the address of a known one replaces it, and a newer config of the
+        // master is adopted
+        let hello = "127.0.0.1,26380,0000000000000000000000000000000000000003,3,mymaster,\
+                     127.0.0.1,6380,3";
+        let actions = state.handle_hello(hello, 0);
+        assert_eq!(
+            events(&actions),
+            vec![
+                "-dup-sentinel",
+                "+sentinel",
+                "+new-epoch",
+                "+config-update-from",
+                "+switch-master"
+            ]
+        );
+        let master = &state.masters["mymaster"];
+        assert_eq!(master.instance.addr(), "127.0.0.1:6380");
+        assert_eq!(master.config_epoch, 3);
+        assert_eq!(
+            master.replicas.keys().collect::<Vec<_>>(),
+            vec!["127.0.0.1:6379"]
+        );
+        assert_eq!(state.current_epoch, 3);
+    }
+
+    #[test]
+    fn test_vote() {
+        let mut state = sentinel(0);
+        let (leader, epoch, _) = state.vote("mymaster", 1, "a", 0);
+        assert_eq!((leader.as_deref(), epoch), (Some("a"), 1));
+        // One vote per epoch
+        let (leader, epoch, _) = state.vote("mymaster", 1, "b", 0);
+        assert_eq!((leader.as_deref(), epoch), (Some("a"), 1));
+        let (leader, epoch, _) = state.vote("mymaster", 2, "b", 0);
+        assert_eq!((leader.as_deref(), epoch), (Some("b"), 2));
+        assert_eq!(state.current_epoch, 2);
+    }
+
+    #[test]
+    fn test_failover() {
+        // Far enough from 0 that the last failover isn't recent
+        let t = 1_000_000;
+        let mut state = sentinel(t);
+        state.apply_info(&master(), "role:master\r\n", t);
+        let info = "run_id:r1\r\nrole:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\n\
+                    master_sync_in_progress:0\r\n";
+        state.record_ping(
+            &replica(),
+            &RESPValue::SimpleString("PONG".to_string()),
+            t + 1500,
+        );
+        state.apply_info(&replica(), info, t + 1500);
+        state.record_ping(
+            &peer(),
+            &RESPValue::SimpleString("PONG".to_string()),
+            t + 1500,
+        );
+
+        // Down here, but the other sentinel has to agree for the quorum of 2
+        assert_eq!(events(&state.tick(t + 1500)), vec!["+sdown"]);
+        assert!(state.down_question("mymaster").is_some());
+        let agrees = RESPValue::Array(Some(vec![
+            RESPValue::integer(1),
+            bulk("*"),
+            RESPValue::integer(0),
+        ]));
+        state.record_down_reply(&peer(), &agrees, t + 1600);
+        assert_eq!(events(&state.tick(t + 1600)), vec!["+odown"]);
+
+        // The failover starts after a random delay, asking the other sentinel for its vote
+        let pong = RESPValue::SimpleString("PONG".to_string());
+        state.record_ping(&replica(), &pong, t + 2600);
+        state.record_ping(&peer(), &pong, t + 2600);
+        let actions = state.tick(t + 2600);
+        assert_eq!(
+            events(&actions),
+            vec!["+new-epoch", "+try-failover", "+vote-for-leader"]
+        );
+        assert!(actions.contains(&Action::Ask(peer())));
+        let question = state.down_question("mymaster").unwrap();
+        assert_eq!(question[4], "1");
+        assert_eq!(question[5], state.myid);
+
+        // Elected once the other sentinel votes for this one
+        let votes = RESPValue::Array(Some(vec![
+            RESPValue::integer(1),
+            bulk(&state.myid),
+            RESPValue::integer(1),
+        ]));
+        state.record_down_reply(&peer(), &votes, t + 2700);
+        let actions = state.tick(t + 2700);
+        assert_eq!(
+            events(&actions),
+            vec![
+                "+elected-leader",
+                "+selected-slave",
+                "+failover-state-wait-promotion"
+            ]
+        );
+        assert!(actions.contains(&Action::Send(
+            "127.0.0.1".to_string(),
+            6380,
+            vec!["REPLICAOF".to_string(), "NO".to_string(), "ONE".to_string()]
+        )));
+
+        let actions = state.apply_info(&replica(), "run_id:r1\r\nrole:master\r\n", t + 2800);
+        assert_eq!(
+            events(&actions),
+            vec![
+                "+promoted-slave",
+                "+failover-state-reconf-slaves",
+                "+failover-end",
+                "+switch-master"
+            ]
+        );
+        let master = &state.masters["mymaster"];
+        assert_eq!(master.instance.addr(), "127.0.0.1:6380");
+        assert_eq!(master.config_epoch, 1);
+        assert!(master.failover.is_none());
+        assert!(master.replicas.contains_key("127.0.0.1:6379"));
+    }
+
+    #[test]
+    fn test_select_replica() {
+        let mut master = Master::new("m", "127.0.0.1", 6379, 1, 0);
+        for (port, priority, run_id) in [(6380, 0, "a"), (6381, 10, "c"), (6382, 10, "b")] {
+            let mut replica = Replica::new("127.0.0.1", port, 0);
+            replica.priority = priority;
+            replica.instance.run_id = Some(run_id.to_string());
+            replica.synced = true;
+            master.replicas.insert(replica.instance.addr(), replica);
+        }
+        assert_eq!(
+            select_replica(&master, 0).as_deref(),
+            Some("127.0.0.1:6382")
+        );
+        // The one with the most data comes first
+        let replica = master.replicas.get_mut("127.0.0.1:6381").unwrap();
+        replica.repl_offset = 100;
+        assert_eq!(
+            select_replica(&master, 0).as_deref(),
+            Some("127.0.0.1:6381")
+        );
+        // Replicas without the data of the master are never promoted
+        master.replicas.get_mut("127.0.0.1:6381").unwrap().synced = false;
+        let replica = master.replicas.get_mut("127.0.0.1:6382").unwrap();
+        replica.master_link_down = master.down_after * 10 + 1;
+        assert_eq!(select_replica(&master, 0), None);
+        // Replicas whose INFO is too old aren't promoted
+        assert_eq!(select_replica(&master, INFO_VALIDITY + 1), None);
+    }
+}
@@ -0,0 +1,195 @@
+// The log of commands that took longer than slowlog-log-slower-than, and the SLOWLOG command
+use crate::strings::wrong_number_of_arguments;
+use crate::value;
+use crate::Server;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Same limits as Redis, so that huge commands don't take up the memory of the log
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LENGTH: usize = 128;
+const DEFAULT_COUNT: usize = 10;
+
+struct Entry {
+    id: u64,
+    // Unix time the command was logged at, in seconds
+    timestamp: u64,
+    duration: Duration,
+    args: Vec<String>,
+    addr: String,
+    name: String,
+}
+
+#[derive(Default)]
+struct Entries {
+    // Newest first
+    entries: VecDeque<Entry>,
+    next_id: u64,
+}
+
+#[derive(Default)]
+pub struct Slowlog {
+    entries: Mutex<Entries>,
+}
+
+// Keeps at most MAX_ARGS arguments of at most MAX_ARG_LENGTH characters, saying what was left out
+fn trim_args(args: &[&str]) -> Vec<String> {
+    let kept = if args.len() > MAX_ARGS {
+        MAX_ARGS - 1
+    } else {
+        args.len()
+    };
+    let mut trimmed: Vec<String> = args[..kept]
+        .iter()
+        .map(|arg| match arg.char_indices().nth(MAX_ARG_LENGTH) {
+            Some((end, _)) => format!(
+                "{}... ({} more bytes)",
+                &arg[..end],
+                arg.chars().count() - MAX_ARG_LENGTH
+            ),
+            None => arg.to_string(),
+        })
+        .collect();
+    if kept < args.len() {
+        trimmed.push(format!("... ({} more arguments)", args.len() - kept));
+    }
+    trimmed
+}
+
+impl Slowlog {
+    fn entries(&self) -> Result<MutexGuard<'_, Entries>, String> {
+        self.entries
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for slowlog {}", e))
+    }
+
+    // Logs the command if it's slow enough, a negative threshold disables the log
+    pub fn record(
+        &self,
+        args: &[&str],
+        duration: Duration,
+        (addr, name): (String, String),
+        threshold_micros: i64,
+        max_len: usize,
+    ) -> Result<(), String> {
+        if threshold_micros < 0 || duration.as_micros() < threshold_micros as u128 {
+            return Ok(());
+        }
+        let mut log = self.entries()?;
+        let id = log.next_id;
+        log.next_id += 1;
+        log.entries.push_front(Entry {
+            id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration,
+            args: trim_args(args),
+            addr,
+            name,
+        });
+        log.entries.truncate(max_len);
+        Ok(())
+    }
+}
+
+pub fn slowlog_command(args: &[BulkString], server: &Server) -> Result<RESPValue, String> {
+    let mut args = Args::new("slowlog", args);
+    let (subcommand, args) = match args.next_str().and_then(|s| Ok((s, args.rest()?))) {
+        Ok((subcommand, args)) => (subcommand.to_uppercase(), args),
+        Err(e) => return Ok(e.into()),
+    };
+    match (subcommand.as_str(), &args[..]) {
+        ("GET", []) => slowlog_get(server, DEFAULT_COUNT),
+        ("GET", [count]) => match value::parse_integer(count) {
+            Some(-1) => slowlog_get(server, usize::MAX),
+            Some(count) if count >= 0 => slowlog_get(server, count as usize),
+            _ => Ok(RESPValue::error(
+                "ERR count should be greater than or equal to -1".to_string(),
+            )),
+        },
+        ("LEN", []) => Ok(RESPValue::integer(
+            server.slowlog.entries()?.entries.len() as i64
+        )),
+        ("RESET", []) => {
+            server.slowlog.entries()?.entries.clear();
+            Ok(RESPValue::simple_string("OK".to_string()))
+        }
+        ("GET", _) | ("LEN", _) | ("RESET", _) => Ok(wrong_number_of_arguments(&format!(
+            "slowlog|{}",
+            subcommand.to_lowercase()
+        ))),
+        (s, _) => Ok(RESPValue::error(format!(
+            "ERR unknown subcommand '{}'. Try SLOWLOG HELP.",
+            s.to_lowercase()
+        ))),
+    }
+}
+
+fn slowlog_get(server: &Server, count: usize) -> Result<RESPValue, String> {
+    let log = server.slowlog.entries()?;
+    let bulk = |s: &str| RESPValue::bulk_string(Some(s.to_string()));
+    Ok(RESPValue::Array(Some(
+        log.entries
+            .iter()
+            .take(count)
+            .map(|e| {
+                RESPValue::Array(Some(vec![
+                    RESPValue::integer(e.id as i64),
+                    RESPValue::integer(e.timestamp as i64),
+                    RESPValue::integer(e.duration.as_micros() as i64),
+                    RESPValue::Array(Some(e.args.iter().map(|a| bulk(a)).collect())),
+                    bulk(&e.addr),
+                    bulk(&e.name),
+                ]))
+            })
+            .collect(),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let slowlog = Slowlog::default();
+        let client = || ("127.0.0.1:1000".to_string(), String::new());
+        let fast = Duration::from_micros(5);
+        slowlog
+            .record(&["GET", "a"], fast, client(), 10, 2)
+            .unwrap();
+        slowlog
+            .record(&["GET", "a"], fast, client(), -1, 2)
+            .unwrap();
+        assert!(slowlog.entries().unwrap().entries.is_empty());
+        for i in 0..3 {
+            let key = i.to_string();
+            slowlog
+                .record(&["GET", &key], fast, client(), 0, 2)
+                .unwrap();
+        }
+        let log = slowlog.entries().unwrap();
+        let ids: Vec<u64> = log.entries.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert_eq!(log.entries[0].args, vec!["GET", "2"]);
+    }
+
+    #[test]
+    fn test_trim_args() {
+        let long = "x".repeat(130);
+        let args: Vec<&str> = std::iter::repeat_n(long.as_str(), 40).collect();
+        let trimmed = trim_args(&args);
+        assert_eq!(trimmed.len(), MAX_ARGS);
+        assert_eq!(trimmed[0], format!("{}... (2 more bytes)", "x".repeat(128)));
+        assert_eq!(trimmed[31], "... (9 more arguments)");
+    }
+}
@@ -0,0 +1,145 @@
+// Sorted sets: members ordered by score, then lexicographically
+use crate::dict::Keyspace;
+use crate::get_live_entry;
+use crate::memory::Access;
+use crate::value::Value;
+use crate::wrong_type;
+use redis_starter_rust::RESPValue;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+// Scores are never NaN, so a total order is safe
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // Adds the member or updates its score, returning the previous score
+    pub fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        let old_score = self.scores.insert(member.clone(), score);
+        if let Some(old_score) = old_score {
+            self.ordered.remove(&(Score(old_score), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old_score
+    }
+
+    // Members are stored twice, once in each index
+    pub fn memory_usage(&self) -> usize {
+        let per_member = size_of::<(String, f64)>() + size_of::<(Score, String)>();
+        self.scores
+            .keys()
+            .map(|member| per_member + 2 * member.len())
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        self.ordered.iter().map(|(s, m)| (m.as_str(), s.0))
+    }
+
+    // Members with min <= score < max, in order
+    pub fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = (&str, f64)> {
+        let range = if min < max {
+            Some((Score(min), String::new())..(Score(max), String::new()))
+        } else {
+            None
+        };
+        range
+            .into_iter()
+            .flat_map(move |range| self.ordered.range(range))
+            .map(|(s, m)| (m.as_str(), s.0))
+    }
+}
+
+// Returns the sorted set stored at a key, or WRONGTYPE if the key holds another type
+pub fn get_sorted_set<'a>(
+    t: &'a mut Keyspace,
+    key: &str,
+) -> Result<Option<&'a mut SortedSet>, RESPValue> {
+    match get_live_entry(t, key) {
+        Some((Value::SortedSet(set), _, _)) => Ok(Some(set)),
+        Some(_) => Err(wrong_type()),
+        None => Ok(None),
+    }
+}
+
+// Like get_sorted_set, creating an empty set if the key doesn't exist
+pub fn get_or_create_sorted_set<'a>(
+    t: &'a mut Keyspace,
+    key: &str,
+) -> Result<&'a mut SortedSet, RESPValue> {
+    if get_sorted_set(t, key)?.is_none() {
+        t.insert(
+            key.to_string(),
+            (Value::SortedSet(SortedSet::default()), None, Access::new()),
+        );
+    }
+    match t.get_mut(key) {
+        Some((Value::SortedSet(set), _, _)) => Ok(set),
+        _ => Err(wrong_type()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut set = SortedSet::default();
+        assert_eq!(set.insert("a".to_string(), 2.0), None);
+        assert_eq!(set.insert("b".to_string(), 1.0), None);
+        assert_eq!(set.insert("a".to_string(), 0.5), Some(2.0));
+        assert_eq!(set.score("a"), Some(0.5));
+        assert_eq!(
+            set.range_by_score(f64::NEG_INFINITY, f64::INFINITY)
+                .collect::<Vec<_>>(),
+            [("a", 0.5), ("b", 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let mut set = SortedSet::default();
+        for (member, score) in [("c", 2.0), ("a", 1.0), ("b", 1.0), ("d", 3.0)] {
+            set.insert(member.to_string(), score);
+        }
+        let members = |min, max| {
+            set.range_by_score(min, max)
+                .map(|(m, _)| m)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(members(1.0, 3.0), ["a", "b", "c"]);
+        assert_eq!(members(1.5, 10.0), ["c", "d"]);
+        assert!(members(3.0, 3.0).is_empty());
+    }
+}
@@ -0,0 +1,782 @@
+// Commands operating on string values
+use crate::dict::Keyspace;
+use crate::get_live_entry;
+use crate::memory::Access;
+use crate::not_an_integer;
+use crate::value;
+use crate::value::Value;
+use crate::write_table;
+use crate::wrong_type;
+use crate::Entry;
+use crate::Table;
+use redis_starter_rust::bytes_to_string;
+pub use redis_starter_rust::parse_float;
+use redis_starter_rust::string_to_bytes;
+use redis_starter_rust::ArgError;
+use redis_starter_rust::Args;
+use redis_starter_rust::BulkString;
+use redis_starter_rust::RESPValue;
+use std::mem::size_of;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+// Same limit as Redis' default proto-max-bulk-len
+pub const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+fn ok() -> RESPValue {
+    RESPValue::simple_string("OK".to_string())
+}
+
+pub fn syntax_error() -> RESPValue {
+    RESPValue::error("ERR syntax error".to_string())
+}
+
+pub fn wrong_number_of_arguments(command: &str) -> RESPValue {
+    RESPValue::error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+// Returns the entry of a key holding a string, or WRONGTYPE if it holds another type
+pub fn get_string_entry<'a>(
+    t: &'a mut Keyspace,
+    key: &str,
+) -> Result<Option<&'a mut Entry>, RESPValue> {
+    match get_live_entry(t, key) {
+        Some((value, _, _)) if !value.is_string() => Err(wrong_type()),
+        entry => Ok(entry),
+    }
+}
+
+pub fn get_string(t: &mut Keyspace, key: &str) -> Result<Option<String>, RESPValue> {
+    get_string_entry(t, key).map(|entry| entry.map(|(value, _, _)| value.to_string()))
+}
+
+// Replaces the value and expiry of a key
+pub fn set_string(t: &mut Keyspace, key: &str, value: String, ttl: Option<Duration>) {
+    t.insert(
+        key.to_string(),
+        (
+            Value::from_string(value),
+            ttl.map(|d| (Instant::now(), d)),
+            Access::new(),
+        ),
+    );
+}
+
+// Parses the argument of EX/PX/EXAT/PXAT into the time left until the key expires.
+// Returns a zero duration for times in the past.
+fn parse_expire_time(command: &str, unit: &str, arg: &str) -> Result<Duration, RESPValue> {
+    let invalid = || {
+        RESPValue::error(format!(
+            "ERR invalid expire time in '{}' command",
+            command.to_lowercase()
+        ))
+    };
+    let n = value::parse_integer(arg).ok_or_else(not_an_integer)?;
+    if n <= 0 {
+        return Err(invalid());
+    }
+    let millis = match unit {
+        "EX" | "EXAT" => n.checked_mul(1000).ok_or_else(invalid)?,
+        _ => n,
+    } as u64;
+    Ok(match unit {
+        "EXAT" | "PXAT" => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            Duration::from_millis(millis).saturating_sub(now)
+        }
+        _ => Duration::from_millis(millis),
+    })
+}
+
+// Handles INCR, DECR, INCRBY and DECRBY
+pub fn incr_by(command: &str, args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new(command, args);
+    let parsed = args.next_key().and_then(|key| {
+        let delta = match command {
+            "INCR" => 1,
+            "DECR" => -1,
+            _ => args.next_i64()?,
+        };
+        args.end()?;
+        Ok((key, delta))
+    });
+    let (key, delta) = match parsed {
+        Ok((key, delta)) if command == "DECRBY" => match delta.checked_neg() {
+            Some(delta) => (key, delta),
+            None => return Ok(RESPValue::error("ERR decrement would overflow".to_string())),
+        },
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+
+    let mut t = write_table(&table)?;
+    let entry = match get_string_entry(&mut t, key) {
+        Ok(entry) => entry,
+        Err(e) => return Ok(e),
+    };
+    let new_value = match entry {
+        Some((value, _, _)) => match value.as_integer() {
+            Some(current) => match current.checked_add(delta) {
+                Some(n) => {
+                    *value = Value::Integer(n);
+                    n
+                }
+                None => {
+                    return Ok(RESPValue::error(
+                        "ERR increment or decrement would overflow".to_string(),
+                    ))
+                }
+            },
+            None => return Ok(not_an_integer()),
+        },
+        None => {
+            t.insert(
+                key.to_string(),
+                (Value::Integer(delta), None, Access::new()),
+            );
+            delta
+        }
+    };
+    Ok(RESPValue::integer(new_value))
+}
+
+pub fn incr_by_float(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("incrbyfloat", args);
+    let parsed = args.next_key().and_then(|key| {
+        let increment = args.next_f64()?;
+        args.end()?;
+        Ok((key, increment))
+    });
+    let (key, increment) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+
+    let mut t = write_table(&table)?;
+    let entry = match get_string_entry(&mut t, key) {
+        Ok(entry) => entry,
+        Err(e) => return Ok(e),
+    };
+    let current = match &entry {
+        Some((value, _, _)) => match parse_float(&value.to_string()) {
+            Some(f) => f,
+            None => return Ok(ArgError::NotAFloat.into()),
+        },
+        None => 0.0,
+    };
+    let new_value = current + increment;
+    if !new_value.is_finite() {
+        return Ok(RESPValue::error(
+            "ERR increment would produce NaN or Infinity".to_string(),
+        ));
+    }
+
+    let formatted = format_float(new_value);
+    match entry {
+        Some((value, _, _)) => *value = Value::from_string(formatted.clone()),
+        None => {
+            t.insert(
+                key.to_string(),
+                (Value::from_string(formatted.clone()), None, Access::new()),
+            );
+        }
+    }
+    Ok(RESPValue::bulk_string(Some(formatted)))
+}
+
+// Formats floats like Redis does with "%.17Lg": plain notation unless the exponent is below -4 or
+// at least 17, and no trailing zeros. Redis adds long doubles, so the digits an f64 can't hold
+// reliably (beyond DBL_DIG) are rounded away, and 0.1 + 0.2 gives 0.3 there too.
+fn format_float(f: f64) -> String {
+    const DIGITS: usize = 15;
+    const PRECISION: i32 = 17;
+    let scientific = format!("{:.*e}", DIGITS - 1, f.abs());
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    let digits = mantissa.replace('.', "");
+    let digits = digits.trim_end_matches('0');
+    let sign = if f.is_sign_negative() { "-" } else { "" };
+    if digits.is_empty() {
+        return format!("{}0", sign);
+    }
+    if !(-4..PRECISION).contains(&exponent) {
+        let (first, rest) = digits.split_at(1);
+        let point = if rest.is_empty() { "" } else { "." };
+        let exponent_sign = if exponent < 0 { '-' } else { '+' };
+        return format!(
+            "{}{}{}{}e{}{:02}",
+            sign,
+            first,
+            point,
+            rest,
+            exponent_sign,
+            exponent.abs()
+        );
+    }
+    if exponent < 0 {
+        let zeros = "0".repeat((-exponent - 1) as usize);
+        return format!("{}0.{}{}", sign, zeros, digits);
+    }
+    let integer_len = exponent as usize + 1;
+    if digits.len() <= integer_len {
+        let zeros = "0".repeat(integer_len - digits.len());
+        format!("{}{}{}", sign, digits, zeros)
+    } else {
+        let (integer, fraction) = digits.split_at(integer_len);
+        format!("{}{}.{}", sign, integer, fraction)
+    }
+}
+
+pub fn append(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let parsed = Args::new("append", args).parse(|args| Ok((args.next_key()?, args.next_str()?)));
+    let (key, suffix) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let entry = match get_string_entry(&mut t, key) {
+        Ok(entry) => entry,
+        Err(e) => return Ok(e),
+    };
+    let len = match entry {
+        Some((value, _, _)) => {
+            // Appended to the stored text, growing it like Redis' sdscatlen instead of copying it
+            let s = value.string_mut().unwrap();
+            let len = s.chars().count() + suffix.chars().count();
+            if len > MAX_STRING_LEN {
+                value.normalize();
+                return Ok(RESPValue::error(
+                    "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+                ));
+            }
+            s.push_str(suffix);
+            value.normalize();
+            len
+        }
+        None => {
+            set_string(&mut t, key, suffix.to_string(), None);
+            suffix.chars().count()
+        }
+    };
+    Ok(RESPValue::integer(len as i64))
+}
+
+pub fn strlen(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let key = match Args::new("strlen", args).parse(Args::next_key) {
+        Ok(key) => key,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let len = match get_string(&mut t, key) {
+        Ok(s) => s.map_or(0, |s| s.chars().count()),
+        Err(e) => return Ok(e),
+    };
+    Ok(RESPValue::integer(len as i64))
+}
+
+pub fn getrange(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let parsed = Args::new("getrange", args)
+        .parse(|args| Ok((args.next_key()?, args.next_i64()?, args.next_i64()?)));
+    let (key, mut start, mut end) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let s = match get_string(&mut t, key) {
+        Ok(s) => string_to_bytes(&s.unwrap_or_default()),
+        Err(e) => return Ok(e),
+    };
+    let len = s.len() as i64;
+    let empty = || Ok(RESPValue::bulk_string(Some(String::new())));
+    if start < 0 && end < 0 && start > end {
+        return empty();
+    }
+    if start < 0 {
+        start += len;
+    }
+    if end < 0 {
+        end += len;
+    }
+    start = start.max(0);
+    end = end.max(0).min(len - 1);
+    if len == 0 || start > end {
+        return empty();
+    }
+    Ok(RESPValue::bulk_string(Some(bytes_to_string(
+        &s[start as usize..=end as usize],
+    ))))
+}
+
+pub fn setrange(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let parsed = Args::new("setrange", args)
+        .parse(|args| Ok((args.next_key()?, args.next_i64()?, args.next_str()?)));
+    let (key, offset, patch) = match parsed {
+        Ok((_, offset, _)) if offset < 0 => {
+            return Ok(RESPValue::error("ERR offset is out of range".to_string()))
+        }
+        Ok((key, offset, patch)) => (key, offset as usize, patch),
+        Err(e) => return Ok(e.into()),
+    };
+    let patch_len = patch.chars().count();
+    if offset + patch_len > MAX_STRING_LEN {
+        return Ok(RESPValue::error(
+            "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+        ));
+    }
+
+    let mut t = write_table(&table)?;
+    let entry = match get_string_entry(&mut t, key) {
+        Ok(entry) => entry,
+        Err(e) => return Ok(e),
+    };
+    let value = match entry {
+        // An empty patch never creates or pads the key
+        Some((value, _, _)) if patch_len == 0 => {
+            let len = value.string_mut().unwrap().chars().count();
+            value.normalize();
+            return Ok(RESPValue::integer(len as i64));
+        }
+        None if patch_len == 0 => return Ok(RESPValue::integer(0)),
+        Some((value, _, _)) => value,
+        None => {
+            set_string(&mut t, key, String::new(), None);
+            &mut t.get_mut(key).unwrap().0
+        }
+    };
+    // Patched in place, only the text after the patch moves when it changes the encoded width
+    let s = value.string_mut().unwrap();
+    let len = s.chars().count();
+    if len < offset + patch_len {
+        s.extend(std::iter::repeat_n('\0', offset + patch_len - len));
+    }
+    let start = char_to_byte(s, offset);
+    let end = start + char_to_byte(&s[start..], patch_len);
+    s.replace_range(start..end, patch);
+    value.normalize();
+    Ok(RESPValue::integer(len.max(offset + patch_len) as i64))
+}
+
+// Byte position in s of the character at index, the length of s past its end
+fn char_to_byte(s: &str, index: usize) -> usize {
+    s.char_indices().nth(index).map_or(s.len(), |(i, _)| i)
+}
+
+pub fn getdel(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let key = match Args::new("getdel", args).parse(Args::next_key) {
+        Ok(key) => key,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let value = match get_string(&mut t, key) {
+        Ok(value) => value,
+        Err(e) => return Ok(e),
+    };
+    t.remove(key);
+    Ok(RESPValue::bulk_string(value))
+}
+
+pub fn getex(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("getex", args);
+    let (key, options) = match args.next_key().and_then(|key| Ok((key, args.rest()?))) {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    // None leaves the expiry untouched, Some(None) removes it
+    let new_ttl = match options[..] {
+        [] => None,
+        [option] if option.eq_ignore_ascii_case("PERSIST") => Some(None),
+        [option, time] => {
+            let unit = option.to_uppercase();
+            if !["EX", "PX", "EXAT", "PXAT"].contains(&unit.as_str()) {
+                return Ok(syntax_error());
+            }
+            match parse_expire_time("getex", &unit, time) {
+                Ok(ttl) => Some(Some(ttl)),
+                Err(e) => return Ok(e),
+            }
+        }
+        _ => return Ok(syntax_error()),
+    };
+
+    let mut t = write_table(&table)?;
+    let entry = match get_string_entry(&mut t, key) {
+        Ok(entry) => entry,
+        Err(e) => return Ok(e),
+    };
+    let value = match entry {
+        Some((value, _, _)) => value.to_string(),
+        None => return Ok(RESPValue::bulk_string(None)),
+    };
+    match new_ttl {
+        Some(Some(ttl)) if ttl == Duration::ZERO => {
+            t.remove(key);
+        }
+        Some(ttl) => t.set_expiry(key, ttl.map(|d| (Instant::now(), d))),
+        None => {}
+    }
+    Ok(RESPValue::bulk_string(Some(value)))
+}
+
+pub fn getset(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let parsed = Args::new("getset", args).parse(|args| Ok((args.next_key()?, args.next_str()?)));
+    let (key, new_value) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    let old_value = match get_string(&mut t, key) {
+        Ok(value) => value,
+        Err(e) => return Ok(e),
+    };
+    set_string(&mut t, key, new_value.to_string(), None);
+    Ok(RESPValue::bulk_string(old_value))
+}
+
+pub fn mget(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let keys = match Args::new("mget", args).rest_non_empty() {
+        Ok(keys) => keys,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    Ok(RESPValue::Array(Some(
+        keys.into_iter()
+            // Keys holding other types are returned as nil
+            .map(|key| RESPValue::bulk_string(get_string(&mut t, key).unwrap_or_default()))
+            .collect(),
+    )))
+}
+
+// Handles MSET and MSETNX. All keys are set under a single lock.
+pub fn mset(
+    args: &[BulkString],
+    table: Table,
+    only_if_none_exist: bool,
+) -> Result<RESPValue, String> {
+    let command = if only_if_none_exist { "msetnx" } else { "mset" };
+    let args = match Args::new(command, args).rest_non_empty() {
+        Ok(args) if args.len().is_multiple_of(2) => args,
+        Ok(_) => return Ok(wrong_number_of_arguments(command)),
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    if only_if_none_exist
+        && args
+            .chunks(2)
+            .any(|pair| get_live_entry(&mut t, pair[0]).is_some())
+    {
+        return Ok(RESPValue::integer(0));
+    }
+    for pair in args.chunks(2) {
+        set_string(&mut t, pair[0], pair[1].to_string(), None);
+    }
+    Ok(if only_if_none_exist {
+        RESPValue::integer(1)
+    } else {
+        ok()
+    })
+}
+
+pub fn setnx(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let parsed = Args::new("setnx", args).parse(|args| Ok((args.next_key()?, args.next_str()?)));
+    let (key, value) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut t = write_table(&table)?;
+    if get_live_entry(&mut t, key).is_some() {
+        return Ok(RESPValue::integer(0));
+    }
+    set_string(&mut t, key, value.to_string(), None);
+    Ok(RESPValue::integer(1))
+}
+
+// Handles SETEX (`unit` "EX") and PSETEX (`unit` "PX")
+pub fn setex(args: &[BulkString], table: Table, unit: &str) -> Result<RESPValue, String> {
+    let command = if unit == "EX" { "setex" } else { "psetex" };
+    let parsed = Args::new(command, args)
+        .parse(|args| Ok((args.next_key()?, args.next_str()?, args.next_str()?)));
+    let (key, time, value) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let ttl = match parse_expire_time(command, unit, time) {
+        Ok(ttl) => ttl,
+        Err(e) => return Ok(e),
+    };
+    let mut t = write_table(&table)?;
+    set_string(&mut t, key, value.to_string(), Some(ttl));
+    Ok(ok())
+}
+
+pub fn lcs(args: &[BulkString], table: Table) -> Result<RESPValue, String> {
+    let mut args = Args::new("lcs", args);
+    let parsed = args
+        .next_key()
+        .and_then(|key1| Ok((key1, args.next_key()?, args.rest()?)));
+    let (key1, key2, options) = match parsed {
+        Ok(parsed) => parsed,
+        Err(e) => return Ok(e.into()),
+    };
+    let mut get_len = false;
+    let mut get_idx = false;
+    let mut with_match_len = false;
+    let mut min_match_len = 0;
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "LEN" => get_len = true,
+            "IDX" => get_idx = true,
+            "WITHMATCHLEN" => with_match_len = true,
+            "MINMATCHLEN" => match options.next().map(value::parse_integer) {
+                Some(Some(n)) => min_match_len = n.max(0) as usize,
+                Some(None) => return Ok(not_an_integer()),
+                None => return Ok(syntax_error()),
+            },
+            _ => return Ok(syntax_error()),
+        }
+    }
+    if get_len && get_idx {
+        return Ok(RESPValue::error(
+            "ERR If you want both the length and indexes, please just use IDX.".to_string(),
+        ));
+    }
+
+    let mut t = write_table(&table)?;
+    let (a, b) = match (get_string(&mut t, key1), get_string(&mut t, key2)) {
+        (Ok(a), Ok(b)) => (
+            string_to_bytes(&a.unwrap_or_default()),
+            string_to_bytes(&b.unwrap_or_default()),
+        ),
+        _ => return Ok(wrong_type()),
+    };
+    drop(t);
+
+    if get_len {
+        return Ok(RESPValue::integer(lcs_len(&a, &b) as i64));
+    }
+    // Like Redis, the table needed to walk the LCS back is limited to proto-max-bulk-len
+    let cells = (a.len() as u64 + 1) * (b.len() as u64 + 1);
+    if cells.saturating_mul(size_of::<u32>() as u64) > MAX_STRING_LEN as u64 {
+        return Ok(RESPValue::error(
+            "ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len"
+                .to_string(),
+        ));
+    }
+    let (lcs, matches) = longest_common_subsequence(&a, &b);
+    if !get_idx {
+        return Ok(RESPValue::bulk_string(Some(bytes_to_string(&lcs))));
+    }
+
+    let range = |(start, end): (usize, usize)| {
+        RESPValue::Array(Some(vec![
+            RESPValue::integer(start as i64),
+            RESPValue::integer(end as i64),
+        ]))
+    };
+    let matches = matches
+        .into_iter()
+        .filter(|m| m.len >= min_match_len)
+        .map(|m| {
+            let mut fields = vec![range(m.a), range(m.b)];
+            if with_match_len {
+                fields.push(RESPValue::integer(m.len as i64));
+            }
+            RESPValue::Array(Some(fields))
+        })
+        .collect();
+    Ok(RESPValue::Array(Some(vec![
+        RESPValue::bulk_string(Some("matches".to_string())),
+        RESPValue::Array(Some(matches)),
+        RESPValue::bulk_string(Some("len".to_string())),
+        RESPValue::integer(lcs.len() as i64),
+    ])))
+}
+
+#[derive(Debug, PartialEq)]
+struct LcsMatch {
+    // Inclusive ranges in each string
+    a: (usize, usize),
+    b: (usize, usize),
+    len: usize,
+}
+
+// Length of the LCS, keeping only the previous row of lengths so that any two strings fit
+fn lcs_len(a: &[u8], b: &[u8]) -> usize {
+    let (a, b) = if a.len() < b.len() { (b, a) } else { (a, b) };
+    let mut previous = vec![0u32; b.len() + 1];
+    let mut row = vec![0u32; b.len() + 1];
+    for &x in a {
+        for j in 1..=b.len() {
+            row[j] = if x == b[j - 1] {
+                previous[j - 1] + 1
+            } else {
+                previous[j].max(row[j - 1])
+            };
+        }
+        std::mem::swap(&mut previous, &mut row);
+    }
+    previous[b.len()] as usize
+}
+
+// Returns the LCS and its contiguous matching ranges, last match first, the same way Redis walks them
+fn longest_common_subsequence(a: &[u8], b: &[u8]) -> (Vec<u8>, Vec<LcsMatch>) {
+    // lengths[i][j] is the LCS length of a[..i] and b[..j]
+    let mut lengths = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            lengths[i][j] = if a[i - 1] == b[j - 1] {
+                lengths[i - 1][j - 1] + 1
+            } else {
+                lengths[i - 1][j].max(lengths[i][j - 1])
+            };
+        }
+    }
+
+    let mut lcs = vec![];
+    let mut matches = vec![];
+    let (mut i, mut j) = (a.len(), b.len());
+    // (a_start, a_end, b_start, b_end) of the match being extended backwards
+    let mut current: Option<(usize, usize, usize, usize)> = None;
+    while i > 0 && j > 0 {
+        let mut emit = false;
+        if a[i - 1] == b[j - 1] {
+            lcs.push(a[i - 1]);
+            current = match current {
+                None => Some((i - 1, i - 1, j - 1, j - 1)),
+                Some((a_start, a_end, b_start, b_end)) if a_start == i && b_start == j => {
+                    Some((a_start - 1, a_end, b_start - 1, b_end))
+                }
+                other => {
+                    emit = true;
+                    other
+                }
+            };
+            if matches!(current, Some((0, _, _, _)) | Some((_, _, 0, _))) {
+                emit = true;
+            }
+            i -= 1;
+            j -= 1;
+        } else {
+            if lengths[i - 1][j] > lengths[i][j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            emit = current.is_some();
+        }
+
+        if emit {
+            if let Some((a_start, a_end, b_start, b_end)) = current.take() {
+                matches.push(LcsMatch {
+                    a: (a_start, a_end),
+                    b: (b_start, b_end),
+                    len: a_end - a_start + 1,
+                });
+            }
+        }
+    }
+    lcs.reverse();
+    (lcs, matches)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::RwLock;
+
+    fn args(args: &[&str]) -> Vec<BulkString> {
+        args.iter()
+            .map(|s| BulkString::from(s.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_format_float() {
+        assert_eq!(format_float(0.1 + 0.2), "0.3");
+        assert_eq!(format_float(5e20), "5e+20");
+        assert_eq!(format_float(3.0), "3");
+        assert_eq!(format_float(-2.5), "-2.5");
+        assert_eq!(format_float(0.0), "0");
+        assert_eq!(format_float(1e16), "10000000000000000");
+        assert_eq!(format_float(1e17), "1e+17");
+        assert_eq!(format_float(0.0001), "0.0001");
+        assert_eq!(format_float(0.00001), "1e-05");
+        assert_eq!(format_float(-1.5e-7), "-1.5e-07");
+        assert_eq!(format_float(1234.5678), "1234.5678");
+    }
+
+    #[test]
+    fn test_incr_by_float() {
+        let table: Table = Arc::new(RwLock::new(Keyspace::default()));
+        let incr = |increment: &str| incr_by_float(&args(&["f", increment]), table.clone());
+        let bulk = |s: &str| Ok(RESPValue::bulk_string(Some(s.to_string())));
+        assert_eq!(incr("0.1"), bulk("0.1"));
+        assert_eq!(incr("0.2"), bulk("0.3"));
+        assert_eq!(incr("-0.3"), bulk("0"));
+        assert_eq!(incr("5e20"), bulk("5e+20"));
+        assert_eq!(incr("-5e20"), bulk("0"));
+    }
+
+    #[test]
+    fn test_longest_common_subsequence() {
+        let (lcs, matches) = longest_common_subsequence(b"ohmytext", b"mynewtext");
+        assert_eq!(lcs, b"mytext".to_vec());
+        assert_eq!(
+            matches,
+            vec![
+                LcsMatch {
+                    a: (4, 7),
+                    b: (5, 8),
+                    len: 4
+                },
+                LcsMatch {
+                    a: (2, 3),
+                    b: (0, 1),
+                    len: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lcs_len() {
+        assert_eq!(lcs_len(b"ohmytext", b"mynewtext"), 6);
+        assert_eq!(lcs_len(b"mynewtext", b"ohmytext"), 6);
+        assert_eq!(lcs_len(b"", b"abc"), 0);
+        assert_eq!(lcs_len(b"abc", b"xyz"), 0);
+    }
+
+    #[test]
+    fn test_append_setrange() {
+        let table: Table = Arc::new(RwLock::new(Keyspace::default()));
+        let integer = |n| Ok(RESPValue::integer(n));
+        assert_eq!(append(&args(&["s", "12"]), table.clone()), integer(2));
+        assert_eq!(append(&args(&["s", "3"]), table.clone()), integer(3));
+        assert_eq!(table.read().unwrap()["s"].0, Value::Integer(123));
+        assert_eq!(
+            setrange(&args(&["s", "1", "\u{e9}x"]), table.clone()),
+            integer(3)
+        );
+        assert_eq!(setrange(&args(&["s", "5", "y"]), table.clone()), integer(6));
+        assert_eq!(setrange(&args(&["s", "0", ""]), table.clone()), integer(6));
+        let expected = Value::String("1\u{e9}x\0\0y".to_string());
+        assert_eq!(table.read().unwrap()["s"].0, expected);
+        assert_eq!(setrange(&args(&["n", "2", "a"]), table.clone()), integer(3));
+        assert_eq!(setrange(&args(&["e", "2", ""]), table.clone()), integer(0));
+        assert!(!table.read().unwrap().contains_key("e"));
+    }
+
+    #[test]
+    fn test_longest_common_subsequence_empty() {
+        assert_eq!(longest_common_subsequence(b"", b"abc"), (vec![], vec![]));
+        assert_eq!(longest_common_subsequence(b"abc", b"xyz"), (vec![], vec![]));
+    }
+}
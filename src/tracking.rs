@@ -0,0 +1,383 @@
+// Client side caching: the keys tracking connections read, or the prefixes they asked to be told
+// about, and the invalidation messages sent to them when those keys change, like Redis'
+// tracking.c
+use crate::commands;
+use crate::strings::syntax_error;
+use crate::strings::wrong_number_of_arguments;
+use crate::value;
+use crate::Server;
+use crate::Session;
+use redis_starter_rust::RESPValue;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+// RESP2 connections have no push replies, they get invalidations as messages of this channel
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Options {
+    // Broadcasting sends invalidations for every key starting with one of the prefixes, whether
+    // the connection read it or not
+    bcast: bool,
+    prefixes: BTreeSet<String>,
+    // Only the keys read right after CLIENT CACHING YES are tracked
+    optin: bool,
+    // The keys read right after CLIENT CACHING NO aren't tracked
+    optout: bool,
+    // No invalidations for the keys the connection changes itself
+    noloop: bool,
+    // The connection invalidations are sent to instead
+    redirect: Option<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<u64, Options>,
+    // Connections that read each key, tracked until it's invalidated
+    keys: HashMap<String, BTreeSet<u64>>,
+    // CLIENT CACHING YES or NO, for the next command of the connection only
+    caching: HashMap<u64, bool>,
+}
+
+#[derive(Default)]
+pub struct Tracking {
+    state: Mutex<State>,
+}
+
+impl Tracking {
+    fn state(&self) -> Result<MutexGuard<'_, State>, String> {
+        self.state
+            .lock()
+            .map_err(|e| format!("Failed to acquire lock for tracking {}", e))
+    }
+
+    // Tracks the keys the command read for the connection, if it wants them
+    pub fn record_command(&self, client_id: u64, args: &[&str]) -> Result<(), String> {
+        let command = match commands::lookup(args) {
+            Some(command) if command.name != "client|caching" => command,
+            _ => return Ok(()),
+        };
+        let mut state = self.state()?;
+        let caching = state.caching.remove(&client_id);
+        let tracked = match state.clients.get(&client_id) {
+            Some(options) if options.bcast => false,
+            Some(options) if options.optin => caching == Some(true),
+            Some(options) if options.optout => caching != Some(false),
+            Some(_) => true,
+            None => false,
+        };
+        if tracked && command.has_category("read") {
+            for key in command.keys(args) {
+                state
+                    .keys
+                    .entry(key.to_string())
+                    .or_default()
+                    .insert(client_id);
+            }
+        }
+        Ok(())
+    }
+
+    // Tells the connections caching the keys that they changed. `client_id` is the connection
+    // that changed them, None when they expired or were evicted.
+    pub fn invalidate(
+        &self,
+        keys: &[&str],
+        client_id: Option<u64>,
+        server: &Server,
+    ) -> Result<(), String> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut invalidated: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        {
+            let mut state = self.state()?;
+            for key in keys {
+                for id in state.keys.remove(*key).unwrap_or_default() {
+                    invalidated.entry(id).or_default().push(key.to_string());
+                }
+            }
+            for (id, options) in &state.clients {
+                if !options.bcast {
+                    continue;
+                }
+                let matching: Vec<String> = keys
+                    .iter()
+                    .filter(|key| options.prefixes.iter().any(|p| key.starts_with(p.as_str())))
+                    .map(|key| key.to_string())
+                    .collect();
+                if !matching.is_empty() {
+                    invalidated.entry(*id).or_default().extend(matching);
+                }
+            }
+            // Connections may have stopped tracking since they read the keys
+            invalidated.retain(|id, _| {
+                state
+                    .clients
+                    .get(id)
+                    .is_some_and(|options| !(options.noloop && client_id == Some(*id)))
+            });
+        }
+        for (id, mut keys) in invalidated {
+            keys.sort();
+            keys.dedup();
+            self.send(id, Some(keys), server)?;
+        }
+        Ok(())
+    }
+
+    // Tells every tracking connection to drop its whole cache, after FLUSHDB and FLUSHALL
+    pub fn invalidate_all(&self, server: &Server) -> Result<(), String> {
+        let ids: Vec<u64> = {
+            let mut state = self.state()?;
+            state.keys.clear();
+            state.clients.keys().copied().collect()
+        };
+        for id in ids {
+            self.send(id, None, server)?;
+        }
+        Ok(())
+    }
+
+    // Sends the invalidation of the keys, or of everything when None, to the connection or the
+    // one it redirects to
+    fn send(
+        &self,
+        client_id: u64,
+        keys: Option<Vec<String>>,
+        server: &Server,
+    ) -> Result<(), String> {
+        let redirect = match self.state()?.clients.get(&client_id) {
+            Some(options) => options.redirect,
+            None => return Ok(()),
+        };
+        let target = redirect.unwrap_or(client_id);
+        let (pushes, resp3) = match server.clients.pushes(target)? {
+            Some(target) => target,
+            None => {
+                // Like Redis, the connection is told when the one it redirected to is gone
+                if let Some((pushes, true)) = server.clients.pushes(client_id)? {
+                    let _ = pushes.send(RESPValue::Array(Some(vec![
+                        bulk("tracking-redir-broken"),
+                        RESPValue::integer(target as i64),
+                    ])));
+                }
+                return Ok(());
+            }
+        };
+        let keys = RESPValue::Array(keys.map(|keys| keys.iter().map(|k| bulk(k)).collect()));
+        let message = if resp3 {
+            RESPValue::Array(Some(vec![bulk("invalidate"), keys]))
+        } else if server.pubsub.subscriptions(target)? > 0 {
+            RESPValue::Array(Some(vec![bulk("message"), bulk(INVALIDATE_CHANNEL), keys]))
+        } else {
+            // Without pushes nor subscription there is no way to tell a RESP2 connection
+            return Ok(());
+        };
+        // Fails only when the target is going away
+        let _ = pushes.send(message);
+        Ok(())
+    }
+
+    // Forgets the connection that went away
+    pub fn remove_client(&self, client_id: u64) -> Result<(), String> {
+        let mut state = self.state()?;
+        disable(&mut state, client_id);
+        Ok(())
+    }
+}
+
+fn bulk(s: &str) -> RESPValue {
+    RESPValue::bulk_string(Some(s.to_string()))
+}
+
+fn disable(state: &mut State, client_id: u64) {
+    state.clients.remove(&client_id);
+    state.caching.remove(&client_id);
+    state.keys.retain(|_, ids| {
+        ids.remove(&client_id);
+        !ids.is_empty()
+    });
+}
+
+// CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX prefix ...] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]
+pub fn tracking(args: &[&str], session: &Session, server: &Server) -> Result<RESPValue, String> {
+    let (on, options) = match args.split_first() {
+        Some((on, options)) if on.eq_ignore_ascii_case("ON") => (true, options),
+        Some((off, options)) if off.eq_ignore_ascii_case("OFF") => (false, options),
+        Some(_) => return Ok(syntax_error()),
+        None => return Ok(wrong_number_of_arguments("client|tracking")),
+    };
+    let mut new = Options::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "BCAST" => new.bcast = true,
+            "OPTIN" => new.optin = true,
+            "OPTOUT" => new.optout = true,
+            "NOLOOP" => new.noloop = true,
+            "REDIRECT" => match options.next().map(|id| value::parse_integer(id)) {
+                Some(Some(id)) if id > 0 => new.redirect = Some(id as u64),
+                Some(_) => {
+                    return Ok(RESPValue::error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    ))
+                }
+                None => return Ok(syntax_error()),
+            },
+            "PREFIX" => match options.next() {
+                Some(prefix) => {
+                    new.prefixes.insert(prefix.to_string());
+                }
+                None => return Ok(syntax_error()),
+            },
+            _ => return Ok(syntax_error()),
+        }
+    }
+
+    let mut state = server.tracking.state()?;
+    if !on {
+        disable(&mut state, session.client_id);
+        return Ok(RESPValue::simple_string("OK".to_string()));
+    }
+    let error = |message: &str| Ok(RESPValue::error(format!("ERR {}", message)));
+    if !new.bcast && !new.prefixes.is_empty() {
+        return error("PREFIX option requires BCAST mode to be enabled");
+    }
+    if new.optin && new.optout {
+        return error("You can't use both OPTIN and OPTOUT");
+    }
+    if new.bcast && (new.optin || new.optout) {
+        return error("OPTIN and OPTOUT are not compatible with BCAST");
+    }
+    if let Some(id) = new.redirect {
+        if id == session.client_id {
+            return error("You can't redirect to yourself");
+        }
+        if server.clients.pushes(id)?.is_none() {
+            return error("The client ID you want redirect to does not exist");
+        }
+    }
+    if let Some(current) = state.clients.get(&session.client_id) {
+        if current.bcast != new.bcast {
+            return error(
+                "You can't switch BCAST mode on/off before disabling tracking for this client, \
+                 and then re-enabling it with a different mode.",
+            );
+        }
+        // Calling it again adds prefixes
+        new.prefixes.extend(current.prefixes.iter().cloned());
+    }
+    // Without prefixes, broadcasting is for every key
+    if new.bcast && new.prefixes.is_empty() {
+        new.prefixes.insert(String::new());
+    }
+    let prefixes: Vec<&String> = new.prefixes.iter().collect();
+    for (i, prefix) in prefixes.iter().enumerate() {
+        if let Some(other) = prefixes[i + 1..]
+            .iter()
+            .find(|other| other.starts_with(prefix.as_str()))
+        {
+            return error(&format!(
+                "Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client \
+                 must not overlap.",
+                other, prefix
+            ));
+        }
+    }
+    state.clients.insert(session.client_id, new);
+    Ok(RESPValue::simple_string("OK".to_string()))
+}
+
+// CLIENT CACHING YES|NO, deciding whether the next command's keys are tracked
+pub fn caching(args: &[&str], session: &Session, server: &Server) -> Result<RESPValue, String> {
+    let yes = match args {
+        [yes] if yes.eq_ignore_ascii_case("YES") => true,
+        [no] if no.eq_ignore_ascii_case("NO") => false,
+        [_] => return Ok(syntax_error()),
+        _ => return Ok(wrong_number_of_arguments("client|caching")),
+    };
+    let mut state = server.tracking.state()?;
+    let error = |message: &str| Ok(RESPValue::error(format!("ERR {}", message)));
+    match state.clients.get(&session.client_id) {
+        Some(options) if yes && !options.optin => {
+            error("CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.")
+        }
+        Some(options) if !yes && !options.optout => {
+            error("CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.")
+        }
+        Some(_) => {
+            state.caching.insert(session.client_id, yes);
+            Ok(RESPValue::simple_string("OK".to_string()))
+        }
+        None => error(
+            "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or \
+             OPTOUT mode enabled",
+        ),
+    }
+}
+
+// CLIENT GETREDIR: -1 when not tracking, 0 when not redirecting
+pub fn getredir(session: &Session, server: &Server) -> Result<RESPValue, String> {
+    let state = server.tracking.state()?;
+    Ok(RESPValue::integer(
+        match state.clients.get(&session.client_id) {
+            Some(options) => options.redirect.map_or(0, |id| id as i64),
+            None => -1,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tracking(clients: &[(u64, Options)]) -> Tracking {
+        let tracking = Tracking::default();
+        tracking.state().unwrap().clients = clients.iter().cloned().collect();
+        tracking
+    }
+
+    fn tracked(tracking: &Tracking, key: &str) -> Vec<u64> {
+        let state = tracking.state().unwrap();
+        state
+            .keys
+            .get(key)
+            .map_or(vec![], |ids| ids.iter().copied().collect())
+    }
+
+    #[test]
+    fn test_record_command() {
+        let optin = Options {
+            optin: true,
+            ..Options::default()
+        };
+        let bcast = Options {
+            bcast: true,
+            ..Options::default()
+        };
+        let tracking = tracking(&[(1, Options::default()), (2, optin), (3, bcast)]);
+        for id in 1..=4 {
+            tracking.record_command(id, &["MGET", "a", "b"]).unwrap();
+        }
+        assert_eq!(tracked(&tracking, "a"), vec![1]);
+
+        tracking.state().unwrap().caching.insert(2, true);
+        tracking.record_command(2, &["GET", "c"]).unwrap();
+        tracking.record_command(2, &["GET", "d"]).unwrap();
+        assert_eq!(tracked(&tracking, "c"), vec![2]);
+        assert!(tracked(&tracking, "d").is_empty());
+
+        // Writes aren't tracked
+        tracking.record_command(1, &["SET", "e", "1"]).unwrap();
+        assert!(tracked(&tracking, "e").is_empty());
+
+        let mut state = tracking.state().unwrap();
+        disable(&mut state, 1);
+        assert!(!state.keys.contains_key("a"));
+        assert!(state.keys.contains_key("c"));
+    }
+}
@@ -0,0 +1,125 @@
+// Values stored in the table
+use crate::sorted_set::SortedSet;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    // Strings that are the canonical representation of an i64 are kept as integers
+    Integer(i64),
+    SortedSet(SortedSet),
+}
+
+impl Value {
+    pub fn from_string(s: String) -> Self {
+        match parse_integer(&s) {
+            Some(i) => Self::Integer(i),
+            None => Self::String(s),
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Self::Integer(i) => Some(*i),
+            Self::String(s) => parse_integer(s),
+            Self::SortedSet(_) => None,
+        }
+    }
+
+    // The text of a string value to modify in place, integers are turned into their text first.
+    // Callers call `normalize` once done, in case the text is an integer again.
+    pub fn string_mut(&mut self) -> Option<&mut String> {
+        if let Self::Integer(i) = self {
+            *self = Self::String(i.to_string());
+        }
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn normalize(&mut self) {
+        // No i64 takes more than 20 characters, so long strings are left without parsing them
+        if let Self::String(s) = self {
+            if s.len() <= 20 {
+                *self = Self::from_string(std::mem::take(s));
+            }
+        }
+    }
+
+    pub fn is_string(&self) -> bool {
+        matches!(self, Self::String(_) | Self::Integer(_))
+    }
+
+    // Bytes allocated for the value besides the Value itself, approximately
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            Self::String(s) => s.capacity(),
+            Self::Integer(_) => 0,
+            Self::SortedSet(set) => set.memory_usage(),
+        }
+    }
+
+    // Name reported by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) | Self::Integer(_) => "string",
+            Self::SortedSet(_) => "zset",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "{}", s),
+            Self::Integer(i) => write!(f, "{}", i),
+            // Only strings have a textual form, callers check the type first
+            Self::SortedSet(_) => Ok(()),
+        }
+    }
+}
+
+pub use redis_starter_rust::parse_integer;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_string() {
+        assert_eq!(Value::from_string("123".to_string()), Value::Integer(123));
+        assert_eq!(Value::from_string("-5".to_string()), Value::Integer(-5));
+        for s in [
+            "0123",
+            "+1",
+            " 1",
+            "-0",
+            "1.5",
+            "99999999999999999999",
+            "abc",
+        ] {
+            assert_eq!(
+                Value::from_string(s.to_string()),
+                Value::String(s.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_string_mut() {
+        let mut value = Value::Integer(12);
+        value.string_mut().unwrap().push('a');
+        value.normalize();
+        assert_eq!(value, Value::String("12a".to_string()));
+        value.string_mut().unwrap().pop();
+        value.normalize();
+        assert_eq!(value, Value::Integer(12));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Value::Integer(-42).to_string(), "-42");
+        assert_eq!(Value::String("hello".to_string()).to_string(), "hello");
+    }
+}
@@ -0,0 +1,247 @@
+// Runs masters, replicas and sentinels or cluster nodes as separate processes, and checks that the
+// keys written to a master are still there on the replica promoted once the master dies.
+use redis_starter_rust::RESPValue;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+const MASTER_PORT: u16 = 17101;
+const REPLICA_PORT: u16 = 17102;
+const SENTINEL_PORT: u16 = 17103;
+// Three masters and a replica of the first, their buses listening 10000 ports higher
+const CLUSTER_PORTS: [u16; 4] = [17201, 17202, 17203, 17204];
+
+// Killed when dropped, so that a failed assertion doesn't leave the servers running
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start(args: &[&str]) -> Process {
+    let child = Command::new(env!("CARGO_BIN_EXE_redis-starter-rust"))
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start the server");
+    Process(child)
+}
+
+// Sends one command on a new connection and returns its reply, None if the server can't be reached
+fn call(port: u16, command: &[&str]) -> Option<RESPValue> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(2))).ok()?;
+    let command = RESPValue::Array(Some(
+        command
+            .iter()
+            .map(|arg| RESPValue::bulk_string(Some(arg.to_string())))
+            .collect(),
+    ));
+    stream.write_all(&command.to_bytes()).ok()?;
+    let mut reply = vec![];
+    let mut chunk = [0; 4096];
+    loop {
+        let n = stream.read(&mut chunk).ok()?;
+        if n == 0 {
+            return None;
+        }
+        reply.extend_from_slice(&chunk[..n]);
+        if let Ok((value, _)) = RESPValue::parse(&reply) {
+            return Some(value);
+        }
+    }
+}
+
+fn bulk(s: &str) -> Option<RESPValue> {
+    Some(RESPValue::bulk_string(Some(s.to_string())))
+}
+
+fn wait_for(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_keys_survive_failover() {
+    let dir = temp_dir("failover");
+    let dir_arg = dir.to_str().unwrap();
+    let mut master = start(&["--port", &MASTER_PORT.to_string(), "--dir", dir_arg]);
+    let _replica = start(&["--port", &REPLICA_PORT.to_string(), "--dir", dir_arg]);
+    let ping = Some(RESPValue::simple_string("PONG".to_string()));
+    let up = |port| wait_for(Duration::from_secs(10), || call(port, &["PING"]) == ping);
+    assert!(up(MASTER_PORT) && up(REPLICA_PORT));
+
+    for i in 0..100 {
+        let (key, value) = (format!("key:{}", i), i.to_string());
+        let reply = call(MASTER_PORT, &["SET", &key, &value]);
+        assert_eq!(reply, Some(RESPValue::simple_string("OK".to_string())));
+    }
+    let reply = call(
+        REPLICA_PORT,
+        &["REPLICAOF", "127.0.0.1", &MASTER_PORT.to_string()],
+    );
+    assert_eq!(reply, Some(RESPValue::simple_string("OK".to_string())));
+    // Written once the replica is syncing, so they come through the command stream
+    for i in 100..200 {
+        let (key, value) = (format!("key:{}", i), i.to_string());
+        call(MASTER_PORT, &["SET", &key, &value]);
+    }
+    assert!(wait_for(Duration::from_secs(10), || {
+        call(REPLICA_PORT, &["GET", "key:199"]) == bulk("199")
+    }));
+
+    let config = dir.join("sentinel.conf");
+    fs::write(
+        &config,
+        format!(
+            "sentinel monitor mymaster 127.0.0.1 {} 1\n\
+             sentinel down-after-milliseconds mymaster 1000\n",
+            MASTER_PORT
+        ),
+    )
+    .unwrap();
+    let port = SENTINEL_PORT.to_string();
+    let _sentinel = start(&[config.to_str().unwrap(), "--port", &port, "--sentinel"]);
+    let get_master = ["SENTINEL", "get-master-addr-by-name", "mymaster"];
+    let address = |port: u16| {
+        Some(RESPValue::Array(Some(vec![
+            RESPValue::bulk_string(Some("127.0.0.1".to_string())),
+            RESPValue::bulk_string(Some(port.to_string())),
+        ])))
+    };
+    assert!(wait_for(Duration::from_secs(10), || {
+        call(SENTINEL_PORT, &get_master) == address(MASTER_PORT)
+    }));
+    // The sentinel only promotes replicas it knows to be synced from the INFO of the master
+    assert!(wait_for(Duration::from_secs(15), || {
+        match call(SENTINEL_PORT, &["SENTINEL", "replicas", "mymaster"]) {
+            Some(RESPValue::Array(Some(replicas))) => !replicas.is_empty(),
+            _ => false,
+        }
+    }));
+
+    master.0.kill().unwrap();
+    master.0.wait().unwrap();
+    assert!(wait_for(Duration::from_secs(30), || {
+        call(SENTINEL_PORT, &get_master) == address(REPLICA_PORT)
+    }));
+    assert!(wait_for(Duration::from_secs(10), || {
+        call(REPLICA_PORT, &["SET", "after", "failover"])
+            == Some(RESPValue::simple_string("OK".to_string()))
+    }));
+    for i in 0..200 {
+        let value = i.to_string();
+        assert_eq!(
+            call(REPLICA_PORT, &["GET", &format!("key:{}", i)]),
+            bulk(&value)
+        );
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_keys_survive_cluster_failover() {
+    let dir = temp_dir("cluster-failover");
+    let mut nodes: Vec<Process> = CLUSTER_PORTS
+        .iter()
+        .map(|port| {
+            let config = dir.join(format!("nodes-{}.conf", port));
+            start(&[
+                "--port",
+                &port.to_string(),
+                "--cluster-enabled",
+                "yes",
+                "--cluster-config-file",
+                config.to_str().unwrap(),
+                "--cluster-node-timeout",
+                "1000",
+            ])
+        })
+        .collect();
+    let ping = Some(RESPValue::simple_string("PONG".to_string()));
+    for port in CLUSTER_PORTS {
+        assert!(wait_for(Duration::from_secs(10), || call(port, &["PING"]) == ping));
+    }
+    let ok = Some(RESPValue::simple_string("OK".to_string()));
+    for port in &CLUSTER_PORTS[1..] {
+        let port = port.to_string();
+        assert_eq!(
+            call(CLUSTER_PORTS[0], &["CLUSTER", "MEET", "127.0.0.1", &port]),
+            ok
+        );
+    }
+    for (i, port) in CLUSTER_PORTS[..3].iter().enumerate() {
+        let slots: Vec<String> = (i * 16384 / 3..(i + 1) * 16384 / 3)
+            .map(|slot| slot.to_string())
+            .collect();
+        let mut command = vec!["CLUSTER", "ADDSLOTS"];
+        command.extend(slots.iter().map(String::as_str));
+        assert_eq!(call(*port, &command), ok);
+    }
+    let master_id = match call(CLUSTER_PORTS[0], &["CLUSTER", "MYID"]) {
+        Some(RESPValue::BulkString(Some(id))) => id,
+        reply => panic!("Unexpected reply to CLUSTER MYID {:?}", reply),
+    };
+    // The replica can only replicate the master once it heard of it
+    assert!(wait_for(Duration::from_secs(10), || {
+        call(CLUSTER_PORTS[3], &["CLUSTER", "REPLICATE", &master_id]) == ok
+    }));
+    let cluster_ok = |port| match call(port, &["CLUSTER", "INFO"]) {
+        Some(RESPValue::BulkString(Some(info))) => info.contains("cluster_state:ok"),
+        _ => false,
+    };
+    assert!(wait_for(Duration::from_secs(10), || {
+        CLUSTER_PORTS.iter().all(|port| cluster_ok(*port))
+    }));
+
+    // The keys of the slots of the first master, the others are redirected
+    let keys: Vec<String> = (0..300)
+        .map(|i| format!("key:{}", i))
+        .filter(|key| call(CLUSTER_PORTS[0], &["SET", key, key]) == ok)
+        .collect();
+    assert!(!keys.is_empty());
+    assert!(wait_for(Duration::from_secs(10), || {
+        match call(CLUSTER_PORTS[3], &["INFO", "replication"]) {
+            Some(RESPValue::BulkString(Some(info))) => info.contains("master_sync_in_progress:0"),
+            _ => false,
+        }
+    }));
+
+    let master = nodes.remove(0);
+    drop(master);
+    // Once elected, the replica serves the slots of its master with its keys
+    let last = keys.last().unwrap();
+    assert!(wait_for(Duration::from_secs(30), || {
+        call(CLUSTER_PORTS[3], &["GET", last]) == bulk(last)
+    }));
+    for key in &keys {
+        assert_eq!(call(CLUSTER_PORTS[3], &["GET", key]), bulk(key));
+    }
+    assert_eq!(call(CLUSTER_PORTS[3], &["SET", last, "after"]), ok);
+    drop(nodes);
+    let _ = fs::remove_dir_all(&dir);
+}